@@ -0,0 +1,429 @@
+//! Identifying songs from their audio fingerprint with [AcoustID](https://acoustid.org/webservice).
+//!
+//! Only compilation songs without an origin MBID are fingerprinted, see [AcoustIdDeriver].
+//! A lookup needs an application API key, so this is only enabled when the config has an `[acoustid]` section.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::MetadataDeriver;
+use crate::data_model::user_defined::AcoustIdConfig;
+use crate::data_model::{AlbumInputGroup, Chromaprint, CompilationInputSong, MbId, metadata};
+use crate::rate_limit::RateLimiter;
+
+const DEFAULT_BASE_URL: &str = "https://api.acoustid.org/v2";
+
+/// AcoustID allows each application three requests per second
+const REQUEST_INTERVAL: Duration = Duration::from_millis(334);
+
+/// A set of recordings AcoustID matched a fingerprint to
+#[derive(Deserialize, Debug)]
+pub struct LookupResult {
+    /// How closely the fingerprint matched, from 0 to 1
+    pub score: f64,
+    /// The MusicBrainz recordings with this audio. Missing if no recordings are known for the match.
+    #[serde(default)]
+    pub recordings: Vec<LookupRecording>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LookupRecording {
+    pub id: MbId,
+}
+
+/// The parts of the AcoustID web service used by [AcoustIdDeriver]
+#[async_trait]
+pub trait AcoustIdApi: Send + Sync {
+    /// Look up the MusicBrainz recordings whose audio matches a fingerprint
+    async fn lookup(&self, chromaprint: &Chromaprint) -> anyhow::Result<Vec<LookupResult>>;
+}
+
+/// [AcoustIdApi] over HTTP, using the base URL and API key from [AcoustIdConfig]
+pub struct AcoustIdHttp {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: String,
+    rate_limit: RateLimiter,
+}
+
+impl AcoustIdHttp {
+    pub fn new(config: &AcoustIdConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .user_agent(crate::musicbrainz::USER_AGENT)
+            .build()?;
+        Ok(Self {
+            client,
+            base_url: config
+                .base_url
+                .as_deref()
+                .unwrap_or(DEFAULT_BASE_URL)
+                .trim_end_matches('/')
+                .to_owned(),
+            api_key: config.api_key.clone(),
+            rate_limit: RateLimiter::new(REQUEST_INTERVAL),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    #[serde(default)]
+    results: Vec<LookupResult>,
+    error: Option<LookupError>,
+}
+
+#[derive(Deserialize)]
+struct LookupError {
+    message: String,
+}
+
+#[async_trait]
+impl AcoustIdApi for AcoustIdHttp {
+    async fn lookup(&self, chromaprint: &Chromaprint) -> anyhow::Result<Vec<LookupResult>> {
+        self.rate_limit.wait().await;
+        let duration = chromaprint.duration_secs.to_string();
+        // Fingerprints are too long to reliably fit in a URL, so they're POSTed as a form
+        let response = self
+            .client
+            .post(format!("{}/lookup", self.base_url))
+            .form(&[
+                ("format", "json"),
+                ("client", &self.api_key),
+                ("meta", "recordingids"),
+                ("duration", &duration),
+                ("fingerprint", &chromaprint.fingerprint),
+            ])
+            .send()
+            .await?;
+        // Errors have a JSON body explaining them, so the status isn't checked first
+        let status = response.status();
+        let response = response
+            .json::<LookupResponse>()
+            .await
+            .with_context(|| format!("AcoustID returned an unexpected response ({status})"))?;
+        match response.error {
+            Some(error) => anyhow::bail!("AcoustID returned an error: {}", error.message),
+            None => Ok(response.results),
+        }
+    }
+}
+
+/// A [MetadataDeriver] which identifies the compilation songs its inner deriver couldn't,
+/// by looking up their [Chromaprint] on AcoustID.
+/// The inner deriver does everything else, including looking up the metadata of the recordings found here.
+///
+/// Songs are only looked up if they've been fingerprinted, see [crate::fingerprint].
+pub struct AcoustIdDeriver<D: MetadataDeriver, Api: AcoustIdApi = AcoustIdHttp> {
+    inner: D,
+    api: Api,
+    min_score: f64,
+    /// Lookups that failed. They don't stop the rest of the library from resolving, but should be reported.
+    errors: Vec<anyhow::Error>,
+}
+
+impl<D: MetadataDeriver, Api: AcoustIdApi> AcoustIdDeriver<D, Api> {
+    pub fn new(inner: D, api: Api, config: &AcoustIdConfig) -> Self {
+        Self {
+            inner,
+            api,
+            min_score: config.min_score,
+            errors: vec![],
+        }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    /// Take every lookup error encountered so far
+    pub fn take_errors(&mut self) -> Vec<anyhow::Error> {
+        std::mem::take(&mut self.errors)
+    }
+}
+
+#[async_trait]
+impl<D: MetadataDeriver + Send + Sync, Api: AcoustIdApi> MetadataDeriver
+    for AcoustIdDeriver<D, Api>
+{
+    fn get_derived_album(
+        &self,
+        album_path: &Path,
+        album: &AlbumInputGroup,
+    ) -> Option<metadata::album::DerivedMetadataSource> {
+        self.inner.get_derived_album(album_path, album)
+    }
+
+    async fn try_rederive_album(
+        &mut self,
+        album_path: &Path,
+        album: &AlbumInputGroup,
+    ) -> Option<metadata::album::DerivedMetadataSource> {
+        self.inner.try_rederive_album(album_path, album).await
+    }
+
+    fn get_cached_album(
+        &self,
+        album_path: &Path,
+        src: &metadata::album::DerivedMetadataSource,
+    ) -> Option<metadata::album::Cached> {
+        self.inner.get_cached_album(album_path, src)
+    }
+
+    async fn try_recache_album(
+        &mut self,
+        album_path: &Path,
+        src: &metadata::album::DerivedMetadataSource,
+    ) -> Option<metadata::album::Cached> {
+        self.inner.try_recache_album(album_path, src).await
+    }
+
+    fn get_derived_compilation_song(
+        &self,
+        compilation_path: &Path,
+        song: &CompilationInputSong,
+    ) -> Option<metadata::song::CompilationDerivedMetadataSource> {
+        self.inner
+            .get_derived_compilation_song(compilation_path, song)
+    }
+
+    async fn try_rederive_compilation_song(
+        &mut self,
+        compilation_path: &Path,
+        song: &CompilationInputSong,
+    ) -> Option<metadata::song::CompilationDerivedMetadataSource> {
+        if let Some(derived) = self
+            .inner
+            .try_rederive_compilation_song(compilation_path, song)
+            .await
+        {
+            return Some(derived);
+        }
+        // The user already said which recording this is, even if the inner deriver couldn't use it
+        if song.origin_mbid().is_some() {
+            return None;
+        }
+
+        let chromaprint = song.chromaprint()?;
+        let results = self.api.lookup(chromaprint).await.with_context(|| {
+            format!(
+                "couldn't look up the fingerprint of {} on AcoustID",
+                song.file().display()
+            )
+        });
+        let results = match results {
+            Ok(results) => results,
+            Err(err) => {
+                self.errors.push(err);
+                return None;
+            }
+        };
+        Some(metadata::song::CompilationDerivedMetadataSource {
+            chromaprint: Some(chromaprint.clone()),
+            mb_recording_id: Some(best_recording(results, self.min_score)?),
+        })
+    }
+
+    fn get_cached_compilation_song(
+        &self,
+        compilation_path: &Path,
+        song: &CompilationInputSong,
+        src: &metadata::song::CompilationDerivedMetadataSource,
+    ) -> Option<metadata::song::Cached> {
+        self.inner
+            .get_cached_compilation_song(compilation_path, song, src)
+    }
+
+    async fn try_recache_compilation_song(
+        &mut self,
+        compilation_path: &Path,
+        song: &CompilationInputSong,
+        src: &metadata::song::CompilationDerivedMetadataSource,
+    ) -> Option<metadata::song::Cached> {
+        self.inner
+            .try_recache_compilation_song(compilation_path, song, src)
+            .await
+    }
+
+    fn num_failed_lookups(&self) -> usize {
+        self.errors.len() + self.inner.num_failed_lookups()
+    }
+}
+
+/// The first recording of the best-scoring match, if it scored at least `min_score`.
+/// Of equally scoring matches, the first one AcoustID returned wins.
+fn best_recording(results: Vec<LookupResult>, min_score: f64) -> Option<MbId> {
+    results
+        .into_iter()
+        .filter(|r| r.score >= min_score && !r.recordings.is_empty())
+        .min_by(|a, b| b.score.total_cmp(&a.score))
+        .and_then(|r| r.recordings.into_iter().next())
+        .map(|r| r.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::NullDeriver;
+    use crate::data_model::CompilationInputGroup;
+    use crate::data_model::user_defined::CacheConfig;
+    use crate::store::{GroupCacheFile, StoredDeriver};
+
+    fn result(score: f64, recordings: &[&str]) -> LookupResult {
+        LookupResult {
+            score,
+            recordings: recordings
+                .iter()
+                .map(|&id| LookupRecording { id: MbId::new(id) })
+                .collect(),
+        }
+    }
+
+    fn best(results: Vec<LookupResult>) -> Option<String> {
+        best_recording(results, 0.8).map(|id| id.as_str().to_owned())
+    }
+
+    #[test]
+    fn best_recording_takes_the_highest_score() {
+        let results = vec![
+            result(0.85, &["ok"]),
+            result(0.97, &["best", "also best"]),
+            result(0.9, &["good"]),
+        ];
+        assert_eq!(best(results).as_deref(), Some("best"));
+    }
+
+    #[test]
+    fn best_recording_ignores_scores_below_the_minimum() {
+        assert_eq!(best(vec![result(0.79, &["poor"])]), None);
+        // The minimum itself is good enough
+        assert_eq!(best(vec![result(0.8, &["ok"])]).as_deref(), Some("ok"));
+    }
+
+    #[test]
+    fn best_recording_breaks_ties_by_order() {
+        let results = vec![result(0.9, &["first"]), result(0.9, &["second"])];
+        assert_eq!(best(results).as_deref(), Some("first"));
+    }
+
+    #[test]
+    fn best_recording_skips_matches_without_recordings() {
+        assert_eq!(best(vec![]), None);
+        assert_eq!(best(vec![result(0.99, &[])]), None);
+        let results = vec![result(0.99, &[]), result(0.9, &["known"])];
+        assert_eq!(best(results).as_deref(), Some("known"));
+    }
+
+    /// Answers every lookup with the same results, counting the lookups
+    struct FakeAcoustId {
+        results: fn() -> anyhow::Result<Vec<LookupResult>>,
+        lookups: Mutex<usize>,
+    }
+
+    impl FakeAcoustId {
+        fn new(results: fn() -> anyhow::Result<Vec<LookupResult>>) -> Self {
+            Self {
+                results,
+                lookups: Mutex::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl AcoustIdApi for FakeAcoustId {
+        async fn lookup(&self, _chromaprint: &Chromaprint) -> anyhow::Result<Vec<LookupResult>> {
+            *self.lookups.lock().unwrap() += 1;
+            (self.results)()
+        }
+    }
+
+    fn deriver(api: FakeAcoustId) -> AcoustIdDeriver<NullDeriver, FakeAcoustId> {
+        let config = AcoustIdConfig {
+            api_key: "key".to_owned(),
+            base_url: None,
+            min_score: 0.8,
+        };
+        AcoustIdDeriver::new(NullDeriver, api, &config)
+    }
+
+    /// A fingerprinted compilation of one song, with the given origin MBID
+    fn compilation(dir: &Path, origin_mbid: Option<&str>) -> CompilationInputGroup {
+        let mut group = CompilationInputGroup::for_test(dir, &[("song.flac", b"", origin_mbid)]);
+        group.songs_mut()[0].set_chromaprint(Some(Chromaprint {
+            algorithm: chromaprint::CHROMAPRINT_ALGORITHM_DEFAULT,
+            fingerprint: "AQAAAA".to_owned(),
+            duration_secs: 180,
+        }));
+        group
+    }
+
+    #[tokio::test]
+    async fn unidentified_songs_are_looked_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let group = compilation(dir.path(), None);
+        let mut deriver = deriver(FakeAcoustId::new(|| Ok(vec![result(0.9, &["found"])])));
+        let derived = deriver
+            .try_rederive_compilation_song(dir.path(), &group.songs()[0])
+            .await
+            .unwrap();
+        assert_eq!(derived.mb_recording_id, Some(MbId::new("found")));
+        assert_eq!(derived.chromaprint, group.songs()[0].chromaprint().cloned());
+        assert_eq!(*deriver.api.lookups.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn songs_with_an_origin_mbid_are_not_looked_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let group = compilation(dir.path(), Some("origin"));
+        let mut deriver = deriver(FakeAcoustId::new(|| Ok(vec![result(0.9, &["found"])])));
+        let derived = deriver
+            .try_rederive_compilation_song(dir.path(), &group.songs()[0])
+            .await;
+        assert!(derived.is_none());
+        assert_eq!(*deriver.api.lookups.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let group = compilation(dir.path(), None);
+        let mut deriver = deriver(FakeAcoustId::new(|| anyhow::bail!("invalid API key")));
+        let derived = deriver
+            .try_rederive_compilation_song(dir.path(), &group.songs()[0])
+            .await;
+        assert!(derived.is_none());
+        let errors = deriver.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("song.flac"));
+
+        // and aren't stored as having found nothing, so they're tried again next run
+        let mut stored = StoredDeriver::new(deriver, &CacheConfig::default(), Default::default());
+        let song = &group.songs()[0];
+        assert!(
+            stored
+                .try_rederive_compilation_song(dir.path(), song)
+                .await
+                .is_none()
+        );
+        assert!(!stored.is_compilation_song_fresh(dir.path(), song));
+        assert!(!GroupCacheFile::path_for_group(dir.path()).exists());
+        assert_eq!(stored.inner_mut().take_errors().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn poor_matches_derive_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let group = compilation(dir.path(), None);
+        let mut deriver = deriver(FakeAcoustId::new(|| Ok(vec![result(0.5, &["poor"])])));
+        let derived = deriver
+            .try_rederive_compilation_song(dir.path(), &group.songs()[0])
+            .await;
+        assert!(derived.is_none());
+        assert!(deriver.take_errors().is_empty());
+    }
+}
@@ -1,4 +1,622 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
 
-fn main() {
-    println!("Hello, world!");
+use clap::{Parser, Subcommand};
+use turnip_music2::acoustid::{AcoustIdDeriver, AcoustIdHttp};
+use turnip_music2::cddb::CddbHttp;
+use turnip_music2::discid::album_toc;
+use turnip_music2::fingerprint::{FingerprintSummary, Fingerprinter};
+use turnip_music2::library::Library;
+use turnip_music2::musicbrainz::{MusicBrainzDeriver, MusicBrainzHttp};
+use turnip_music2::playlist::write_playlists;
+use turnip_music2::prune::{PruneMode, PruneSummary, prune_output};
+use turnip_music2::render::{JOB_CACHE_FILE_NAME, RenderSummary, Renderer};
+use turnip_music2::resolver::{ResolvedGroup, ResolvedGroupKind, Resolver};
+use turnip_music2::riplog::RipQuality;
+use turnip_music2::scanner::{Group, ScanResult};
+use turnip_music2::store::{FileHashes, StoredDeriver};
+use turnip_music2::{MetadataDeriver, NullDeriver};
+
+/// Build an output music library from the groups of source music described by a `library.tm2.toml`
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// Path to the library config file, usually named `library.tm2.toml`
+    config: PathBuf,
+
+    /// Don't look anything up on MusicBrainz, AcoustID or CDDB, resolve songs using only their native tags and overrides
+    #[arg(long, global = true)]
+    offline: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Find every group in the library and scan it for songs
+    Scan,
+    /// Resolve the metadata for every song in the library
+    Resolve {
+        /// Print which layer (native, cached, override) supplied each field
+        #[arg(long)]
+        explain: bool,
+    },
+    /// Render the output library
+    Render {
+        /// Path to the ffmpeg binary, overriding the one in the config
+        #[arg(long)]
+        ffmpeg: Option<PathBuf>,
+        /// Don't render or prune anything, only print the files that would be pruned
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Compute the audio fingerprint of every song, storing them alongside each group
+    Fingerprint {
+        /// Path to the ffmpeg binary used to decode the songs, overriding the one in the config
+        #[arg(long)]
+        ffmpeg: Option<PathBuf>,
+    },
+    /// Compute the MusicBrainz and CDDB disc IDs of every album, from its rip log, cue sheet or lossless songs
+    Discid,
+    /// Print a summary of the library
+    Status,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(&cli).await {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns Ok(false) if the command ran to completion but some groups failed
+async fn run(cli: &Cli) -> anyhow::Result<bool> {
+    match &cli.command {
+        Command::Scan => {
+            let library = Library::load(&cli.config)?;
+            let scan = &library.scan;
+            for group in &scan.groups {
+                println!("{}", describe_group(group));
+            }
+            Ok(report_failures(scan))
+        }
+        Command::Resolve { explain } => {
+            let mut library = Library::load(&cli.config)?;
+            let resolver = Resolver::new(&library.config);
+            let lookup_failures =
+                find_missing_metadata(cli, &None, &resolver, &mut library, Default::default())
+                    .await?;
+            for group in &library.scan.groups {
+                print_resolved_group(&resolver.resolve(group), *explain);
+            }
+            let lookups_ok = report_lookup_failures(&lookup_failures);
+            Ok(report_failures(&library.scan) && lookups_ok)
+        }
+        Command::Render { ffmpeg, dry_run } => {
+            let mut library = Library::load(&cli.config)?;
+            let (Some(_), Some(output_root)) = (&library.config.output, &library.output_root)
+            else {
+                anyhow::bail!("the config file has no [output] section");
+            };
+            let output_root = output_root.clone();
+
+            let resolver = Resolver::new(&library.config);
+            let hashes = Arc::new(FileHashes::default());
+            let lookup_failures =
+                find_missing_metadata(cli, ffmpeg, &resolver, &mut library, hashes.clone()).await?;
+            let ffmpeg = ffmpeg_path(ffmpeg, &library);
+            let resolved = library
+                .scan
+                .groups
+                .iter()
+                .map(|g| resolver.resolve(g))
+                .collect::<Vec<_>>();
+            let extension = &library
+                .config
+                .output
+                .as_ref()
+                .expect("checked above")
+                .extension;
+            let plan = match library.plan_output_paths(&resolved, extension) {
+                Ok(plan) => plan,
+                Err(diagnostics) => {
+                    eprintln!("{} output path(s) can't be used:", diagnostics.len());
+                    for diagnostic in &diagnostics {
+                        eprintln!("  {diagnostic}");
+                    }
+                    anyhow::bail!(
+                        "not rendering, fix the metadata or add overrides for these songs"
+                    );
+                }
+            };
+
+            let rendered_ok = if *dry_run {
+                true
+            } else {
+                let output_config = library.config.output.as_ref().expect("checked above");
+                let mut renderer = Renderer::new(ffmpeg, output_root.clone(), output_config)?;
+                let rendered_ok = report_render(&renderer.render(&resolved, &plan, &hashes)?);
+                for playlist in write_playlists(&output_root, &resolved, &plan)? {
+                    println!("wrote playlist {}", playlist.display());
+                }
+                rendered_ok
+            };
+
+            // Outputs of groups that failed to scan aren't in the plan, but shouldn't be deleted
+            let pruned_ok = if library.scan.has_failures() {
+                eprintln!("not pruning the output library, because some groups failed to scan");
+                true
+            } else {
+                let mode = match (&library.quarantine_root, *dry_run) {
+                    (quarantine, true) => PruneMode::DryRun {
+                        quarantine: quarantine.clone(),
+                    },
+                    (Some(quarantine), false) => PruneMode::Quarantine(quarantine.clone()),
+                    (None, false) => PruneMode::Delete,
+                };
+                let mut expected = plan.all_paths();
+                expected.insert(Path::new(JOB_CACHE_FILE_NAME));
+                report_prune(&prune_output(&output_root, &expected, &mode)?, &mode)
+            };
+
+            let lookups_ok = report_lookup_failures(&lookup_failures);
+            Ok(report_failures(&library.scan) && lookups_ok && rendered_ok && pruned_ok)
+        }
+        Command::Fingerprint { ffmpeg } => {
+            let mut library = Library::load(&cli.config)?;
+            let fingerprinter = Fingerprinter::new(ffmpeg_path(ffmpeg, &library));
+            let fingerprinted_ok = report_fingerprint(
+                &fingerprinter.fingerprint(&mut library.scan.groups, &FileHashes::default()),
+            );
+            Ok(report_failures(&library.scan) && fingerprinted_ok)
+        }
+        Command::Discid => {
+            let library = Library::load(&cli.config)?;
+            let discids_ok = print_discids(&library.scan);
+            Ok(report_failures(&library.scan) && discids_ok)
+        }
+        Command::Status => {
+            let library = Library::load(&cli.config)?;
+            let scan = &library.scan;
+            let num_albums = scan
+                .groups
+                .iter()
+                .filter(|g| matches!(g, Group::PartialAlbum(..)))
+                .count();
+            let num_compilations = scan.groups.len() - num_albums;
+            let num_songs = scan.groups.iter().map(Group::num_songs).sum::<usize>();
+            println!(
+                "{} groups ({} albums, {} compilations), {} songs",
+                scan.groups.len(),
+                num_albums,
+                num_compilations,
+                num_songs
+            );
+            print_rip_quality(scan);
+            Ok(report_failures(scan))
+        }
+    }
+}
+
+/// The ffmpeg binary from the command line, then the config, then the PATH
+fn ffmpeg_path(arg: &Option<PathBuf>, library: &Library) -> PathBuf {
+    arg.clone()
+        .or_else(|| {
+            let output_config = library.config.output.as_ref()?;
+            output_config.ffmpeg.as_ref().map(PathBuf::from)
+        })
+        .unwrap_or_else(|| PathBuf::from("ffmpeg"))
+}
+
+fn describe_group(group: &Group) -> String {
+    let kind = match group {
+        Group::PartialAlbum(..) => "album",
+        Group::Compilation(..) => "compilation",
+    };
+    format!(
+        "{:<12} {:>4} songs  {}",
+        kind,
+        group.num_songs(),
+        group.path().display()
+    )
+}
+
+/// Look up the missing metadata for every group in the library, from each group's cache file and then MusicBrainz.
+/// If AcoustID is configured, compilation songs are fingerprinted first (decoding them with `ffmpeg`) so they can be identified by their audio.
+/// Only songs AcoustID would be asked about are fingerprinted: those without an origin MBID, or a fresh entry in the cache file.
+/// If we're offline, only the cache files are used.
+/// Returns the lookups that failed, alongside the group (or file) they failed for.
+async fn find_missing_metadata(
+    cli: &Cli,
+    ffmpeg: &Option<PathBuf>,
+    resolver: &Resolver,
+    library: &mut Library,
+    hashes: Arc<FileHashes>,
+) -> anyhow::Result<Vec<(PathBuf, anyhow::Error)>> {
+    if cli.offline {
+        // Storing what NullDeriver didn't find would stop later online runs from looking it up
+        let mut deriver = StoredDeriver::read_only(NullDeriver, &library.config.cache, hashes);
+        return Ok(find_missing_metadata_with(resolver, library, &mut deriver, |_| vec![]).await);
+    }
+
+    let deriver = MusicBrainzDeriver::new(
+        MusicBrainzHttp::new(&library.config.musicbrainz)?,
+        CddbHttp::new(&library.config.cddb)?,
+    );
+    let Some(acoustid) = &library.config.acoustid else {
+        let mut deriver = StoredDeriver::new(deriver, &library.config.cache, hashes);
+        return Ok(
+            find_missing_metadata_with(resolver, library, &mut deriver, |d| {
+                d.inner_mut().take_errors()
+            })
+            .await,
+        );
+    };
+
+    let deriver = AcoustIdDeriver::new(deriver, AcoustIdHttp::new(acoustid)?, acoustid);
+    let mut deriver = StoredDeriver::new(deriver, &library.config.cache, hashes.clone());
+    let fingerprinter = Fingerprinter::new(ffmpeg_path(ffmpeg, library));
+    let fingerprints = fingerprinter.fingerprint_where(
+        &mut library.scan.groups,
+        &hashes,
+        |group, idx| match group {
+            Group::Compilation(compilation, path) => {
+                let song = &compilation.songs()[idx];
+                song.origin_mbid().is_none() && !deriver.is_compilation_song_fresh(path, song)
+            }
+            Group::PartialAlbum(..) => false,
+        },
+    );
+    let mut failures = fingerprints
+        .failures
+        .into_iter()
+        .map(|f| (f.path, f.error.context("couldn't fingerprint")))
+        .collect::<Vec<_>>();
+    failures.extend(
+        find_missing_metadata_with(resolver, library, &mut deriver, |d| {
+            let acoustid = d.inner_mut();
+            let mut errors = acoustid.take_errors();
+            errors.extend(acoustid.inner_mut().take_errors());
+            errors
+        })
+        .await,
+    );
+    Ok(failures)
+}
+
+async fn find_missing_metadata_with<D: MetadataDeriver + Send + Sync>(
+    resolver: &Resolver,
+    library: &mut Library,
+    deriver: &mut StoredDeriver<D>,
+    take_inner_errors: impl Fn(&mut StoredDeriver<D>) -> Vec<anyhow::Error>,
+) -> Vec<(PathBuf, anyhow::Error)> {
+    let mut failures = vec![];
+    for group in &mut library.scan.groups {
+        let fit = resolver.find_missing_metadata(group, deriver).await;
+        let errors = take_inner_errors(deriver)
+            .into_iter()
+            .chain(deriver.take_errors())
+            .chain(fit.err());
+        failures.extend(errors.map(|err| (group.path().to_owned(), err)));
+    }
+    failures
+}
+
+fn print_resolved_group(group: &ResolvedGroup, explain: bool) {
+    match &group.kind {
+        ResolvedGroupKind::Album => println!("{}", group.path.display()),
+        ResolvedGroupKind::Compilation { title } => {
+            println!("{} (compilation \"{}\")", group.path.display(), title)
+        }
+    }
+    for song in &group.songs {
+        let output = &song.output;
+        let position = match (output.disc_idx, output.track_idx) {
+            (Some(disc), Some(track)) => format!("{disc}-{track:02}"),
+            (None, Some(track)) => format!("{track:02}"),
+            _ => "--".to_owned(),
+        };
+        println!(
+            "  [{}] {} - {} ({} - {})",
+            position,
+            output.song_artists.join(", "),
+            output.song_title,
+            output.album_artists.join(", "),
+            output.album_title.as_deref().unwrap_or("no album"),
+        );
+        if explain {
+            let provenance = &song.provenance;
+            println!("      file:               {}", song.input_path.display());
+            println!("      song title:         {:?}", provenance.song_title);
+            println!("      song artists:       {:?}", provenance.song_artists);
+            println!("      album title:        {:?}", provenance.album_title);
+            println!("      album artists:      {:?}", provenance.album_artists);
+            println!("      disc index:         {:?}", provenance.disc_idx);
+            println!("      track index:        {:?}", provenance.track_idx);
+            // Optional fields are only worth mentioning when something supplied them
+            let joined = |v: &[String]| Some(v.join("; ")).filter(|s| !s.is_empty());
+            let optional = [
+                (
+                    "song title sort",
+                    provenance.song_title_sort,
+                    output.song_title_sort.clone(),
+                ),
+                (
+                    "song artists sort",
+                    provenance.song_artists_sort,
+                    joined(&output.song_artists_sort),
+                ),
+                (
+                    "album title sort",
+                    provenance.album_title_sort,
+                    output.album_title_sort.clone(),
+                ),
+                (
+                    "album artists sort",
+                    provenance.album_artists_sort,
+                    joined(&output.album_artists_sort),
+                ),
+                ("date", provenance.date, output.date.clone()),
+                (
+                    "original date",
+                    provenance.original_date,
+                    output.original_date.clone(),
+                ),
+                ("genres", provenance.genres, joined(&output.genres)),
+                ("label", provenance.label, output.label.clone()),
+                (
+                    "catalog number",
+                    provenance.catalog_number,
+                    output.catalog_number.clone(),
+                ),
+                ("barcode", provenance.barcode, output.barcode.clone()),
+                ("isrc", provenance.isrc, output.isrc.clone()),
+                ("composers", provenance.composers, joined(&output.composers)),
+            ];
+            for (name, layer, value) in optional {
+                if let Some(value) = value {
+                    println!("      {:<20}{layer:?} {value:?}", format!("{name}:"));
+                }
+            }
+        }
+    }
+}
+
+/// Print the disc IDs of every album, and every album whose TOC couldn't be read to stderr.
+/// Returns true if there were no failures.
+fn print_discids(scan: &ScanResult) -> bool {
+    let mut failures = vec![];
+    for group in &scan.groups {
+        let Group::PartialAlbum(album, path) = group else {
+            continue;
+        };
+        match album_toc(path, album) {
+            Ok(Some((toc, source))) => {
+                let (mb_discid, cddb_discid) = (toc.mb_discid(), toc.cddb_discid());
+                println!(
+                    "{}: MusicBrainz disc ID {}, CDDB disc ID {} (from {})",
+                    path.display(),
+                    mb_discid.as_str(),
+                    cddb_discid.as_str(),
+                    source
+                );
+                println!("  TOC {}", toc.mb_toc_string());
+                let origin = album.origin();
+                if let Some(origin_discid) = &origin.mb_discid
+                    && *origin_discid != mb_discid
+                {
+                    println!(
+                        "  but the Origin has MusicBrainz disc ID {}",
+                        origin_discid.as_str()
+                    );
+                }
+                if let Some(origin_discid) = &origin.cddb_discid
+                    && *origin_discid != cddb_discid
+                {
+                    println!(
+                        "  but the Origin has CDDB disc ID {}",
+                        origin_discid.as_str()
+                    );
+                }
+            }
+            Ok(None) => println!(
+                "{}: no rip log, cue sheet or lossless songs to find the TOC from",
+                path.display()
+            ),
+            Err(err) => failures.push((path, err)),
+        }
+    }
+    if failures.is_empty() {
+        return true;
+    }
+    eprintln!(
+        "{} album(s) had a TOC that couldn't be read:",
+        failures.len()
+    );
+    for (path, err) in &failures {
+        eprintln!("  {}: {:#}", path.display(), err);
+    }
+    false
+}
+
+/// Print how many rip logs found each quality of rip, and every rip that isn't accurate with the reasons it's suspect
+fn print_rip_quality(scan: &ScanResult) {
+    let rip_logs = scan
+        .groups
+        .iter()
+        .filter_map(|g| match g {
+            Group::PartialAlbum(album, path) => Some((path, album.rip_logs())),
+            Group::Compilation(..) => None,
+        })
+        .flat_map(|(path, logs)| {
+            logs.iter()
+                .map(move |(log_path, log)| (path, log_path, log))
+        })
+        .collect::<Vec<_>>();
+    if rip_logs.is_empty() {
+        return;
+    }
+
+    let count = |quality: RipQuality| {
+        rip_logs
+            .iter()
+            .filter(|(_, _, log)| log.quality() == quality)
+            .count()
+    };
+    println!(
+        "{} rip logs: {} accurate, {} consistent, {} unverified, {} suspect",
+        rip_logs.len(),
+        count(RipQuality::Accurate),
+        count(RipQuality::Consistent),
+        count(RipQuality::Unverified),
+        count(RipQuality::Suspect)
+    );
+    for (path, log_path, log) in rip_logs {
+        let quality = log.quality();
+        if quality == RipQuality::Accurate {
+            continue;
+        }
+        println!(
+            "  {}: {} ({} log {})",
+            path.display(),
+            quality,
+            log.ripper,
+            log_path.display()
+        );
+        for problem in log.problems() {
+            println!("    {problem}");
+        }
+    }
+}
+
+/// Print what was rendered, and every failed render to stderr. Returns true if there were no failures.
+fn report_render(summary: &RenderSummary) -> bool {
+    for path in &summary.rendered {
+        println!("rendered {}", path.display());
+    }
+    println!(
+        "{} rendered, {} up to date, {} failed",
+        summary.rendered.len(),
+        summary.skipped,
+        summary.failures.len()
+    );
+    if summary.failures.is_empty() {
+        return true;
+    }
+    eprintln!("{} song(s) failed to render:", summary.failures.len());
+    for failure in &summary.failures {
+        eprintln!(
+            "  {} -> {}: {:#}",
+            failure.input_path.display(),
+            failure.output_path.display(),
+            failure.error
+        );
+    }
+    false
+}
+
+/// Print what was fingerprinted, and every failed fingerprint to stderr. Returns true if there were no failures.
+fn report_fingerprint(summary: &FingerprintSummary) -> bool {
+    for path in &summary.fingerprinted {
+        println!("fingerprinted {}", path.display());
+    }
+    println!(
+        "{} fingerprinted, {} up to date, {} failed",
+        summary.fingerprinted.len(),
+        summary.skipped,
+        summary.failures.len()
+    );
+    if summary.failures.is_empty() {
+        return true;
+    }
+    eprintln!(
+        "{} file(s) couldn't be fingerprinted:",
+        summary.failures.len()
+    );
+    for failure in &summary.failures {
+        eprintln!("  {}: {:#}", failure.path.display(), failure.error);
+    }
+    false
+}
+
+/// Print what was pruned, and every failed prune to stderr. Returns true if there were no failures.
+fn report_prune(summary: &PruneSummary, mode: &PruneMode) -> bool {
+    let (file_verb, dir_verb) = match mode {
+        PruneMode::Delete => ("deleted", "removed"),
+        PruneMode::Quarantine(_) => ("quarantined", "removed"),
+        PruneMode::DryRun { .. } => ("would prune", "would remove"),
+    };
+    for path in &summary.files {
+        println!("{} {}", file_verb, path.display());
+    }
+    for path in &summary.dirs {
+        println!("{} empty folder {}", dir_verb, path.display());
+    }
+    println!(
+        "{} {} stale file(s), {} {} empty folder(s)",
+        file_verb,
+        summary.files.len(),
+        dir_verb,
+        summary.dirs.len()
+    );
+    if summary.failures.is_empty() {
+        return true;
+    }
+    eprintln!("{} file(s) couldn't be pruned:", summary.failures.len());
+    for (path, err) in &summary.failures {
+        eprintln!("  {}: {:#}", path.display(), err);
+    }
+    false
+}
+
+/// Print every failed lookup to stderr. Returns true if there were no failures.
+fn report_lookup_failures(failures: &[(PathBuf, anyhow::Error)]) -> bool {
+    if failures.is_empty() {
+        return true;
+    }
+    eprintln!(
+        "{} metadata lookup(s) failed or didn't fit their group:",
+        failures.len()
+    );
+    for (path, err) in failures {
+        eprintln!("  {}: {:#}", path.display(), err);
+    }
+    false
+}
+
+/// Print every group warning and failed group to stderr. Returns true if there were no failures.
+fn report_failures(scan: &ScanResult) -> bool {
+    for group in &scan.groups {
+        for warning in group.warnings() {
+            eprintln!("warning: {}: {}", group.path().display(), warning);
+        }
+    }
+    // The songs are still used without their tags, so these aren't failures
+    let tag_errors = scan.tag_errors();
+    if !tag_errors.is_empty() {
+        eprintln!(
+            "{} file(s) had tags that couldn't be read:",
+            tag_errors.len()
+        );
+        for err in tag_errors {
+            eprintln!("  {err}");
+        }
+    }
+    if !scan.has_failures() {
+        return true;
+    }
+    eprintln!("{} group(s) failed to scan:", scan.failures.len());
+    for failure in &scan.failures {
+        eprintln!("  {}: {:#}", failure.path.display(), failure.error);
+    }
+    false
 }
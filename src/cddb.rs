@@ -0,0 +1,389 @@
+//! Looking up CDDB disc IDs on a [gnudb](https://gnudb.org/) (or any other freedb protocol) server.
+//!
+//! CDDB entries only hold free text, so they're used to find candidate MusicBrainz releases
+//! rather than as a source of metadata themselves, see [crate::musicbrainz::MusicBrainzDeriver].
+//!
+//! [CddbHttp] sends freedb protocol commands to the server's `cddb.cgi` HTTP endpoint;
+//! only `cddb query` and `cddb read` are used.
+//!
+//! - <https://gnudb.org/howto.php>
+//! - <https://ftp.freedb.org/pub/freedb/latest/CDDBPROTO>
+//! - <https://ftp.freedb.org/pub/freedb/latest/DBFORMAT>
+
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+
+use crate::data_model::CddbDiscId;
+use crate::data_model::user_defined::CddbConfig;
+use crate::rate_limit::RateLimiter;
+
+const DEFAULT_HOST: &str = "gnudb.gnudb.org";
+
+/// gnudb doesn't document a rate limit, so this keeps to the same one request per second as MusicBrainz
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A CDDB database entry for a disc
+#[derive(Debug, Clone, PartialEq)]
+pub struct CddbEntry {
+    pub category: String,
+    pub artist: String,
+    pub title: String,
+    /// The length of each track in seconds, from the frame offsets in the entry
+    pub track_lengths: Vec<f64>,
+}
+
+/// The parts of the CDDB protocol used by [crate::musicbrainz::MusicBrainzDeriver]
+#[async_trait]
+pub trait CddbApi: Send + Sync {
+    /// Read every entry matching a disc ID, from any category.
+    /// Entries that can't be read are skipped, unless none can be.
+    async fn read(&self, id: &CddbDiscId) -> anyhow::Result<Vec<CddbEntry>>;
+}
+
+/// [CddbApi] over HTTP, using the host from [CddbConfig]
+pub struct CddbHttp {
+    client: reqwest::Client,
+    url: String,
+    hello: String,
+    rate_limit: RateLimiter,
+}
+
+impl CddbHttp {
+    pub fn new(config: &CddbConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .user_agent(crate::musicbrainz::USER_AGENT)
+            .build()?;
+        let host = config.host.as_deref().unwrap_or(DEFAULT_HOST);
+        Ok(Self {
+            client,
+            url: format!("http://{host}/~cddb/cddb.cgi"),
+            // `<user> <host> <client name> <client version>`
+            hello: format!(
+                "{} localhost {} {}",
+                config.user.as_deref().unwrap_or("anonymous"),
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            ),
+            rate_limit: RateLimiter::new(REQUEST_INTERVAL),
+        })
+    }
+
+    /// Run a CDDB command, returning the status code and the rest of the response
+    async fn command(&self, command: &str) -> anyhow::Result<(u16, String)> {
+        self.rate_limit.wait().await;
+        let response = self
+            .client
+            .get(&self.url)
+            .query(&[("cmd", command), ("hello", &self.hello), ("proto", "6")])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let code = response
+            .get(..3)
+            .and_then(|c| c.parse::<u16>().ok())
+            .with_context(|| format!("the CDDB server sent a malformed response {response:?}"))?;
+        Ok((code, response))
+    }
+}
+
+#[async_trait]
+impl CddbApi for CddbHttp {
+    async fn read(&self, id: &CddbDiscId) -> anyhow::Result<Vec<CddbEntry>> {
+        let (code, response) = self.command(&query_command(id)?).await?;
+        let matches = parse_query_response(code, &response)?;
+
+        let mut entries = vec![];
+        let mut errors = vec![];
+        for (category, match_id) in &matches {
+            let entry = self
+                .command(&format!("cddb read {category} {match_id}"))
+                .await
+                .and_then(|(code, response)| match code {
+                    210 => parse_entry(category, &response),
+                    _ => anyhow::bail!("{}", response.lines().next().unwrap_or_default()),
+                })
+                .with_context(|| format!("couldn't read the {category} entry {match_id}"));
+            match entry {
+                Ok(entry) => entries.push(entry),
+                Err(err) => errors.push(err),
+            }
+        }
+        match errors.into_iter().next() {
+            Some(err) if entries.is_empty() => Err(err),
+            _ => Ok(entries),
+        }
+    }
+}
+
+/// A `cddb query` for a disc ID, which lists the entries for it in every category.
+/// The query should hold the disc's TOC, but servers match exactly on the disc ID and only use the TOC for fuzzy matching,
+/// so the track count and length in the disc ID are enough, with the tracks spread evenly over the length.
+fn query_command(id: &CddbDiscId) -> anyhow::Result<String> {
+    let id_number = u32::from_str_radix(id.as_str(), 16)
+        .with_context(|| format!("{} isn't a CDDB disc ID", id.as_str()))?;
+    let num_tracks = id_number & 0xff;
+    let length_secs = (id_number >> 8) & 0xffff;
+    if num_tracks == 0 {
+        anyhow::bail!("CDDB disc ID {} has no tracks", id.as_str());
+    }
+    // The first track starts after the 2 second lead-in
+    let offsets = (0..num_tracks)
+        .map(|i| (150 + i * length_secs * 75 / num_tracks).to_string())
+        .collect::<Vec<_>>();
+    Ok(format!(
+        "cddb query {} {num_tracks} {} {}",
+        id.as_str(),
+        offsets.join(" "),
+        length_secs + 2
+    ))
+}
+
+/// The category and disc ID of each entry in the response to a `cddb query`, e.g.
+///
+/// ```text
+/// 210 Found exact matches, list follows (until terminating `.')
+/// rock 7a0b6f09 Artist / Album
+/// misc 7a0b6f09 Artist / Album
+/// .
+/// ```
+fn parse_query_response(code: u16, response: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let parse_match = |line: &str| {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(category), Some(id)) => Ok((category.to_owned(), id.to_owned())),
+            _ => Err(anyhow::anyhow!(
+                "the CDDB server sent a malformed match {line:?}"
+            )),
+        }
+    };
+    match code {
+        // One exact match, on the status line
+        200 => {
+            let line = response.lines().next().unwrap_or_default();
+            Ok(vec![parse_match(line.get(4..).unwrap_or_default())?])
+        }
+        // Exact or inexact matches, one per line
+        210 | 211 => response
+            .lines()
+            .skip(1)
+            .map(str::trim)
+            .take_while(|line| *line != ".")
+            .filter(|line| !line.is_empty())
+            .map(parse_match)
+            .collect(),
+        // No match
+        202 => Ok(vec![]),
+        _ => anyhow::bail!(
+            "the CDDB server refused the query: {}",
+            response.lines().next().unwrap_or_default()
+        ),
+    }
+}
+
+/// Parse an entry in the xmcd format, which holds the track offsets and disc length in comments:
+///
+/// ```text
+/// # Track frame offsets:
+/// #       150
+/// #       18901
+/// # Disc length: 2754 seconds
+/// DTITLE=Artist / Album
+/// ```
+fn parse_entry(category: &str, text: &str) -> anyhow::Result<CddbEntry> {
+    let mut offsets = vec![];
+    let mut disc_length = None;
+    let mut dtitle = String::new();
+    let mut in_offsets = false;
+
+    for line in text.lines() {
+        let line = line.trim_end();
+        if let Some(comment) = line.strip_prefix('#') {
+            let comment = comment.trim();
+            if comment.starts_with("Track frame offsets") {
+                in_offsets = true;
+            } else if in_offsets && let Ok(offset) = comment.parse::<u32>() {
+                offsets.push(offset);
+            } else {
+                in_offsets = false;
+                if let Some(length) = comment.strip_prefix("Disc length:") {
+                    disc_length = length
+                        .split_whitespace()
+                        .next()
+                        .and_then(|s| s.parse::<u32>().ok());
+                }
+            }
+        } else if let Some(title) = line.strip_prefix("DTITLE=") {
+            // Long values are split across several lines with the same key
+            dtitle.push_str(title);
+        }
+    }
+
+    let disc_length = disc_length.context("the entry has no disc length")?;
+    if offsets.is_empty() {
+        anyhow::bail!("the entry has no track offsets");
+    }
+    let mut track_lengths = offsets
+        .windows(2)
+        .map(|w| (w[1] as f64 - w[0] as f64) / 75.0)
+        .collect::<Vec<_>>();
+    let last_offset = *offsets.last().expect("checked above");
+    track_lengths.push(disc_length as f64 - last_offset as f64 / 75.0);
+
+    // `Artist / Album`, or just `Album` if it's by the same name as the artist
+    let (artist, title) = match dtitle.split_once(" / ") {
+        Some((artist, title)) => (artist.trim(), title.trim()),
+        None => (dtitle.trim(), dtitle.trim()),
+    };
+    Ok(CddbEntry {
+        category: category.to_owned(),
+        artist: artist.to_owned(),
+        title: title.to_owned(),
+        track_lengths,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discid::Toc;
+
+    /// A `cddb read` response as gnudb sends it, with DTITLE and a track title split across lines
+    const ENTRY: &str = "210 rock 2103e804 CD database entry follows (until terminating `.')
+# xmcd
+#
+# Track frame offsets:
+#\t150
+#\t18901
+#\t39776
+#\t56426
+#
+# Disc length: 1002 seconds
+#
+# Revision: 3
+# Processed by: cddbd v1.5.2PL0 Copyright (c) Steve Scherf et al.
+# Submitted via: EAC v1.6
+#
+DISCID=2103e804
+DTITLE=The Long Winded Band With A Very Long Name / An Album Whose Title Is So Lo
+DTITLE=ng It Needs Two Lines
+DYEAR=1998
+DGENRE=Rock
+TTITLE0=Opening
+TTITLE1=The Second Song Has A Title Long Enough To Be Split Across Two Lin
+TTITLE1=es Of The Entry
+TTITLE2=Interlude
+TTITLE3=Closing
+EXTD=
+EXTT0=
+EXTT1=
+EXTT2=
+EXTT3=
+PLAYORDER=
+.
+";
+
+    fn assert_lengths(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?}");
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 0.01, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn entry_is_parsed() {
+        let entry = parse_entry("rock", ENTRY).unwrap();
+        assert_eq!(entry.category, "rock");
+        assert_eq!(entry.artist, "The Long Winded Band With A Very Long Name");
+        assert_eq!(
+            entry.title,
+            "An Album Whose Title Is So Long It Needs Two Lines"
+        );
+        // The last track runs to the disc length
+        assert_lengths(&entry.track_lengths, &[250.01, 278.33, 222.0, 249.65]);
+    }
+
+    #[test]
+    fn entry_offsets_match_its_disc_id() {
+        let toc = Toc::new(1, vec![150, 18901, 39776, 56426], 1002 * 75, None).unwrap();
+        assert_eq!(toc.cddb_discid().as_str(), "2103e804");
+    }
+
+    #[test]
+    fn entry_without_an_artist_is_by_its_title() {
+        let text = ENTRY.replace(
+            "DTITLE=The Long Winded Band With A Very Long Name / An Album Whose Title Is So Lo\nDTITLE=ng It Needs Two Lines",
+            "DTITLE=Eponymous",
+        );
+        let entry = parse_entry("misc", &text).unwrap();
+        assert_eq!(entry.artist, "Eponymous");
+        assert_eq!(entry.title, "Eponymous");
+    }
+
+    #[test]
+    fn entry_without_offsets_or_length_is_malformed() {
+        let no_length = ENTRY.replace("# Disc length: 1002 seconds", "");
+        assert!(parse_entry("rock", &no_length).is_err());
+        let no_offsets = ENTRY.replace("# Track frame offsets:", "");
+        assert!(parse_entry("rock", &no_offsets).is_err());
+    }
+
+    #[test]
+    fn query_is_built_from_the_disc_id() {
+        let id = CddbDiscId::new("2103e804");
+        assert_eq!(
+            query_command(&id).unwrap(),
+            "cddb query 2103e804 4 150 18900 37650 56400 1002"
+        );
+        assert!(query_command(&CddbDiscId::new("not hex")).is_err());
+        assert!(query_command(&CddbDiscId::new("2103e800")).is_err());
+    }
+
+    fn matches(code: u16, response: &str) -> Vec<(String, String)> {
+        parse_query_response(code, response).unwrap()
+    }
+
+    fn owned(matches: &[(&str, &str)]) -> Vec<(String, String)> {
+        matches
+            .iter()
+            .map(|(c, id)| (c.to_string(), id.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn query_responses_are_parsed() {
+        assert_eq!(
+            matches(200, "200 rock 2103e804 Band / Album\r\n"),
+            owned(&[("rock", "2103e804")])
+        );
+        assert_eq!(
+            matches(
+                210,
+                "210 Found exact matches, list follows (until terminating `.')\r\n\
+                 rock 2103e804 Band / Album\r\n\
+                 misc 2103e804 Band / Album (Remaster)\r\n\
+                 .\r\n"
+            ),
+            owned(&[("rock", "2103e804"), ("misc", "2103e804")])
+        );
+        assert_eq!(
+            matches(
+                211,
+                "211 Found inexact matches, list follows (until terminating `.')\n\
+                 jazz 2103e904 Band / Album\n\
+                 .\n"
+            ),
+            owned(&[("jazz", "2103e904")])
+        );
+        assert!(matches(202, "202 No match found\n").is_empty());
+    }
+
+    #[test]
+    fn query_errors_are_reported() {
+        assert!(parse_query_response(403, "403 Database entry is corrupt\n").is_err());
+        assert!(parse_query_response(200, "200 rock\n").is_err());
+    }
+}
@@ -9,9 +9,10 @@
 //!
 //!   For example, it holds the `Origin` data on where the group came from (e.g. if it was ripped from a disc, which disc?);
 //!   and any media-specific overrides for that metadata.
-//!   A separate file (TODO: TOML or SQLite?) also holds:
+//!   A separate tool-controlled file `music.tm2.cache.toml` (see [crate::store]) also holds:
 //!    - a cache of the derived metadata source, found automatically from the Origin;
 //!    - a cache of the actual metadata extracted from that source for each song;
+//!    - the audio fingerprint of each song, by the hash of its file (see [crate::fingerprint]);
 //! - Source Music files, stored inside folders (recursive search) containing Group Metadata files.
 //!
 //! Loading a library consists of
@@ -24,31 +25,33 @@
 //!         - If the Song is inside an Album Group, the metadata for the Song is derived from that of the Album's MusicBrainz release
 //!           *and* the media index/track index of the Song.
 //!             - the "source" disc and track indices of each Song are derived from the source file metadata if present, and otherwise
-//!           are respectively kept constant and incremented from the previous Song in an alphanumeric sorting by file name within the Group,
-//!           starting at (1,1).
-//!             - TODO the Album Group should then have the ability to offset the track number or fix the disc number
+//!               are respectively kept constant and incremented from the previous Song in an alphanumeric sorting by file name within the Group,
+//!               starting at (1,1). Songs sharing a position, and gaps between positions, are reported as warnings.
+//!             - the Album Group can then offset the track number or fix the disc number, e.g. for a folder holding only disc 2 of a box set.
+//!               These shift where the sequence starts; songs which override their own disc or track index use exactly that,
+//!               and the songs after them continue counting from it.
 //!             - The song metadata is then looked up from the given media and the given track.
-//!             - If the track number is too large for the given media index, increment the media index and decrement the track number by the length of that media.
+//!             - If the track number is too large for the given media index, increment the media index and decrement the track number by the length of that media (see [metadata::album::Cached::split_position]).
 //!             - This allows long sequential incrementing track numbers to be automatically split across disks.
 //!         - If the Song is inside a Compilation Group, the metadata for the song is derived from the origin MusicBrainz ID if one is present.
 //!     - If there is override metadata in the Group Metadata file, override with that
-//! - Creating a 1:1 mapping of Songs -> output Songs
+//! - Creating a 1:1 mapping of Songs -> output Songs (see [crate::planner])
 //!     - if within an Album Group, `<First Artist of Album>/<Album Name>/<Song Name>`
 //!     - if within a Compilation Group, `<First Artist of Song>/<Song Name>`
 //!     - all path components are deduplicated if necessary with uppercase alpha "ABCDE..." postfixes.
 //!     - if any path component contains special characters the output process stops (UTF-8 allowed, but not filesystem-breakers such as NTFS `/\:*"?<>|`)
-//! - Use FFMPEG to render out output files
+//! - Use FFMPEG to render out output files (see [crate::render])
 //!     - If same extension, don't bother - avoid recompressing MP3->MP3? TODO add config option for that
 //!     - If same input file hash as previous (job cache?) and output file exists
 //!         - TODO if output file has different hash than expected, also rerender?
 //!         - if input and output file hashes change that indicates loss of integrity, if input file is the same assume that's fine?
-//!     - Delete output files that aren't supposed to be there.
-//! - Create .m3u8 files for the compilations
+//!     - Delete output files that aren't supposed to be there (see [crate::prune]).
+//! - Create .m3u8 files for the compilations (see [crate::playlist])
 //!     - Can just delete old ones and remake, no point in doing sensitivity there?
 //!     - Compilations retain the same track ordering as alphanumeric input file sorting, so ordered compilations can be created if desired but otherwise do not matter.
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     path::{Path, PathBuf},
 };
 
@@ -56,23 +59,59 @@ use chromaprint::ChromaprintAlgorithm;
 use serde::{Deserialize, Serialize};
 
 use crate::data_model::{
-    native_metadata::{NativeMetadata, NativeMetadataFormat},
+    native_metadata::{NativeMetadata, NativeMetadataError, NativeMetadataFormat},
     user_defined::{AlbumInputSongOverride, CompilationInputSongOverride, Origin, ScanFilter},
 };
+use crate::riplog::RipLog;
 
 /// MusicBrainz ID <https://musicbrainz.org/doc/MusicBrainz_Identifier>,
 /// which can be for one of many different kinds of [entities](https://musicbrainz.org/doc/MusicBrainz_Entity)
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct MbId(String);
+impl MbId {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
 /// https://musicbrainz.org/doc/Disc_ID
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct MbDiscId(String);
+impl MbDiscId {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
 /// https://en.wikipedia.org/wiki/CDDB#Example_calculation_of_a_CDDB1_(FreeDB)_disc_ID
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct CddbDiscId(String);
+impl CddbDiscId {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
 
-/// Song audio fingerprint via chromaprint, which allows lookup via MusicBrainz
-pub struct Chromaprint(ChromaprintAlgorithm, Vec<u8>);
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Song audio fingerprint via chromaprint, which allows lookup via MusicBrainz.
+/// Computed from the decoded audio by [crate::fingerprint].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Chromaprint {
+    pub algorithm: ChromaprintAlgorithm,
+    /// The compressed fingerprint, base64 encoded
+    pub fingerprint: String,
+    /// Length of the whole song in seconds, which lookups need alongside the fingerprint
+    pub duration_secs: u64,
+}
 
 /// Data types defining the user-controlled TOML files
 pub mod user_defined {
@@ -82,8 +121,95 @@ pub mod user_defined {
 
     #[derive(Serialize, Deserialize, Debug)]
     pub struct ConfigFile {
+        /// Folders to search for groups, relative to the config file
         pub search_paths: Vec<String>,
+        #[serde(default)]
         pub artist_name_overrides: Vec<ConfigArtistNameOverride>,
+        #[serde(default)]
+        pub musicbrainz: MusicBrainzConfig,
+        #[serde(default)]
+        pub cache: CacheConfig,
+        #[serde(default)]
+        pub cddb: CddbConfig,
+        /// Identifying compilation songs without an origin MBID by their audio fingerprint.
+        /// If unset, they're only identified by the MusicBrainz IDs in their native tags.
+        pub acoustid: Option<AcoustIdConfig>,
+        /// Where and how to render the output library. Only required by `render`.
+        pub output: Option<OutputConfig>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct OutputConfig {
+        /// Folder to render the output library into, relative to the config file
+        pub path: String,
+        /// The FFmpeg audio encoder, e.g. `libmp3lame`, `aac`, `libopus`, `flac`
+        #[serde(default = "OutputConfig::default_codec")]
+        pub codec: String,
+        /// The FFmpeg audio bitrate e.g. `320k`. If unset, the encoder's default is used.
+        pub bitrate: Option<String>,
+        /// Extension of the output files, which FFmpeg also uses to pick the container
+        #[serde(default = "OutputConfig::default_extension")]
+        pub extension: String,
+        /// Path to the ffmpeg binary. Defaults to `ffmpeg` from the PATH.
+        pub ffmpeg: Option<String>,
+        /// If set, files pruned from the output library are moved into this folder (relative to the config file)
+        /// instead of being deleted.
+        pub quarantine: Option<String>,
+    }
+    impl OutputConfig {
+        fn default_codec() -> String {
+            "libmp3lame".to_owned()
+        }
+        fn default_extension() -> String {
+            "mp3".to_owned()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Default)]
+    pub struct CacheConfig {
+        /// Derived metadata sources and cached metadata older than this are looked up again.
+        /// If unset, they're kept until the group's Origin or input files change.
+        pub max_age_days: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Default)]
+    pub struct MusicBrainzConfig {
+        /// The base URL of the MusicBrainz web service, e.g. `http://localhost:5000/ws/2` for a local fixture server.
+        /// Defaults to `https://musicbrainz.org/ws/2`.
+        pub base_url: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Default)]
+    pub struct CddbConfig {
+        /// The host (and optionally port) of the gnudb/freedb server CDDB disc IDs are looked up on,
+        /// e.g. `localhost:8880` for a local stand-in. Defaults to `gnudb.gnudb.org`.
+        pub host: Option<String>,
+        /// The user to introduce ourselves to the server as. gnudb asks for an email address.
+        pub user: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct AcoustIdConfig {
+        /// The application API key to look fingerprints up with, see <https://acoustid.org/new-application>
+        pub api_key: String,
+        /// The base URL of the AcoustID web service, e.g. `http://localhost:5000/v2` for a local fixture server.
+        /// Defaults to `https://api.acoustid.org/v2`.
+        pub base_url: Option<String>,
+        /// Matches scoring less than this, from 0 to 1, are ignored
+        #[serde(default = "AcoustIdConfig::default_min_score")]
+        pub min_score: f64,
+    }
+    impl AcoustIdConfig {
+        fn default_min_score() -> f64 {
+            0.8
+        }
+    }
+    impl ConfigFile {
+        pub fn from_file(p: &Path) -> anyhow::Result<ConfigFile> {
+            let document = std::fs::read_to_string(p)?.parse::<toml_edit::DocumentMut>()?;
+            let file = toml_edit::de::from_document(document)?;
+            Ok(file)
+        }
     }
 
     #[derive(Serialize, Deserialize, Debug)]
@@ -93,7 +219,7 @@ pub mod user_defined {
     }
 
     /// A set of concrete sources for metadata, controlled by the user, that are never discarded.
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
     pub struct Origin {
         pub url: Option<String>,
         pub mb_release_group_id: Option<MbId>,
@@ -123,7 +249,7 @@ pub mod user_defined {
             origin: Origin,
             scan_filter: Option<ScanFilter>,
             album_art_rel_path: Option<String>,
-            override_metadata: Option<metadata::album::Override>,
+            override_metadata: Option<Box<metadata::album::Override>>,
             songs: Vec<AlbumInputSongOverride>,
         },
     }
@@ -163,17 +289,36 @@ pub mod user_defined {
 pub mod metadata {
     use super::*;
 
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
     pub struct CachedArtist {
-        id: MbId,
-        name: String,
+        pub id: MbId,
+        pub name: String,
+        /// e.g. "Beatles, The". Missing from caches written before sort names were fetched.
+        #[serde(default)]
+        pub sort_name: Option<String>,
+    }
+
+    /// Each field of a [song::Output] is taken from the highest layer that supplied it.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+    pub enum MetadataLayer {
+        /// No layer supplied the field, so it was made up from e.g. the file name or the position in the group.
+        #[default]
+        Fallback,
+        /// The tags inside the source music file.
+        Native,
+        /// Metadata cached from the group's derived metadata source i.e. MusicBrainz.
+        Cached,
+        /// The user's overrides in the group's `music.tm2.toml`.
+        Override,
     }
 
     pub mod song {
-        use super::CachedArtist;
+        use super::{CachedArtist, MetadataLayer};
         use crate::data_model::{Chromaprint, MbId};
         use serde::{Deserialize, Serialize};
 
         /// Derived by the tool from the Origin and other metadata and cached as an association with each group.
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
         pub struct CompilationDerivedMetadataSource {
             pub chromaprint: Option<Chromaprint>,
             pub mb_recording_id: Option<MbId>,
@@ -183,16 +328,82 @@ pub mod metadata {
         pub struct Override {
             pub song_title: Option<String>,
             pub song_artists: Option<Vec<String>>,
+            pub song_title_sort: Option<String>,
+            pub song_artists_sort: Option<Vec<String>>,
+            pub isrc: Option<String>,
+            pub composers: Option<Vec<String>>,
         }
 
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
         pub struct Cached {
             pub song_title: String,
             pub song_artists: Vec<CachedArtist>,
+            // The fields below are missing from caches written before they were fetched
+            #[serde(default)]
+            pub isrc: Option<String>,
+            #[serde(default)]
+            pub composers: Vec<String>,
         }
 
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
         pub struct Output {
             pub song_title: String,
             pub song_artists: Vec<String>,
+            pub album_title: Option<String>,
+            pub album_artists: Vec<String>,
+            pub disc_idx: Option<u64>,
+            pub track_idx: Option<u64>,
+            /// How players should sort the fields above.
+            /// Only taken from the layer that supplied the field itself or a higher one, so they never disagree.
+            #[serde(default)]
+            pub song_title_sort: Option<String>,
+            #[serde(default)]
+            pub song_artists_sort: Vec<String>,
+            #[serde(default)]
+            pub album_title_sort: Option<String>,
+            #[serde(default)]
+            pub album_artists_sort: Vec<String>,
+            /// Release date of the album e.g. `2001`, `2001-05` or `2001-05-03`
+            #[serde(default)]
+            pub date: Option<String>,
+            /// Release date of the first release of the album
+            #[serde(default)]
+            pub original_date: Option<String>,
+            #[serde(default)]
+            pub genres: Vec<String>,
+            #[serde(default)]
+            pub label: Option<String>,
+            #[serde(default)]
+            pub catalog_number: Option<String>,
+            #[serde(default)]
+            pub barcode: Option<String>,
+            #[serde(default)]
+            pub isrc: Option<String>,
+            #[serde(default)]
+            pub composers: Vec<String>,
+        }
+
+        /// Which [MetadataLayer] supplied each field of an [Output]
+        #[derive(Debug, Clone, Copy, Default)]
+        pub struct OutputProvenance {
+            pub song_title: MetadataLayer,
+            pub song_artists: MetadataLayer,
+            pub album_title: MetadataLayer,
+            pub album_artists: MetadataLayer,
+            pub disc_idx: MetadataLayer,
+            pub track_idx: MetadataLayer,
+            pub song_title_sort: MetadataLayer,
+            pub song_artists_sort: MetadataLayer,
+            pub album_title_sort: MetadataLayer,
+            pub album_artists_sort: MetadataLayer,
+            pub date: MetadataLayer,
+            pub original_date: MetadataLayer,
+            pub genres: MetadataLayer,
+            pub label: MetadataLayer,
+            pub catalog_number: MetadataLayer,
+            pub barcode: MetadataLayer,
+            pub isrc: MetadataLayer,
+            pub composers: MetadataLayer,
         }
     }
     pub mod album {
@@ -201,11 +412,13 @@ pub mod metadata {
         use serde::{Deserialize, Serialize};
 
         /// Derived by the tool from the Origin and other metadata and cached as an association with each group.
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
         pub struct DerivedMetadataSource {
             pub mb_release_group_and_release_ids: Option<(MbId, MbId)>,
             pub derived_songs: Vec<SongDerivedMetadataSource>,
         }
 
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
         pub struct SongDerivedMetadataSource {
             pub chromaprint: Option<Chromaprint>,
             pub media_track_idxs: Option<(i64, i64)>,
@@ -216,13 +429,69 @@ pub mod metadata {
         pub struct Override {
             pub album_title: Option<String>,
             pub album_artists: Option<Vec<String>>,
+            pub album_title_sort: Option<String>,
+            pub album_artists_sort: Option<Vec<String>>,
+            pub date: Option<String>,
+            pub original_date: Option<String>,
+            pub genres: Option<Vec<String>>,
+            pub label: Option<String>,
+            pub catalog_number: Option<String>,
+            pub barcode: Option<String>,
+            /// The disc the songs are on, instead of 1 or their disc tags. Songs tagged with another disc are warned about.
             pub fixed_disc_idx: Option<u64>,
+            /// Added to the track index of the songs, until a song overrides its own track index
             pub offset_track_idx: Option<i64>,
         }
 
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
         pub struct Cached {
             pub title: String,
             pub artists: Vec<CachedArtist>,
+            /// The tracklist of each medium (i.e. disc) in the release, in order.
+            /// Songs look up their metadata from here using their 1-indexed disc and track indices.
+            pub media: Vec<Vec<super::song::Cached>>,
+            // The fields below are missing from caches written before they were fetched
+            #[serde(default)]
+            pub date: Option<String>,
+            #[serde(default)]
+            pub original_date: Option<String>,
+            #[serde(default)]
+            pub genres: Vec<String>,
+            #[serde(default)]
+            pub label: Option<String>,
+            #[serde(default)]
+            pub catalog_number: Option<String>,
+            #[serde(default)]
+            pub barcode: Option<String>,
+        }
+
+        impl Cached {
+            pub fn song(&self, disc_idx: u64, track_idx: u64) -> Option<&super::song::Cached> {
+                let medium = self
+                    .media
+                    .get(usize::try_from(disc_idx).ok()?.checked_sub(1)?)?;
+                medium.get(usize::try_from(track_idx).ok()?.checked_sub(1)?)
+            }
+
+            /// Where a 1-indexed disc and track index actually lands in the release.
+            /// If the track index is too large for the disc, move to the next disc and subtract the length of the previous one,
+            /// so long sequential track numbers are split across the media.
+            /// Returns None if the track runs past the last medium.
+            pub fn split_position(&self, disc_idx: u64, track_idx: u64) -> Option<(u64, u64)> {
+                if disc_idx == 0 || track_idx == 0 {
+                    return None;
+                }
+                let (mut disc_idx, mut track_idx) = (disc_idx, track_idx);
+                loop {
+                    let medium_len =
+                        self.media.get(usize::try_from(disc_idx - 1).ok()?)?.len() as u64;
+                    if track_idx <= medium_len {
+                        return Some((disc_idx, track_idx));
+                    }
+                    track_idx -= medium_len;
+                    disc_idx += 1;
+                }
+            }
         }
     }
 }
@@ -235,6 +504,38 @@ pub mod metadata {
 // }
 type FileId = PathBuf;
 
+/// Hex encoded SHA256 digest of a file, used to detect when files change
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileHash(String);
+impl FileHash {
+    pub fn of_file(p: &Path) -> std::io::Result<Self> {
+        use sha2::Digest;
+        use std::io::Read;
+
+        let mut hasher = sha2::Sha256::new();
+        let mut file = std::fs::File::open(p)?;
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(Self(
+            hasher
+                .finalize()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+        ))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 pub mod native_metadata;
 
 pub struct CompilationInputGroup {
@@ -242,6 +543,8 @@ pub struct CompilationInputGroup {
     scan_filter: Option<user_defined::ScanFilter>,
     title: String,
     song_files: Vec<CompilationInputSong>,
+    warnings: Vec<String>,
+    tag_errors: Vec<NativeMetadataError>,
 }
 
 pub struct CompilationInputSong {
@@ -252,6 +555,7 @@ pub struct CompilationInputSong {
     derived_metadata_src: Option<metadata::song::CompilationDerivedMetadataSource>,
     cached_metadata: Option<metadata::song::Cached>,
     native_metadata: NativeMetadata,
+    chromaprint: Option<Chromaprint>,
 }
 
 impl CompilationInputGroup {
@@ -264,28 +568,37 @@ impl CompilationInputGroup {
         songs: Vec<CompilationInputSongOverride>,
 
         non_rel_song_paths: Vec<PathBuf>,
-    ) -> Self {
+    ) -> anyhow::Result<Self> {
         // Build a set of song information for all songs scanned
         let mut mapping = HashMap::new();
+        let mut warnings = vec![];
+        let mut tag_errors = vec![];
         // sort music_files by path alphanumeric descending, this is the first step of the ordering.
         let mut rel_song_paths = non_rel_song_paths
             .into_iter()
             .map(|p| {
+                let unprefixed_p = p
+                    .strip_prefix(path)
+                    .expect("non_rel_song_paths had a path that wasn't prefixed with the parent")
+                    .to_owned();
                 mapping.insert(
-                    p.clone(),
+                    unprefixed_p.clone(),
                     CompilationInputSong {
-                        file: p.clone(),
+                        file: unprefixed_p.clone(),
                         origin_mbid: None,
                         override_metadata: None,
                         derived_metadata_src: None,
                         cached_metadata: None,
-                        native_metadata: NativeMetadataFormat::parse_from_file(&p)
-                            .unwrap_or_default(), // TODO log errors
+                        chromaprint: None,
+                        native_metadata: read_native_metadata(
+                            &p,
+                            &unprefixed_p,
+                            &mut warnings,
+                            &mut tag_errors,
+                        ),
                     },
                 );
-                (p.strip_prefix(path)
-                    .expect("non_rel_song_paths had a path that wasn't prefixed with the parent")
-                    .to_owned())
+                unprefixed_p
             })
             // uniquify
             .collect::<HashSet<_>>()
@@ -294,7 +607,7 @@ impl CompilationInputGroup {
         rel_song_paths.sort();
 
         if mapping.len() != rel_song_paths.len() {
-            panic!("rel_song_paths had duplicates");
+            anyhow::bail!("rel_song_paths had duplicates");
         }
 
         // For each override:
@@ -304,23 +617,36 @@ impl CompilationInputGroup {
 
             // - apply the reordering if present. we want to apply the reorderings in file order so it makes sense to the user.
             // TODO does this make sense or is it just confusing? it will be stable but if the user asks for "z is 5, y is 4, x is 3" they will/will not get the exact indices they want
-            match s.override_position {
-                Some(override_pos) => {
-                    let existing_pos = rel_song_paths.iter().position(|p| p.as_os_str() == path.as_os_str()).expect("CompilationInputGroup file contained an override for a file that isn't in the compilation");
-                    // if we need to, reorder by shifting things up and down.
-                    if existing_pos < override_pos {
-                        (&mut rel_song_paths[existing_pos..=override_pos]).rotate_left(1);
-                    } else if existing_pos > override_pos {
-                        (&mut rel_song_paths[override_pos..=existing_pos]).rotate_right(1);
-                    }
+            if let Some(override_pos) = s.override_position {
+                let Some(existing_pos) = rel_song_paths
+                    .iter()
+                    .position(|p| p.as_os_str() == path.as_os_str())
+                else {
+                    anyhow::bail!(
+                        "CompilationInputGroup file contained an override for {:?}, which isn't in the compilation",
+                        path
+                    );
+                };
+                if override_pos >= rel_song_paths.len() {
+                    anyhow::bail!(
+                        "CompilationInputGroup moved {:?} to position {}, but there are only {} songs",
+                        path,
+                        override_pos,
+                        rel_song_paths.len()
+                    );
+                }
+                // if we need to, reorder by shifting things up and down.
+                if existing_pos < override_pos {
+                    rel_song_paths[existing_pos..=override_pos].rotate_left(1);
+                } else if existing_pos > override_pos {
+                    rel_song_paths[override_pos..=existing_pos].rotate_right(1);
                 }
-                None => {}
             };
 
             // - update the mapping with the override information
             let s_mapping = mapping.get_mut(&path);
             match s_mapping {
-                None => panic!(
+                None => anyhow::bail!(
                     "CompilationInputGroup referred to song {:?} not present",
                     path
                 ),
@@ -338,10 +664,14 @@ impl CompilationInputGroup {
         }
 
         // pull the data out of the mapping, ordered by the final ordering of rel_song_paths
-        CompilationInputGroup {
+        warnings.sort();
+        tag_errors.sort_by(|a: &NativeMetadataError, b| a.path.cmp(&b.path));
+        Ok(CompilationInputGroup {
             origin,
             scan_filter,
             title,
+            warnings,
+            tag_errors,
             song_files: rel_song_paths
                 .into_iter()
                 .map(|p| {
@@ -350,7 +680,92 @@ impl CompilationInputGroup {
                         .expect("Removing from a list that was populated with mapping")
                 })
                 .collect(),
-        }
+        })
+    }
+
+    pub fn num_songs(&self) -> usize {
+        self.song_files.len()
+    }
+
+    pub fn origin(&self) -> &Origin {
+        &self.origin
+    }
+
+    pub fn scan_filter(&self) -> Option<&ScanFilter> {
+        self.scan_filter.as_ref()
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// Problems with the group that don't stop it being used, e.g. songs whose contents don't match their extension
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Songs whose native metadata (or some of its fields) couldn't be read.
+    /// They're still in the group, with whatever native metadata could be read.
+    pub fn tag_errors(&self) -> &[NativeMetadataError] {
+        &self.tag_errors
+    }
+
+    /// The songs in their final compilation order
+    pub fn songs(&self) -> &[CompilationInputSong] {
+        &self.song_files
+    }
+
+    pub fn songs_mut(&mut self) -> &mut [CompilationInputSong] {
+        &mut self.song_files
+    }
+}
+
+impl CompilationInputSong {
+    /// Path relative to the group
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    pub fn origin_mbid(&self) -> Option<&MbId> {
+        self.origin_mbid.as_ref()
+    }
+
+    pub fn override_metadata(&self) -> Option<&metadata::song::Override> {
+        self.override_metadata.as_ref()
+    }
+
+    pub fn native_metadata(&self) -> &NativeMetadata {
+        &self.native_metadata
+    }
+
+    pub fn derived_metadata_src(
+        &self,
+    ) -> Option<&metadata::song::CompilationDerivedMetadataSource> {
+        self.derived_metadata_src.as_ref()
+    }
+
+    pub fn set_derived_metadata_src(
+        &mut self,
+        src: Option<metadata::song::CompilationDerivedMetadataSource>,
+    ) {
+        self.derived_metadata_src = src;
+    }
+
+    pub fn cached_metadata(&self) -> Option<&metadata::song::Cached> {
+        self.cached_metadata.as_ref()
+    }
+
+    pub fn set_cached_metadata(&mut self, cached: Option<metadata::song::Cached>) {
+        self.cached_metadata = cached;
+    }
+
+    /// The song's audio fingerprint, if the group has been fingerprinted
+    pub fn chromaprint(&self) -> Option<&Chromaprint> {
+        self.chromaprint.as_ref()
+    }
+
+    pub fn set_chromaprint(&mut self, chromaprint: Option<Chromaprint>) {
+        self.chromaprint = chromaprint;
     }
 }
 
@@ -361,9 +776,17 @@ pub struct AlbumInputGroup {
     album_art: Option<FileId>,
 
     song_files: Vec<AlbumInputSong>,
+    /// Rip logs in the group folder, by their path relative to the group
+    rip_logs: Vec<(PathBuf, RipLog)>,
+    /// Warnings that don't depend on where the songs are, which `warnings` starts with
+    scan_warnings: Vec<String>,
+    /// `scan_warnings` followed by the warnings about the songs' positions, which change when they're split across the cached release
+    warnings: Vec<String>,
+    tag_errors: Vec<NativeMetadataError>,
 
     derived_metadata: Option<metadata::album::DerivedMetadataSource>,
-    cached_metadata: Option<(metadata::album::Cached, Vec<metadata::song::Cached>)>,
+    /// The cached album metadata, and the cached metadata looked up for each song in `song_files` (if the song was in the release).
+    cached_metadata: Option<(metadata::album::Cached, Vec<Option<metadata::song::Cached>>)>,
 }
 pub struct AlbumInputSong {
     file: FileId,
@@ -372,8 +795,16 @@ pub struct AlbumInputSong {
 
     adjusted_disc_idx: u64,
     adjusted_track_idx: u64,
+    /// Where the adjusted disc and track indices came from
+    adjusted_idx_layer: metadata::MetadataLayer,
+    /// The adjusted disc and track indices after splitting them across the media of the cached release,
+    /// see [metadata::album::Cached::split_position]
+    split_idx: Option<(u64, u64)>,
+    chromaprint: Option<Chromaprint>,
 }
 impl AlbumInputGroup {
+    /// If there's exactly one rip log, the disc IDs it gives are checked against the Origin's.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         path: &Path,
 
@@ -384,7 +815,8 @@ impl AlbumInputGroup {
         songs: Vec<AlbumInputSongOverride>,
 
         non_rel_song_paths: Vec<PathBuf>,
-    ) -> Self {
+        non_rel_rip_log_paths: Vec<PathBuf>,
+    ) -> anyhow::Result<Self> {
         // Build a set of song information for all songs scanned
         let mut override_mapping = HashMap::new();
 
@@ -415,6 +847,8 @@ impl AlbumInputGroup {
         }
 
         let mut native_metadata_mapping = HashMap::new();
+        let mut native_warnings = vec![];
+        let mut tag_errors = vec![];
 
         // sort music_files by path alphanumeric descending, this is the first step of the ordering.
         // at the same time, build a mapping for the native metadata
@@ -427,7 +861,7 @@ impl AlbumInputGroup {
                     .to_owned();
                 native_metadata_mapping.insert(
                     unprefixed_p.clone(),
-                    NativeMetadataFormat::parse_from_file(&p).unwrap_or_default(), // TODO log errors
+                    read_native_metadata(&p, &unprefixed_p, &mut native_warnings, &mut tag_errors),
                 );
                 unprefixed_p
             })
@@ -437,54 +871,874 @@ impl AlbumInputGroup {
             .collect::<Vec<_>>();
         rel_song_paths.sort();
 
-        // For each override:
-        let mut adjusted_disc_idx = 1;
-        let mut adjusted_track_idx = 0;
+        // Number the songs, in alphanumeric order.
+        // Songs with native disc/track tags use those, adjusted by the album override's fixed disc and track offset.
+        // Songs without them continue counting from the previous song,
+        // with the first song starting on the fixed disc (or 1) at the offset track (or 1).
+        // A song tagged with a new disc but no track starts that disc again from the offset track (or 1).
+        // Per-song override indices are absolute, and the following songs continue counting from them.
+        // A fixed disc replaces the songs' disc tags, so songs tagged with a different disc are warned about.
+        let fixed_disc_idx = override_metadata.as_ref().and_then(|o| o.fixed_disc_idx);
+        let offset_track_idx = override_metadata
+            .as_ref()
+            .and_then(|o| o.offset_track_idx)
+            .unwrap_or(0);
+        let album_idx_layer = if fixed_disc_idx.is_some() || offset_track_idx != 0 {
+            metadata::MetadataLayer::Override
+        } else {
+            metadata::MetadataLayer::Fallback
+        };
+        let mut adjusted_disc_idx = fixed_disc_idx.unwrap_or(1);
+        let mut adjusted_track_idx = offset_track_idx;
+        let mut other_disc_tags = vec![];
         let song_files = rel_song_paths
             .into_iter()
             .map(|r| {
+                let native_metadata = native_metadata_mapping
+                    .remove(&r)
+                    .expect("This must have been built, we know rel_song_paths doesn't have dupes");
+
+                let mut adjusted_idx_layer = album_idx_layer;
+                let mut other_disc_tag = fixed_disc_idx
+                    .zip(native_metadata.disc_idx)
+                    .filter(|(fixed, d)| fixed != d)
+                    .map(|(_, d)| d);
                 adjusted_track_idx += 1;
+                if let Some(d) = native_metadata.disc_idx {
+                    let disc_idx = fixed_disc_idx.unwrap_or(d);
+                    if disc_idx != adjusted_disc_idx {
+                        adjusted_track_idx = offset_track_idx + 1;
+                    }
+                    adjusted_disc_idx = disc_idx;
+                }
+                if let Some(t) = native_metadata.track_idx {
+                    adjusted_track_idx = t as i64 + offset_track_idx;
+                }
+                if (native_metadata.disc_idx.is_some() || native_metadata.track_idx.is_some())
+                    && album_idx_layer == metadata::MetadataLayer::Fallback
+                {
+                    adjusted_idx_layer = metadata::MetadataLayer::Native;
+                }
+
                 let override_metadata = match override_mapping.remove(&r) {
                     Some(s) => {
                         if let Some(d) = s.override_disc_idx {
                             adjusted_disc_idx = d;
+                            adjusted_idx_layer = metadata::MetadataLayer::Override;
+                            other_disc_tag = None;
                         }
                         if let Some(t) = s.override_track_idx {
-                            adjusted_track_idx = t;
+                            adjusted_track_idx = t as i64;
+                            adjusted_idx_layer = metadata::MetadataLayer::Override;
                         }
                         s.override_metadata
                     }
                     None => None,
                 };
-                let native_metadata = native_metadata_mapping
-                    .remove(&r)
-                    .expect("This must have been built, we know rel_song_paths doesn't have dupes");
-                AlbumInputSong {
+                if adjusted_disc_idx < 1 || adjusted_track_idx < 1 {
+                    anyhow::bail!(
+                        "{} would be disc {} track {}, but both must be at least 1 - check offset_track_idx and the song overrides",
+                        r.display(),
+                        adjusted_disc_idx,
+                        adjusted_track_idx
+                    );
+                }
+                if let Some(d) = other_disc_tag {
+                    other_disc_tags.push(format!("{} (disc {})", r.display(), d));
+                }
+                Ok(AlbumInputSong {
                     file: r,
                     override_metadata,
                     native_metadata,
                     adjusted_disc_idx,
-                    adjusted_track_idx,
-                }
+                    adjusted_track_idx: adjusted_track_idx as u64,
+                    adjusted_idx_layer,
+                    split_idx: None,
+                    chromaprint: None,
+                })
             })
-            .collect();
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        native_warnings.sort();
+        tag_errors.sort_by(|a: &NativeMetadataError, b| a.path.cmp(&b.path));
+        let mut scan_warnings = native_warnings;
+        if let Some(fixed_disc_idx) = fixed_disc_idx
+            && !other_disc_tags.is_empty()
+        {
+            scan_warnings.push(format!(
+                "fixed_disc_idx puts songs on disc {}, ignoring their disc tags: {}",
+                fixed_disc_idx,
+                other_disc_tags.join(", ")
+            ));
+        }
+        let rip_logs = read_rip_logs(path, non_rel_rip_log_paths, &mut scan_warnings);
+        if let [(log_path, log)] = rip_logs.as_slice() {
+            check_rip_log_discids(&origin, log_path, log, &mut scan_warnings);
+        }
 
-        if override_mapping.len() > 0 {
-            panic!(
+        if !override_mapping.is_empty() {
+            anyhow::bail!(
                 "Overrode some songs that weren't found: {:?}",
-                override_mapping
+                override_mapping.keys().collect::<Vec<_>>()
             );
         }
 
         // pull the data out of the mapping, ordered by the final ordering of rel_song_paths
-        AlbumInputGroup {
+        let mut album = AlbumInputGroup {
             origin,
             override_metadata,
             scan_filter,
             album_art: album_art.map(|s| s.into()),
             song_files,
+            rip_logs,
+            scan_warnings,
+            warnings: vec![],
+            tag_errors,
             derived_metadata: None,
             cached_metadata: None,
+        };
+        album.update_warnings();
+        Ok(album)
+    }
+
+    /// Recompute the position warnings from where the songs are now
+    fn update_warnings(&mut self) {
+        // Songs offset from the start of the disc are expected not to have the tracks before them
+        let skip_leading_gaps = self
+            .override_metadata
+            .as_ref()
+            .and_then(|o| o.offset_track_idx)
+            .is_some_and(|offset| offset != 0);
+        self.warnings = self.scan_warnings.clone();
+        self.warnings
+            .extend(position_warnings(&self.song_files, skip_leading_gaps));
+    }
+
+    /// Problems with the group that don't stop it being used, e.g. two songs with the same disc and track index
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Songs whose native metadata (or some of its fields) couldn't be read.
+    /// They're still in the group, with whatever native metadata could be read.
+    pub fn tag_errors(&self) -> &[NativeMetadataError] {
+        &self.tag_errors
+    }
+
+    pub fn num_songs(&self) -> usize {
+        self.song_files.len()
+    }
+
+    pub fn origin(&self) -> &Origin {
+        &self.origin
+    }
+
+    pub fn override_metadata(&self) -> Option<&metadata::album::Override> {
+        self.override_metadata.as_ref()
+    }
+
+    pub fn scan_filter(&self) -> Option<&ScanFilter> {
+        self.scan_filter.as_ref()
+    }
+
+    /// Path relative to the group
+    pub fn album_art(&self) -> Option<&Path> {
+        self.album_art.as_deref()
+    }
+
+    /// The songs in alphanumeric order of their paths
+    pub fn songs(&self) -> &[AlbumInputSong] {
+        &self.song_files
+    }
+
+    pub fn songs_mut(&mut self) -> &mut [AlbumInputSong] {
+        &mut self.song_files
+    }
+
+    /// The rip logs that could be read, by their path relative to the group, in alphanumeric order
+    pub fn rip_logs(&self) -> &[(PathBuf, RipLog)] {
+        &self.rip_logs
+    }
+
+    /// The MusicBrainz and CDDB disc IDs from the group's rip log, if it has exactly one.
+    /// They're kept apart from the Origin because unlike the user's IDs, the disc may not be in either database.
+    pub fn rip_log_discids(&self) -> Option<(MbDiscId, CddbDiscId)> {
+        match self.rip_logs.as_slice() {
+            [(_, log)] => Some((log.toc.mb_discid(), log.toc.cddb_discid())),
+            _ => None,
+        }
+    }
+
+    pub fn derived_metadata(&self) -> Option<&metadata::album::DerivedMetadataSource> {
+        self.derived_metadata.as_ref()
+    }
+
+    pub fn set_derived_metadata(&mut self, src: Option<metadata::album::DerivedMetadataSource>) {
+        self.derived_metadata = src;
+    }
+
+    pub fn cached_metadata(&self) -> Option<&metadata::album::Cached> {
+        self.cached_metadata.as_ref().map(|(album, _)| album)
+    }
+
+    /// The cached metadata for the song at `idx` in [Self::songs], looked up by its disc and track index
+    pub fn cached_song_metadata(&self, idx: usize) -> Option<&metadata::song::Cached> {
+        self.cached_metadata
+            .as_ref()
+            .and_then(|(_, songs)| songs.get(idx))
+            .and_then(Option::as_ref)
+    }
+
+    /// Set the cached metadata for the album, and look up the cached metadata for each song from it.
+    /// Songs whose track index overflows their disc are split onto the following discs of the release.
+    /// Returns an error naming every song that ran past the last medium; those songs get no cached metadata.
+    pub fn set_cached_metadata(
+        &mut self,
+        cached: Option<metadata::album::Cached>,
+    ) -> anyhow::Result<()> {
+        let Some(album) = cached else {
+            self.cached_metadata = None;
+            for s in &mut self.song_files {
+                s.split_idx = None;
+            }
+            self.update_warnings();
+            return Ok(());
+        };
+
+        let mut overflowed = vec![];
+        let songs = self
+            .song_files
+            .iter_mut()
+            .map(|s| {
+                s.split_idx = album.split_position(s.adjusted_disc_idx, s.adjusted_track_idx);
+                match s.split_idx {
+                    Some((disc_idx, track_idx)) => album.song(disc_idx, track_idx).cloned(),
+                    None => {
+                        overflowed.push(format!(
+                            "{} (disc {} track {})",
+                            s.file.display(),
+                            s.adjusted_disc_idx,
+                            s.adjusted_track_idx
+                        ));
+                        None
+                    }
+                }
+            })
+            .collect();
+        let medium_lens = album.media.iter().map(Vec::len).collect::<Vec<_>>();
+        self.cached_metadata = Some((album, songs));
+        self.update_warnings();
+
+        if !overflowed.is_empty() {
+            anyhow::bail!(
+                "{} song(s) run past the last medium of the release, which has media of {:?} tracks: {}",
+                overflowed.len(),
+                medium_lens,
+                overflowed.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Read the native metadata of a song, warning about `rel_path` if its contents don't match its extension.
+/// Songs whose tags can't be read still belong to the group, they just have no native metadata.
+/// Fields that can't be read are recorded in `tag_errors` too, but the rest of the song's tags are kept.
+fn read_native_metadata(
+    path: &Path,
+    rel_path: &Path,
+    warnings: &mut Vec<String>,
+    tag_errors: &mut Vec<NativeMetadataError>,
+) -> NativeMetadata {
+    match NativeMetadataFormat::parse_from_file(path) {
+        Ok(mut native) => {
+            tag_errors.extend(
+                std::mem::take(&mut native.field_errors)
+                    .into_iter()
+                    .map(|kind| NativeMetadataError {
+                        path: path.to_owned(),
+                        kind,
+                    }),
+            );
+            match (native.format_mismatch, native.fmt) {
+                (None, _) => {}
+                (Some(ext_fmt), NativeMetadataFormat::None) => warnings.push(format!(
+                    "{} has the extension of {:?} but its contents weren't recognised, ignoring its tags",
+                    rel_path.display(),
+                    ext_fmt
+                )),
+                (Some(ext_fmt), fmt) => warnings.push(format!(
+                    "{} has the extension of {:?} but contains {:?}, reading it as {:?}",
+                    rel_path.display(),
+                    ext_fmt,
+                    fmt,
+                    fmt
+                )),
+            }
+            native
+        }
+        Err(err) => {
+            tag_errors.push(err);
+            NativeMetadata::default()
+        }
+    }
+}
+
+/// Read every EAC or XLD rip log, warning about (and skipping) the ones that can't be read.
+/// Other `.log` files are skipped without a warning.
+fn read_rip_logs(
+    path: &Path,
+    non_rel_paths: Vec<PathBuf>,
+    warnings: &mut Vec<String>,
+) -> Vec<(PathBuf, RipLog)> {
+    let mut rip_logs = vec![];
+    for p in non_rel_paths {
+        let rel_path = p
+            .strip_prefix(path)
+            .expect("non_rel_rip_log_paths had a path that wasn't prefixed with the parent")
+            .to_owned();
+        match RipLog::read(&p) {
+            Ok(Some(log)) => rip_logs.push((rel_path, log)),
+            // Other programs' logs are no concern of ours
+            Ok(None) => {}
+            Err(err) => warnings.push(format!(
+                "couldn't read the rip log {}: {:#}",
+                rel_path.display(),
+                err
+            )),
+        }
+    }
+    rip_logs.sort_by(|(a, _), (b, _)| a.cmp(b));
+    rip_logs
+}
+
+/// Warn if the disc IDs the Origin has disagree with the ones from a rip log
+fn check_rip_log_discids(
+    origin: &Origin,
+    log_path: &Path,
+    log: &RipLog,
+    warnings: &mut Vec<String>,
+) {
+    let mb_discid = log.toc.mb_discid();
+    if let Some(origin_discid) = &origin.mb_discid
+        && *origin_discid != mb_discid
+    {
+        warnings.push(format!(
+            "the Origin has MusicBrainz disc ID {}, but {} gives {}",
+            origin_discid.as_str(),
+            log_path.display(),
+            mb_discid.as_str()
+        ));
+    }
+    let cddb_discid = log.toc.cddb_discid();
+    if let Some(origin_discid) = &origin.cddb_discid
+        && *origin_discid != cddb_discid
+    {
+        warnings.push(format!(
+            "the Origin has CDDB disc ID {}, but {} gives {}",
+            origin_discid.as_str(),
+            log_path.display(),
+            cddb_discid.as_str()
+        ));
+    }
+}
+
+/// Warn about songs sharing a disc and track index, and about tracks missing from a disc.
+/// Tracks missing before the first song on a disc are only warned about if `skip_leading_gaps` isn't set.
+fn position_warnings(songs: &[AlbumInputSong], skip_leading_gaps: bool) -> Vec<String> {
+    let mut discs: BTreeMap<u64, BTreeMap<u64, Vec<&Path>>> = BTreeMap::new();
+    for s in songs {
+        discs
+            .entry(s.disc_idx())
+            .or_default()
+            .entry(s.track_idx())
+            .or_default()
+            .push(&s.file);
+    }
+
+    let mut warnings = vec![];
+    for (disc_idx, tracks) in &discs {
+        for (track_idx, files) in tracks {
+            if files.len() > 1 {
+                warnings.push(format!(
+                    "{} songs are all disc {} track {}: {}",
+                    files.len(),
+                    disc_idx,
+                    track_idx,
+                    files
+                        .iter()
+                        .map(|f| f.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
         }
+        let (Some(&first_track_idx), Some(&last_track_idx)) =
+            (tracks.keys().next(), tracks.keys().next_back())
+        else {
+            continue;
+        };
+        let first_track_idx = if skip_leading_gaps {
+            first_track_idx
+        } else {
+            1
+        };
+        let missing = (first_track_idx..last_track_idx)
+            .filter(|t| !tracks.contains_key(t))
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            warnings.push(format!(
+                "disc {} is missing track(s) {}",
+                disc_idx,
+                missing.join(", ")
+            ));
+        }
+    }
+    warnings
+}
+
+impl AlbumInputSong {
+    /// Path relative to the group
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    pub fn override_metadata(&self) -> Option<&metadata::song::Override> {
+        self.override_metadata.as_ref()
+    }
+
+    pub fn native_metadata(&self) -> &NativeMetadata {
+        &self.native_metadata
+    }
+
+    /// The 1-indexed disc this song is on, after applying overrides and splitting across the cached release
+    pub fn disc_idx(&self) -> u64 {
+        self.split_idx.map_or(self.adjusted_disc_idx, |(d, _)| d)
+    }
+
+    /// The 1-indexed track of this song within its disc, after applying overrides and splitting across the cached release
+    pub fn track_idx(&self) -> u64 {
+        self.split_idx.map_or(self.adjusted_track_idx, |(_, t)| t)
+    }
+
+    /// The song's audio fingerprint, if the group has been fingerprinted
+    pub fn chromaprint(&self) -> Option<&Chromaprint> {
+        self.chromaprint.as_ref()
+    }
+
+    pub fn set_chromaprint(&mut self, chromaprint: Option<Chromaprint>) {
+        self.chromaprint = chromaprint;
+    }
+
+    /// [metadata::MetadataLayer::Cached] if splitting across the cached release moved the song, otherwise where the adjusted indices came from
+    pub fn disc_track_idx_layer(&self) -> metadata::MetadataLayer {
+        match self.split_idx {
+            Some(split) if split != (self.adjusted_disc_idx, self.adjusted_track_idx) => {
+                metadata::MetadataLayer::Cached
+            }
+            _ => self.adjusted_idx_layer,
+        }
+    }
+}
+
+#[cfg(test)]
+impl AlbumInputGroup {
+    /// An album of songs with the given file names and contents, written to `dir`
+    pub(crate) fn for_test(
+        dir: &Path,
+        origin: Origin,
+        override_metadata: Option<metadata::album::Override>,
+        songs: &[(&str, &[u8])],
+    ) -> Self {
+        let paths = songs
+            .iter()
+            .map(|(name, contents)| {
+                let path = dir.join(name);
+                std::fs::write(&path, contents).unwrap();
+                path
+            })
+            .collect();
+        Self::new(
+            dir,
+            origin,
+            override_metadata,
+            None,
+            None,
+            vec![],
+            paths,
+            vec![],
+        )
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+impl CompilationInputGroup {
+    /// A compilation of songs with the given file names, contents and origin MBIDs, written to `dir`
+    pub(crate) fn for_test(dir: &Path, songs: &[(&str, &[u8], Option<&str>)]) -> Self {
+        let paths = songs
+            .iter()
+            .map(|(name, contents, _)| {
+                let path = dir.join(name);
+                std::fs::write(&path, contents).unwrap();
+                path
+            })
+            .collect();
+        let overrides = songs
+            .iter()
+            .map(|(name, _, origin_mbid)| CompilationInputSongOverride {
+                file_rel_path: (*name).to_owned(),
+                origin_mbid: origin_mbid.map(MbId::new),
+                override_metadata: None,
+                override_position: None,
+            })
+            .collect();
+        Self::new(
+            dir,
+            Origin::default(),
+            None,
+            "Compilation".to_owned(),
+            overrides,
+            paths,
+        )
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached_album(medium_lens: &[usize]) -> metadata::album::Cached {
+        let media = medium_lens
+            .iter()
+            .enumerate()
+            .map(|(disc, &len)| {
+                (1..=len)
+                    .map(|track| metadata::song::Cached {
+                        song_title: format!("Disc {} Track {track}", disc + 1),
+                        song_artists: vec![],
+                        isrc: None,
+                        composers: vec![],
+                    })
+                    .collect()
+            })
+            .collect();
+        metadata::album::Cached {
+            title: "Album".to_owned(),
+            artists: vec![],
+            media,
+            date: None,
+            original_date: None,
+            genres: vec![],
+            label: None,
+            catalog_number: None,
+            barcode: None,
+        }
+    }
+
+    #[test]
+    fn split_position_within_a_medium() {
+        let album = cached_album(&[3, 2]);
+        assert_eq!(album.split_position(1, 1), Some((1, 1)));
+        assert_eq!(album.split_position(1, 3), Some((1, 3)));
+        assert_eq!(album.split_position(2, 2), Some((2, 2)));
+    }
+
+    #[test]
+    fn split_position_across_medium_boundaries() {
+        let album = cached_album(&[3, 2, 4]);
+        assert_eq!(album.split_position(1, 4), Some((2, 1)));
+        assert_eq!(album.split_position(1, 5), Some((2, 2)));
+        assert_eq!(album.split_position(1, 6), Some((3, 1)));
+        assert_eq!(album.split_position(1, 9), Some((3, 4)));
+        // Counting on from a later disc only skips the media after it
+        assert_eq!(album.split_position(2, 3), Some((3, 1)));
+    }
+
+    #[test]
+    fn split_position_past_the_last_medium() {
+        let album = cached_album(&[3, 2]);
+        assert_eq!(album.split_position(1, 6), None);
+        assert_eq!(album.split_position(2, 3), None);
+        assert_eq!(album.split_position(3, 1), None);
+        assert_eq!(cached_album(&[]).split_position(1, 1), None);
+    }
+
+    #[test]
+    fn split_position_is_1_indexed() {
+        let album = cached_album(&[3, 2]);
+        assert_eq!(album.split_position(1, 0), None);
+        assert_eq!(album.split_position(0, 1), None);
+        assert_eq!(album.split_position(0, 0), None);
+    }
+
+    #[test]
+    fn split_position_skips_empty_media() {
+        let album = cached_album(&[2, 0, 2]);
+        assert_eq!(album.split_position(1, 3), Some((3, 1)));
+        assert_eq!(album.split_position(2, 1), Some((3, 1)));
+    }
+
+    fn album_override(
+        fixed_disc_idx: Option<u64>,
+        offset_track_idx: Option<i64>,
+    ) -> metadata::album::Override {
+        metadata::album::Override {
+            album_title: None,
+            album_artists: None,
+            album_title_sort: None,
+            album_artists_sort: None,
+            date: None,
+            original_date: None,
+            genres: None,
+            label: None,
+            catalog_number: None,
+            barcode: None,
+            fixed_disc_idx,
+            offset_track_idx,
+        }
+    }
+
+    /// An album of untagged songs `01.flac`, `02.flac`...
+    fn untagged_album(
+        dir: &tempfile::TempDir,
+        num_songs: usize,
+        override_metadata: Option<metadata::album::Override>,
+    ) -> AlbumInputGroup {
+        let names = (1..=num_songs)
+            .map(|i| format!("{i:02}.flac"))
+            .collect::<Vec<_>>();
+        let songs = names
+            .iter()
+            .map(|name| (name.as_str(), &b""[..]))
+            .collect::<Vec<_>>();
+        AlbumInputGroup::for_test(dir.path(), Origin::default(), override_metadata, &songs)
+    }
+
+    fn split_idxs(album: &AlbumInputGroup) -> Vec<Option<(u64, u64)>> {
+        album.songs().iter().map(|s| s.split_idx).collect()
+    }
+
+    #[test]
+    fn cached_metadata_is_split_across_media() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut album = untagged_album(&dir, 5, Some(album_override(None, Some(0))));
+        album
+            .set_cached_metadata(Some(cached_album(&[3, 2])))
+            .unwrap();
+        assert_eq!(
+            split_idxs(&album),
+            [
+                Some((1, 1)),
+                Some((1, 2)),
+                Some((1, 3)),
+                Some((2, 1)),
+                Some((2, 2))
+            ]
+        );
+        assert_eq!(
+            album.cached_song_metadata(3).unwrap().song_title,
+            "Disc 2 Track 1"
+        );
+    }
+
+    #[test]
+    fn cached_metadata_is_split_after_the_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut album = untagged_album(&dir, 2, Some(album_override(None, Some(3))));
+        album
+            .set_cached_metadata(Some(cached_album(&[3, 2])))
+            .unwrap();
+        assert_eq!(split_idxs(&album), [Some((2, 1)), Some((2, 2))]);
+    }
+
+    #[test]
+    fn songs_past_the_last_medium_are_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut album = untagged_album(&dir, 6, None);
+        let err = album
+            .set_cached_metadata(Some(cached_album(&[3, 2])))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("06.flac (disc 1 track 6)"), "{err}");
+        // The songs that fit still get their metadata
+        assert_eq!(split_idxs(&album)[4], Some((2, 2)));
+        assert_eq!(split_idxs(&album)[5], None);
+        assert!(album.cached_song_metadata(5).is_none());
+    }
+
+    /// An album of MP3s with these native disc and track indices
+    fn tagged_album(
+        dir: &tempfile::TempDir,
+        songs: &[(&str, Option<u32>, Option<u32>)],
+        override_metadata: Option<metadata::album::Override>,
+    ) -> AlbumInputGroup {
+        use id3::TagLike;
+
+        let songs = songs
+            .iter()
+            .map(|&(name, disc, track)| {
+                let path = dir.path().join(name);
+                std::fs::write(&path, b"").unwrap();
+                let mut tag = id3::Tag::new();
+                if let Some(disc) = disc {
+                    tag.set_disc(disc);
+                }
+                if let Some(track) = track {
+                    tag.set_track(track);
+                }
+                tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+                path
+            })
+            .collect();
+        AlbumInputGroup::new(
+            dir.path(),
+            Origin::default(),
+            override_metadata,
+            None,
+            None,
+            vec![],
+            songs,
+            vec![],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn gaps_in_the_middle_of_a_disc_are_warned_about() {
+        let dir = tempfile::tempdir().unwrap();
+        let songs = [
+            ("a.mp3", Some(1), Some(1)),
+            ("b.mp3", Some(1), Some(4)),
+            ("c.mp3", Some(2), Some(1)),
+        ];
+        let album = tagged_album(&dir, &songs, None);
+        assert_eq!(album.warnings(), ["disc 1 is missing track(s) 2, 3"]);
+    }
+
+    #[test]
+    fn leading_gaps_are_only_skipped_with_an_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let songs = [("a.mp3", None, Some(3)), ("b.mp3", None, Some(4))];
+        let album = tagged_album(&dir, &songs, None);
+        assert_eq!(album.warnings(), ["disc 1 is missing track(s) 1, 2"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let songs = [("a.mp3", None, None), ("b.mp3", None, None)];
+        let album = tagged_album(&dir, &songs, Some(album_override(None, Some(3))));
+        assert_eq!(
+            album
+                .songs()
+                .iter()
+                .map(|s| s.track_idx())
+                .collect::<Vec<_>>(),
+            [4, 5]
+        );
+        assert!(album.warnings().is_empty(), "{:?}", album.warnings());
+    }
+
+    #[test]
+    fn fixed_disc_idx_warns_about_other_disc_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let songs = [
+            ("a.mp3", Some(2), Some(1)),
+            ("b.mp3", Some(1), Some(2)),
+            ("c.mp3", None, Some(3)),
+        ];
+        let album = tagged_album(&dir, &songs, Some(album_override(Some(2), None)));
+        assert_eq!(
+            album
+                .songs()
+                .iter()
+                .map(|s| s.disc_idx())
+                .collect::<Vec<_>>(),
+            [2, 2, 2]
+        );
+        assert_eq!(
+            album.warnings(),
+            ["fixed_disc_idx puts songs on disc 2, ignoring their disc tags: b.mp3 (disc 1)"]
+        );
+
+        // Tags that agree are fine
+        let dir = tempfile::tempdir().unwrap();
+        let songs = [("a.mp3", Some(2), Some(1)), ("b.mp3", None, Some(2))];
+        let album = tagged_album(&dir, &songs, Some(album_override(Some(2), None)));
+        assert!(album.warnings().is_empty(), "{:?}", album.warnings());
+    }
+
+    #[test]
+    fn songs_tagged_with_a_new_disc_restart_its_tracks() {
+        let positions = |album: &AlbumInputGroup| {
+            album
+                .songs()
+                .iter()
+                .map(|s| (s.disc_idx(), s.track_idx()))
+                .collect::<Vec<_>>()
+        };
+        let songs = [
+            ("a.mp3", Some(1), Some(1)),
+            ("b.mp3", Some(1), None),
+            ("c.mp3", Some(2), None),
+            ("d.mp3", None, None),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let album = tagged_album(&dir, &songs, None);
+        assert_eq!(positions(&album), [(1, 1), (1, 2), (2, 1), (2, 2)]);
+
+        // from the offset track
+        let dir = tempfile::tempdir().unwrap();
+        let album = tagged_album(&dir, &songs, Some(album_override(None, Some(2))));
+        assert_eq!(positions(&album), [(1, 3), (1, 4), (2, 3), (2, 4)]);
+    }
+
+    #[test]
+    fn position_warnings_follow_the_split() {
+        let dir = tempfile::tempdir().unwrap();
+        let songs = [
+            ("a.mp3", Some(1), Some(1)),
+            ("b.mp3", Some(1), Some(2)),
+            ("c.mp3", Some(1), Some(3)),
+            ("d.mp3", Some(1), Some(4)),
+            ("e.mp3", Some(2), Some(1)),
+        ];
+        let mut album = tagged_album(&dir, &songs, None);
+        assert!(album.warnings().is_empty(), "{:?}", album.warnings());
+
+        // The release's first disc only has 3 tracks, so track 4 becomes the first track of disc 2
+        album
+            .set_cached_metadata(Some(cached_album(&[3, 2])))
+            .unwrap();
+        assert_eq!(
+            album.warnings(),
+            ["2 songs are all disc 2 track 1: d.mp3, e.mp3"]
+        );
+
+        album.set_cached_metadata(None).unwrap();
+        assert!(album.warnings().is_empty(), "{:?}", album.warnings());
+    }
+
+    #[test]
+    fn format_mismatches_are_warned_about() {
+        let dir = tempfile::tempdir().unwrap();
+        let read_warnings = |name: &str, contents: &[u8]| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, contents).unwrap();
+            let (mut warnings, mut tag_errors) = (vec![], vec![]);
+            read_native_metadata(&path, Path::new(name), &mut warnings, &mut tag_errors);
+            warnings
+        };
+
+        assert_eq!(
+            read_warnings("song.mp3", b"RIFF\x04\0\0\0WAVE"),
+            ["song.mp3 has the extension of ID3 but contains WAV, reading it as WAV"]
+        );
+        assert_eq!(
+            read_warnings("song.flac", b"not audio"),
+            [
+                "song.flac has the extension of FLAC but its contents weren't recognised, ignoring its tags"
+            ]
+        );
+        assert!(read_warnings("song.wav", b"RIFF\x04\0\0\0WAVE").is_empty());
     }
 }
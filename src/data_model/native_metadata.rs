@@ -1,17 +1,35 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use id3::TagLike;
 use mp4ameta::ChplTimescale;
 
+use crate::data_model::MbId;
+
+mod mpeg;
+mod ogg;
+mod riff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NativeMetadataFormat {
     None,
     ID3,
     M4A,
     FLAC,
+    /// Ogg Vorbis or Ogg Opus, both tagged with Vorbis comments
+    OGG,
+    /// RIFF WAVE, tagged with an ID3 chunk and/or LIST INFO chunk
+    WAV,
+    /// AIFF, tagged with an ID3 chunk and/or NAME/AUTH chunks
+    AIFF,
 }
 
-pub const NATIVE_MUSIC_EXTS: [&'static str; 6] = [
-    "mp3", "ogg", "flac", "wav", "aiff",
+pub const NATIVE_MUSIC_EXTS: [&str; 8] = [
+    "mp3", "ogg", "opus", "flac", "wav", "aiff", "aif",
     "m4a",
     // TODO m4b support one day? requires general splitting-big-file support.
 ];
@@ -22,10 +40,133 @@ pub struct NativeMetadata {
     pub album: Option<String>,
     pub album_artists: Vec<String>,
     pub artist: Vec<String>,
+    /// How players should sort the fields above e.g. "Beatles, The" for "The Beatles"
+    pub name_sort: Option<String>,
+    pub album_sort: Option<String>,
+    pub album_artists_sort: Vec<String>,
+    pub artist_sort: Vec<String>,
+    /// Release date of the album, as written e.g. `2001`, `2001-05` or `2001-05-03`
+    pub date: Option<String>,
+    /// Release date of the first release of the album, in the same format as [Self::date]
+    pub original_date: Option<String>,
+    pub genres: Vec<String>,
+    pub label: Option<String>,
+    pub catalog_number: Option<String>,
+    pub barcode: Option<String>,
+    pub isrc: Option<String>,
+    pub composers: Vec<String>,
     pub num_discs: Option<u64>,
     pub disc_idx: Option<u64>,
     pub num_tracks: Option<u64>,
     pub track_idx: Option<u64>,
+    /// Length of the audio, if the format records it
+    pub duration: Option<Duration>,
+    /// If the file's contents didn't match its extension, the format the extension implied.
+    /// The file is read according to its contents, i.e. [Self::fmt].
+    pub format_mismatch: Option<NativeMetadataFormat>,
+    pub mb_ids: NativeMbIds,
+    /// Fields (or embedded tags) that couldn't be read, and were left out while the rest of the tags were read
+    pub field_errors: Vec<NativeMetadataErrorKind>,
+}
+
+/// The MusicBrainz IDs written by taggers such as [Picard](https://picard.musicbrainz.org/),
+/// under the names from <https://picard-docs.musicbrainz.org/en/appendices/tag_mapping.html>
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NativeMbIds {
+    /// `MUSICBRAINZ_ALBUMID`
+    pub release_id: Option<MbId>,
+    /// `MUSICBRAINZ_RELEASEGROUPID`
+    pub release_group_id: Option<MbId>,
+    /// `MUSICBRAINZ_TRACKID`, which despite the name identifies the recording rather than the track
+    pub recording_id: Option<MbId>,
+    /// `MUSICBRAINZ_ARTISTID`, one for each artist of the song
+    pub artist_ids: Vec<MbId>,
+}
+
+/// Why the native metadata of a file couldn't be read
+#[derive(Debug)]
+pub struct NativeMetadataError {
+    pub path: PathBuf,
+    pub kind: NativeMetadataErrorKind,
+}
+
+#[derive(Debug)]
+pub enum NativeMetadataErrorKind {
+    /// The file couldn't be opened or read
+    Io(std::io::Error),
+    /// The container holds something we can't read tags from, e.g. an Ogg stream that's neither Vorbis nor Opus
+    UnsupportedContainer(String),
+    /// The tags (or the container structure around them) are corrupt or truncated
+    MalformedTag(String),
+    /// A numeric field, e.g. the track number, wasn't a number
+    BadNumber { field: &'static str, value: String },
+}
+
+impl NativeMetadataErrorKind {
+    /// An error from reading `what`, where running out of file means it's truncated rather than an I/O failure
+    fn reading(what: &str, err: std::io::Error) -> Self {
+        if err.kind() == std::io::ErrorKind::UnexpectedEof {
+            Self::MalformedTag(format!("{what} is truncated"))
+        } else {
+            Self::Io(err)
+        }
+    }
+}
+
+impl Display for NativeMetadataErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "couldn't read the file: {err}"),
+            Self::UnsupportedContainer(reason) => write!(f, "unsupported container: {reason}"),
+            Self::MalformedTag(reason) => write!(f, "malformed tags: {reason}"),
+            Self::BadNumber { field, value } => write!(f, "{field} {value:?} isn't a number"),
+        }
+    }
+}
+
+impl Display for NativeMetadataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.kind)
+    }
+}
+
+impl std::error::Error for NativeMetadataError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            NativeMetadataErrorKind::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<id3::Error> for NativeMetadataErrorKind {
+    fn from(err: id3::Error) -> Self {
+        let reason = err.to_string();
+        match err.kind {
+            id3::ErrorKind::Io(err) => Self::reading("ID3 tag", err),
+            _ => Self::MalformedTag(reason),
+        }
+    }
+}
+
+impl From<mp4ameta::Error> for NativeMetadataErrorKind {
+    fn from(err: mp4ameta::Error) -> Self {
+        let reason = err.to_string();
+        match err.kind {
+            mp4ameta::ErrorKind::Io(err) => Self::reading("MP4 atom", err),
+            _ => Self::MalformedTag(reason),
+        }
+    }
+}
+
+impl From<metaflac::Error> for NativeMetadataErrorKind {
+    fn from(err: metaflac::Error) -> Self {
+        let reason = err.to_string();
+        match err.kind {
+            metaflac::ErrorKind::Io(err) => Self::reading("FLAC metadata block", err),
+            _ => Self::MalformedTag(reason),
+        }
+    }
 }
 
 impl Default for NativeMetadata {
@@ -36,59 +177,144 @@ impl Default for NativeMetadata {
             album: Default::default(),
             album_artists: Default::default(),
             artist: Default::default(),
+            name_sort: Default::default(),
+            album_sort: Default::default(),
+            album_artists_sort: Default::default(),
+            artist_sort: Default::default(),
+            date: Default::default(),
+            original_date: Default::default(),
+            genres: Default::default(),
+            label: Default::default(),
+            catalog_number: Default::default(),
+            barcode: Default::default(),
+            isrc: Default::default(),
+            composers: Default::default(),
             num_discs: Default::default(),
             disc_idx: Default::default(),
             num_tracks: Default::default(),
             track_idx: Default::default(),
+            duration: Default::default(),
+            format_mismatch: Default::default(),
+            mb_ids: Default::default(),
+            field_errors: Default::default(),
         }
     }
 }
 
 impl NativeMetadataFormat {
-    pub fn parse_from_file(path: &Path) -> Result<NativeMetadata, String> {
-        // TODO more robust detection could use e.g. Symphonia
-
-        let fmt = {
-            let ext = path.extension();
-            match ext {
-                Some(s)
-                    if s.eq_ignore_ascii_case("mp3")
-                        || s.eq_ignore_ascii_case("wav")
-                        || s.eq_ignore_ascii_case("aiff") =>
-                {
-                    NativeMetadataFormat::ID3
-                }
-                Some(s) if s.eq_ignore_ascii_case("flac") => NativeMetadataFormat::FLAC,
-                Some(s) if s.eq_ignore_ascii_case("m4a") => NativeMetadataFormat::M4A,
-                _ => NativeMetadataFormat::None,
+    /// The format implied by a file's extension
+    pub fn from_extension(path: &Path) -> NativeMetadataFormat {
+        match path.extension() {
+            Some(s) if s.eq_ignore_ascii_case("mp3") => NativeMetadataFormat::ID3,
+            Some(s) if s.eq_ignore_ascii_case("wav") => NativeMetadataFormat::WAV,
+            Some(s) if s.eq_ignore_ascii_case("aiff") || s.eq_ignore_ascii_case("aif") => {
+                NativeMetadataFormat::AIFF
+            }
+            Some(s) if s.eq_ignore_ascii_case("flac") => NativeMetadataFormat::FLAC,
+            Some(s) if s.eq_ignore_ascii_case("m4a") => NativeMetadataFormat::M4A,
+            Some(s) if s.eq_ignore_ascii_case("ogg") || s.eq_ignore_ascii_case("opus") => {
+                NativeMetadataFormat::OGG
+            }
+            _ => NativeMetadataFormat::None,
+        }
+    }
+
+    /// The format implied by the magic bytes at the start of a file
+    pub fn sniff(path: &Path) -> std::io::Result<NativeMetadataFormat> {
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 12];
+        let len = read_up_to(&mut file, &mut magic)?;
+        let magic = &magic[..len];
+
+        let fmt = if magic.starts_with(b"fLaC") {
+            NativeMetadataFormat::FLAC
+        } else if magic.starts_with(b"ID3") && magic.len() >= 10 {
+            // FLAC files are occasionally prefixed with an ID3 tag, which has to be skipped to find out
+            let tag_len = magic[6..10]
+                .iter()
+                .fold(0u64, |len, &b| (len << 7) | (b & 0x7f) as u64);
+            file.seek(SeekFrom::Start(10 + tag_len))?;
+            let mut after_tag = [0u8; 4];
+            if read_up_to(&mut file, &mut after_tag)? == 4 && &after_tag == b"fLaC" {
+                NativeMetadataFormat::FLAC
+            } else {
+                NativeMetadataFormat::ID3
             }
+        } else if magic.len() >= 2
+            && magic[0] == 0xff
+            && magic[1] & 0xe0 == 0xe0
+            && magic[1] & 0x06 != 0
+        {
+            // MPEG audio frame sync, i.e. an MP3 without an ID3v2 tag.
+            // Layer bits of 00 are reserved for MPEG audio, and are what raw AAC (ADTS) streams have instead.
+            NativeMetadataFormat::ID3
+        } else if magic.len() >= 8 && &magic[4..8] == b"ftyp" {
+            NativeMetadataFormat::M4A
+        } else if magic.starts_with(b"OggS") {
+            NativeMetadataFormat::OGG
+        } else if magic.starts_with(b"RIFF") && magic.get(8..12) == Some(b"WAVE") {
+            NativeMetadataFormat::WAV
+        } else if magic.starts_with(b"FORM") && matches!(magic.get(8..12), Some(b"AIFF" | b"AIFC"))
+        {
+            NativeMetadataFormat::AIFF
+        } else {
+            NativeMetadataFormat::None
+        };
+        Ok(fmt)
+    }
+
+    /// Read the native metadata from a file, choosing the format by its contents rather than its extension.
+    /// A file whose contents didn't match its extension is still read, but reports it in [NativeMetadata::format_mismatch].
+    pub fn parse_from_file(path: &Path) -> Result<NativeMetadata, NativeMetadataError> {
+        let ext_fmt = Self::from_extension(path);
+        let parse = || {
+            let fmt = Self::sniff(path).map_err(NativeMetadataErrorKind::Io)?;
+            let mut metadata = Self::parse_as(fmt, path)?;
+            metadata.format_mismatch =
+                (ext_fmt != NativeMetadataFormat::None && ext_fmt != fmt).then_some(ext_fmt);
+            Ok(metadata)
         };
+        parse().map_err(|kind| NativeMetadataError {
+            path: path.to_owned(),
+            kind,
+        })
+    }
 
+    fn parse_as(
+        fmt: NativeMetadataFormat,
+        path: &Path,
+    ) -> Result<NativeMetadata, NativeMetadataErrorKind> {
         match fmt {
             NativeMetadataFormat::None => Ok(NativeMetadata::default()),
             NativeMetadataFormat::ID3 => {
-                let tag = id3::Tag::read_from_path(&path).map_err(|err| err.to_string())?;
-                Ok(NativeMetadata {
-                    fmt,
-                    name: tag.title().map(str::to_owned),
-                    album: tag.album().map(str::to_owned),
-                    album_artists: match tag.album_artist() {
-                        Some(s) => vec![s.to_owned()],
-                        None => vec![],
+                let mut metadata = match id3::Tag::read_from_path(path) {
+                    Ok(tag) => from_id3(fmt, &tag),
+                    // An MP3 without any tags
+                    Err(err) if matches!(err.kind, id3::ErrorKind::NoTag) => NativeMetadata {
+                        fmt,
+                        ..Default::default()
                     },
-                    artist: tag
-                        .artists()
-                        .map(|v| v.into_iter().map(|s| s.to_owned()).collect())
-                        .unwrap_or_default(),
-                    num_discs: tag.total_discs().map(Into::into),
-                    disc_idx: tag.disc().map(Into::into),
-                    num_tracks: tag.total_tracks().map(Into::into),
-                    track_idx: tag.track().map(Into::into),
-                })
+                    Err(err) => return Err(err.into()),
+                };
+                // TLEN is rarely written, and is only trusted if the stream itself can't be measured
+                match mpeg::read_duration(path) {
+                    Ok(duration) => metadata.duration = duration.or(metadata.duration),
+                    Err(err) => metadata.field_errors.push(err),
+                }
+                Ok(metadata)
+            }
+            NativeMetadataFormat::WAV => {
+                let chunks = riff::read_chunks(path, riff::Container::Wav)?;
+                Ok(from_chunks(fmt, chunks, *b"INAM", *b"IART", Some(*b"IPRD")))
+            }
+            NativeMetadataFormat::AIFF => {
+                let chunks = riff::read_chunks(path, riff::Container::Aiff)?;
+                // AIFF has no chunk for the album
+                Ok(from_chunks(fmt, chunks, *b"NAME", *b"AUTH", None))
             }
             NativeMetadataFormat::M4A => {
                 let mut tag = mp4ameta::Tag::read_with_path(
-                    &path,
+                    path,
                     &mp4ameta::ReadConfig {
                         read_meta_items: true,
                         read_image_data: false,
@@ -97,89 +323,546 @@ impl NativeMetadataFormat {
                         read_audio_info: true,
                         chpl_timescale: ChplTimescale::DEFAULT,
                     },
-                )
-                .map_err(|err| err.to_string())?;
+                )?;
+                let freeform = |name: &'static str| -> Vec<String> {
+                    let ident = mp4ameta::FreeformIdent::new_static(
+                        mp4ameta::ident::APPLE_ITUNES_MEAN,
+                        name,
+                    );
+                    tag.strings_of(&ident).map(str::to_owned).collect()
+                };
+                let original_date = freeform("ORIGINALDATE").into_iter().next();
+                let catalog_number = freeform("CATALOGNUMBER").into_iter().next();
+                let barcode = freeform("BARCODE").into_iter().next();
+                let mb_ids = NativeMbIds {
+                    release_id: first_mb_id(freeform("MusicBrainz Album Id")),
+                    release_group_id: first_mb_id(freeform("MusicBrainz Release Group Id")),
+                    recording_id: first_mb_id(freeform("MusicBrainz Track Id")),
+                    artist_ids: all_mb_ids(freeform("MusicBrainz Artist Id")),
+                };
                 Ok(NativeMetadata {
                     fmt,
                     name: tag.take_title(),
-                    // TODO take_title_sort_order
                     album: tag.take_album(),
-                    // TODO take_album_sort_order
                     album_artists: tag.take_album_artists().collect::<Vec<_>>(),
-                    // TODO take album_artists_sort_orders
                     artist: tag.take_artists().collect::<Vec<_>>(),
-                    // TODO take artists_sort_orders
+                    name_sort: tag.take_title_sort_order(),
+                    album_sort: tag.take_album_sort_order(),
+                    album_artists_sort: tag.take_album_artist_sort_orders().collect::<Vec<_>>(),
+                    artist_sort: tag.take_artist_sort_orders().collect::<Vec<_>>(),
+                    date: tag.take_year(),
+                    original_date,
+                    genres: tag.take_genres().collect::<Vec<_>>(),
+                    label: tag.take_label(),
+                    catalog_number,
+                    barcode,
+                    isrc: tag.take_isrc(),
+                    composers: tag.take_composers().collect::<Vec<_>>(),
                     num_discs: tag.disc().1.map(Into::into),
                     disc_idx: tag.disc().0.map(Into::into),
                     num_tracks: tag.track().1.map(Into::into),
                     track_idx: tag.track().0.map(Into::into),
+                    duration: Some(tag.duration()).filter(|d| !d.is_zero()),
+                    format_mismatch: None,
+                    mb_ids,
+                    field_errors: vec![],
                 })
             }
             NativeMetadataFormat::FLAC => {
-                let tag = metaflac::Tag::read_from_path(&path).map_err(|err| err.to_string())?;
-
-                // https://xiph.org/vorbis/doc/v-comment.html
-                // TODO include musicbrainz tags?
-                // e.g.
-                // Title            Dance!
-                // Artist           ATLUS
-                // Album            PERSONA4 DANCING ALL NIGHT Original Soundtrack Disc3
-                // TrackNumber      1/17
-                let name = tag
-                    .get_vorbis("title")
-                    .map(|iter| iter.last().map(str::to_owned))
-                    .flatten();
-                // TODO include Version? or keep that separate
-                let album = tag
-                    .get_vorbis("album")
-                    .map(|iter| iter.last().map(str::to_owned))
-                    .flatten();
-                let artist = tag
-                    .get_vorbis("artist")
-                    .map(|iter| iter.last().map(str::to_owned))
-                    .flatten();
-
-                let track_number_str = tag
-                    .get_vorbis("artist")
-                    .map(|iter| iter.last()) // NOT to_owned, don't need that
-                    .flatten()
+                let tag = metaflac::Tag::read_from_path(path)?;
+
+                let comments = tag
+                    .vorbis_comments()
+                    .map(|c| c.comments.clone())
                     .unwrap_or_default();
-                let track_num_regex =
-                    regex::Regex::new(r"(\d+)(/(\d+))?").expect("regex must never fail");
-                let (track_idx, num_tracks) = {
-                    match track_num_regex.captures(track_number_str) {
-                        Some(cs) => {
-                            let track_idx = cs
-                                .get(1)
-                                .expect("can't match regex without first group")
-                                .as_str()
-                                .parse::<u64>()
-                                .map_err(|err| err.to_string())?;
-                            let track_num = match cs.get(2) {
-                                Some(m) => {
-                                    Some(m.as_str().parse::<u64>().map_err(|err| err.to_string())?)
-                                }
-                                None => None,
-                            };
-
-                            (Some(track_idx), track_num)
-                        }
-                        None => (None, None),
-                    }
-                };
+                let duration = tag
+                    .get_streaminfo()
+                    .filter(|info| info.sample_rate > 0 && info.total_samples > 0)
+                    .map(|info| {
+                        Duration::from_secs_f64(info.total_samples as f64 / info.sample_rate as f64)
+                    });
+                Ok(from_vorbis_comments(fmt, &comments, duration))
+            }
+            NativeMetadataFormat::OGG => {
+                let info = ogg::read_ogg(path)?;
+                Ok(from_vorbis_comments(fmt, &info.comments, info.duration))
+            }
+        }
+    }
+}
 
-                Ok(NativeMetadata {
-                    fmt,
-                    name,
-                    album,
-                    album_artists: vec![],
-                    artist: artist.into_iter().collect(),
-                    num_discs: None,
-                    disc_idx: None,
-                    num_tracks: track_idx,
-                    track_idx: num_tracks,
-                })
+fn from_id3(fmt: NativeMetadataFormat, tag: &id3::Tag) -> NativeMetadata {
+    let txxx = |description: &str| -> Vec<String> {
+        tag.extended_texts()
+            .filter(|t| t.description.eq_ignore_ascii_case(description))
+            .map(|t| t.value.clone())
+            .collect()
+    };
+    // Picard stores the recording ID in a UFID frame, rather than a TXXX frame like the others
+    let recording_id = tag
+        .unique_file_identifiers()
+        .filter(|ufid| ufid.owner_identifier == "http://musicbrainz.org")
+        .map(|ufid| String::from_utf8_lossy(&ufid.identifier).into_owned())
+        .collect();
+
+    NativeMetadata {
+        fmt,
+        name: tag.title().map(str::to_owned),
+        album: tag.album().map(str::to_owned),
+        album_artists: match tag.album_artist() {
+            Some(s) => vec![s.to_owned()],
+            None => vec![],
+        },
+        artist: tag
+            .artists()
+            .map(|v| v.into_iter().map(|s| s.to_owned()).collect())
+            .unwrap_or_default(),
+        name_sort: tag.text_for_frame_id("TSOT").map(str::to_owned),
+        album_sort: tag.text_for_frame_id("TSOA").map(str::to_owned),
+        // TSO2 isn't in the ID3 spec, but iTunes and Picard both write it
+        album_artists_sort: text_values(tag, "TSO2"),
+        artist_sort: text_values(tag, "TSOP"),
+        // ID3v2.4 dates, falling back to the ID3v2.3 years
+        date: tag
+            .date_recorded()
+            .map(|d| d.to_string())
+            .or_else(|| tag.text_for_frame_id("TYER").map(str::to_owned)),
+        original_date: tag
+            .original_date_released()
+            .map(|d| d.to_string())
+            .or_else(|| tag.text_for_frame_id("TORY").map(str::to_owned)),
+        genres: tag
+            .genres_parsed()
+            .into_iter()
+            .filter(|g| !g.is_empty())
+            .map(|g| g.into_owned())
+            .collect(),
+        label: tag.text_for_frame_id("TPUB").map(str::to_owned),
+        catalog_number: first_txxx(txxx("CATALOGNUMBER")),
+        barcode: first_txxx(txxx("BARCODE")),
+        isrc: tag.text_for_frame_id("TSRC").map(str::to_owned),
+        composers: text_values(tag, "TCOM"),
+        num_discs: tag.total_discs().map(Into::into),
+        disc_idx: tag.disc().map(Into::into),
+        num_tracks: tag.total_tracks().map(Into::into),
+        track_idx: tag.track().map(Into::into),
+        // TLEN, in milliseconds
+        duration: tag.duration().map(|ms| Duration::from_millis(ms.into())),
+        format_mismatch: None,
+        mb_ids: NativeMbIds {
+            release_id: first_mb_id(txxx("MusicBrainz Album Id")),
+            release_group_id: first_mb_id(txxx("MusicBrainz Release Group Id")),
+            recording_id: first_mb_id(recording_id),
+            artist_ids: all_mb_ids(txxx("MusicBrainz Artist Id")),
+        },
+        field_errors: vec![],
+    }
+}
+
+/// Every value of a (possibly multi-valued) ID3 text frame
+fn text_values(tag: &id3::Tag, frame_id: &str) -> Vec<String> {
+    tag.text_values_for_frame_id(frame_id)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Build the metadata for a WAV or AIFF file, preferring its ID3 chunk and filling the gaps from its text chunks.
+/// The `*_id`s are the IDs of the text chunks for each field.
+fn from_chunks(
+    fmt: NativeMetadataFormat,
+    chunks: riff::ChunkTags,
+    title_id: [u8; 4],
+    artist_id: [u8; 4],
+    album_id: Option<[u8; 4]>,
+) -> NativeMetadata {
+    let mut metadata = match &chunks.id3 {
+        Some(tag) => from_id3(fmt, tag),
+        None => NativeMetadata {
+            fmt,
+            ..Default::default()
+        },
+    };
+    let text = |id: [u8; 4]| chunks.text.get(&id).filter(|s| !s.is_empty()).cloned();
+
+    if metadata.name.is_none() {
+        metadata.name = text(title_id);
+    }
+    if metadata.artist.is_empty() {
+        metadata.artist = text(artist_id).into_iter().collect();
+    }
+    if metadata.album.is_none() {
+        metadata.album = album_id.and_then(text);
+    }
+    // Not in the WAV spec, but written by common rippers. The AIFF IDs never appear.
+    if metadata.track_idx.is_none()
+        && let Some((id, track)) = ["ITRK", "IPRT"]
+            .into_iter()
+            .find_map(|id| Some((id, text(id.as_bytes().try_into().expect("4 bytes"))?)))
+    {
+        let (track_idx, num_tracks) =
+            parse_index_of_total(id, Some(&track), &mut metadata.field_errors);
+        metadata.track_idx = track_idx;
+        metadata.num_tracks = metadata.num_tracks.or(num_tracks);
+    }
+    // WAV only, AIFF has no equivalent chunks
+    if metadata.date.is_none() {
+        metadata.date = text(*b"ICRD");
+    }
+    if metadata.genres.is_empty() {
+        metadata.genres = text(*b"IGNR").into_iter().collect();
+    }
+    metadata.duration = metadata.duration.or(chunks.duration);
+    metadata.field_errors.extend(chunks.errors);
+    metadata
+}
+
+/// Build the metadata for a format tagged with Vorbis comments, keyed by uppercased field name.
+/// Fields like ARTIST can be repeated to give multiple values.
+/// Numbers that can't be parsed are left out and recorded in [NativeMetadata::field_errors].
+///
+/// <https://xiph.org/vorbis/doc/v-comment.html>
+/// e.g.
+/// ```text
+/// Title            Dance!
+/// Artist           ATLUS
+/// Album            PERSONA4 DANCING ALL NIGHT Original Soundtrack Disc3
+/// TrackNumber      1/17
+/// ```
+fn from_vorbis_comments(
+    fmt: NativeMetadataFormat,
+    comments: &HashMap<String, Vec<String>>,
+    duration: Option<Duration>,
+) -> NativeMetadata {
+    let mut field_errors = vec![];
+    let all = |key: &str| -> Vec<String> {
+        comments
+            .get(key)
+            .into_iter()
+            .flatten()
+            .filter(|s| !s.is_empty())
+            .cloned()
+            .collect()
+    };
+    let last = |key: &str| -> Option<&str> {
+        comments
+            .get(key)
+            .and_then(|values| values.last())
+            .map(String::as_str)
+    };
+    let last_u64 = |key: &'static str, field_errors: &mut Vec<NativeMetadataErrorKind>| {
+        let s = last(key)?;
+        match s.trim().parse::<u64>() {
+            Ok(n) => Some(n),
+            Err(_) => {
+                field_errors.push(NativeMetadataErrorKind::BadNumber {
+                    field: key,
+                    value: s.to_owned(),
+                });
+                None
             }
         }
+    };
+
+    // TODO include Version? or keep that separate
+    let (track_idx, num_tracks) =
+        parse_index_of_total("TRACKNUMBER", last("TRACKNUMBER"), &mut field_errors);
+    let (disc_idx, num_discs) =
+        parse_index_of_total("DISCNUMBER", last("DISCNUMBER"), &mut field_errors);
+    let num_tracks = num_tracks.or_else(|| last_u64("TOTALTRACKS", &mut field_errors));
+    let num_discs = num_discs.or_else(|| last_u64("TOTALDISCS", &mut field_errors));
+    // ALBUMARTIST is the de facto standard, but some taggers write it with a space
+    let mut album_artists = all("ALBUMARTIST");
+    if album_artists.is_empty() {
+        album_artists = all("ALBUM ARTIST");
+    }
+
+    NativeMetadata {
+        fmt,
+        name: last("TITLE").map(str::to_owned),
+        album: last("ALBUM").map(str::to_owned),
+        album_artists,
+        artist: all("ARTIST"),
+        name_sort: last("TITLESORT").map(str::to_owned),
+        album_sort: last("ALBUMSORT").map(str::to_owned),
+        album_artists_sort: all("ALBUMARTISTSORT"),
+        artist_sort: all("ARTISTSORT"),
+        date: last("DATE").map(str::to_owned),
+        original_date: last("ORIGINALDATE")
+            .or_else(|| last("ORIGINALYEAR"))
+            .map(str::to_owned),
+        genres: all("GENRE"),
+        // LABEL is what Picard writes, ORGANIZATION is what the Vorbis spec suggests
+        label: last("LABEL")
+            .or_else(|| last("ORGANIZATION"))
+            .map(str::to_owned),
+        catalog_number: last("CATALOGNUMBER").map(str::to_owned),
+        barcode: last("BARCODE").map(str::to_owned),
+        isrc: last("ISRC").map(str::to_owned),
+        composers: all("COMPOSER"),
+        num_discs,
+        disc_idx,
+        num_tracks,
+        track_idx,
+        duration,
+        format_mismatch: None,
+        mb_ids: NativeMbIds {
+            release_id: first_mb_id(all("MUSICBRAINZ_ALBUMID")),
+            release_group_id: first_mb_id(all("MUSICBRAINZ_RELEASEGROUPID")),
+            recording_id: first_mb_id(all("MUSICBRAINZ_TRACKID")),
+            artist_ids: all_mb_ids(all("MUSICBRAINZ_ARTISTID")),
+        },
+        field_errors,
+    }
+}
+
+/// Every MusicBrainz ID in a set of tag values.
+/// Formats without multi-valued fields join IDs with '/' (ID3v2.3) or NUL (ID3v2.4), neither of which appear in an ID.
+fn all_mb_ids(values: Vec<String>) -> Vec<MbId> {
+    values
+        .iter()
+        .flat_map(|v| v.split(['/', '\0']))
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(MbId::new)
+        .collect()
+}
+
+/// The first non-empty value of a TXXX frame
+fn first_txxx(values: Vec<String>) -> Option<String> {
+    values.into_iter().find(|v| !v.is_empty())
+}
+
+fn first_mb_id(values: Vec<String>) -> Option<MbId> {
+    all_mb_ids(values).into_iter().next()
+}
+
+/// Parse a Vorbis comment style index e.g. `3` or `3/12` in the given field into the index and the total.
+/// A part that isn't a number is left out and recorded in `field_errors`, without losing the other part.
+fn parse_index_of_total(
+    field: &'static str,
+    s: Option<&str>,
+    field_errors: &mut Vec<NativeMetadataErrorKind>,
+) -> (Option<u64>, Option<u64>) {
+    let Some(s) = s else {
+        return (None, None);
+    };
+    let (idx, total) = match s.split_once('/') {
+        Some((idx, total)) => (idx, Some(total)),
+        None => (s, None),
+    };
+    let mut parse = |part: &str| match part.trim().parse::<u64>() {
+        Ok(n) => Some(n),
+        Err(_) => {
+            field_errors.push(NativeMetadataErrorKind::BadNumber {
+                field,
+                value: s.to_owned(),
+            });
+            None
+        }
+    };
+    let idx = parse(idx);
+    // `3/` is as good as no total at all
+    let total = total.filter(|t| !t.trim().is_empty()).and_then(parse);
+    (idx, total)
+}
+
+/// Like [Read::read_exact], but stops early at the end of the file. Returns how many bytes were read.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bad_fields(errors: &[NativeMetadataErrorKind]) -> Vec<(&'static str, &str)> {
+        errors
+            .iter()
+            .map(|err| match err {
+                NativeMetadataErrorKind::BadNumber { field, value } => (*field, value.as_str()),
+                err => panic!("unexpected error {err}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn index_of_total() {
+        let mut errors = vec![];
+        assert_eq!(parse_index_of_total("F", None, &mut errors), (None, None));
+        assert_eq!(
+            parse_index_of_total("F", Some("3"), &mut errors),
+            (Some(3), None)
+        );
+        assert_eq!(
+            parse_index_of_total("F", Some(" 3 / 12 "), &mut errors),
+            (Some(3), Some(12))
+        );
+        assert_eq!(
+            parse_index_of_total("F", Some("3/"), &mut errors),
+            (Some(3), None)
+        );
+        assert!(errors.is_empty());
+
+        assert_eq!(
+            parse_index_of_total("F", Some("3/twelve"), &mut errors),
+            (Some(3), None)
+        );
+        assert_eq!(
+            parse_index_of_total("F", Some("A3/12"), &mut errors),
+            (None, Some(12))
+        );
+        assert_eq!(bad_fields(&errors), [("F", "3/twelve"), ("F", "A3/12")]);
+    }
+
+    #[test]
+    fn bad_vorbis_numbers_keep_the_other_fields() {
+        let comments: HashMap<String, Vec<String>> = [
+            ("TITLE", "Dance!"),
+            ("TRACKNUMBER", "one"),
+            ("TOTALTRACKS", "17"),
+            ("DISCNUMBER", "3"),
+            ("TOTALDISCS", "lots"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), vec![v.to_owned()]))
+        .collect();
+
+        let metadata = from_vorbis_comments(NativeMetadataFormat::FLAC, &comments, None);
+        assert_eq!(metadata.name.as_deref(), Some("Dance!"));
+        assert_eq!(metadata.track_idx, None);
+        assert_eq!(metadata.num_tracks, Some(17));
+        assert_eq!(metadata.disc_idx, Some(3));
+        assert_eq!(metadata.num_discs, None);
+        assert_eq!(
+            bad_fields(&metadata.field_errors),
+            [("TRACKNUMBER", "one"), ("TOTALDISCS", "lots")]
+        );
+    }
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((data.len() as u32).to_le_bytes());
+        chunk.extend(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn write_wav(chunks: &[u8]) -> (tempfile::TempDir, PathBuf) {
+        let mut wav = b"RIFF".to_vec();
+        wav.extend((4 + chunks.len() as u32).to_le_bytes());
+        wav.extend(b"WAVE");
+        wav.extend(chunks);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("song.wav");
+        std::fs::write(&path, wav).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn bad_id3_chunk_keeps_the_info_tags() {
+        let mut info = b"INFO".to_vec();
+        info.extend(chunk(b"INAM", b"Dance!\0"));
+        info.extend(chunk(b"ITRK", b"1/17\0"));
+        let (_dir, path) =
+            write_wav(&[chunk(b"LIST", &info), chunk(b"id3 ", b"not an ID3 tag")].concat());
+
+        let metadata = NativeMetadataFormat::parse_from_file(&path).unwrap();
+        assert_eq!(metadata.fmt, NativeMetadataFormat::WAV);
+        assert_eq!(metadata.name.as_deref(), Some("Dance!"));
+        assert_eq!(metadata.track_idx, Some(1));
+        assert_eq!(metadata.num_tracks, Some(17));
+        assert!(matches!(
+            metadata.field_errors[..],
+            [NativeMetadataErrorKind::MalformedTag(_)]
+        ));
+    }
+
+    #[test]
+    fn bad_track_number_names_the_chunk_it_came_from() {
+        let mut info = b"INFO".to_vec();
+        info.extend(chunk(b"IPRT", b"first\0"));
+        let (_dir, path) = write_wav(&chunk(b"LIST", &info));
+
+        let metadata = NativeMetadataFormat::parse_from_file(&path).unwrap();
+        assert_eq!(metadata.track_idx, None);
+        assert_eq!(bad_fields(&metadata.field_errors), [("IPRT", "first")]);
+    }
+
+    fn sniff_bytes(bytes: &[u8]) -> NativeMetadataFormat {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, bytes).unwrap();
+        NativeMetadataFormat::sniff(file.path()).unwrap()
+    }
+
+    /// An ID3v2 header for a tag of `len` bytes, followed by that many bytes of padding
+    fn id3_prefix(len: u8) -> Vec<u8> {
+        let mut tag = b"ID3\x04\x00\x00\x00\x00\x00".to_vec();
+        tag.push(len);
+        tag.extend(vec![0; len as usize]);
+        tag
+    }
+
+    #[test]
+    fn sniff_recognises_each_magic() {
+        use NativeMetadataFormat as F;
+        assert_eq!(sniff_bytes(b"fLaC\0\0\0\x22"), F::FLAC);
+        assert_eq!(
+            sniff_bytes(&[id3_prefix(20), b"fLaC".to_vec()].concat()),
+            F::FLAC
+        );
+        assert_eq!(
+            sniff_bytes(&[id3_prefix(20), vec![0xff, 0xfb]].concat()),
+            F::ID3
+        );
+        assert_eq!(sniff_bytes(&[0xff, 0xfb, 0x90, 0x00]), F::ID3);
+        assert_eq!(sniff_bytes(b"\0\0\0\x20ftypM4A "), F::M4A);
+        assert_eq!(sniff_bytes(b"OggS\0\x02"), F::OGG);
+        assert_eq!(sniff_bytes(b"RIFF\x24\0\0\0WAVE"), F::WAV);
+        assert_eq!(sniff_bytes(b"FORM\0\0\0\x24AIFF"), F::AIFF);
+        assert_eq!(sniff_bytes(b"FORM\0\0\0\x24AIFC"), F::AIFF);
+    }
+
+    #[test]
+    fn sniff_rejects_near_misses() {
+        use NativeMetadataFormat as F;
+        // AAC in ADTS has the MPEG sync bits but a layer of 00
+        assert_eq!(sniff_bytes(&[0xff, 0xf1, 0x50, 0x80]), F::None);
+        assert_eq!(sniff_bytes(b"RIFF\x24\0\0\0AVI "), F::None);
+        assert_eq!(sniff_bytes(b"FORM\0\0\0\x24ILBM"), F::None);
+        assert_eq!(sniff_bytes(b"ID3"), F::None);
+        assert_eq!(sniff_bytes(b""), F::None);
+    }
+
+    #[test]
+    fn contents_that_dont_match_the_extension_are_reported() {
+        let (dir, wav_path) = write_wav(&[]);
+        let renamed = dir.path().join("song.mp3");
+        std::fs::rename(&wav_path, &renamed).unwrap();
+        let metadata = NativeMetadataFormat::parse_from_file(&renamed).unwrap();
+        assert_eq!(metadata.fmt, NativeMetadataFormat::WAV);
+        assert_eq!(metadata.format_mismatch, Some(NativeMetadataFormat::ID3));
+
+        let unknown = dir.path().join("song.flac");
+        std::fs::write(&unknown, b"not audio").unwrap();
+        let metadata = NativeMetadataFormat::parse_from_file(&unknown).unwrap();
+        assert_eq!(metadata.fmt, NativeMetadataFormat::None);
+        assert_eq!(metadata.format_mismatch, Some(NativeMetadataFormat::FLAC));
+
+        let (_dir, matching) = write_wav(&[]);
+        let metadata = NativeMetadataFormat::parse_from_file(&matching).unwrap();
+        assert_eq!(metadata.format_mismatch, None);
     }
 }
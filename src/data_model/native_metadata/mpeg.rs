@@ -0,0 +1,381 @@
+//! Just enough of the MPEG audio stream to find the duration of an MP3, which its tags often don't hold.
+//!
+//! The duration comes from the Xing/Info or VBRI header LAME and other encoders write in the first frame.
+//! Without one the stream is assumed to be constant bitrate,
+//! and the duration is estimated from the size of the stream and the bitrate of the first frame,
+//! so scanning a library never has to read every frame of every file.
+//!
+//! - <http://www.mp3-tech.org/programmer/frame_header.html>
+//! - <https://www.codeproject.com/Articles/8295/MPEG-Audio-Frame-Header>
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use super::{NativeMetadataErrorKind, read_up_to};
+
+const FRAME_HEADER_LEN: usize = 4;
+/// How far past the ID3v2 tag to look for the first frame, since some files have junk before it
+const MAX_SYNC_SEARCH: usize = 64 * 1024;
+const ID3V1_LEN: u64 = 128;
+
+/// Bitrates in kbit/s by bitrate index, for MPEG-1 layers I, II and III and MPEG-2/2.5 layers I and II/III.
+/// Index 0 is the "free" bitrate, which can't be used to find the frame length.
+const BITRATES: [[u32; 15]; 5] = [
+    [
+        0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    Mpeg1,
+    Mpeg2,
+    Mpeg25,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FrameHeader {
+    version: Version,
+    /// 1, 2 or 3
+    layer: u8,
+    /// In bit/s
+    bitrate: u32,
+    sample_rate: u32,
+    /// Length of the whole frame including the header
+    len: usize,
+    samples: u32,
+    mono: bool,
+}
+
+impl FrameHeader {
+    /// Parse a frame header, or None if it isn't one (or has a free or invalid bitrate)
+    fn parse(bytes: [u8; FRAME_HEADER_LEN]) -> Option<Self> {
+        if bytes[0] != 0xff || bytes[1] & 0xe0 != 0xe0 {
+            return None;
+        }
+        let version = match (bytes[1] >> 3) & 0b11 {
+            0b00 => Version::Mpeg25,
+            0b10 => Version::Mpeg2,
+            0b11 => Version::Mpeg1,
+            _ => return None,
+        };
+        let layer = match (bytes[1] >> 1) & 0b11 {
+            0b01 => 3,
+            0b10 => 2,
+            0b11 => 1,
+            _ => return None,
+        };
+        let bitrate_idx = (bytes[2] >> 4) as usize;
+        let table = match (version, layer) {
+            (Version::Mpeg1, layer) => layer as usize - 1,
+            (_, 1) => 3,
+            _ => 4,
+        };
+        let bitrate = *BITRATES[table].get(bitrate_idx).filter(|&&b| b != 0)? * 1000;
+        let sample_rate = [44100, 48000, 32000].get(((bytes[2] >> 2) & 0b11) as usize)?
+            / match version {
+                Version::Mpeg1 => 1,
+                Version::Mpeg2 => 2,
+                Version::Mpeg25 => 4,
+            };
+        let padding = ((bytes[2] >> 1) & 1) as usize;
+        let samples = match (version, layer) {
+            (_, 1) => 384,
+            (Version::Mpeg1, _) | (_, 2) => 1152,
+            _ => 576,
+        };
+        // Layer I frames are made of 4 byte slots, the others of single bytes
+        let len = if layer == 1 {
+            (12 * bitrate / sample_rate) as usize * 4 + padding * 4
+        } else {
+            (samples / 8 * bitrate / sample_rate) as usize + padding
+        };
+        Some(Self {
+            version,
+            layer,
+            bitrate,
+            sample_rate,
+            len,
+            samples,
+            mono: bytes[3] >> 6 == 0b11,
+        })
+    }
+
+    fn duration(&self, frames: u64) -> Duration {
+        Duration::from_secs_f64(frames as f64 * self.samples as f64 / self.sample_rate as f64)
+    }
+
+    /// Where a Xing/Info header would start in the frame, after the side information
+    fn xing_offset(&self) -> usize {
+        FRAME_HEADER_LEN
+            + match (self.version, self.mono) {
+                (Version::Mpeg1, false) => 32,
+                (Version::Mpeg1, true) | (_, false) => 17,
+                (_, true) => 9,
+            }
+    }
+}
+
+/// The duration of the MPEG audio stream in an MP3 file, or None if no frames were found
+pub fn read_duration(path: &Path) -> Result<Option<Duration>, NativeMetadataErrorKind> {
+    let file = File::open(path).map_err(NativeMetadataErrorKind::Io)?;
+    let mut reader = BufReader::new(file);
+    let read_err = |err| NativeMetadataErrorKind::reading("MPEG audio stream", err);
+
+    let start = skip_id3v2(&mut reader).map_err(read_err)?;
+    let Some((first_frame_start, header)) =
+        find_first_frame(&mut reader, start).map_err(read_err)?
+    else {
+        return Ok(None);
+    };
+
+    reader
+        .seek(SeekFrom::Start(first_frame_start))
+        .map_err(read_err)?;
+    let mut first_frame = vec![0u8; header.len];
+    let len = read_up_to(&mut reader, &mut first_frame).map_err(read_err)?;
+    if let Some(frames) = vbr_header_frames(&header, &first_frame[..len]) {
+        return Ok(Some(header.duration(frames)));
+    }
+
+    // Without a VBR header, assume the whole stream has the bitrate of the first frame
+    let file_len = reader.seek(SeekFrom::End(0)).map_err(read_err)?;
+    let mut stream_len = file_len.saturating_sub(first_frame_start);
+    if has_id3v1(&mut reader, file_len).map_err(read_err)? {
+        stream_len = stream_len.saturating_sub(ID3V1_LEN);
+    }
+    Ok(Some(Duration::from_secs_f64(
+        stream_len as f64 * 8.0 / header.bitrate as f64,
+    )))
+}
+
+/// Whether the file ends in a 128 byte ID3v1 tag, which isn't part of the stream
+fn has_id3v1(reader: &mut (impl Read + Seek), file_len: u64) -> std::io::Result<bool> {
+    let Some(start) = file_len.checked_sub(ID3V1_LEN) else {
+        return Ok(false);
+    };
+    reader.seek(SeekFrom::Start(start))?;
+    let mut id = [0u8; 3];
+    Ok(read_up_to(reader, &mut id)? == id.len() && &id == b"TAG")
+}
+
+/// Skip the ID3v2 tag at the start of the file if there is one, returning where the audio starts
+fn skip_id3v2(reader: &mut (impl Read + Seek)) -> std::io::Result<u64> {
+    let mut header = [0u8; 10];
+    if read_up_to(reader, &mut header)? < header.len() || &header[0..3] != b"ID3" {
+        return Ok(0);
+    }
+    let size = header[6..10]
+        .iter()
+        .fold(0u64, |len, &b| (len << 7) | (b & 0x7f) as u64);
+    // The footer flag adds a copy of the header after the tag
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    Ok(10 + size + footer)
+}
+
+/// The first frame at or after `start`, which is only trusted if another frame follows it
+fn find_first_frame(
+    reader: &mut (impl Read + Seek),
+    start: u64,
+) -> std::io::Result<Option<(u64, FrameHeader)>> {
+    reader.seek(SeekFrom::Start(start))?;
+    let mut window = vec![0u8; MAX_SYNC_SEARCH + FRAME_HEADER_LEN];
+    let len = read_up_to(reader, &mut window)?;
+    let window = &window[..len];
+
+    for i in 0..window.len().saturating_sub(FRAME_HEADER_LEN - 1) {
+        let header_at = |i: usize| {
+            let bytes = window.get(i..i + FRAME_HEADER_LEN)?;
+            FrameHeader::parse(bytes.try_into().expect("4 bytes"))
+        };
+        let Some(header) = header_at(i) else {
+            continue;
+        };
+        let next = i + header.len;
+        let next_follows = if next + FRAME_HEADER_LEN <= window.len() {
+            header_at(next).is_some_and(|n| n.version == header.version && n.layer == header.layer)
+        } else {
+            // The stream is a single frame, or the next frame is past the window
+            let mut bytes = [0u8; FRAME_HEADER_LEN];
+            reader.seek(SeekFrom::Start(start + next as u64))?;
+            match read_up_to(reader, &mut bytes)? {
+                0 => true,
+                FRAME_HEADER_LEN => FrameHeader::parse(bytes).is_some(),
+                _ => false,
+            }
+        };
+        if next_follows {
+            return Ok(Some((start + i as u64, header)));
+        }
+    }
+    Ok(None)
+}
+
+/// The number of frames in the stream, from the Xing/Info or VBRI header in the first frame
+fn vbr_header_frames(header: &FrameHeader, frame: &[u8]) -> Option<u64> {
+    let be_u32 = |offset: usize| {
+        frame
+            .get(offset..offset + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().expect("4 bytes")))
+    };
+    let xing = header.xing_offset();
+    if matches!(frame.get(xing..xing + 4), Some(b"Xing" | b"Info")) {
+        let flags = be_u32(xing + 4)?;
+        // The frame count is only there if the first flag is set
+        return if flags & 1 != 0 {
+            be_u32(xing + 8).map(Into::into).filter(|&n: &u64| n > 0)
+        } else {
+            None
+        };
+    }
+    // VBRI is always 32 bytes after the header
+    let vbri = FRAME_HEADER_LEN + 32;
+    if frame.get(vbri..vbri + 4) == Some(b"VBRI") {
+        return be_u32(vbri + 14).map(Into::into).filter(|&n: &u64| n > 0);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MPEG-1 layer III, 128kbit/s, 44.1kHz, stereo
+    const HEADER: [u8; 4] = [0xff, 0xfb, 0x90, 0x00];
+    /// 144 * 128000 / 44100
+    const FRAME_LEN: usize = 417;
+
+    fn frame() -> Vec<u8> {
+        let mut frame = HEADER.to_vec();
+        frame.resize(FRAME_LEN, 0);
+        frame
+    }
+
+    fn frames(n: usize) -> Vec<u8> {
+        (0..n).flat_map(|_| frame()).collect()
+    }
+
+    /// A first frame holding a Xing/Info header which says the stream has `num_frames` frames
+    fn xing_frame(id: &[u8; 4], num_frames: u32) -> Vec<u8> {
+        let mut frame = frame();
+        let offset = FRAME_HEADER_LEN + 32;
+        frame[offset..offset + 4].copy_from_slice(id);
+        frame[offset + 4..offset + 8].copy_from_slice(&1u32.to_be_bytes());
+        frame[offset + 8..offset + 12].copy_from_slice(&num_frames.to_be_bytes());
+        frame
+    }
+
+    fn duration_of(bytes: &[u8]) -> Option<Duration> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("song.mp3");
+        std::fs::write(&path, bytes).unwrap();
+        read_duration(&path).unwrap()
+    }
+
+    fn assert_secs(duration: Option<Duration>, secs: f64) {
+        let duration = duration.expect("a duration").as_secs_f64();
+        assert!((duration - secs).abs() < 1e-6, "{duration} != {secs}");
+    }
+
+    /// Estimates from the bitrate are out by the padding, which the test frames don't have
+    fn assert_about_secs(duration: Option<Duration>, secs: f64) {
+        let duration = duration.expect("a duration").as_secs_f64();
+        assert!(
+            (duration - secs).abs() < secs * 0.01,
+            "{duration} != {secs}"
+        );
+    }
+
+    #[test]
+    fn header_is_parsed() {
+        let header = FrameHeader::parse(HEADER).unwrap();
+        assert_eq!(header.version, Version::Mpeg1);
+        assert_eq!(header.layer, 3);
+        assert_eq!(header.sample_rate, 44100);
+        assert_eq!(header.len, FRAME_LEN);
+        assert_eq!(header.samples, 1152);
+        assert!(!header.mono);
+
+        // Padded, MPEG-2 layer III at 64kbit/s and 22.05kHz, mono: 72 * 64000 / 22050 + 1
+        let header = FrameHeader::parse([0xff, 0xf3, 0x82, 0xc0]).unwrap();
+        assert_eq!(header.version, Version::Mpeg2);
+        assert_eq!(header.sample_rate, 22050);
+        assert_eq!(header.len, 209);
+        assert_eq!(header.samples, 576);
+        assert!(header.mono);
+
+        // Free and invalid bitrates, a reserved sample rate, and no sync
+        assert_eq!(FrameHeader::parse([0xff, 0xfb, 0x00, 0x00]), None);
+        assert_eq!(FrameHeader::parse([0xff, 0xfb, 0xf0, 0x00]), None);
+        assert_eq!(FrameHeader::parse([0xff, 0xfb, 0x9c, 0x00]), None);
+        assert_eq!(FrameHeader::parse([0x49, 0x44, 0x33, 0x04]), None);
+    }
+
+    #[test]
+    fn duration_is_estimated_from_the_bitrate_without_a_vbr_header() {
+        // Exactly the size of the stream at 128kbit/s
+        assert_secs(
+            duration_of(&frames(100)),
+            100.0 * FRAME_LEN as f64 * 8.0 / 128_000.0,
+        );
+        assert_about_secs(duration_of(&frames(100)), 100.0 * 1152.0 / 44100.0);
+    }
+
+    #[test]
+    fn xing_and_info_headers_give_the_frame_count() {
+        for id in [b"Xing", b"Info"] {
+            let mut bytes = xing_frame(id, 10_000);
+            bytes.extend(frames(10));
+            assert_secs(duration_of(&bytes), 10_000.0 * 1152.0 / 44100.0);
+        }
+    }
+
+    #[test]
+    fn vbri_header_gives_the_frame_count() {
+        let mut bytes = frame();
+        let offset = FRAME_HEADER_LEN + 32;
+        bytes[offset..offset + 4].copy_from_slice(b"VBRI");
+        bytes[offset + 14..offset + 18].copy_from_slice(&5000u32.to_be_bytes());
+        bytes.extend(frames(10));
+        assert_secs(duration_of(&bytes), 5000.0 * 1152.0 / 44100.0);
+    }
+
+    #[test]
+    fn tags_and_junk_around_the_stream_are_skipped() {
+        // An ID3v2 tag of 20 bytes, some junk, the frames, and an ID3v1 tag
+        let mut bytes = b"ID3\x04\x00\x00\x00\x00\x00\x14".to_vec();
+        bytes.extend([0u8; 20]);
+        bytes.extend([0xffu8, 0x00, 0x12]);
+        bytes.extend(frames(50));
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, 0xff);
+        bytes.extend(id3v1);
+        assert_secs(
+            duration_of(&bytes),
+            50.0 * FRAME_LEN as f64 * 8.0 / 128_000.0,
+        );
+    }
+
+    #[test]
+    fn files_without_frames_have_no_duration() {
+        assert_eq!(duration_of(b""), None);
+        assert_eq!(duration_of(b"ID3\x04\x00\x00\x00\x00\x00\x00"), None);
+        assert_eq!(duration_of(&[0u8; 1000]), None);
+        // A lone frame sync that isn't followed by another frame
+        let mut bytes = HEADER.to_vec();
+        bytes.resize(2000, 0x55);
+        assert_eq!(duration_of(&bytes), None);
+    }
+}
@@ -0,0 +1,355 @@
+//! Just enough of the Ogg container to read the Vorbis comments and duration of Ogg Vorbis and Ogg Opus files.
+//!
+//! - <https://xiph.org/ogg/doc/framing.html>
+//! - <https://xiph.org/vorbis/doc/Vorbis_I_spec.html#x1-600004.2>
+//! - <https://datatracker.ietf.org/doc/html/rfc7845#section-5>
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use super::NativeMetadataErrorKind;
+
+const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+/// The fixed part of a page header, before the segment table
+const PAGE_HEADER_LEN: usize = 27;
+/// Opus granule positions always count samples at 48kHz, regardless of the input sample rate
+const OPUS_GRANULE_RATE: u64 = 48000;
+
+pub struct OggInfo {
+    /// Uppercased field name -> every value, in file order
+    pub comments: HashMap<String, Vec<String>>,
+    pub duration: Option<Duration>,
+}
+
+struct PageHeader {
+    serial: u32,
+    segment_table: Vec<u8>,
+}
+
+fn read_page_header(reader: &mut impl Read) -> Result<PageHeader, NativeMetadataErrorKind> {
+    let mut header = [0u8; PAGE_HEADER_LEN];
+    reader
+        .read_exact(&mut header)
+        .map_err(|err| NativeMetadataErrorKind::reading("Ogg page", err))?;
+    if &header[0..4] != CAPTURE_PATTERN {
+        return Err(NativeMetadataErrorKind::MalformedTag(
+            "not an Ogg page".to_owned(),
+        ));
+    }
+    let mut segment_table = vec![0u8; header[26] as usize];
+    reader
+        .read_exact(&mut segment_table)
+        .map_err(|err| NativeMetadataErrorKind::reading("Ogg page", err))?;
+    Ok(PageHeader {
+        serial: u32::from_le_bytes(header[14..18].try_into().expect("4 bytes")),
+        segment_table,
+    })
+}
+
+/// Read the first `n` packets of the first logical stream
+fn read_packets(
+    reader: &mut impl Read,
+    n: usize,
+) -> Result<(u32, Vec<Vec<u8>>), NativeMetadataErrorKind> {
+    let mut stream_serial = None;
+    let mut packets = vec![];
+    let mut current = vec![];
+    while packets.len() < n {
+        let page = read_page_header(reader)?;
+        let mut data = vec![0u8; page.segment_table.iter().map(|&s| s as usize).sum()];
+        reader
+            .read_exact(&mut data)
+            .map_err(|err| NativeMetadataErrorKind::reading("Ogg page", err))?;
+        // Pages from other multiplexed streams are skipped
+        if *stream_serial.get_or_insert(page.serial) != page.serial {
+            continue;
+        }
+        let mut offset = 0;
+        for &segment_len in &page.segment_table {
+            current.extend_from_slice(&data[offset..offset + segment_len as usize]);
+            offset += segment_len as usize;
+            // A segment shorter than 255 bytes ends the packet
+            if segment_len < 255 {
+                packets.push(std::mem::take(&mut current));
+                if packets.len() == n {
+                    break;
+                }
+            }
+        }
+    }
+    Ok((stream_serial.expect("read at least one page"), packets))
+}
+
+/// Reads little-endian fields from the front of a byte slice
+struct FieldReader<'a>(&'a [u8]);
+
+impl<'a> FieldReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], NativeMetadataErrorKind> {
+        if self.0.len() < len {
+            return Err(NativeMetadataErrorKind::MalformedTag(
+                "Vorbis comment block is truncated".to_owned(),
+            ));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn take_u32(&mut self) -> Result<u32, NativeMetadataErrorKind> {
+        Ok(u32::from_le_bytes(
+            self.take(4)?.try_into().expect("4 bytes"),
+        ))
+    }
+}
+
+/// Parse a Vorbis comment block, without the codec-specific packet prefix
+fn parse_comments(data: &[u8]) -> Result<HashMap<String, Vec<String>>, NativeMetadataErrorKind> {
+    let mut reader = FieldReader(data);
+    let vendor_len = reader.take_u32()? as usize;
+    reader.take(vendor_len)?;
+    let num_comments = reader.take_u32()?;
+
+    let mut comments: HashMap<String, Vec<String>> = HashMap::new();
+    for _ in 0..num_comments {
+        let len = reader.take_u32()? as usize;
+        let comment = String::from_utf8_lossy(reader.take(len)?);
+        // Comments without a '=' are invalid, but not worth failing the whole file over
+        if let Some((key, value)) = comment.split_once('=') {
+            comments
+                .entry(key.to_ascii_uppercase())
+                .or_default()
+                .push(value.to_owned());
+        }
+    }
+    Ok(comments)
+}
+
+/// The granule position of the last page in the given stream, found by searching backwards from the end of the file
+fn last_granule_position(file: &mut File, serial: u32) -> Option<u64> {
+    const TAIL_LEN: u64 = 64 * 1024;
+    let file_len = file.seek(SeekFrom::End(0)).ok()?;
+    let start = file_len.saturating_sub(TAIL_LEN);
+    file.seek(SeekFrom::Start(start)).ok()?;
+    let mut tail = vec![];
+    file.read_to_end(&mut tail).ok()?;
+
+    (0..tail.len().saturating_sub(PAGE_HEADER_LEN))
+        .rev()
+        .filter(|&i| &tail[i..i + 4] == CAPTURE_PATTERN)
+        .map(|i| &tail[i..i + PAGE_HEADER_LEN])
+        .filter(|header| u32::from_le_bytes(header[14..18].try_into().expect("4 bytes")) == serial)
+        .map(|header| u64::from_le_bytes(header[6..14].try_into().expect("8 bytes")))
+        // -1 means no packet finishes on this page, so the position is on an earlier one
+        .find(|&granule| granule != u64::MAX)
+}
+
+pub fn read_ogg(path: &Path) -> Result<OggInfo, NativeMetadataErrorKind> {
+    let file = File::open(path).map_err(NativeMetadataErrorKind::Io)?;
+    let malformed = |reason: &str| NativeMetadataErrorKind::MalformedTag(reason.to_owned());
+    let mut reader = BufReader::new(file);
+    let (serial, packets) = read_packets(&mut reader, 2)?;
+    let (ident, comment) = (&packets[0], &packets[1]);
+
+    let (comments, granule_rate, pre_skip) = if ident.starts_with(b"\x01vorbis") {
+        let sample_rate = ident
+            .get(12..16)
+            .map(|b| u32::from_le_bytes(b.try_into().expect("4 bytes")))
+            .ok_or_else(|| malformed("Vorbis identification header is truncated"))?;
+        let comments = comment
+            .strip_prefix(b"\x03vorbis")
+            .ok_or_else(|| malformed("second Vorbis packet isn't a comment header"))?;
+        (parse_comments(comments)?, sample_rate as u64, 0)
+    } else if ident.starts_with(b"OpusHead") {
+        let pre_skip = ident
+            .get(10..12)
+            .map(|b| u16::from_le_bytes(b.try_into().expect("2 bytes")))
+            .ok_or_else(|| malformed("Opus identification header is truncated"))?;
+        let comments = comment
+            .strip_prefix(b"OpusTags")
+            .ok_or_else(|| malformed("second Opus packet isn't a comment header"))?;
+        (
+            parse_comments(comments)?,
+            OPUS_GRANULE_RATE,
+            pre_skip as u64,
+        )
+    } else {
+        return Err(NativeMetadataErrorKind::UnsupportedContainer(
+            "Ogg stream is neither Vorbis nor Opus".to_owned(),
+        ));
+    };
+
+    let duration = last_granule_position(reader.get_mut(), serial)
+        .filter(|_| granule_rate > 0)
+        .map(|granule| {
+            Duration::from_secs_f64(granule.saturating_sub(pre_skip) as f64 / granule_rate as f64)
+        });
+
+    Ok(OggInfo { comments, duration })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An Ogg page holding `packets`, the last of which carries on into the next page if `continued`
+    fn page(serial: u32, granule: u64, packets: &[&[u8]], continued: bool) -> Vec<u8> {
+        let mut segment_table = vec![];
+        for (idx, packet) in packets.iter().enumerate() {
+            segment_table.extend(std::iter::repeat_n(255u8, packet.len() / 255));
+            if !(continued && idx == packets.len() - 1) {
+                segment_table.push((packet.len() % 255) as u8);
+            }
+        }
+        let mut page = CAPTURE_PATTERN.to_vec();
+        page.extend([0, 0]); // version, header type
+        page.extend(granule.to_le_bytes());
+        page.extend(serial.to_le_bytes());
+        page.extend([0; 8]); // sequence number, checksum
+        page.push(segment_table.len() as u8);
+        page.extend(segment_table);
+        page.extend(packets.concat());
+        page
+    }
+
+    fn comment_block(prefix: &[u8], comments: &[&str]) -> Vec<u8> {
+        let mut block = prefix.to_vec();
+        block.extend(6u32.to_le_bytes());
+        block.extend(b"vendor");
+        block.extend((comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend((comment.len() as u32).to_le_bytes());
+            block.extend(comment.as_bytes());
+        }
+        block
+    }
+
+    fn opus_head(pre_skip: u16) -> Vec<u8> {
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend(pre_skip.to_le_bytes());
+        head.extend(44100u32.to_le_bytes()); // input sample rate, which doesn't affect granules
+        head.extend([0, 0, 0]); // output gain, channel mapping family
+        head
+    }
+
+    fn vorbis_ident(sample_rate: u32) -> Vec<u8> {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend(0u32.to_le_bytes()); // version
+        ident.push(2); // channels
+        ident.extend(sample_rate.to_le_bytes());
+        ident.extend([0; 13]); // bitrates, block sizes, framing
+        ident
+    }
+
+    fn write_ogg(pages: &[Vec<u8>]) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, &pages.concat()).unwrap();
+        file
+    }
+
+    #[test]
+    fn packets_are_reassembled_across_pages() {
+        let long = vec![7u8; 300];
+        let bytes = [
+            page(1, 0, &[&long[..255]], true),
+            // A page from another multiplexed stream, between the two halves of the packet
+            page(2, 0, &[b"other"], false),
+            page(1, 0, &[&long[255..], b"second", b"third"], false),
+        ]
+        .concat();
+
+        let (serial, packets) = read_packets(&mut bytes.as_slice(), 2).unwrap();
+        assert_eq!(serial, 1);
+        assert_eq!(packets, vec![long, b"second".to_vec()]);
+    }
+
+    #[test]
+    fn packet_of_exactly_255_bytes_ends_with_an_empty_segment() {
+        let exact = vec![1u8; 255];
+        let bytes = page(1, 0, &[&exact, b"next"], false);
+        let (_, packets) = read_packets(&mut bytes.as_slice(), 2).unwrap();
+        assert_eq!(packets, vec![exact, b"next".to_vec()]);
+    }
+
+    #[test]
+    fn stream_ending_before_enough_packets_is_an_error() {
+        let bytes = page(1, 0, &[b"only"], false);
+        assert!(read_packets(&mut bytes.as_slice(), 2).is_err());
+    }
+
+    #[test]
+    fn opus_comments_and_duration_excluding_pre_skip() {
+        let file = write_ogg(&[
+            page(5, 0, &[&opus_head(312)], false),
+            page(
+                5,
+                0,
+                &[&comment_block(
+                    b"OpusTags",
+                    &["title=Song", "ARTIST=A", "artist=B", "junk"],
+                )],
+                false,
+            ),
+            page(5, 48000 * 2 + 312, &[b"audio"], false),
+        ]);
+        let info = read_ogg(file.path()).unwrap();
+        assert_eq!(info.comments["TITLE"], ["Song"]);
+        assert_eq!(info.comments["ARTIST"], ["A", "B"]);
+        assert_eq!(info.comments.len(), 2);
+        assert_eq!(info.duration, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn vorbis_duration_uses_the_sample_rate() {
+        let file = write_ogg(&[
+            page(9, 0, &[&vorbis_ident(44100)], false),
+            page(
+                9,
+                0,
+                &[&comment_block(b"\x03vorbis", &["ALBUM=Record"])],
+                false,
+            ),
+            page(9, 44100 * 3, &[b"audio"], false),
+        ]);
+        let info = read_ogg(file.path()).unwrap();
+        assert_eq!(info.comments["ALBUM"], ["Record"]);
+        assert_eq!(info.duration, Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn duration_comes_from_the_last_page_that_finishes_a_packet() {
+        let long = vec![0u8; 255];
+        let file = write_ogg(&[
+            page(9, 0, &[&vorbis_ident(1000)], false),
+            page(9, 0, &[&comment_block(b"\x03vorbis", &[])], false),
+            page(9, 4000, &[b"audio"], false),
+            // Other streams and pages where no packet finishes don't count
+            page(3, 99000, &[b"other"], false),
+            page(9, u64::MAX, &[&long], true),
+        ]);
+        assert_eq!(
+            read_ogg(file.path()).unwrap().duration,
+            Some(Duration::from_secs(4))
+        );
+    }
+
+    #[test]
+    fn vorbis_without_comment_header_is_malformed() {
+        let file = write_ogg(&[page(9, 0, &[&vorbis_ident(44100), b"\x05vorbis"], false)]);
+        assert!(matches!(
+            read_ogg(file.path()),
+            Err(NativeMetadataErrorKind::MalformedTag(_))
+        ));
+    }
+
+    #[test]
+    fn other_codecs_are_unsupported() {
+        let file = write_ogg(&[page(1, 0, &[b"\x7fFLAC", b"comments"], false)]);
+        assert!(matches!(
+            read_ogg(file.path()),
+            Err(NativeMetadataErrorKind::UnsupportedContainer(_))
+        ));
+    }
+}
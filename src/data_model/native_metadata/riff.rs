@@ -0,0 +1,384 @@
+//! Reading tags from the chunks of WAV (RIFF) and AIFF (IFF) files.
+//!
+//! Both formats are a sequence of chunks with a four-character ID and a length, padded to an even length.
+//! WAV is little-endian and AIFF is big-endian.
+//! Either can hold an ID3 tag in an `id3 ` or `ID3 ` chunk.
+//! WAV files can also hold a `LIST` chunk of type `INFO` with simple text fields,
+//! and AIFF files can hold `NAME` and `AUTH` text chunks.
+//!
+//! - <https://www.mmsp.ece.mcgill.ca/Documents/AudioFormats/WAVE/WAVE.html>
+//! - <https://www.mmsp.ece.mcgill.ca/Documents/AudioFormats/AIFF/AIFF.html>
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use super::NativeMetadataErrorKind;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Wav,
+    Aiff,
+}
+
+#[derive(Default)]
+pub struct ChunkTags {
+    pub id3: Option<id3::Tag>,
+    /// Text fields keyed by their four-character ID, e.g. `INAM` for WAV or `NAME` for AIFF
+    pub text: HashMap<[u8; 4], String>,
+    pub duration: Option<Duration>,
+    /// Tags that couldn't be parsed, which don't stop the other chunks being read,
+    /// and the chunk that couldn't be read if the file is truncated or unreadable, which does
+    pub errors: Vec<NativeMetadataErrorKind>,
+}
+
+/// Chunks larger than this are skipped rather than read into memory, they're audio not tags.
+const MAX_TAG_CHUNK_LEN: u32 = 64 * 1024 * 1024;
+
+pub fn read_chunks(
+    path: &Path,
+    container: Container,
+) -> Result<ChunkTags, NativeMetadataErrorKind> {
+    let mut reader = BufReader::new(File::open(path).map_err(NativeMetadataErrorKind::Io)?);
+    let read_u32 = |b: [u8; 4]| match container {
+        Container::Wav => u32::from_le_bytes(b),
+        Container::Aiff => u32::from_be_bytes(b),
+    };
+
+    let mut header = [0u8; 12];
+    reader
+        .read_exact(&mut header)
+        .map_err(|err| NativeMetadataErrorKind::reading("container header", err))?;
+    let valid = match container {
+        Container::Wav => &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE",
+        Container::Aiff => &header[0..4] == b"FORM" && matches!(&header[8..12], b"AIFF" | b"AIFC"),
+    };
+    if !valid {
+        return Err(NativeMetadataErrorKind::UnsupportedContainer(
+            match container {
+                Container::Wav => "not a RIFF WAVE file".to_owned(),
+                Container::Aiff => "not an AIFF file".to_owned(),
+            },
+        ));
+    }
+
+    let mut tags = ChunkTags::default();
+    // WAV needs both the byte rate from `fmt ` and the size of `data` to find the duration
+    let mut wav_byte_rate = None;
+    let mut wav_data_len = None;
+    let file_len = reader
+        .get_ref()
+        .metadata()
+        .map_err(NativeMetadataErrorKind::Io)?
+        .len();
+
+    loop {
+        // Chunks that can't be read end the loop, keeping whatever tags were read before them
+        let chunk_start = match reader.stream_position() {
+            Ok(pos) => pos,
+            Err(err) => {
+                tags.errors.push(NativeMetadataErrorKind::Io(err));
+                break;
+            }
+        };
+        // The padding byte after an odd-length last chunk is often left out, so this may be past the end
+        if chunk_start >= file_len {
+            break;
+        }
+        let mut chunk_header = [0u8; 8];
+        if let Err(err) = reader.read_exact(&mut chunk_header) {
+            tags.errors
+                .push(NativeMetadataErrorKind::reading("chunk header", err));
+            break;
+        }
+        let id: [u8; 4] = chunk_header[0..4].try_into().expect("4 bytes");
+        let len = read_u32(chunk_header[4..8].try_into().expect("4 bytes"));
+        let padded_len = len as i64 + (len % 2) as i64;
+        let available_len = (file_len - chunk_start - 8).min(len as u64);
+
+        if id == *b"data" {
+            // Whatever audio a truncated file still has can be played
+            wav_data_len = Some(available_len);
+        }
+        if available_len < len as u64 {
+            tags.errors
+                .push(NativeMetadataErrorKind::MalformedTag(format!(
+                    "{:?} chunk is truncated",
+                    String::from_utf8_lossy(&id)
+                )));
+            break;
+        }
+
+        let wanted = matches!(
+            (&id, container),
+            (b"id3 " | b"ID3 ", _)
+                | (b"LIST" | b"fmt ", Container::Wav)
+                | (b"NAME" | b"AUTH" | b"COMM", Container::Aiff)
+        );
+        if !wanted || len > MAX_TAG_CHUNK_LEN {
+            if let Err(err) = reader.seek(SeekFrom::Current(padded_len)) {
+                tags.errors.push(NativeMetadataErrorKind::Io(err));
+                break;
+            }
+            continue;
+        }
+
+        let mut data = vec![0u8; len as usize];
+        if let Err(err) = reader.read_exact(&mut data) {
+            tags.errors.push(NativeMetadataErrorKind::reading(
+                &format!("{:?} chunk", String::from_utf8_lossy(&id)),
+                err,
+            ));
+            break;
+        }
+        if len % 2 == 1 {
+            let _ = reader.seek(SeekFrom::Current(1));
+        }
+
+        match &id {
+            b"id3 " | b"ID3 " => match id3::Tag::read_from2(std::io::Cursor::new(data)) {
+                Ok(tag) => tags.id3 = Some(tag),
+                Err(err) => tags.errors.push(err.into()),
+            },
+            b"LIST" if data.starts_with(b"INFO") => parse_info(&data[4..], &mut tags.text),
+            b"fmt " => {
+                wav_byte_rate = data
+                    .get(8..12)
+                    .map(|b| u32::from_le_bytes(b.try_into().expect("4 bytes")));
+            }
+            b"NAME" | b"AUTH" => {
+                tags.text.insert(id, text(&data));
+            }
+            b"COMM" => tags.duration = aiff_duration(&data),
+            _ => {}
+        }
+    }
+
+    if let (Some(byte_rate), Some(data_len)) = (wav_byte_rate, wav_data_len)
+        && byte_rate > 0
+    {
+        tags.duration = Some(Duration::from_secs_f64(data_len as f64 / byte_rate as f64));
+    }
+    Ok(tags)
+}
+
+/// The subchunks of a `LIST` `INFO` chunk are always little-endian text
+fn parse_info(mut data: &[u8], text_fields: &mut HashMap<[u8; 4], String>) {
+    while data.len() >= 8 {
+        let id: [u8; 4] = data[0..4].try_into().expect("4 bytes");
+        let len = u32::from_le_bytes(data[4..8].try_into().expect("4 bytes")) as usize;
+        let Some(value) = data.get(8..8 + len) else {
+            break;
+        };
+        text_fields.insert(id, text(value));
+        data = data.get(8 + len + len % 2..).unwrap_or_default();
+    }
+}
+
+/// Text chunks are usually NUL-terminated and sometimes padded
+fn text(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches('\0')
+        .trim()
+        .to_owned()
+}
+
+/// The duration from an AIFF `COMM` chunk: channels (u16), sample frames (u32), sample size (u16), sample rate (80-bit float)
+fn aiff_duration(comm: &[u8]) -> Option<Duration> {
+    let num_frames = u32::from_be_bytes(comm.get(2..6)?.try_into().ok()?);
+    let rate = comm.get(8..18)?;
+    let exponent = (((rate[0] & 0x7f) as i32) << 8) | rate[1] as i32;
+    let mantissa = u64::from_be_bytes(rate[2..10].try_into().ok()?);
+    let sample_rate = mantissa as f64 * 2f64.powi(exponent - 16383 - 63);
+    (sample_rate > 0.0).then(|| Duration::from_secs_f64(num_frames as f64 / sample_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use id3::TagLike;
+
+    use super::*;
+
+    fn chunk(container: Container, id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let len = data.len() as u32;
+        let mut chunk = id.to_vec();
+        chunk.extend(match container {
+            Container::Wav => len.to_le_bytes(),
+            Container::Aiff => len.to_be_bytes(),
+        });
+        chunk.extend(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    /// Write a file with the container's header, whose length field covers `chunks`
+    fn write(container: Container, chunks: &[u8]) -> tempfile::NamedTempFile {
+        let (outer_id, form_type) = match container {
+            Container::Wav => (b"RIFF", b"WAVE"),
+            Container::Aiff => (b"FORM", b"AIFF"),
+        };
+        let form = [form_type, chunks].concat();
+        let mut bytes = chunk(container, outer_id, &form);
+        // The outer chunk's own padding isn't part of the file
+        bytes.truncate(8 + form.len());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, &bytes).unwrap();
+        file
+    }
+
+    fn read(container: Container, chunks: &[u8]) -> ChunkTags {
+        read_chunks(write(container, chunks).path(), container).unwrap()
+    }
+
+    fn id3_tag(title: &str) -> Vec<u8> {
+        let mut tag = id3::Tag::new();
+        tag.set_title(title);
+        let mut bytes = vec![];
+        tag.write_to(&mut bytes, id3::Version::Id3v24).unwrap();
+        bytes
+    }
+
+    /// `fmt ` with a byte rate of 1000 bytes per second
+    fn wav_fmt() -> Vec<u8> {
+        let mut fmt = vec![1, 0, 1, 0]; // PCM, mono
+        fmt.extend(500u32.to_le_bytes()); // sample rate
+        fmt.extend(1000u32.to_le_bytes()); // byte rate
+        fmt.extend([2, 0, 16, 0]); // block align, bits per sample
+        chunk(Container::Wav, b"fmt ", &fmt)
+    }
+
+    fn wav_info(fields: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut info = b"INFO".to_vec();
+        for (id, value) in fields {
+            info.extend(chunk(Container::Wav, id, value));
+        }
+        chunk(Container::Wav, b"LIST", &info)
+    }
+
+    fn text_field<'a>(tags: &'a ChunkTags, id: &[u8; 4]) -> Option<&'a str> {
+        tags.text.get(id).map(String::as_str)
+    }
+
+    #[test]
+    fn wav_info_fields_and_duration() {
+        let tags = read(
+            Container::Wav,
+            &[
+                wav_fmt(),
+                // Odd lengths, so the subchunks and chunks after them only line up if the padding is skipped
+                wav_info(&[(b"INAM", b"Odd\0\0"), (b"IART", b"Artist\0")]),
+                chunk(Container::Wav, b"junk", b"odd"),
+                chunk(Container::Wav, b"data", &[0; 1500]),
+            ]
+            .concat(),
+        );
+        assert!(tags.errors.is_empty());
+        assert_eq!(text_field(&tags, b"INAM"), Some("Odd"));
+        assert_eq!(text_field(&tags, b"IART"), Some("Artist"));
+        assert_eq!(tags.duration, Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn missing_padding_after_the_last_chunk_is_fine() {
+        let chunks = [wav_fmt(), chunk(Container::Wav, b"data", &[0; 1001])].concat();
+        let tags = read(Container::Wav, &chunks[..chunks.len() - 1]);
+        assert!(tags.errors.is_empty());
+        assert_eq!(tags.duration, Some(Duration::from_millis(1001)));
+    }
+
+    #[test]
+    fn id3_chunk_in_either_case() {
+        let wav = read(
+            Container::Wav,
+            &chunk(Container::Wav, b"id3 ", &id3_tag("Lower")),
+        );
+        let aiff = read(
+            Container::Aiff,
+            &chunk(Container::Aiff, b"ID3 ", &id3_tag("Upper")),
+        );
+        assert_eq!(wav.id3.unwrap().title(), Some("Lower"));
+        assert_eq!(aiff.id3.unwrap().title(), Some("Upper"));
+    }
+
+    #[test]
+    fn aiff_text_chunks_and_duration() {
+        let mut comm = 2u16.to_be_bytes().to_vec(); // channels
+        comm.extend(88200u32.to_be_bytes()); // sample frames
+        comm.extend(16u16.to_be_bytes()); // sample size
+        // 44100 as an 80-bit extended float
+        comm.extend((16383u16 + 15).to_be_bytes());
+        comm.extend((44100u64 << 48).to_be_bytes());
+
+        let tags = read(
+            Container::Aiff,
+            &[
+                chunk(Container::Aiff, b"NAME", b"Title"),
+                chunk(Container::Aiff, b"AUTH", b"Author"),
+                chunk(Container::Aiff, b"COMM", &comm),
+            ]
+            .concat(),
+        );
+        assert!(tags.errors.is_empty());
+        assert_eq!(text_field(&tags, b"NAME"), Some("Title"));
+        assert_eq!(text_field(&tags, b"AUTH"), Some("Author"));
+        assert_eq!(tags.duration, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn truncated_data_chunk_keeps_the_tags_and_audio_before_it() {
+        let chunks = [
+            wav_fmt(),
+            wav_info(&[(b"INAM", b"Cut short\0")]),
+            chunk(Container::Wav, b"data", &[0; 2000]),
+        ]
+        .concat();
+        let tags = read(Container::Wav, &chunks[..chunks.len() - 1500]);
+        assert_eq!(text_field(&tags, b"INAM"), Some("Cut short"));
+        assert_eq!(tags.duration, Some(Duration::from_millis(500)));
+        assert!(matches!(
+            &tags.errors[..],
+            [NativeMetadataErrorKind::MalformedTag(reason)] if reason.contains("data")
+        ));
+    }
+
+    #[test]
+    fn truncated_tag_chunk_is_reported() {
+        let chunks = [
+            chunk(Container::Aiff, b"NAME", b"Title"),
+            chunk(Container::Aiff, b"ID3 ", &id3_tag("Cut short")),
+        ]
+        .concat();
+        let tags = read(Container::Aiff, &chunks[..chunks.len() - 4]);
+        assert!(tags.id3.is_none());
+        assert_eq!(text_field(&tags, b"NAME"), Some("Title"));
+        assert!(matches!(
+            &tags.errors[..],
+            [NativeMetadataErrorKind::MalformedTag(reason)] if reason.contains("ID3 ")
+        ));
+    }
+
+    #[test]
+    fn truncated_chunk_header_is_reported() {
+        let chunks = [wav_info(&[(b"INAM", b"Title\0")]), b"LIS".to_vec()].concat();
+        let tags = read(Container::Wav, &chunks);
+        assert_eq!(text_field(&tags, b"INAM"), Some("Title"));
+        assert!(matches!(
+            &tags.errors[..],
+            [NativeMetadataErrorKind::MalformedTag(_)]
+        ));
+    }
+
+    #[test]
+    fn wrong_container_is_unsupported() {
+        let file = write(Container::Wav, &[]);
+        assert!(matches!(
+            read_chunks(file.path(), Container::Aiff),
+            Err(NativeMetadataErrorKind::UnsupportedContainer(_))
+        ));
+    }
+}
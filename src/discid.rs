@@ -0,0 +1,561 @@
+//! Computing [MusicBrainz disc IDs](https://musicbrainz.org/doc/Disc_ID_Calculation) and
+//! [CDDB disc IDs](https://en.wikipedia.org/wiki/CDDB#Example_calculation_of_a_CDDB1_(FreeDB)_disc_ID)
+//! from the table of contents (TOC) of a CD, without needing the CD itself.
+//!
+//! The TOC of an album is found from, in order of preference,
+//! - the TOC table of an EAC or XLD rip log in the group folder (see [crate::riplog]),
+//! - a cue sheet in the group folder, with the lengths of the files it refers to,
+//! - the lengths of the songs themselves, if they're all lossless and make up one whole disc.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use base64::Engine;
+use base64::alphabet::Alphabet;
+use base64::engine::{GeneralPurpose, general_purpose};
+use regex::Regex;
+use sha1::Digest;
+
+use crate::data_model::native_metadata::NativeMetadataFormat;
+use crate::data_model::{AlbumInputGroup, CddbDiscId, MbDiscId};
+
+/// Audio starts after a 2 second lead-in, which every offset in a [Toc] includes
+const LEAD_IN_SECTORS: u32 = 150;
+/// CD audio has 75 sectors (of 588 samples) per second
+const SECTORS_PER_SEC: f64 = 75.0;
+/// On an Enhanced CD, the data track starts this far after the end of the audio session
+const DATA_TRACK_GAP_SECTORS: u32 = 11400;
+
+/// Base64 with the alphabet of MusicBrainz disc IDs
+const MB_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &match Alphabet::new("ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789._") {
+        Ok(alphabet) => alphabet,
+        Err(_) => panic!("invalid base64 alphabet"),
+    },
+    general_purpose::PAD,
+);
+
+/// The layout of the tracks on a CD
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Toc {
+    first_track: u8,
+    /// Start of each audio track, in sectors including the lead-in
+    offsets: Vec<u32>,
+    /// Start of the lead-out, in sectors including the lead-in
+    leadout: u32,
+    /// Start of the data track after the audio tracks on an Enhanced CD, in sectors including the lead-in.
+    /// The CDDB disc ID counts it as a track, but the MusicBrainz disc ID doesn't.
+    data_track: Option<u32>,
+}
+
+impl Toc {
+    /// `offsets` are the start of each audio track and `leadout` the end of the disc,
+    /// in sectors including the 150 sector lead-in.
+    pub fn new(
+        first_track: u8,
+        offsets: Vec<u32>,
+        leadout: u32,
+        data_track: Option<u32>,
+    ) -> anyhow::Result<Self> {
+        let Some(&last_offset) = offsets.last() else {
+            anyhow::bail!("the TOC has no audio tracks");
+        };
+        let num_tracks = offsets.len() + data_track.is_some() as usize;
+        if first_track == 0 || first_track as usize + num_tracks - 1 > 99 {
+            anyhow::bail!(
+                "the TOC has tracks {} to {}, but CDs can only have tracks 1 to 99",
+                first_track,
+                first_track as usize + num_tracks - 1
+            );
+        }
+        if offsets[0] < LEAD_IN_SECTORS || offsets.windows(2).any(|w| w[0] >= w[1]) {
+            anyhow::bail!("the TOC's tracks overlap or are out of order");
+        }
+        let end = data_track.unwrap_or(last_offset);
+        if data_track.is_some_and(|d| d < last_offset + DATA_TRACK_GAP_SECTORS) || leadout <= end {
+            anyhow::bail!("the TOC's lead-out is before its last track");
+        }
+        Ok(Self {
+            first_track,
+            offsets,
+            leadout,
+            data_track,
+        })
+    }
+
+    /// The TOC of a disc holding tracks of these lengths in sectors, one after the other from track 1.
+    pub fn from_track_lengths(lengths: &[u32]) -> anyhow::Result<Self> {
+        let mut offsets = vec![];
+        let mut offset = LEAD_IN_SECTORS;
+        for &length in lengths {
+            offsets.push(offset);
+            offset += length;
+        }
+        Self::new(1, offsets, offset, None)
+    }
+
+    /// The TOC from the "TOC of the extracted CD" table in an EAC or XLD rip log.
+    /// The table's sectors don't include the lead-in.
+    pub fn from_rip_log(log: &str) -> anyhow::Result<Self> {
+        // e.g. `        1  |  0:00.00 |  4:02.50 |         0    |    18199   `
+        let row = Regex::new(r"^\s*(\d+)\s*\|[^|]*\|[^|]*\|\s*(\d+)\s*\|\s*(\d+)\s*$")
+            .expect("valid regex");
+        let mut tracks = vec![];
+        let mut lines = log
+            .lines()
+            .skip_while(|l| !l.trim().starts_with("TOC of the extracted CD"));
+        // The table starts after its header, and ends at the first line that isn't a row
+        for line in lines.by_ref() {
+            if line.trim_start().starts_with("---") {
+                break;
+            }
+        }
+        for line in lines {
+            let Some(captures) = row.captures(line) else {
+                break;
+            };
+            let number = |i: usize| captures[i].parse::<u32>();
+            tracks.push((number(1)?, number(2)?, number(3)?));
+        }
+        let (&(first_track, _, _), &(_, _, last_end)) = tracks
+            .first()
+            .zip(tracks.last())
+            .context("the log has no TOC")?;
+        let first_track = u8::try_from(first_track).context("the log's TOC is malformed")?;
+
+        // A data track after a gap following the audio session is an Enhanced CD's data track
+        let mut data_track = None;
+        if let [.., (_, _, audio_end), (_, data_start, _)] = tracks[..]
+            && data_start == audio_end + 1 + DATA_TRACK_GAP_SECTORS
+        {
+            data_track = Some(data_start + LEAD_IN_SECTORS);
+            tracks.pop();
+        }
+        Self::new(
+            first_track,
+            tracks
+                .iter()
+                .map(|&(_, start, _)| start + LEAD_IN_SECTORS)
+                .collect(),
+            last_end + 1 + LEAD_IN_SECTORS,
+            data_track,
+        )
+    }
+
+    /// The TOC from a cue sheet. `file_length` gives the length in sectors of each file it refers to, by name.
+    /// Each track starts at its `INDEX 01`, and the disc ends at the end of the last file.
+    pub fn from_cue_sheet(
+        cue: &str,
+        mut file_length: impl FnMut(&str) -> anyhow::Result<u32>,
+    ) -> anyhow::Result<Self> {
+        let mut first_track = None;
+        let mut offsets = vec![];
+        let mut data_track = None;
+        // Start of the current file
+        let mut file_start = LEAD_IN_SECTORS;
+        let mut file_end = None;
+        let mut track_is_audio = true;
+
+        for line in cue.lines() {
+            let line = line.trim();
+            let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => {
+                    // `FILE "name with spaces.wav" WAVE`, where the name is only quoted if it needs to be
+                    let name = match args.strip_prefix('"') {
+                        Some(quoted) => quoted.rsplit_once('"').map_or(quoted, |(name, _)| name),
+                        None => args.split_whitespace().next().unwrap_or_default(),
+                    };
+                    let length = file_length(name)
+                        .with_context(|| format!("couldn't find the length of {name:?}"))?;
+                    file_start = file_end.unwrap_or(file_start);
+                    file_end = Some(file_start + length);
+                }
+                "TRACK" => {
+                    let number = args
+                        .split_whitespace()
+                        .next()
+                        .and_then(|n| n.parse::<u8>().ok())
+                        .with_context(|| format!("malformed cue sheet line {line:?}"))?;
+                    first_track.get_or_insert(number);
+                    track_is_audio = args.to_ascii_uppercase().ends_with("AUDIO");
+                }
+                "INDEX" => {
+                    let mut args = args.split_whitespace();
+                    if args.next().and_then(|i| i.parse::<u32>().ok()) != Some(1) {
+                        continue;
+                    }
+                    let index = args
+                        .next()
+                        .and_then(parse_msf)
+                        .with_context(|| format!("malformed cue sheet line {line:?}"))?;
+                    if file_end.is_none() {
+                        anyhow::bail!("the cue sheet has a track before its first FILE");
+                    }
+                    if track_is_audio {
+                        offsets.push(file_start + index);
+                    } else {
+                        data_track = Some(file_start + index);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let leadout = file_end.context("the cue sheet has no FILEs")?;
+        Self::new(first_track.unwrap_or(1), offsets, leadout, data_track)
+    }
+
+    /// Number of the last audio track
+    fn last_track(&self) -> u8 {
+        self.first_track + self.offsets.len() as u8 - 1
+    }
+
+    /// Number of each audio track
+    pub fn track_numbers(&self) -> std::ops::RangeInclusive<u8> {
+        self.first_track..=self.last_track()
+    }
+
+    /// <https://musicbrainz.org/doc/Disc_ID_Calculation>
+    pub fn mb_discid(&self) -> MbDiscId {
+        // The MusicBrainz disc ID is of the audio session only, which ends before the gap before the data track
+        let leadout = self
+            .data_track
+            .map_or(self.leadout, |d| d - DATA_TRACK_GAP_SECTORS);
+        let mut toc = format!(
+            "{:02X}{:02X}{:08X}",
+            self.first_track,
+            self.last_track(),
+            leadout
+        );
+        for i in 0..99usize {
+            let offset = match (i + 1).checked_sub(self.first_track as usize) {
+                Some(i) => self.offsets.get(i).copied().unwrap_or(0),
+                None => 0,
+            };
+            toc.push_str(&format!("{offset:08X}"));
+        }
+        // Base64 with `.`, `_` and `-` instead of `+`, `/` and `=`, so it can be used in URLs.
+        // The padding character can't be changed in the engine, so it's replaced afterwards.
+        let encoded = MB_BASE64
+            .encode(sha1::Sha1::digest(toc.as_bytes()))
+            .replace('=', "-");
+        MbDiscId::new(encoded)
+    }
+
+    /// <https://en.wikipedia.org/wiki/CDDB#Example_calculation_of_a_CDDB1_(FreeDB)_disc_ID>
+    pub fn cddb_discid(&self) -> CddbDiscId {
+        let secs = |sectors: u32| sectors / SECTORS_PER_SEC as u32;
+        let digit_sum = |mut n: u32| {
+            let mut sum = 0;
+            while n > 0 {
+                sum += n % 10;
+                n /= 10;
+            }
+            sum
+        };
+        let offsets = self.offsets.iter().chain(&self.data_track);
+        let checksum = offsets.clone().map(|&o| digit_sum(secs(o))).sum::<u32>();
+        let length = secs(self.leadout) - secs(self.offsets[0]);
+        let num_tracks = offsets.count() as u32;
+        CddbDiscId::new(format!(
+            "{:08x}",
+            ((checksum % 0xff) << 24) | (length << 8) | num_tracks
+        ))
+    }
+
+    /// The TOC in the form MusicBrainz accepts for fuzzy lookups:
+    /// first track, last track, lead-out and the offset of each track, separated by spaces
+    pub fn mb_toc_string(&self) -> String {
+        let mut parts = vec![
+            self.first_track.to_string(),
+            self.last_track().to_string(),
+            self.data_track
+                .map_or(self.leadout, |d| d - DATA_TRACK_GAP_SECTORS)
+                .to_string(),
+        ];
+        parts.extend(self.offsets.iter().map(u32::to_string));
+        parts.join(" ")
+    }
+}
+
+/// Where the TOC of an album was found
+pub enum TocSource {
+    RipLog(PathBuf),
+    CueSheet(PathBuf),
+    SongLengths,
+}
+
+impl Display for TocSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TocSource::RipLog(path) => write!(f, "rip log {}", path.display()),
+            TocSource::CueSheet(path) => write!(f, "cue sheet {}", path.display()),
+            TocSource::SongLengths => write!(f, "the lengths of the songs"),
+        }
+    }
+}
+
+/// Find the TOC of the disc an album was ripped from, see the [module docs](self).
+/// Returns None if the group has no rip log or cue sheet, and its songs aren't all lossless.
+/// Rip logs are read when the album is scanned, and ones that couldn't be read are reported as the album's warnings instead.
+pub fn album_toc(
+    album_path: &Path,
+    album: &AlbumInputGroup,
+) -> anyhow::Result<Option<(Toc, TocSource)>> {
+    let logs = album.rip_logs();
+    if logs.len() > 1 {
+        anyhow::bail!(
+            "there are {} rip logs, so it's unclear which disc the songs came from",
+            logs.len()
+        );
+    }
+    if let Some((log_path, log)) = logs.first() {
+        return Ok(Some((
+            log.toc.clone(),
+            TocSource::RipLog(album_path.join(log_path)),
+        )));
+    }
+
+    // Every song's length in sectors, by its file name without the extension.
+    // Cue sheets often refer to the WAV files that were ripped, even after they were converted to FLAC.
+    let lengths = album
+        .songs()
+        .iter()
+        .filter_map(|s| {
+            let metadata = s.native_metadata();
+            let lossless = matches!(
+                metadata.fmt,
+                NativeMetadataFormat::FLAC | NativeMetadataFormat::WAV | NativeMetadataFormat::AIFF
+            );
+            let length = metadata
+                .duration
+                .filter(|_| lossless)
+                .map(|d| (d.as_secs_f64() * SECTORS_PER_SEC).round() as u32);
+            Some((s.file().file_stem()?.to_owned(), length))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let cues = files_with_extension(album_path, "cue")?;
+    if cues.len() > 1 {
+        anyhow::bail!(
+            "there are {} cue sheets, so it's unclear which disc the songs came from",
+            cues.len()
+        );
+    }
+    if let Some(cue) = cues.into_iter().next() {
+        let file_length = |name: &str| {
+            let stem = Path::new(name).file_stem().unwrap_or_default();
+            match lengths.get(stem) {
+                Some(Some(length)) => Ok(*length),
+                Some(None) => anyhow::bail!("it isn't a lossless song with a known length"),
+                None => anyhow::bail!("it isn't one of the group's songs"),
+            }
+        };
+        let toc = std::fs::read_to_string(&cue)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Toc::from_cue_sheet(&text, file_length))
+            .with_context(|| format!("couldn't read the TOC from {}", cue.display()))?;
+        return Ok(Some((toc, TocSource::CueSheet(cue))));
+    }
+
+    // The songs only make up a disc if they're all lossless, and are tracks 1, 2, 3... of the same disc
+    let songs = album.songs();
+    let mut song_lengths = vec![];
+    for s in songs {
+        let stem = s.file().file_stem().unwrap_or_default();
+        let Some(&Some(length)) = lengths.get(stem) else {
+            return Ok(None);
+        };
+        song_lengths.push((s.disc_idx(), s.track_idx(), length));
+    }
+    song_lengths.sort();
+    if let Some(&(disc_idx, _, _)) = song_lengths.first()
+        && song_lengths.iter().any(|&(d, _, _)| d != disc_idx)
+    {
+        anyhow::bail!("the songs are from more than one disc, so don't make up one TOC");
+    }
+    if let Some(track_idx) = song_lengths
+        .iter()
+        .enumerate()
+        .find_map(|(i, &(_, t, _))| (t != i as u64 + 1).then_some(i as u64 + 1))
+    {
+        anyhow::bail!("track {track_idx} is missing, so the songs don't make up the whole disc");
+    }
+    let lengths = song_lengths.iter().map(|&(_, _, l)| l).collect::<Vec<_>>();
+    Ok(Some((
+        Toc::from_track_lengths(&lengths)?,
+        TocSource::SongLengths,
+    )))
+}
+
+/// Files directly inside `dir` with the given extension, ignoring case, in alphanumeric order
+fn files_with_extension(dir: &Path, extension: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("couldn't read {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.is_file()
+            && path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case(extension))
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// A cue sheet position `mm:ss:ff` in sectors, where `ff` is sectors (of which there are 75 per second)
+fn parse_msf(msf: &str) -> Option<u32> {
+    let mut parts = msf.split(':').map(|p| p.parse::<u32>().ok());
+    let (minutes, secs, sectors) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || secs >= 60 || sectors >= 75 {
+        return None;
+    }
+    Some((minutes * 60 + secs) * 75 + sectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example disc from <https://musicbrainz.org/doc/Disc_ID_Calculation>
+    fn example_toc() -> Toc {
+        Toc::new(1, vec![150, 15363, 32314, 46592, 63414, 80489], 95462, None).unwrap()
+    }
+
+    #[test]
+    fn mb_discid_matches_the_musicbrainz_example() {
+        assert_eq!(
+            example_toc().mb_discid().as_str(),
+            "49HHV7Eb8UKF3aQiNmu1GR8vKTY-"
+        );
+    }
+
+    #[test]
+    fn cddb_discid_of_the_musicbrainz_example() {
+        // checksum: digit sums of 2, 204, 430, 621, 845, 1073 seconds = 2 + 6 + 7 + 9 + 17 + 11 = 52 = 0x34
+        // length: 1272 - 2 = 1270 seconds = 0x04f6, with 6 tracks
+        assert_eq!(example_toc().cddb_discid().as_str(), "3404f606");
+    }
+
+    /// An XLD log of an Enhanced CD: 3 audio tracks, then the data track after the gap
+    const ENHANCED_CD_LOG: &str = "X Lossless Decoder version 20230627 (157.2)
+
+TOC of the extracted CD
+     Track |   Start  |  Length  | Start sector | End sector
+    ---------------------------------------------------------
+        1  | 00:00:00 | 04:24:50 |         0    |    19849
+        2  | 04:24:50 | 04:26:50 |     19850    |    39849
+        3  | 08:51:25 | 04:26:50 |     39850    |    59849
+        4  | 15:50:00 | 06:40:00 |     71250    |   101249
+
+AccurateRip Summary
+";
+
+    #[test]
+    fn enhanced_cd_data_track_is_only_in_the_cddb_discid() {
+        let toc = Toc::from_rip_log(ENHANCED_CD_LOG).unwrap();
+        assert_eq!(toc.track_numbers(), 1..=3);
+        assert_eq!(
+            toc,
+            Toc::new(1, vec![150, 20000, 40000], 101400, Some(71400)).unwrap()
+        );
+        // MusicBrainz: 3 tracks, with the audio session ending before the gap
+        assert_eq!(toc.mb_discid().as_str(), "fBjpGnkUEx.Q9RI738jCR7WxqxI-");
+        assert_eq!(toc.mb_toc_string(), "1 3 60000 150 20000 40000");
+        // CDDB: 4 tracks, with the length running to the end of the data track
+        assert_eq!(toc.cddb_discid().as_str(), "2b054604");
+    }
+
+    #[test]
+    fn data_track_without_the_gap_is_an_audio_track() {
+        let log = ENHANCED_CD_LOG.replace("71250    |   101249", "59850    |    89849");
+        let toc = Toc::from_rip_log(&log).unwrap();
+        assert_eq!(toc.track_numbers(), 1..=4);
+    }
+
+    fn file_lengths(name: &str) -> anyhow::Result<u32> {
+        match name {
+            "01 Opening.wav" => Ok(18000),
+            "02 Closing.wav" => Ok(20000),
+            _ => anyhow::bail!("no such file"),
+        }
+    }
+
+    #[test]
+    fn cue_sheet_with_a_file_per_track() {
+        // EAC's default, with the pregap of track 2 at the end of the first file
+        let cue = r#"REM GENRE Rock
+PERFORMER "Band"
+TITLE "Album"
+FILE "01 Opening.wav" WAVE
+  TRACK 01 AUDIO
+    TITLE "Opening"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Closing"
+    INDEX 00 03:58:00
+FILE "02 Closing.wav" WAVE
+    INDEX 01 00:00:00
+"#;
+        let toc = Toc::from_cue_sheet(cue, file_lengths).unwrap();
+        assert_eq!(toc, Toc::new(1, vec![150, 18150], 38150, None).unwrap());
+    }
+
+    #[test]
+    fn cue_sheet_with_a_pregap_at_the_start_of_a_file() {
+        // The pregap of track 2 is at the start of its own file, so the track starts 2 seconds in
+        let cue = r#"FILE "01 Opening.wav" WAVE
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+FILE "02 Closing.wav" WAVE
+  TRACK 02 AUDIO
+    INDEX 00 00:00:00
+    INDEX 01 00:02:00
+"#;
+        let toc = Toc::from_cue_sheet(cue, file_lengths).unwrap();
+        assert_eq!(toc, Toc::new(1, vec![150, 18300], 38150, None).unwrap());
+    }
+
+    #[test]
+    fn cue_sheet_with_unknown_files_fails() {
+        let cue = "FILE missing.wav WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n";
+        assert!(Toc::from_cue_sheet(cue, file_lengths).is_err());
+        let no_files = "TRACK 01 AUDIO\n  INDEX 01 00:00:00\n";
+        assert!(Toc::from_cue_sheet(no_files, file_lengths).is_err());
+    }
+
+    #[test]
+    fn msf_is_parsed_in_bounds() {
+        assert_eq!(parse_msf("00:00:00"), Some(0));
+        assert_eq!(parse_msf("00:02:00"), Some(150));
+        assert_eq!(parse_msf("03:58:74"), Some((3 * 60 + 58) * 75 + 74));
+        // Minutes aren't limited, since cue sheets can describe files longer than an hour
+        assert_eq!(parse_msf("99:59:74"), Some((99 * 60 + 59) * 75 + 74));
+
+        assert_eq!(parse_msf("00:60:00"), None);
+        assert_eq!(parse_msf("00:00:75"), None);
+        assert_eq!(parse_msf("00:00"), None);
+        assert_eq!(parse_msf("00:00:00:00"), None);
+        assert_eq!(parse_msf("00:-1:00"), None);
+        assert_eq!(parse_msf("aa:00:00"), None);
+    }
+
+    #[test]
+    fn toc_rejects_impossible_layouts() {
+        assert!(Toc::new(1, vec![], 1000, None).is_err());
+        assert!(Toc::new(0, vec![150], 1000, None).is_err());
+        assert!(Toc::new(1, vec![100], 1000, None).is_err());
+        assert!(Toc::new(1, vec![150, 150], 1000, None).is_err());
+        assert!(Toc::new(1, vec![150, 500], 500, None).is_err());
+        assert!(Toc::new(99, vec![150, 500], 1000, None).is_err());
+        // The data track must be after the gap
+        assert!(Toc::new(1, vec![150, 500], 20000, Some(1000)).is_err());
+    }
+}
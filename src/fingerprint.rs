@@ -0,0 +1,486 @@
+//! Computing [Chromaprint] audio fingerprints of songs, which can be matched against MusicBrainz recordings.
+//!
+//! Every input file is decoded to PCM with FFmpeg, so anything FFmpeg can read can be fingerprinted.
+//! Fingerprints are stored in each group's cache file (see [crate::store]) by the hash of the file they were computed from,
+//! so a file is only decoded again when its contents change.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::Context;
+
+use crate::data_model::Chromaprint;
+use crate::scanner::Group;
+use crate::store::{FileHashes, FingerprintCacheEntry, GroupCacheFile};
+
+/// Audio is decoded at the rate chromaprint resamples everything to internally
+const SAMPLE_RATE: u64 = 11025;
+/// Only the start of each song is fingerprinted, the same amount as `fpcalc`
+const FINGERPRINT_SECS: u64 = 120;
+
+pub struct FingerprintFailure {
+    /// Either the input file or the group's cache file, whichever is most relevant to the error
+    pub path: PathBuf,
+    pub error: anyhow::Error,
+}
+
+#[derive(Default)]
+pub struct FingerprintSummary {
+    /// Input files that were decoded and fingerprinted
+    pub fingerprinted: Vec<PathBuf>,
+    /// Number of input files whose fingerprint was already stored
+    pub skipped: usize,
+    pub failures: Vec<FingerprintFailure>,
+}
+
+pub struct Fingerprinter {
+    ffmpeg: PathBuf,
+}
+
+impl Fingerprinter {
+    /// `ffmpeg` is the binary to invoke.
+    /// It's called as `ffmpeg <options> -i <input> <options> -` and must write raw 16-bit mono PCM to stdout.
+    pub fn new(ffmpeg: PathBuf) -> Self {
+        Self { ffmpeg }
+    }
+
+    /// Set the fingerprint of every song in `groups`, from each group's cache file or by decoding the song.
+    /// A song failing to fingerprint doesn't stop the others, it just has no fingerprint.
+    /// Each cache file is saved afterwards, forgetting the fingerprints of files that are no longer in the group.
+    pub fn fingerprint<'a>(
+        &self,
+        groups: impl IntoIterator<Item = &'a mut Group>,
+        hashes: &FileHashes,
+    ) -> FingerprintSummary {
+        self.fingerprint_where(groups, hashes, |_, _| true)
+    }
+
+    /// Like [Self::fingerprint], but only for the songs `wanted` picks by their group and index in the group's songs.
+    /// The other songs are left without a fingerprint, and their stored fingerprints are kept as they are.
+    pub fn fingerprint_where<'a>(
+        &self,
+        groups: impl IntoIterator<Item = &'a mut Group>,
+        hashes: &FileHashes,
+        wanted: impl Fn(&Group, usize) -> bool,
+    ) -> FingerprintSummary {
+        let mut summary = FingerprintSummary::default();
+        for group in groups {
+            let wanted = (0..group.num_songs())
+                .map(|idx| wanted(group, idx))
+                .collect::<Vec<_>>();
+            if !wanted.contains(&true) {
+                continue;
+            }
+            if let Err(error) = self.fingerprint_group(group, &wanted, hashes, &mut summary) {
+                summary.failures.push(FingerprintFailure {
+                    path: GroupCacheFile::path_for_group(group.path()),
+                    error,
+                });
+            }
+        }
+        summary
+    }
+
+    fn fingerprint_group(
+        &self,
+        group: &mut Group,
+        wanted: &[bool],
+        hashes: &FileHashes,
+        summary: &mut FingerprintSummary,
+    ) -> anyhow::Result<()> {
+        let group_path = group.path().to_owned();
+        let mut cache = GroupCacheFile::load(&group_path)?;
+        let old_entries = std::mem::take(&mut cache.fingerprints);
+        let mut stored = old_entries
+            .iter()
+            .map(|entry| (entry.input_hash.clone(), entry.chromaprint.clone()))
+            .collect::<HashMap<_, _>>();
+        let num_fingerprinted = summary.fingerprinted.len();
+
+        let mut chromaprints = vec![];
+        for (file, wanted) in song_files(group).into_iter().zip(wanted) {
+            if !wanted {
+                chromaprints.push(None);
+                continue;
+            }
+            let path = group_path.join(&file);
+            let result = hashes.of_file(&path).and_then(|input_hash| {
+                let chromaprint = match stored.get(&input_hash) {
+                    Some(chromaprint) => {
+                        summary.skipped += 1;
+                        chromaprint.clone()
+                    }
+                    None => {
+                        let chromaprint = self.fingerprint_file(&path)?;
+                        summary.fingerprinted.push(path.clone());
+                        stored.insert(input_hash.clone(), chromaprint.clone());
+                        chromaprint
+                    }
+                };
+                // Identical files share an entry
+                if !cache
+                    .fingerprints
+                    .iter()
+                    .any(|e| e.input_hash == input_hash)
+                {
+                    cache.fingerprints.push(FingerprintCacheEntry {
+                        input_hash,
+                        chromaprint: chromaprint.clone(),
+                    });
+                }
+                Ok(chromaprint)
+            });
+            match result {
+                Ok(chromaprint) => chromaprints.push(Some(chromaprint)),
+                Err(error) => {
+                    summary.failures.push(FingerprintFailure { path, error });
+                    chromaprints.push(None);
+                }
+            }
+        }
+        set_chromaprints(group, chromaprints);
+
+        // We don't know which files the songs we skipped have without hashing them, so keep everything
+        if wanted.contains(&false) {
+            for entry in old_entries.iter() {
+                if !cache
+                    .fingerprints
+                    .iter()
+                    .any(|e| e.input_hash == entry.input_hash)
+                {
+                    cache.fingerprints.push(FingerprintCacheEntry {
+                        input_hash: entry.input_hash.clone(),
+                        chromaprint: entry.chromaprint.clone(),
+                    });
+                }
+            }
+        }
+
+        // Only touch the cache file if something was added or forgotten
+        if summary.fingerprinted.len() != num_fingerprinted
+            || cache.fingerprints.len() != old_entries.len()
+        {
+            cache.save(&group_path)?;
+        }
+        Ok(())
+    }
+
+    /// Decode a file with FFmpeg and fingerprint its audio
+    pub fn fingerprint_file(&self, path: &Path) -> anyhow::Result<Chromaprint> {
+        let mut child = Command::new(&self.ffmpeg)
+            .args(["-hide_banner", "-loglevel", "error", "-nostdin"])
+            .arg("-i")
+            .arg(path)
+            // Only the first audio stream, downmixed and resampled to what chromaprint wants
+            .args(["-map", "0:a:0", "-ac", "1", "-ar", &SAMPLE_RATE.to_string()])
+            .args(["-f", "s16le", "-"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("couldn't run {}", self.ffmpeg.display()))?;
+
+        // Read stderr on another thread, so ffmpeg can't get stuck writing to it while we read stdout
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let stderr = std::thread::spawn(move || {
+            let mut s = String::new();
+            let _ = stderr.read_to_string(&mut s);
+            s
+        });
+
+        let mut context = chromaprint::Chromaprint::new();
+        if !context.start(SAMPLE_RATE as i32, 1) {
+            anyhow::bail!("couldn't start chromaprint");
+        }
+        // If feeding fails, stdout is dropped here so ffmpeg stops early
+        let num_samples = feed(child.stdout.take().expect("stdout is piped"), |samples| {
+            context.feed(samples)
+        });
+        let status = child
+            .wait()
+            .with_context(|| format!("couldn't run {}", self.ffmpeg.display()))?;
+        let stderr = stderr.join().unwrap_or_default();
+        // A failure to read makes ffmpeg fail too, so report it first
+        let num_samples = num_samples?;
+        if !status.success() {
+            anyhow::bail!("ffmpeg failed ({}): {}", status, stderr.trim());
+        }
+        if num_samples == 0 {
+            anyhow::bail!("ffmpeg didn't decode any audio");
+        }
+
+        if !context.finish() {
+            anyhow::bail!("chromaprint couldn't finish the fingerprint");
+        }
+        let fingerprint = context
+            .fingerprint()
+            .context("chromaprint didn't produce a fingerprint")?;
+        Ok(Chromaprint {
+            algorithm: context.algorithm(),
+            fingerprint,
+            duration_secs: num_samples / SAMPLE_RATE,
+        })
+    }
+}
+
+/// Feed the first [FINGERPRINT_SECS] of little-endian 16-bit samples from `pcm` to chromaprint (via `consume`),
+/// and count the rest. Returns the total number of samples.
+fn feed(mut pcm: impl Read, mut consume: impl FnMut(&[i16]) -> bool) -> anyhow::Result<u64> {
+    let max_samples = SAMPLE_RATE * FINGERPRINT_SECS;
+    let mut num_samples = 0;
+    let mut buf = vec![0u8; 1 << 16];
+    let mut samples = Vec::with_capacity(buf.len() / 2);
+    // Bytes at the start of `buf` left over from the last read, if it ended halfway through a sample
+    let mut pending = 0;
+    loop {
+        let n = pcm
+            .read(&mut buf[pending..])
+            .context("couldn't read the decoded audio")?;
+        if n == 0 {
+            return Ok(num_samples);
+        }
+        let len = pending + n;
+        let whole = len / 2 * 2;
+        let num_new = (whole / 2) as u64;
+        let num_wanted = max_samples.saturating_sub(num_samples).min(num_new) as usize;
+        if num_wanted > 0 {
+            samples.clear();
+            samples.extend(
+                buf[..num_wanted * 2]
+                    .chunks_exact(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]])),
+            );
+            if !consume(&samples) {
+                anyhow::bail!("chromaprint couldn't process the decoded audio");
+            }
+        }
+        num_samples += num_new;
+        buf.copy_within(whole..len, 0);
+        pending = len - whole;
+    }
+}
+
+/// Paths of every song in a group, relative to the group, in the order of the group's songs
+fn song_files(group: &Group) -> Vec<PathBuf> {
+    match group {
+        Group::PartialAlbum(album, _) => {
+            album.songs().iter().map(|s| s.file().to_owned()).collect()
+        }
+        Group::Compilation(compilation, _) => compilation
+            .songs()
+            .iter()
+            .map(|s| s.file().to_owned())
+            .collect(),
+    }
+}
+
+fn set_chromaprints(group: &mut Group, chromaprints: Vec<Option<Chromaprint>>) {
+    match group {
+        Group::PartialAlbum(album, _) => {
+            for (song, chromaprint) in album.songs_mut().iter_mut().zip(chromaprints) {
+                song.set_chromaprint(chromaprint);
+            }
+        }
+        Group::Compilation(compilation, _) => {
+            for (song, chromaprint) in compilation.songs_mut().iter_mut().zip(chromaprints) {
+                song.set_chromaprint(chromaprint);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_model::{CompilationInputGroup, FileHash};
+
+    /// Reads `data` in chunks of the given sizes, cycling through them
+    struct ChunkedReader {
+        data: Vec<u8>,
+        pos: usize,
+        chunk_sizes: Vec<usize>,
+        num_reads: usize,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let size = self.chunk_sizes[self.num_reads % self.chunk_sizes.len()];
+            self.num_reads += 1;
+            let n = size.min(buf.len()).min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    fn pcm(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    fn feed_all(pcm: impl Read) -> (anyhow::Result<u64>, Vec<i16>) {
+        let mut fed = vec![];
+        let result = feed(pcm, |samples| {
+            fed.extend_from_slice(samples);
+            true
+        });
+        (result, fed)
+    }
+
+    #[test]
+    fn feed_joins_samples_split_across_reads() {
+        let samples = (0..1000)
+            .map(|i| (i * 37 - 18000) as i16)
+            .collect::<Vec<_>>();
+        let reader = ChunkedReader {
+            data: pcm(&samples),
+            pos: 0,
+            chunk_sizes: vec![1, 3, 2, 5, 7],
+            num_reads: 0,
+        };
+        let (num_samples, fed) = feed_all(reader);
+        assert_eq!(num_samples.unwrap(), 1000);
+        assert_eq!(fed, samples);
+    }
+
+    #[test]
+    fn feed_stops_feeding_after_the_limit_but_keeps_counting() {
+        let max_samples = (SAMPLE_RATE * FINGERPRINT_SECS) as usize;
+        let samples = (0..max_samples + 12345)
+            .map(|i| i as i16)
+            .collect::<Vec<_>>();
+        let (num_samples, fed) = feed_all(std::io::Cursor::new(pcm(&samples)));
+        assert_eq!(num_samples.unwrap(), samples.len() as u64);
+        assert_eq!(fed, samples[..max_samples]);
+    }
+
+    #[test]
+    fn feed_ignores_a_trailing_half_sample() {
+        let mut data = pcm(&[1, 2, 3]);
+        data.push(0x7f);
+        let (num_samples, fed) = feed_all(std::io::Cursor::new(data));
+        assert_eq!(num_samples.unwrap(), 3);
+        assert_eq!(fed, [1, 2, 3]);
+    }
+
+    #[test]
+    fn feed_reports_chromaprint_failing() {
+        let result = feed(std::io::Cursor::new(pcm(&[1, 2, 3])), |_| false);
+        assert!(result.is_err());
+    }
+
+    fn chromaprint(fingerprint: &str) -> Chromaprint {
+        Chromaprint {
+            algorithm: chromaprint::CHROMAPRINT_ALGORITHM_DEFAULT,
+            fingerprint: fingerprint.to_owned(),
+            duration_secs: 180,
+        }
+    }
+
+    /// A compilation of songs with the given file contents
+    fn compilation(dir: &Path, files: &[(&str, &[u8])]) -> Group {
+        let songs = files
+            .iter()
+            .map(|&(name, contents)| (name, contents, None))
+            .collect::<Vec<_>>();
+        let group = CompilationInputGroup::for_test(dir, &songs);
+        Group::Compilation(Box::new(group), dir.to_owned())
+    }
+
+    fn store(dir: &Path, entries: &[(&[u8], &str)]) {
+        let file = GroupCacheFile {
+            fingerprints: entries
+                .iter()
+                .map(|(contents, fingerprint)| FingerprintCacheEntry {
+                    input_hash: hash_of(contents),
+                    chromaprint: chromaprint(fingerprint),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        file.save(dir).unwrap();
+    }
+
+    fn hash_of(contents: &[u8]) -> FileHash {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, contents).unwrap();
+        FileHash::of_file(&path).unwrap()
+    }
+
+    fn stored_fingerprints(dir: &Path) -> Vec<String> {
+        GroupCacheFile::load(dir)
+            .unwrap()
+            .fingerprints
+            .into_iter()
+            .map(|e| e.chromaprint.fingerprint)
+            .collect()
+    }
+
+    fn fingerprints(group: &Group) -> Vec<Option<String>> {
+        let Group::Compilation(compilation, _) = group else {
+            panic!("not a compilation");
+        };
+        compilation
+            .songs()
+            .iter()
+            .map(|s| s.chromaprint().map(|c| c.fingerprint.clone()))
+            .collect()
+    }
+
+    /// Any file that isn't already fingerprinted fails, because there's no ffmpeg to decode it
+    fn fingerprinter(dir: &Path) -> Fingerprinter {
+        Fingerprinter::new(dir.join("no-ffmpeg"))
+    }
+
+    #[test]
+    fn stored_fingerprints_are_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut group = compilation(dir.path(), &[("a.flac", b"a"), ("b.flac", b"b")]);
+        // b.flac has the contents it was fingerprinted with before, a.flac doesn't
+        store(dir.path(), &[(b"old a", "AQAAold"), (b"b", "AQAAb")]);
+
+        let summary = fingerprinter(dir.path()).fingerprint([&mut group], &FileHashes::default());
+        assert_eq!(summary.skipped, 1);
+        assert!(summary.fingerprinted.is_empty());
+        let failed = summary.failures.iter().map(|f| &f.path).collect::<Vec<_>>();
+        assert_eq!(failed, [&dir.path().join("a.flac")]);
+        assert_eq!(fingerprints(&group), [None, Some("AQAAb".to_owned())]);
+        // The fingerprint of the old contents of a.flac is forgotten
+        assert_eq!(stored_fingerprints(dir.path()), ["AQAAb"]);
+    }
+
+    #[test]
+    fn skipped_songs_keep_their_stored_fingerprints() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut group = compilation(dir.path(), &[("a.flac", b"a"), ("b.flac", b"b")]);
+        store(dir.path(), &[(b"a", "AQAAa"), (b"b", "AQAAb")]);
+
+        let summary = fingerprinter(dir.path()).fingerprint_where(
+            [&mut group],
+            &FileHashes::default(),
+            |_, idx| idx == 1,
+        );
+        assert_eq!(summary.skipped, 1);
+        assert!(summary.failures.is_empty());
+        assert_eq!(fingerprints(&group), [None, Some("AQAAb".to_owned())]);
+        assert_eq!(stored_fingerprints(dir.path()), ["AQAAa", "AQAAb"]);
+    }
+
+    #[test]
+    fn files_are_not_hashed_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut group = compilation(dir.path(), &[("a.flac", b"a")]);
+        store(dir.path(), &[(b"a", "AQAAa")]);
+        let hashes = FileHashes::default();
+        hashes.of_file(&dir.path().join("a.flac")).unwrap();
+        // If the file was hashed again, its fingerprint wouldn't be found
+        std::fs::write(dir.path().join("a.flac"), b"changed").unwrap();
+
+        let summary = fingerprinter(dir.path()).fingerprint([&mut group], &hashes);
+        assert_eq!(summary.skipped, 1);
+        assert!(summary.failures.is_empty());
+        assert_eq!(fingerprints(&group), [Some("AQAAa".to_owned())]);
+    }
+}
@@ -2,21 +2,37 @@ use std::path::Path;
 
 use async_trait::async_trait;
 
-use crate::data_model::{AlbumInputGroup, metadata};
+use crate::data_model::{AlbumInputGroup, CompilationInputSong, metadata};
 
-mod data_model;
+pub mod acoustid;
+pub mod cddb;
+pub mod data_model;
+pub mod discid;
+pub mod fingerprint;
+pub mod library;
+pub mod musicbrainz;
+pub mod planner;
+pub mod playlist;
+pub mod prune;
+pub mod rate_limit;
+pub mod render;
+pub mod resolver;
+pub mod riplog;
 pub mod scanner;
+pub mod store;
 
 // see docs for each crate
 
-/// TODO all of the things here need to timestamp their derived metadata sources and cached metadatas,
-/// or include hashes of the input data, or something
+/// Every method is given the path to the group folder, so implementations can store what they find alongside the group.
+/// Implementations which store derived metadata sources or cached metadata are responsible for noticing when they're stale,
+/// e.g. [store::StoredDeriver] checks the group's Origin, the hashes of the input files, and the age of each entry.
 #[async_trait]
 pub trait MetadataDeriver {
     /// Retrieve a stored derived-metadata-source for a given Album if one exists
     fn get_derived_album(
         &self,
-        album_path: &Path,
+        _album_path: &Path,
+        _album: &AlbumInputGroup,
     ) -> Option<metadata::album::DerivedMetadataSource> {
         None
     }
@@ -24,47 +40,69 @@ pub trait MetadataDeriver {
     /// e.g. take the origin MBID and pass it through, or take the origin CDDB ID and best-effort look up what it is
     async fn try_rederive_album(
         &mut self,
-        album: &AlbumInputGroup,
+        _album_path: &Path,
+        _album: &AlbumInputGroup,
     ) -> Option<metadata::album::DerivedMetadataSource> {
         None
     }
     /// After finding a derived-metadata-source for an album, look up if we have cached metadata for it
     fn get_cached_album(
         &self,
-        src: metadata::album::DerivedMetadataSource,
+        _album_path: &Path,
+        _src: &metadata::album::DerivedMetadataSource,
     ) -> Option<metadata::album::Cached> {
         None
     }
     /// Using a derived-metadata-source for an album, re-lookup the metadata
     async fn try_recache_album(
         &mut self,
-        src: metadata::album::DerivedMetadataSource,
+        _album_path: &Path,
+        _src: &metadata::album::DerivedMetadataSource,
     ) -> Option<metadata::album::Cached> {
         None
     }
 
     fn get_derived_compilation_song(
         &self,
-        song_path: &Path,
+        _compilation_path: &Path,
+        _song: &CompilationInputSong,
     ) -> Option<metadata::song::CompilationDerivedMetadataSource> {
         None
     }
+    /// Figure out the derived metadata for a Song in a Compilation
+    /// e.g. take the origin MBID and pass it through
     async fn try_rederive_compilation_song(
         &mut self,
-        song_path: &Path,
+        _compilation_path: &Path,
+        _song: &CompilationInputSong,
     ) -> Option<metadata::song::CompilationDerivedMetadataSource> {
         None
     }
     fn get_cached_compilation_song(
         &self,
-        src: metadata::song::CompilationDerivedMetadataSource,
+        _compilation_path: &Path,
+        _song: &CompilationInputSong,
+        _src: &metadata::song::CompilationDerivedMetadataSource,
     ) -> Option<metadata::song::Cached> {
         None
     }
     async fn try_recache_compilation_song(
-        &self,
-        src: metadata::song::CompilationDerivedMetadataSource,
+        &mut self,
+        _compilation_path: &Path,
+        _song: &CompilationInputSong,
+        _src: &metadata::song::CompilationDerivedMetadataSource,
     ) -> Option<metadata::song::Cached> {
         None
     }
+
+    /// How many lookups have failed and not been taken yet, e.g. because the network was down.
+    /// A `try_*` hook which returns None while this goes up failed, rather than finding nothing,
+    /// so its result shouldn't be stored.
+    fn num_failed_lookups(&self) -> usize {
+        0
+    }
 }
+
+/// A [MetadataDeriver] that never finds any metadata, so songs are resolved using only their native tags and overrides.
+pub struct NullDeriver;
+impl MetadataDeriver for NullDeriver {}
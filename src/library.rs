@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::data_model::user_defined::ConfigFile;
+use crate::planner::{self, OutputPlan, PlanDiagnostic};
+use crate::resolver::ResolvedGroup;
+use crate::scanner::{self, GroupScanFailure, ScanResult};
+
+/// A library defined by a `library.tm2.toml` config file, with every search path scanned for groups.
+pub struct Library {
+    pub config_path: PathBuf,
+    pub config: ConfigFile,
+    /// The search paths from the config, resolved relative to the config file
+    pub roots: Vec<PathBuf>,
+    /// The output path from the config, resolved relative to the config file
+    pub output_root: Option<PathBuf>,
+    /// The output quarantine path from the config, resolved relative to the config file
+    pub quarantine_root: Option<PathBuf>,
+    /// Groups from every root, merged together
+    pub scan: ScanResult,
+    /// Group path -> the index of the root it was found under
+    group_roots: HashMap<PathBuf, usize>,
+}
+
+impl Library {
+    pub fn load(config_path: &Path) -> anyhow::Result<Library> {
+        let config = ConfigFile::from_file(config_path)
+            .with_context(|| format!("couldn't read config file {}", config_path.display()))?;
+
+        let config_dir = match config_path.parent() {
+            Some(p) if p != Path::new("") => p.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        let roots = config
+            .search_paths
+            .iter()
+            .map(|search_path| config_dir.join(search_path))
+            .collect::<Vec<_>>();
+        let output_root = config.output.as_ref().map(|o| config_dir.join(&o.path));
+        let quarantine_root = config
+            .output
+            .as_ref()
+            .and_then(|o| o.quarantine.as_ref())
+            .map(|q| config_dir.join(q));
+
+        let mut scan = ScanResult::default();
+        // Canonical group path -> the root it was first found under
+        let mut canonical_roots: HashMap<PathBuf, &Path> = HashMap::new();
+        let mut group_roots = HashMap::new();
+
+        for (root_idx, root) in roots.iter().enumerate() {
+            let root_scan = scanner::scan_library(root.clone())
+                .with_context(|| format!("couldn't scan search path {}", root.display()))?;
+
+            for group in root_scan.groups {
+                let canonical = std::fs::canonicalize(group.path())
+                    .with_context(|| format!("couldn't resolve {}", group.path().display()))?;
+                match canonical_roots.get(&canonical) {
+                    // e.g. if one search path is nested inside another, or two search paths are symlinked together.
+                    // Keep the first one we found and report the rest, because it's unclear which the user intended.
+                    Some(first_root) => scan.failures.push(GroupScanFailure {
+                        path: group.path().to_owned(),
+                        error: anyhow::anyhow!(
+                            "group was found under search path {}, but was already found under search path {}",
+                            root.display(),
+                            first_root.display()
+                        ),
+                    }),
+                    None => {
+                        canonical_roots.insert(canonical, root);
+                        group_roots.insert(group.path().to_owned(), root_idx);
+                        scan.groups.push(group);
+                    }
+                }
+            }
+            scan.failures.extend(root_scan.failures);
+        }
+
+        Ok(Library {
+            config_path: config_path.to_owned(),
+            config,
+            roots,
+            output_root,
+            quarantine_root,
+            scan,
+            group_roots,
+        })
+    }
+
+    /// The search path a group was found under
+    pub fn root_of(&self, group_path: &Path) -> Option<&Path> {
+        self.group_roots
+            .get(group_path)
+            .map(|&idx| self.roots[idx].as_path())
+    }
+
+    /// Map every song to a unique output path, like [planner::plan_output_paths].
+    /// Songs from different search paths which want the same output path are reported rather than postfixed,
+    /// because the same music was probably found twice, e.g. a copy of an album in two search paths.
+    pub fn plan_output_paths(
+        &self,
+        groups: &[ResolvedGroup],
+        ext: &str,
+    ) -> Result<OutputPlan, Vec<PlanDiagnostic>> {
+        // Case-folded wanted path -> the first song that wanted it, and its root
+        let mut wanted: HashMap<String, (&Path, &Path)> = HashMap::new();
+        let mut diagnostics = vec![];
+        for group in groups {
+            let Some(root) = self.root_of(&group.path) else {
+                continue;
+            };
+            for (song, path) in group.songs.iter().zip(planner::wanted_paths(group, ext)) {
+                let folded = path.to_string_lossy().to_lowercase();
+                match wanted.get(&folded) {
+                    Some(&(first_input, first_root)) if first_root != root => {
+                        diagnostics.push(PlanDiagnostic {
+                            group_path: group.path.clone(),
+                            input_path: song.input_path.clone(),
+                            component: path.to_string_lossy().into_owned(),
+                            problem: format!(
+                                "is also wanted by {} from search path {}, but this song is from search path {}",
+                                first_input.display(),
+                                first_root.display(),
+                                root.display()
+                            ),
+                        })
+                    }
+                    Some(_) => {}
+                    None => {
+                        wanted.insert(folded, (&song.input_path, root));
+                    }
+                }
+            }
+        }
+
+        match planner::plan_output_paths(groups, ext) {
+            Ok(plan) if diagnostics.is_empty() => Ok(plan),
+            Ok(_) => Err(diagnostics),
+            Err(mut invalid) => {
+                invalid.extend(diagnostics);
+                Err(invalid)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::Resolver;
+
+    const ALBUM_GROUP_FILE: &str = "type = \"Album\"\nsongs = []\n[origin]\n";
+
+    /// Write an untagged album with the given song file names under `root`
+    fn write_album(root: &Path, album: &str, songs: &[&str]) {
+        let dir = root.join(album);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(scanner::GROUP_FILE_NAME), ALBUM_GROUP_FILE).unwrap();
+        for song in songs {
+            std::fs::write(dir.join(song), b"").unwrap();
+        }
+    }
+
+    fn load(dir: &Path, search_paths: &[&str]) -> Library {
+        let config_path = dir.join("library.tm2.toml");
+        let search_paths = search_paths
+            .iter()
+            .map(|p| format!("{p:?}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        std::fs::write(&config_path, format!("search_paths = [{search_paths}]\n")).unwrap();
+        Library::load(&config_path).unwrap()
+    }
+
+    fn plan(library: &Library) -> Result<OutputPlan, Vec<PlanDiagnostic>> {
+        let resolver = Resolver::new(&library.config);
+        let resolved = library
+            .scan
+            .groups
+            .iter()
+            .map(|g| resolver.resolve(g))
+            .collect::<Vec<_>>();
+        library.plan_output_paths(&resolved, "mp3")
+    }
+
+    #[test]
+    fn groups_from_every_root_are_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        write_album(&dir.path().join("a"), "First", &["1.flac"]);
+        write_album(&dir.path().join("b"), "Second", &["1.flac"]);
+
+        let library = load(dir.path(), &["a", "b"]);
+        assert!(library.scan.failures.is_empty());
+        let mut roots = library
+            .scan
+            .groups
+            .iter()
+            .map(|g| library.root_of(g.path()).unwrap().file_name().unwrap())
+            .collect::<Vec<_>>();
+        roots.sort();
+        assert_eq!(roots, ["a", "b"]);
+        assert!(plan(&library).is_ok());
+    }
+
+    #[test]
+    fn group_found_under_two_roots_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        write_album(&dir.path().join("a/nested"), "Album", &["1.flac"]);
+
+        let library = load(dir.path(), &["a", "a/nested"]);
+        assert_eq!(library.scan.groups.len(), 1);
+        assert_eq!(library.scan.failures.len(), 1);
+    }
+
+    #[test]
+    fn same_output_from_two_roots_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        write_album(&dir.path().join("a"), "Album", &["Song.flac", "Other.flac"]);
+        write_album(&dir.path().join("b"), "Album", &["song.flac"]);
+
+        let library = load(dir.path(), &["a", "b"]);
+        let Err(diagnostics) = plan(&library) else {
+            panic!("the songs from different roots should collide");
+        };
+        assert_eq!(diagnostics.len(), 1);
+        let message = diagnostics[0].to_string();
+        assert!(message.contains("Song.flac"), "{message}");
+        assert!(message.contains(&dir.path().join("a").display().to_string()));
+        assert!(message.contains(&dir.path().join("b").display().to_string()));
+    }
+
+    #[test]
+    fn same_output_within_one_root_is_postfixed() {
+        let dir = tempfile::tempdir().unwrap();
+        write_album(&dir.path().join("a/1"), "Album", &["Song.flac"]);
+        write_album(&dir.path().join("a/2"), "Album", &["Song.flac"]);
+
+        let library = load(dir.path(), &["a"]);
+        let plan = plan(&library).ok().unwrap();
+        let paths = plan.all_paths().into_iter().collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                Path::new("Unknown Artist/Album/Song A.mp3"),
+                Path::new("Unknown Artist/Album/Song B.mp3")
+            ]
+        );
+    }
+}
@@ -0,0 +1,1494 @@
+//! Deriving and caching metadata from [MusicBrainz](https://musicbrainz.org/doc/MusicBrainz_API).
+//!
+//! Releases, recordings and disc IDs are fetched through [MusicBrainzApi].
+//! [MusicBrainzHttp] talks to musicbrainz.org, or to a mirror set as the `base_url` in [MusicBrainzConfig](crate::data_model::user_defined::MusicBrainzConfig),
+//! keeping to the one request per second MusicBrainz allows. Only musicbrainz_rs's entity types are used, not its client.
+
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use musicbrainz_rs::entity::artist_credit::ArtistCredit;
+use musicbrainz_rs::entity::discid::Discid;
+use musicbrainz_rs::entity::recording::Recording;
+use musicbrainz_rs::entity::relations::RelationContent;
+use musicbrainz_rs::entity::release::Release;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use crate::MetadataDeriver;
+use crate::cddb::{CddbApi, CddbHttp};
+use crate::data_model::metadata::{self, CachedArtist};
+use crate::data_model::user_defined::MusicBrainzConfig;
+use crate::data_model::{AlbumInputGroup, CddbDiscId, CompilationInputSong, MbDiscId, MbId};
+use crate::rate_limit::RateLimiter;
+
+pub(crate) const USER_AGENT: &str = concat!(
+    env!("CARGO_PKG_NAME"),
+    "/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/theturboturnip/turnip_music2 )"
+);
+
+const DEFAULT_BASE_URL: &str = "https://musicbrainz.org/ws/2";
+
+/// MusicBrainz allows each client one request per second
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// At most this many releases found for each CDDB entry have their tracklists looked up
+const MAX_CDDB_CANDIDATES: usize = 5;
+/// How far the length of each track in a CDDB entry can be from the MusicBrainz track's, for them to match
+const CDDB_TRACK_LENGTH_TOLERANCE_SECS: f64 = 3.0;
+
+/// The parts of the MusicBrainz web service used by [MusicBrainzDeriver]
+#[async_trait]
+pub trait MusicBrainzApi: Send + Sync {
+    /// Look up a release, including its release group, labels and genres,
+    /// and the artists, ISRCs and composers of every track
+    async fn release(&self, id: &MbId) -> anyhow::Result<Release>;
+    /// Look up every release in a release group, including their media
+    async fn release_group_releases(&self, id: &MbId) -> anyhow::Result<Vec<Release>>;
+    /// Look up every release containing a disc, including their release groups and media.
+    /// Empty if MusicBrainz doesn't know the disc.
+    async fn discid_releases(&self, id: &MbDiscId) -> anyhow::Result<Vec<Release>>;
+    /// Look up a recording, including its artists, ISRCs and composers
+    async fn recording(&self, id: &MbId) -> anyhow::Result<Recording>;
+    /// Search for releases by their title and artist, including their media
+    async fn search_releases(&self, artist: &str, title: &str) -> anyhow::Result<Vec<Release>>;
+}
+
+/// [MusicBrainzApi] over HTTP, using the base URL from [MusicBrainzConfig]
+pub struct MusicBrainzHttp {
+    client: reqwest::Client,
+    base_url: String,
+    rate_limit: RateLimiter,
+}
+
+impl MusicBrainzHttp {
+    pub fn new(config: &MusicBrainzConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+        Ok(Self {
+            client,
+            base_url: config
+                .base_url
+                .as_deref()
+                .unwrap_or(DEFAULT_BASE_URL)
+                .trim_end_matches('/')
+                .to_owned(),
+            rate_limit: RateLimiter::new(REQUEST_INTERVAL),
+        })
+    }
+
+    /// A JSON request for `path` under the base URL
+    fn request(&self, path: &str, query: &[(&str, &str)]) -> reqwest::RequestBuilder {
+        self.client
+            .get(format!("{}/{path}", self.base_url))
+            .query(&[("fmt", "json")])
+            .query(query)
+    }
+
+    /// Make a request, returning None if MusicBrainz doesn't know what was asked for
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> anyhow::Result<Option<T>> {
+        self.rate_limit.wait().await;
+        let response = self.request(path, query).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+}
+
+/// The releases of a browse or search request
+#[derive(Deserialize)]
+struct ReleaseList {
+    #[serde(default)]
+    releases: Vec<Release>,
+}
+
+#[async_trait]
+impl MusicBrainzApi for MusicBrainzHttp {
+    async fn release(&self, id: &MbId) -> anyhow::Result<Release> {
+        // Composers are credited on the work each recording is a performance of
+        let inc = "release-groups artist-credits recordings labels genres isrcs \
+                   recording-level-rels work-rels work-level-rels artist-rels";
+        self.get(&format!("release/{}", id.as_str()), &[("inc", inc)])
+            .await?
+            .context("404 Not Found")
+    }
+
+    async fn release_group_releases(&self, id: &MbId) -> anyhow::Result<Vec<Release>> {
+        let query = [
+            ("release-group", id.as_str()),
+            ("inc", "media"),
+            ("limit", "100"),
+        ];
+        let list: ReleaseList = self
+            .get("release", &query)
+            .await?
+            .context("404 Not Found")?;
+        Ok(list.releases)
+    }
+
+    async fn discid_releases(&self, id: &MbDiscId) -> anyhow::Result<Vec<Release>> {
+        let query = [("inc", "release-groups"), ("cdstubs", "no")];
+        let discid: Option<Discid> = self.get(&format!("discid/{}", id.as_str()), &query).await?;
+        Ok(discid.and_then(|d| d.releases).unwrap_or_default())
+    }
+
+    async fn recording(&self, id: &MbId) -> anyhow::Result<Recording> {
+        let inc = "artists isrcs work-rels work-level-rels artist-rels";
+        self.get(&format!("recording/{}", id.as_str()), &[("inc", inc)])
+            .await?
+            .context("404 Not Found")
+    }
+
+    async fn search_releases(&self, artist: &str, title: &str) -> anyhow::Result<Vec<Release>> {
+        let query = search_query(artist, title);
+        let list: ReleaseList = self
+            .get("release", &[("query", &query), ("limit", "25")])
+            .await?
+            .context("404 Not Found")?;
+        Ok(list.releases)
+    }
+}
+
+/// A Lucene query for releases with a title and artist.
+/// In Lucene syntax, quoted phrases only need quotes and backslashes escaping.
+fn search_query(artist: &str, title: &str) -> String {
+    let phrase = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+    format!("release:{} AND artist:{}", phrase(title), phrase(artist))
+}
+
+/// A lookup which worked, but found nothing.
+/// Unlike other lookup errors, trying again won't help until the group changes.
+#[derive(Debug)]
+struct NotFound(String);
+
+impl Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// A [MetadataDeriver] which derives metadata sources from the MusicBrainz IDs in each group's [Origin](crate::data_model::user_defined::Origin),
+/// and caches metadata by looking those sources up on MusicBrainz.
+/// If the Origin only has a CDDB disc ID, the release is found by searching MusicBrainz for the disc's CDDB entries.
+/// If the Origin has no IDs, the MusicBrainz IDs in the songs' native tags are used instead,
+/// and failing those the disc IDs from the group's rip log (see [AlbumInputGroup::rip_log_discids]).
+pub struct MusicBrainzDeriver<Api: MusicBrainzApi = MusicBrainzHttp, Cddb: CddbApi = CddbHttp> {
+    api: Api,
+    cddb: Cddb,
+    /// Lookups that failed. They don't stop the rest of the library from resolving, but should be reported.
+    errors: Vec<anyhow::Error>,
+}
+
+impl<Api: MusicBrainzApi, Cddb: CddbApi> MusicBrainzDeriver<Api, Cddb> {
+    pub fn new(api: Api, cddb: Cddb) -> Self {
+        Self {
+            api,
+            cddb,
+            errors: vec![],
+        }
+    }
+
+    /// Take every lookup error encountered so far
+    pub fn take_errors(&mut self) -> Vec<anyhow::Error> {
+        std::mem::take(&mut self.errors)
+    }
+
+    fn record_error<T>(&mut self, result: anyhow::Result<T>) -> Option<T> {
+        match result {
+            Ok(t) => Some(t),
+            Err(err) => {
+                self.errors.push(err);
+                None
+            }
+        }
+    }
+
+    async fn derive_release_ids(
+        &self,
+        album: &AlbumInputGroup,
+    ) -> anyhow::Result<Option<(MbId, MbId)>> {
+        let origin = album.origin();
+
+        if let Some(release_id) = &origin.mb_release_id {
+            let release_group_id = match &origin.mb_release_group_id {
+                Some(release_group_id) => release_group_id.clone(),
+                None => {
+                    let release = self.api.release(release_id).await.with_context(|| {
+                        format!("couldn't look up release {}", release_id.as_str())
+                    })?;
+                    release_group_of(&release)?
+                }
+            };
+            return Ok(Some((release_group_id, release_id.clone())));
+        }
+
+        if let Some(release_group_id) = &origin.mb_release_group_id {
+            let releases = self
+                .api
+                .release_group_releases(release_group_id)
+                .await
+                .with_context(|| {
+                    format!(
+                        "couldn't look up release group {}",
+                        release_group_id.as_str()
+                    )
+                })?;
+            let release = pick_release(releases, album.num_songs()).with_context(|| {
+                format!(
+                    "release group {} has no release with at least {} tracks",
+                    release_group_id.as_str(),
+                    album.num_songs()
+                )
+            })?;
+            return Ok(Some((release_group_id.clone(), MbId::new(release.id))));
+        }
+
+        if let Some(discid) = &origin.mb_discid {
+            return Ok(Some(self.discid_release_ids(discid, album).await?));
+        }
+
+        if let Some(cddb_discid) = &origin.cddb_discid {
+            let release = self.cddb_release(cddb_discid).await?;
+            return Ok(Some((release_group_of(&release)?, MbId::new(release.id))));
+        }
+
+        if let Some((release_group_id, release_id)) = native_release_ids(album)? {
+            let release_group_id = match release_group_id {
+                Some(release_group_id) => release_group_id,
+                None => {
+                    let release = self.api.release(&release_id).await.with_context(|| {
+                        format!("couldn't look up release {}", release_id.as_str())
+                    })?;
+                    release_group_of(&release)?
+                }
+            };
+            return Ok(Some((release_group_id, release_id)));
+        }
+
+        // Unlike the Origin's, the rip log's disc IDs weren't chosen by the user and are often in neither database,
+        // so not finding them isn't an error. Lookups that fail still are, so they're tried again.
+        if let Some((discid, cddb_discid)) = album.rip_log_discids() {
+            match self.discid_release_ids(&discid, album).await {
+                Ok(ids) => return Ok(Some(ids)),
+                Err(err) if !err.is::<NotFound>() => return Err(err),
+                Err(_) => {}
+            }
+            match self.cddb_release(&cddb_discid).await {
+                Ok(release) => {
+                    return Ok(Some((release_group_of(&release)?, MbId::new(release.id))));
+                }
+                Err(err) if !err.is::<NotFound>() => return Err(err),
+                Err(_) => {}
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The release group and release of the release with a disc that best fits the album.
+    /// A [NotFound] error if MusicBrainz doesn't know the disc, or none of its releases have enough tracks.
+    async fn discid_release_ids(
+        &self,
+        discid: &MbDiscId,
+        album: &AlbumInputGroup,
+    ) -> anyhow::Result<(MbId, MbId)> {
+        let releases = self
+            .api
+            .discid_releases(discid)
+            .await
+            .with_context(|| format!("couldn't look up disc ID {}", discid.as_str()))?;
+        let release = pick_release(releases, album.num_songs()).ok_or_else(|| {
+            NotFound(format!(
+                "disc ID {} has no release with at least {} tracks",
+                discid.as_str(),
+                album.num_songs()
+            ))
+        })?;
+        Ok((release_group_of(&release)?, MbId::new(release.id)))
+    }
+
+    /// Find the release a CDDB disc ID is for, by searching MusicBrainz for the artist and title of each CDDB entry with that ID.
+    /// Of the releases found, the one with a medium whose track lengths are closest to the entry's is picked.
+    /// A [NotFound] error if the disc ID isn't in the CDDB database, or none of its entries matched without a lookup failing.
+    async fn cddb_release(&self, id: &CddbDiscId) -> anyhow::Result<Release> {
+        let entries = self
+            .cddb
+            .read(id)
+            .await
+            .with_context(|| format!("couldn't look up CDDB disc ID {}", id.as_str()))?;
+        if entries.is_empty() {
+            return Err(NotFound(format!(
+                "CDDB disc ID {} isn't in the CDDB database",
+                id.as_str()
+            ))
+            .into());
+        }
+
+        // A failed search or lookup only rules out that candidate, but is reported if nothing else matches
+        let mut best: Option<(f64, Release)> = None;
+        let mut first_error = None;
+        for entry in &entries {
+            let candidates = self
+                .api
+                .search_releases(&entry.artist, &entry.title)
+                .await
+                .with_context(|| {
+                    format!(
+                        "couldn't search MusicBrainz for {:?} by {:?}",
+                        entry.title, entry.artist
+                    )
+                });
+            let candidates = match candidates {
+                Ok(candidates) => candidates,
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                    continue;
+                }
+            };
+            // Only look up the tracklists of releases with a medium of the right size
+            let candidates = candidates
+                .into_iter()
+                .filter(|r| {
+                    r.media
+                        .iter()
+                        .flatten()
+                        .any(|m| m.track_count as usize == entry.track_lengths.len())
+                })
+                .take(MAX_CDDB_CANDIDATES);
+            for candidate in candidates {
+                let release_id = MbId::new(candidate.id);
+                let release =
+                    match self.api.release(&release_id).await {
+                        Ok(release) => release,
+                        Err(err) => {
+                            first_error.get_or_insert(err.context(format!(
+                                "couldn't look up release {}",
+                                release_id.as_str()
+                            )));
+                            continue;
+                        }
+                    };
+                if let Some(distance) = track_length_distance(&release, &entry.track_lengths)
+                    && best.as_ref().is_none_or(|(best, _)| distance < *best)
+                {
+                    best = Some((distance, release));
+                }
+            }
+        }
+        if let Some((_, release)) = best {
+            return Ok(release);
+        }
+        let no_match = format!(
+            "none of the {} CDDB entries for disc ID {} matched a MusicBrainz release by its track count and lengths",
+            entries.len(),
+            id.as_str()
+        );
+        Err(match first_error {
+            Some(err) => err.context(no_match),
+            None => NotFound(no_match).into(),
+        })
+    }
+}
+
+#[async_trait]
+impl<Api: MusicBrainzApi, Cddb: CddbApi> MetadataDeriver for MusicBrainzDeriver<Api, Cddb> {
+    async fn try_rederive_album(
+        &mut self,
+        _album_path: &Path,
+        album: &AlbumInputGroup,
+    ) -> Option<metadata::album::DerivedMetadataSource> {
+        let ids = self.derive_release_ids(album).await;
+        let ids = self.record_error(ids)??;
+        Some(metadata::album::DerivedMetadataSource {
+            mb_release_group_and_release_ids: Some(ids),
+            derived_songs: album
+                .songs()
+                .iter()
+                .map(|s| metadata::album::SongDerivedMetadataSource {
+                    chromaprint: s.chromaprint().cloned(),
+                    media_track_idxs: Some((s.disc_idx() as i64, s.track_idx() as i64)),
+                })
+                .collect(),
+        })
+    }
+
+    async fn try_recache_album(
+        &mut self,
+        _album_path: &Path,
+        src: &metadata::album::DerivedMetadataSource,
+    ) -> Option<metadata::album::Cached> {
+        let (_, release_id) = src.mb_release_group_and_release_ids.as_ref()?;
+        let release = self
+            .api
+            .release(release_id)
+            .await
+            .with_context(|| format!("couldn't look up release {}", release_id.as_str()));
+        let release = self.record_error(release)?;
+        Some(cached_album(release))
+    }
+
+    async fn try_rederive_compilation_song(
+        &mut self,
+        _compilation_path: &Path,
+        song: &CompilationInputSong,
+    ) -> Option<metadata::song::CompilationDerivedMetadataSource> {
+        let recording_id =
+            song.origin_mbid()
+                .or(song.native_metadata().mb_ids.recording_id.as_ref())?;
+        Some(metadata::song::CompilationDerivedMetadataSource {
+            chromaprint: song.chromaprint().cloned(),
+            mb_recording_id: Some(recording_id.clone()),
+        })
+    }
+
+    async fn try_recache_compilation_song(
+        &mut self,
+        _compilation_path: &Path,
+        _song: &CompilationInputSong,
+        src: &metadata::song::CompilationDerivedMetadataSource,
+    ) -> Option<metadata::song::Cached> {
+        let recording_id = src.mb_recording_id.as_ref()?;
+        let recording = self
+            .api
+            .recording(recording_id)
+            .await
+            .with_context(|| format!("couldn't look up recording {}", recording_id.as_str()));
+        let recording = self.record_error(recording)?;
+        Some(metadata::song::Cached {
+            isrc: first_isrc(&recording),
+            composers: composers_of(&recording),
+            song_title: recording.title,
+            song_artists: cached_artists(recording.artist_credit.as_deref().unwrap_or_default()),
+        })
+    }
+
+    fn num_failed_lookups(&self) -> usize {
+        self.errors.len()
+    }
+}
+
+/// The release (and release group, if they agree on that too) that every song in an album is tagged with.
+/// Returns None if any song isn't tagged with a release, and an error if the songs are tagged with different releases.
+fn native_release_ids(album: &AlbumInputGroup) -> anyhow::Result<Option<(Option<MbId>, MbId)>> {
+    let mb_ids = album
+        .songs()
+        .iter()
+        .map(|s| &s.native_metadata().mb_ids)
+        .collect::<Vec<_>>();
+
+    let release_ids = mb_ids
+        .iter()
+        .filter_map(|ids| ids.release_id.as_ref())
+        .collect::<HashSet<_>>();
+    if release_ids.len() > 1 {
+        anyhow::bail!(
+            "songs are tagged with {} different MusicBrainz releases, set the group's Origin to pick one",
+            release_ids.len()
+        );
+    }
+    let Some(release_id) = release_ids.into_iter().next() else {
+        return Ok(None);
+    };
+    if mb_ids.iter().any(|ids| ids.release_id.is_none()) {
+        return Ok(None);
+    }
+
+    let release_group_ids = mb_ids
+        .iter()
+        .map(|ids| ids.release_group_id.as_ref())
+        .collect::<HashSet<_>>();
+    let release_group_id = match release_group_ids.into_iter().collect::<Vec<_>>()[..] {
+        [Some(release_group_id)] => Some(release_group_id.clone()),
+        _ => None,
+    };
+    Ok(Some((release_group_id, release_id.clone())))
+}
+
+fn release_group_of(release: &Release) -> anyhow::Result<MbId> {
+    match &release.release_group {
+        Some(release_group) => Ok(MbId::new(release_group.id.clone())),
+        None => anyhow::bail!("release {} has no release group", release.id),
+    }
+}
+
+/// Pick the release that best fits the songs in a group:
+/// one with exactly that many tracks, then one with enough tracks.
+/// None if every release has too few tracks, as the songs couldn't all be matched to one.
+fn pick_release(releases: Vec<Release>, num_songs: usize) -> Option<Release> {
+    let num_tracks = |r: &Release| -> usize {
+        r.media
+            .iter()
+            .flatten()
+            .map(|m| m.track_count as usize)
+            .sum()
+    };
+    let best = releases
+        .iter()
+        .position(|r| num_tracks(r) == num_songs)
+        .or_else(|| releases.iter().position(|r| num_tracks(r) >= num_songs))?;
+    releases.into_iter().nth(best)
+}
+
+/// How far the track lengths of a release's closest medium are from `track_lengths` (in seconds), in total.
+/// None if no medium has the same number of tracks, all with lengths within [CDDB_TRACK_LENGTH_TOLERANCE_SECS].
+fn track_length_distance(release: &Release, track_lengths: &[f64]) -> Option<f64> {
+    release
+        .media
+        .iter()
+        .flatten()
+        .filter_map(|medium| {
+            let tracks = medium.tracks.as_deref()?;
+            if tracks.len() != track_lengths.len() {
+                return None;
+            }
+            let mut distance = 0.0;
+            for (track, &expected) in tracks.iter().zip(track_lengths) {
+                let difference = (track.length? as f64 / 1000.0 - expected).abs();
+                if difference > CDDB_TRACK_LENGTH_TOLERANCE_SECS {
+                    return None;
+                }
+                distance += difference;
+            }
+            Some(distance)
+        })
+        .min_by(f64::total_cmp)
+}
+
+fn cached_artists(credits: &[ArtistCredit]) -> Vec<CachedArtist> {
+    credits
+        .iter()
+        .map(|credit| CachedArtist {
+            id: MbId::new(credit.artist.id.clone()),
+            name: credit.name.clone(),
+            sort_name: Some(credit.artist.sort_name.clone()).filter(|s| !s.is_empty()),
+        })
+        .collect()
+}
+
+fn first_isrc(recording: &Recording) -> Option<String> {
+    recording.isrcs.as_ref()?.first().cloned()
+}
+
+/// The composers of every work a recording is a performance of
+fn composers_of(recording: &Recording) -> Vec<String> {
+    let mut composers = vec![];
+    for relation in recording.relations.iter().flatten() {
+        let RelationContent::Work(work) = &relation.content else {
+            continue;
+        };
+        for work_relation in work.relations.iter().flatten() {
+            if work_relation.relation_type == "composer"
+                && let RelationContent::Artist(artist) = &work_relation.content
+                && !composers.contains(&artist.name)
+            {
+                composers.push(artist.name.clone());
+            }
+        }
+    }
+    composers
+}
+
+fn cached_album(release: Release) -> metadata::album::Cached {
+    let album_artists = cached_artists(release.artist_credit.as_deref().unwrap_or_default());
+    let release_group = release.release_group.as_ref();
+
+    // Genres are usually voted on for the release group rather than each release
+    let genres = release
+        .genres
+        .as_ref()
+        .filter(|g| !g.is_empty())
+        .or(release_group.and_then(|rg| rg.genres.as_ref()))
+        .into_iter()
+        .flatten()
+        .map(|g| g.name.clone())
+        .collect();
+    let label_info = release.label_info.as_deref().unwrap_or_default();
+    let label = label_info
+        .iter()
+        .find_map(|info| Some(info.label.as_ref()?.name.clone()));
+    let catalog_number = label_info
+        .iter()
+        .filter_map(|info| info.catalog_number.clone())
+        .find(|c| !c.is_empty());
+
+    let mut media = release.media.unwrap_or_default();
+    media.sort_by_key(|m| m.position);
+
+    metadata::album::Cached {
+        title: release.title,
+        media: media
+            .into_iter()
+            .map(|medium| {
+                let mut tracks = medium.tracks.unwrap_or_default();
+                tracks.sort_by_key(|t| t.position);
+                tracks
+                    .into_iter()
+                    .map(|track| {
+                        // Prefer the credit on the track, then the recording, then fall back to the album artists
+                        let recording = track.recording;
+                        let credits = track
+                            .artist_credit
+                            .or_else(|| recording.as_ref().and_then(|r| r.artist_credit.clone()));
+                        metadata::song::Cached {
+                            song_title: track.title,
+                            song_artists: match credits {
+                                Some(credits) => cached_artists(&credits),
+                                None => album_artists.clone(),
+                            },
+                            isrc: recording.as_ref().and_then(first_isrc),
+                            composers: recording.as_ref().map(composers_of).unwrap_or_default(),
+                        }
+                    })
+                    .collect()
+            })
+            .collect(),
+        artists: album_artists,
+        date: release.date.map(|d| d.0).filter(|d| !d.is_empty()),
+        original_date: release_group
+            .and_then(|rg| rg.first_release_date.clone())
+            .map(|d| d.0)
+            .filter(|d| !d.is_empty()),
+        genres,
+        label,
+        catalog_number,
+        barcode: release.barcode.filter(|b| !b.is_empty()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use id3::TagLike;
+
+    use super::*;
+    use crate::cddb::CddbEntry;
+    use crate::data_model::user_defined::{CacheConfig, Origin};
+    use crate::store::{GroupCacheFile, StoredDeriver};
+
+    /// A release in release group `rg-<id>`, with a medium for each list of track lengths in ms
+    fn release(id: &str, media: &[&[u32]]) -> Release {
+        let media = media
+            .iter()
+            .map(|lengths| {
+                let tracks = lengths
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &length)| {
+                        serde_json::json!({
+                            "id": format!("{id}-track{}", i + 1),
+                            "title": format!("Track {}", i + 1),
+                            "number": (i + 1).to_string(),
+                            "position": i + 1,
+                            "length": length,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                serde_json::json!({ "track-count": lengths.len(), "tracks": tracks })
+            })
+            .collect::<Vec<_>>();
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "title": format!("Release {id}"),
+            "release-group": {
+                "id": format!("rg-{id}"),
+                "title": format!("Release {id}"),
+                "primary-type": "Album",
+                "secondary-types": [],
+                "disambiguation": "",
+            },
+            "media": media,
+        }))
+        .unwrap()
+    }
+
+    /// A MusicBrainz that only knows the releases it's given, and remembers every request
+    #[derive(Default)]
+    struct FakeMusicBrainz {
+        releases: Vec<Release>,
+        release_groups: HashMap<&'static str, Vec<&'static str>>,
+        discids: HashMap<&'static str, Vec<&'static str>>,
+        /// (artist, title) -> release IDs
+        searches: HashMap<(&'static str, &'static str), Vec<&'static str>>,
+        /// Release IDs whose lookup fails
+        broken: Vec<&'static str>,
+        /// Titles whose search fails
+        broken_searches: Vec<&'static str>,
+        /// Disc IDs whose lookup fails
+        broken_discids: Vec<&'static str>,
+        requests: Mutex<Vec<String>>,
+    }
+
+    impl FakeMusicBrainz {
+        fn releases(&self, ids: &[&str]) -> Vec<Release> {
+            ids.iter()
+                .map(|id| self.releases.iter().find(|r| r.id == *id).unwrap().clone())
+                .collect()
+        }
+
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl MusicBrainzApi for FakeMusicBrainz {
+        async fn release(&self, id: &MbId) -> anyhow::Result<Release> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push(format!("release {}", id.as_str()));
+            if self.broken.contains(&id.as_str()) {
+                anyhow::bail!("503 Service Unavailable");
+            }
+            self.releases
+                .iter()
+                .find(|r| r.id == id.as_str())
+                .cloned()
+                .context("404 Not Found")
+        }
+
+        async fn release_group_releases(&self, id: &MbId) -> anyhow::Result<Vec<Release>> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push(format!("release group {}", id.as_str()));
+            let ids = self
+                .release_groups
+                .get(id.as_str())
+                .context("404 Not Found")?;
+            Ok(self.releases(ids))
+        }
+
+        async fn discid_releases(&self, id: &MbDiscId) -> anyhow::Result<Vec<Release>> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push(format!("disc ID {}", id.as_str()));
+            if self.broken_discids.contains(&id.as_str()) {
+                anyhow::bail!("503 Service Unavailable");
+            }
+            // Like MusicBrainzHttp, an unknown disc has no releases
+            let ids = self.discids.get(id.as_str()).cloned().unwrap_or_default();
+            Ok(self.releases(&ids))
+        }
+
+        async fn recording(&self, id: &MbId) -> anyhow::Result<Recording> {
+            anyhow::bail!("recording {} not found", id.as_str())
+        }
+
+        async fn search_releases(&self, artist: &str, title: &str) -> anyhow::Result<Vec<Release>> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push(format!("search {artist} / {title}"));
+            if self.broken_searches.contains(&title) {
+                anyhow::bail!("503 Service Unavailable");
+            }
+            let ids = self
+                .searches
+                .iter()
+                .find(|((a, t), _)| *a == artist && *t == title)
+                .map(|(_, ids)| ids.as_slice())
+                .unwrap_or_default();
+            // Search results only have the track counts of each medium
+            Ok(self
+                .releases(ids)
+                .into_iter()
+                .map(|mut r| {
+                    for m in r.media.iter_mut().flatten() {
+                        m.tracks = None;
+                    }
+                    r
+                })
+                .collect())
+        }
+    }
+
+    /// A CDDB server with the given entries for each disc ID
+    #[derive(Default)]
+    struct FakeCddb(HashMap<&'static str, Vec<CddbEntry>>);
+
+    #[async_trait]
+    impl CddbApi for FakeCddb {
+        async fn read(&self, id: &CddbDiscId) -> anyhow::Result<Vec<CddbEntry>> {
+            Ok(self.0.get(id.as_str()).cloned().unwrap_or_default())
+        }
+    }
+
+    /// An album of `num_songs` MP3s, tagged with `native_release_id` if given, and with `rip_log` alongside them
+    fn album(
+        dir: &tempfile::TempDir,
+        origin: Origin,
+        num_songs: usize,
+        native_release_id: Option<&str>,
+        rip_log: Option<&str>,
+    ) -> AlbumInputGroup {
+        let native_ids = vec![(native_release_id, None); num_songs];
+        tagged_album(dir, origin, &native_ids, rip_log)
+    }
+
+    /// An album whose songs are tagged with the given (release ID, release group ID)s
+    fn tagged_album(
+        dir: &tempfile::TempDir,
+        origin: Origin,
+        native_ids: &[(Option<&str>, Option<&str>)],
+        rip_log: Option<&str>,
+    ) -> AlbumInputGroup {
+        let mut songs = vec![];
+        for (i, (release_id, release_group_id)) in (1..).zip(native_ids) {
+            let path = dir.path().join(format!("{i:02}.mp3"));
+            std::fs::write(&path, b"").unwrap();
+            let mut tag = id3::Tag::new();
+            tag.set_track(i);
+            for (description, value) in [
+                ("MusicBrainz Album Id", release_id),
+                ("MusicBrainz Release Group Id", release_group_id),
+            ] {
+                if let Some(value) = value {
+                    tag.add_frame(id3::frame::ExtendedText {
+                        description: description.to_owned(),
+                        value: (*value).to_owned(),
+                    });
+                }
+            }
+            tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+            songs.push(path);
+        }
+        let mut logs = vec![];
+        if let Some(rip_log) = rip_log {
+            let path = dir.path().join("rip.log");
+            std::fs::write(&path, rip_log).unwrap();
+            logs.push(path);
+        }
+        AlbumInputGroup::new(dir.path(), origin, None, None, None, vec![], songs, logs).unwrap()
+    }
+
+    /// An XLD log of the 2-track disc with MusicBrainz disc ID [LOG_DISCID] and CDDB disc ID [LOG_CDDB_DISCID]
+    const RIP_LOG: &str = "X Lossless Decoder version 20230627 (157.2)
+
+TOC of the extracted CD
+     Track |   Start  |  Length  | Start sector | End sector
+    ---------------------------------------------------------
+        1  | 00:00:00 | 04:10:01 |         0    |    18750
+        2  | 04:10:01 | 04:37:61 |     18751    |    39587
+";
+
+    fn log_discids() -> (MbDiscId, CddbDiscId) {
+        let toc = crate::discid::Toc::from_rip_log(RIP_LOG).unwrap();
+        (toc.mb_discid(), toc.cddb_discid())
+    }
+
+    fn deriver(
+        api: FakeMusicBrainz,
+        cddb: FakeCddb,
+    ) -> MusicBrainzDeriver<FakeMusicBrainz, FakeCddb> {
+        MusicBrainzDeriver::new(api, cddb)
+    }
+
+    fn ids(release_group: &str, release: &str) -> Option<(MbId, MbId)> {
+        Some((MbId::new(release_group), MbId::new(release)))
+    }
+
+    #[tokio::test]
+    async fn origin_release_beats_everything_else() {
+        let dir = tempfile::tempdir().unwrap();
+        let origin = Origin {
+            mb_release_id: Some(MbId::new("rel1")),
+            mb_discid: Some(MbDiscId::new("disc")),
+            ..Default::default()
+        };
+        let album = album(&dir, origin, 2, Some("native"), Some(RIP_LOG));
+        let api = FakeMusicBrainz {
+            releases: vec![release("rel1", &[&[1000, 1000]])],
+            ..Default::default()
+        };
+        let deriver = deriver(api, FakeCddb::default());
+        assert_eq!(
+            deriver.derive_release_ids(&album).await.unwrap(),
+            ids("rg-rel1", "rel1")
+        );
+        assert_eq!(deriver.api.requests(), ["release rel1"]);
+    }
+
+    #[tokio::test]
+    async fn origin_release_group_picks_the_release_that_fits() {
+        let dir = tempfile::tempdir().unwrap();
+        let origin = Origin {
+            mb_release_group_id: Some(MbId::new("rg")),
+            ..Default::default()
+        };
+        let album = album(&dir, origin, 3, Some("native"), None);
+        let api = FakeMusicBrainz {
+            releases: vec![
+                release("short", &[&[1000, 1000]]),
+                release("long", &[&[1000, 1000], &[1000, 1000]]),
+                release("exact", &[&[1000, 1000, 1000]]),
+            ],
+            release_groups: HashMap::from([("rg", vec!["short", "long", "exact"])]),
+            ..Default::default()
+        };
+        let deriver = deriver(api, FakeCddb::default());
+        assert_eq!(
+            deriver.derive_release_ids(&album).await.unwrap(),
+            ids("rg", "exact")
+        );
+    }
+
+    #[tokio::test]
+    async fn origin_discid_beats_native_tags_and_must_be_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let origin = Origin {
+            mb_discid: Some(MbDiscId::new("disc")),
+            ..Default::default()
+        };
+        let album = album(&dir, origin, 2, Some("native"), None);
+        let api = FakeMusicBrainz {
+            releases: vec![release("rel1", &[&[1000, 1000]])],
+            discids: HashMap::from([("disc", vec!["rel1"])]),
+            ..Default::default()
+        };
+        let deriver = deriver(api, FakeCddb::default());
+        assert_eq!(
+            deriver.derive_release_ids(&album).await.unwrap(),
+            ids("rg-rel1", "rel1")
+        );
+
+        // The user chose the disc ID, so not finding it is an error rather than a reason to use the native tags
+        let deriver =
+            super::MusicBrainzDeriver::new(FakeMusicBrainz::default(), FakeCddb::default());
+        assert!(deriver.derive_release_ids(&album).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn native_tags_beat_the_rip_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = album(&dir, Origin::default(), 2, Some("native"), Some(RIP_LOG));
+        let api = FakeMusicBrainz {
+            releases: vec![release("native", &[&[1000, 1000]])],
+            ..Default::default()
+        };
+        let deriver = deriver(api, FakeCddb::default());
+        assert_eq!(
+            deriver.derive_release_ids(&album).await.unwrap(),
+            ids("rg-native", "native")
+        );
+        assert_eq!(deriver.api.requests(), ["release native"]);
+    }
+
+    #[tokio::test]
+    async fn conflicting_native_release_ids_are_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = tagged_album(
+            &dir,
+            Origin::default(),
+            &[(Some("rel1"), None), (Some("rel2"), None)],
+            Some(RIP_LOG),
+        );
+        assert!(native_release_ids(&album).is_err());
+
+        // The rip log isn't a reason to ignore tags that disagree
+        let deriver = deriver(FakeMusicBrainz::default(), FakeCddb::default());
+        assert!(deriver.derive_release_ids(&album).await.is_err());
+        assert!(deriver.api.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn partly_tagged_albums_arent_identified_by_their_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = tagged_album(
+            &dir,
+            Origin::default(),
+            &[(Some("rel1"), Some("rg1")), (None, None)],
+            None,
+        );
+        assert!(native_release_ids(&album).unwrap().is_none());
+
+        let deriver = deriver(FakeMusicBrainz::default(), FakeCddb::default());
+        assert_eq!(deriver.derive_release_ids(&album).await.unwrap(), None);
+        assert!(deriver.api.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn native_release_groups_are_only_trusted_if_they_agree() {
+        let dir = tempfile::tempdir().unwrap();
+        let agreeing = tagged_album(
+            &dir,
+            Origin::default(),
+            &[(Some("rel"), Some("rg")), (Some("rel"), Some("rg"))],
+            None,
+        );
+        let no_lookups = deriver(FakeMusicBrainz::default(), FakeCddb::default());
+        assert_eq!(
+            no_lookups.derive_release_ids(&agreeing).await.unwrap(),
+            ids("rg", "rel")
+        );
+        assert!(no_lookups.api.requests().is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let conflicting = tagged_album(
+            &dir,
+            Origin::default(),
+            &[(Some("rel"), Some("rg1")), (Some("rel"), Some("rg2"))],
+            None,
+        );
+        assert_eq!(
+            native_release_ids(&conflicting).unwrap(),
+            Some((None, MbId::new("rel")))
+        );
+        let api = FakeMusicBrainz {
+            releases: vec![release("rel", &[&[1000, 1000]])],
+            ..Default::default()
+        };
+        let deriver = deriver(api, FakeCddb::default());
+        assert_eq!(
+            deriver.derive_release_ids(&conflicting).await.unwrap(),
+            ids("rg-rel", "rel")
+        );
+        assert_eq!(deriver.api.requests(), ["release rel"]);
+    }
+
+    #[tokio::test]
+    async fn rip_log_discid_is_used_without_native_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = album(&dir, Origin::default(), 2, None, Some(RIP_LOG));
+        let (discid, _) = log_discids();
+        let discid = discid.as_str().to_owned().leak();
+        let api = FakeMusicBrainz {
+            releases: vec![release("rel1", &[&[1000, 1000]])],
+            discids: HashMap::from([(&*discid, vec!["rel1"])]),
+            ..Default::default()
+        };
+        let deriver = deriver(api, FakeCddb::default());
+        assert_eq!(
+            deriver.derive_release_ids(&album).await.unwrap(),
+            ids("rg-rel1", "rel1")
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_rip_log_discids_fall_through() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = album(&dir, Origin::default(), 2, None, Some(RIP_LOG));
+        let deriver = deriver(FakeMusicBrainz::default(), FakeCddb::default());
+        assert_eq!(deriver.derive_release_ids(&album).await.unwrap(), None);
+        let (discid, _) = log_discids();
+        assert_eq!(
+            deriver.api.requests(),
+            [format!("disc ID {}", discid.as_str())]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_rip_log_lookups_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = album(&dir, Origin::default(), 2, None, Some(RIP_LOG));
+        let (discid, _) = log_discids();
+        let api = FakeMusicBrainz {
+            broken_discids: vec![discid.as_str().to_owned().leak()],
+            ..Default::default()
+        };
+        let deriver = deriver(api, FakeCddb::default());
+        let err = deriver.derive_release_ids(&album).await.unwrap_err();
+        assert!(format!("{err:#}").contains("503"), "{err:#}");
+
+        // so the album isn't stored as having found nothing, and is looked up again next run
+        let mut stored = StoredDeriver::new(deriver, &CacheConfig::default(), Default::default());
+        assert_eq!(stored.try_rederive_album(dir.path(), &album).await, None);
+        assert_eq!(stored.inner_mut().take_errors().len(), 1);
+        assert!(!GroupCacheFile::path_for_group(dir.path()).exists());
+    }
+
+    #[tokio::test]
+    async fn nothing_to_go_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = album(&dir, Origin::default(), 2, None, None);
+        let deriver = deriver(FakeMusicBrainz::default(), FakeCddb::default());
+        assert_eq!(deriver.derive_release_ids(&album).await.unwrap(), None);
+        assert!(deriver.api.requests().is_empty());
+    }
+
+    #[test]
+    fn pick_release_prefers_an_exact_track_count() {
+        let releases = vec![
+            release("long", &[&[1000; 5]]),
+            release("exact", &[&[1000; 2], &[1000; 2]]),
+        ];
+        assert_eq!(pick_release(releases, 4).unwrap().id, "exact");
+    }
+
+    #[test]
+    fn pick_release_falls_back_to_enough_tracks() {
+        let releases = vec![
+            release("short", &[&[1000; 3]]),
+            release("long", &[&[1000; 5]]),
+        ];
+        assert_eq!(pick_release(releases, 4).unwrap().id, "long");
+    }
+
+    #[test]
+    fn pick_release_never_picks_too_few_tracks() {
+        let releases = vec![release("short", &[&[1000; 3]])];
+        assert!(pick_release(releases, 4).is_none());
+        assert!(pick_release(vec![], 1).is_none());
+    }
+
+    #[test]
+    fn track_length_distance_sums_the_differences() {
+        let release = release("rel", &[&[60_000, 120_000]]);
+        let distance = track_length_distance(&release, &[61.0, 119.5]).unwrap();
+        assert!((distance - 1.5).abs() < 1e-9, "{distance}");
+    }
+
+    #[test]
+    fn track_length_distance_picks_the_closest_medium() {
+        let release = release("rel", &[&[60_000, 60_000], &[61_000, 60_000]]);
+        let distance = track_length_distance(&release, &[61.0, 60.0]).unwrap();
+        assert!(distance.abs() < 1e-9, "{distance}");
+    }
+
+    #[test]
+    fn track_length_distance_rejects_mismatches() {
+        let release = release("rel", &[&[60_000, 120_000]]);
+        // Too many tracks
+        assert!(track_length_distance(&release, &[60.0, 120.0, 30.0]).is_none());
+        // One track too far off
+        let off = 120.0 + CDDB_TRACK_LENGTH_TOLERANCE_SECS + 0.5;
+        assert!(track_length_distance(&release, &[60.0, off]).is_none());
+
+        // Unknown track lengths can't match
+        let mut unknown = release.clone();
+        unknown.media.as_mut().unwrap()[0].tracks.as_mut().unwrap()[0].length = None;
+        assert!(track_length_distance(&unknown, &[60.0, 120.0]).is_none());
+    }
+
+    #[test]
+    fn search_queries_are_escaped_and_encoded() {
+        assert_eq!(
+            search_query("AC/DC", "Say \"Hi\" \\o/"),
+            "release:\"Say \\\"Hi\\\" \\\\o/\" AND artist:\"AC/DC\""
+        );
+        let http = MusicBrainzHttp::new(&MusicBrainzConfig::default()).unwrap();
+        let request = http
+            .request("release", &[("query", "a b&c\"é")])
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://musicbrainz.org/ws/2/release?fmt=json&query=a+b%26c%22%C3%A9"
+        );
+    }
+
+    #[test]
+    fn requests_go_to_the_configured_base_url() {
+        let config = MusicBrainzConfig {
+            base_url: Some("http://localhost:5000/ws/2/".to_owned()),
+        };
+        let http = MusicBrainzHttp::new(&config).unwrap();
+        let request = http
+            .request("discid/abc", &[("inc", "release-groups artists")])
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "http://localhost:5000/ws/2/discid/abc?fmt=json&inc=release-groups+artists"
+        );
+    }
+
+    fn cddb_entry(artist: &str, title: &str, track_lengths: &[f64]) -> CddbEntry {
+        CddbEntry {
+            category: "rock".to_owned(),
+            artist: artist.to_owned(),
+            title: title.to_owned(),
+            track_lengths: track_lengths.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn cddb_release_picks_the_closest_track_lengths() {
+        let api = FakeMusicBrainz {
+            releases: vec![
+                release("wrong count", &[&[60_000, 60_000, 60_000]]),
+                release("close", &[&[61_000, 90_000]]),
+                release("closer", &[&[60_000, 90_000]]),
+                release("far", &[&[70_000, 90_000]]),
+            ],
+            searches: HashMap::from([
+                (("Band", "Album"), vec!["wrong count", "close", "far"]),
+                (("Band", "Album (Remaster)"), vec!["closer"]),
+            ]),
+            ..Default::default()
+        };
+        let cddb = FakeCddb(HashMap::from([(
+            "1a2b3c02",
+            vec![
+                cddb_entry("Band", "Album", &[60.0, 90.0]),
+                cddb_entry("Band", "Album (Remaster)", &[60.0, 90.0]),
+            ],
+        )]));
+        let deriver = deriver(api, cddb);
+        let release = deriver
+            .cddb_release(&CddbDiscId::new("1a2b3c02"))
+            .await
+            .unwrap();
+        assert_eq!(release.id, "closer");
+        // Only releases with a medium of the right size had their tracklists looked up
+        assert!(
+            !deriver
+                .api
+                .requests()
+                .contains(&"release wrong count".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn cddb_release_skips_failed_lookups() {
+        let api = FakeMusicBrainz {
+            releases: vec![
+                release("broken", &[&[60_000, 90_000]]),
+                release("ok", &[&[61_000, 90_000]]),
+            ],
+            searches: HashMap::from([
+                (("Band", "Album"), vec!["broken", "ok"]),
+                (("Band", "Broken Search"), vec!["broken"]),
+            ]),
+            broken: vec!["broken"],
+            broken_searches: vec!["Broken Search"],
+            ..Default::default()
+        };
+        let cddb = FakeCddb(HashMap::from([(
+            "1a2b3c02",
+            vec![
+                cddb_entry("Band", "Broken Search", &[60.0, 90.0]),
+                cddb_entry("Band", "Album", &[60.0, 90.0]),
+            ],
+        )]));
+        let deriver = deriver(api, cddb);
+        let release = deriver
+            .cddb_release(&CddbDiscId::new("1a2b3c02"))
+            .await
+            .unwrap();
+        assert_eq!(release.id, "ok");
+    }
+
+    #[tokio::test]
+    async fn cddb_release_reports_why_nothing_matched() {
+        let api = FakeMusicBrainz {
+            releases: vec![release("broken", &[&[60_000, 90_000]])],
+            searches: HashMap::from([(("Band", "Album"), vec!["broken"])]),
+            broken: vec!["broken"],
+            ..Default::default()
+        };
+        let cddb = FakeCddb(HashMap::from([(
+            "1a2b3c02",
+            vec![cddb_entry("Band", "Album", &[60.0, 90.0])],
+        )]));
+        let deriver = deriver(api, cddb);
+        let err = deriver
+            .cddb_release(&CddbDiscId::new("1a2b3c02"))
+            .await
+            .unwrap_err();
+        let err = format!("{err:#}");
+        assert!(err.contains("none of the 1 CDDB entries"), "{err}");
+        assert!(err.contains("couldn't look up release broken"), "{err}");
+
+        let err = deriver
+            .cddb_release(&CddbDiscId::new("ffffff02"))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("isn't in the CDDB database"),
+            "{err}"
+        );
+    }
+
+    fn artist(id: &str, name: &str, sort_name: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "name": name,
+            "sort-name": sort_name,
+            "disambiguation": "",
+        })
+    }
+
+    fn credit(id: &str, name: &str, sort_name: &str) -> serde_json::Value {
+        serde_json::json!([{
+            "name": name,
+            "joinphrase": "",
+            "artist": artist(id, name, sort_name),
+        }])
+    }
+
+    /// A recording of a work, with the work's composers
+    fn recording(id: &str, title: &str, isrcs: &[&str], composers: &[&str]) -> serde_json::Value {
+        let composers = composers
+            .iter()
+            .map(|&name| {
+                serde_json::json!({
+                    "type": "composer",
+                    "type-id": "d59d99ea-23d4-4a80-b066-edca32ee158f",
+                    "target-type": "artist",
+                    "direction": "backward",
+                    "artist": artist(&format!("{name}-id"), name, name),
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({
+            "id": id,
+            "title": title,
+            "disambiguation": "",
+            "isrcs": isrcs,
+            "relations": [{
+                "type": "performance",
+                "type-id": "a3005666-a872-32c3-ad06-98af558e99b0",
+                "target-type": "work",
+                "direction": "forward",
+                "work": {
+                    "id": format!("{id}-work"),
+                    "title": title,
+                    "relations": composers,
+                },
+            }],
+        })
+    }
+
+    #[test]
+    fn cached_album_maps_every_field() {
+        let release: Release = serde_json::from_value(serde_json::json!({
+            "id": "rel",
+            "title": "Abbey Road",
+            "date": "2019-09-27",
+            "barcode": "602508007197",
+            "artist-credit": credit("beatles", "The Beatles", "Beatles, The"),
+            // No votes on the release itself, so the release group's are used
+            "genres": [],
+            "release-group": {
+                "id": "rg",
+                "title": "Abbey Road",
+                "primary-type": "Album",
+                "secondary-types": [],
+                "disambiguation": "",
+                "first-release-date": "1969-09-26",
+                "genres": [{ "id": "rock-id", "name": "rock", "count": 5 }],
+            },
+            "label-info": [
+                { "catalog-number": "", "label": null },
+                {
+                    "catalog-number": "0602508007197",
+                    "label": { "id": "apple", "name": "Apple Records", "disambiguation": "" },
+                },
+            ],
+            // Out of order, to check they're sorted by position
+            "media": [
+                {
+                    "position": 2,
+                    "track-count": 1,
+                    "tracks": [{
+                        "id": "t3",
+                        "title": "Here Comes the Sun",
+                        "number": "1",
+                        "position": 1,
+                        "artist-credit": credit("harrison", "George Harrison", "Harrison, George"),
+                        "recording": recording("r3", "Here Comes the Sun", &[], &["George Harrison"]),
+                    }],
+                },
+                {
+                    "position": 1,
+                    "track-count": 2,
+                    "tracks": [
+                        {
+                            "id": "t2",
+                            "title": "Something",
+                            "number": "2",
+                            "position": 2,
+                            "recording": {
+                                "id": "r2",
+                                "title": "Something",
+                                "disambiguation": "",
+                                "artist-credit": credit("harrison", "George Harrison", "Harrison, George"),
+                            },
+                        },
+                        {
+                            "id": "t1",
+                            "title": "Come Together",
+                            "number": "1",
+                            "position": 1,
+                            "recording": recording(
+                                "r1",
+                                "Come Together",
+                                &["GBAYE0601690", "GBUM71905431"],
+                                &["John Lennon", "Paul McCartney", "John Lennon"],
+                            ),
+                        },
+                    ],
+                },
+            ],
+        }))
+        .unwrap();
+
+        let cached = cached_album(release);
+        let beatles = CachedArtist {
+            id: MbId::new("beatles"),
+            name: "The Beatles".to_owned(),
+            sort_name: Some("Beatles, The".to_owned()),
+        };
+        let harrison = CachedArtist {
+            id: MbId::new("harrison"),
+            name: "George Harrison".to_owned(),
+            sort_name: Some("Harrison, George".to_owned()),
+        };
+        assert_eq!(cached.title, "Abbey Road");
+        assert_eq!(cached.artists, std::slice::from_ref(&beatles));
+        assert_eq!(cached.date.as_deref(), Some("2019-09-27"));
+        assert_eq!(cached.original_date.as_deref(), Some("1969-09-26"));
+        assert_eq!(cached.genres, ["rock"]);
+        assert_eq!(cached.label.as_deref(), Some("Apple Records"));
+        assert_eq!(cached.catalog_number.as_deref(), Some("0602508007197"));
+        assert_eq!(cached.barcode.as_deref(), Some("602508007197"));
+
+        let song =
+            |title: &str, artists: &[&CachedArtist], isrc: Option<&str>, composers: &[&str]| {
+                metadata::song::Cached {
+                    song_title: title.to_owned(),
+                    song_artists: artists.iter().map(|&a| a.clone()).collect(),
+                    isrc: isrc.map(str::to_owned),
+                    composers: composers.iter().map(|&c| c.to_owned()).collect(),
+                }
+            };
+        assert_eq!(
+            cached.media,
+            [
+                vec![
+                    // Tracks without their own credit fall back to the album artists
+                    song(
+                        "Come Together",
+                        &[&beatles],
+                        Some("GBAYE0601690"),
+                        &["John Lennon", "Paul McCartney"]
+                    ),
+                    // then the recording's credit
+                    song("Something", &[&harrison], None, &[]),
+                ],
+                vec![song(
+                    "Here Comes the Sun",
+                    &[&harrison],
+                    None,
+                    &["George Harrison"]
+                )],
+            ]
+        );
+    }
+}
@@ -0,0 +1,618 @@
+//! Mapping every resolved Song to a unique path in the output library.
+//!
+//! - if within an Album Group, `<First Artist of Album>/<Album Name>/<Song Name>`
+//! - if within a Compilation Group, `<First Artist of Song>/<Song Name>`
+//!
+//! Each Compilation Group also gets a playlist `<Compilation Title>.m3u8` in the root of the output library.
+//!
+//! Album Groups with the same first artist and album name share a folder, so partial albums are merged back together.
+//! Any other path components which collide (case-insensitively, for the sake of NTFS and APFS)
+//! are all given " A", " B", " C"... postfixes, in alphanumeric order of the things that collided,
+//! skipping any postfixed name that's already taken in the folder.
+//!
+//! Path components are cut short to fit in [MAX_COMPONENT_BYTES], which most filesystems limit names to.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use crate::resolver::{ResolvedGroup, ResolvedGroupKind, ResolvedSong};
+use crate::scanner::GROUP_FILE_NAME;
+
+const UNKNOWN_ARTIST: &str = "Unknown Artist";
+const UNKNOWN_ALBUM: &str = "Unknown Album";
+pub const PLAYLIST_EXT: &str = "m3u8";
+/// The longest name (in bytes) ext4, NTFS, APFS and most other filesystems allow
+pub const MAX_COMPONENT_BYTES: usize = 255;
+
+/// Characters which break at least one common filesystem
+const ILLEGAL_CHARS: [char; 9] = ['/', '\\', ':', '*', '"', '?', '<', '>', '|'];
+/// Names which are reserved on Windows, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The output path of every song, relative to the root of the output library
+pub struct OutputPlan {
+    /// Parallel to the resolved groups and their songs
+    pub groups: Vec<Vec<PathBuf>>,
+    /// Parallel to the resolved groups, the path of the playlist for each compilation
+    pub playlists: Vec<Option<PathBuf>>,
+}
+
+/// A path component that can't be used, alongside the file that needed it.
+/// For compilation titles, that's the group file.
+pub struct PlanDiagnostic {
+    pub group_path: PathBuf,
+    pub input_path: PathBuf,
+    pub component: String,
+    pub problem: String,
+}
+
+impl Display for PlanDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {}: path component {:?} {}",
+            self.group_path.display(),
+            self.input_path.display(),
+            self.component,
+            self.problem
+        )
+    }
+}
+
+/// Something which wants a name in an output folder.
+/// Different Claimants which want the same name are deduplicated.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Claimant {
+    Artist(String),
+    Album(String),
+    Song(PathBuf),
+    /// Identified by the group path
+    Playlist(PathBuf),
+}
+
+/// The names claimed in a single output folder
+#[derive(Default)]
+struct Folder {
+    /// Case-folded full name -> every claimant of that name, and the (name, extension) it wanted.
+    /// Ordered so postfixes are handed out the same way every time.
+    claims: BTreeMap<String, BTreeMap<Claimant, (String, Option<String>)>>,
+}
+
+impl Folder {
+    /// Claim `name` (cut short to fit [MAX_COMPONENT_BYTES] with the extension) for `claimant`
+    fn claim(&mut self, name: &str, ext: Option<&str>, claimant: Claimant) {
+        let name = truncate_name(name, MAX_COMPONENT_BYTES - ext_len(ext));
+        self.claims
+            .entry(with_ext(name, ext).to_lowercase())
+            .or_default()
+            .insert(claimant, (name.to_owned(), ext.map(str::to_owned)));
+    }
+
+    /// The deduplicated name for each claimant
+    fn resolve(&self) -> HashMap<Claimant, String> {
+        let mut names = HashMap::new();
+        // Case-folded names that have been given out
+        let mut used = HashSet::new();
+
+        // Names with one claimant are never postfixed, so they're given out first
+        for (folded, claimants) in &self.claims {
+            if let [(claimant, (name, ext))] = claimants.iter().collect::<Vec<_>>()[..] {
+                used.insert(folded.clone());
+                names.insert(claimant.clone(), with_ext(name, ext.as_deref()));
+            }
+        }
+        // The postfixed names can collide with other names too, so skip the postfixes that are taken
+        for claimants in self.claims.values().filter(|c| c.len() > 1) {
+            let mut postfixes = (0..).map(alpha_postfix);
+            for (claimant, (name, ext)) in claimants {
+                let ext = ext.as_deref();
+                let postfixed = postfixes
+                    .by_ref()
+                    .map(|postfix| {
+                        let max_bytes = MAX_COMPONENT_BYTES - ext_len(ext) - postfix.len() - 1;
+                        with_ext(
+                            &format!("{} {}", truncate_name(name, max_bytes), postfix),
+                            ext,
+                        )
+                    })
+                    .find(|postfixed| used.insert(postfixed.to_lowercase()))
+                    .expect("there are infinitely many postfixes");
+                names.insert(claimant.clone(), postfixed);
+            }
+        }
+        names
+    }
+}
+
+fn with_ext(name: &str, ext: Option<&str>) -> String {
+    match ext {
+        Some(ext) => format!("{name}.{ext}"),
+        None => name.to_owned(),
+    }
+}
+
+/// Number of bytes the extension (and its dot) adds to a name
+fn ext_len(ext: Option<&str>) -> usize {
+    ext.map_or(0, |ext| ext.len() + 1)
+}
+
+/// Cut `name` short to at most `max_bytes`, without splitting a character or leaving a trailing dot or space
+fn truncate_name(name: &str, max_bytes: usize) -> &str {
+    if name.len() <= max_bytes {
+        return name;
+    }
+    let mut end = max_bytes;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name[..end].trim_end_matches(['.', ' '])
+}
+
+/// 0 -> A, 1 -> B, ... 25 -> Z, 26 -> AA, 27 -> AB...
+fn alpha_postfix(mut idx: usize) -> String {
+    let mut postfix = vec![];
+    loop {
+        postfix.push(b'A' + (idx % 26) as u8);
+        if idx < 26 {
+            break;
+        }
+        idx = idx / 26 - 1;
+    }
+    postfix.reverse();
+    String::from_utf8(postfix).expect("ASCII is UTF-8")
+}
+
+/// Returns a description of the problem if `component` can't be used as a path component
+fn check_component(component: &str) -> Option<String> {
+    if let Some(c) = component
+        .chars()
+        .find(|c| ILLEGAL_CHARS.contains(c) || c.is_control())
+    {
+        return Some(format!("contains the illegal character {c:?}"));
+    }
+    if component.is_empty() || component == "." || component == ".." {
+        return Some("is not a valid name".to_owned());
+    }
+    if component.ends_with('.') || component.ends_with(' ') {
+        return Some("ends with a dot or space".to_owned());
+    }
+    let stem = component.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        return Some("is a reserved name on Windows".to_owned());
+    }
+    None
+}
+
+fn first_or(names: &[String], fallback: &str) -> String {
+    names
+        .first()
+        .filter(|n| !n.is_empty())
+        .cloned()
+        .unwrap_or_else(|| fallback.to_owned())
+}
+
+/// Where a song wants to go, before deduplication
+struct SongClaim {
+    artist: String,
+    /// None for songs in compilations, which go directly in the artist folder
+    album: Option<String>,
+    title: String,
+}
+
+impl SongClaim {
+    fn new(kind: &ResolvedGroupKind, song: &ResolvedSong) -> Self {
+        match kind {
+            ResolvedGroupKind::Album => SongClaim {
+                artist: first_or(&song.output.album_artists, UNKNOWN_ARTIST),
+                album: Some(
+                    song.output
+                        .album_title
+                        .clone()
+                        .filter(|a| !a.is_empty())
+                        .unwrap_or_else(|| UNKNOWN_ALBUM.to_owned()),
+                ),
+                title: song.output.song_title.clone(),
+            },
+            ResolvedGroupKind::Compilation { .. } => SongClaim {
+                artist: first_or(&song.output.song_artists, UNKNOWN_ARTIST),
+                album: None,
+                title: song.output.song_title.clone(),
+            },
+        }
+    }
+}
+
+/// The output path each song in `group` wants before deduplication, with the given extension.
+/// Songs from different groups which want the same path (case-insensitively) are given postfixes by [plan_output_paths].
+pub fn wanted_paths(group: &ResolvedGroup, ext: &str) -> Vec<PathBuf> {
+    group
+        .songs
+        .iter()
+        .map(|song| {
+            let claim = SongClaim::new(&group.kind, song);
+            let mut path = PathBuf::from(claim.artist);
+            path.extend(claim.album);
+            path.push(with_ext(&claim.title, Some(ext)));
+            path
+        })
+        .collect()
+}
+
+/// Map every song in `groups` to a unique output path with the given extension.
+/// If any path component is invalid, returns every invalid component instead.
+pub fn plan_output_paths(
+    groups: &[ResolvedGroup],
+    ext: &str,
+) -> Result<OutputPlan, Vec<PlanDiagnostic>> {
+    let claims = groups
+        .iter()
+        .map(|group| {
+            group
+                .songs
+                .iter()
+                .map(|song| SongClaim::new(&group.kind, song))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    // Check every component before deduplicating, so the user sees every problem at once.
+    // Postfixes and the extension can't make a valid component invalid.
+    let mut diagnostics = vec![];
+    for (group, group_claims) in groups.iter().zip(&claims) {
+        if let ResolvedGroupKind::Compilation { title } = &group.kind
+            && let Some(problem) = check_component(title)
+        {
+            diagnostics.push(PlanDiagnostic {
+                group_path: group.path.clone(),
+                input_path: group.path.join(GROUP_FILE_NAME),
+                component: title.clone(),
+                problem,
+            });
+        }
+        for (song, claim) in group.songs.iter().zip(group_claims) {
+            let components = [
+                Some(&claim.artist),
+                claim.album.as_ref(),
+                Some(&claim.title),
+            ];
+            for component in components.into_iter().flatten() {
+                if let Some(problem) = check_component(component) {
+                    diagnostics.push(PlanDiagnostic {
+                        group_path: group.path.clone(),
+                        input_path: song.input_path.clone(),
+                        component: component.clone(),
+                        problem,
+                    });
+                }
+            }
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    // Deduplicate level-by-level, starting at the root.
+    let all_claims = || {
+        groups
+            .iter()
+            .zip(&claims)
+            .flat_map(|(group, group_claims)| {
+                group
+                    .songs
+                    .iter()
+                    .map(|song| song.input_path.as_path())
+                    .zip(group_claims)
+            })
+    };
+
+    let mut root = Folder::default();
+    for (_, claim) in all_claims() {
+        root.claim(&claim.artist, None, Claimant::Artist(claim.artist.clone()));
+    }
+    for group in groups {
+        if let ResolvedGroupKind::Compilation { title } = &group.kind {
+            root.claim(
+                title,
+                Some(PLAYLIST_EXT),
+                Claimant::Playlist(group.path.clone()),
+            );
+        }
+    }
+    let root_names = root.resolve();
+
+    let mut artist_folders: HashMap<&str, Folder> = HashMap::new();
+    for (input_path, claim) in all_claims() {
+        let folder = artist_folders.entry(&claim.artist).or_default();
+        match &claim.album {
+            Some(album) => folder.claim(album, None, Claimant::Album(album.clone())),
+            None => folder.claim(
+                &claim.title,
+                Some(ext),
+                Claimant::Song(input_path.to_owned()),
+            ),
+        }
+    }
+    let artist_folder_names = artist_folders
+        .iter()
+        .map(|(artist, folder)| (*artist, folder.resolve()))
+        .collect::<HashMap<_, _>>();
+
+    let mut album_folders: HashMap<(&str, &str), Folder> = HashMap::new();
+    for (input_path, claim) in all_claims() {
+        if let Some(album) = &claim.album {
+            album_folders
+                .entry((&claim.artist, album))
+                .or_default()
+                .claim(
+                    &claim.title,
+                    Some(ext),
+                    Claimant::Song(input_path.to_owned()),
+                );
+        }
+    }
+    let album_folder_names = album_folders
+        .iter()
+        .map(|(key, folder)| (*key, folder.resolve()))
+        .collect::<HashMap<_, _>>();
+
+    let plan = groups
+        .iter()
+        .zip(&claims)
+        .map(|(group, group_claims)| {
+            group
+                .songs
+                .iter()
+                .zip(group_claims)
+                .map(|(song, claim)| {
+                    let song_claimant = Claimant::Song(song.input_path.clone());
+                    let artist_folder = &artist_folder_names[claim.artist.as_str()];
+
+                    let mut path =
+                        PathBuf::from(&root_names[&Claimant::Artist(claim.artist.clone())]);
+                    match &claim.album {
+                        Some(album) => {
+                            path.push(&artist_folder[&Claimant::Album(album.clone())]);
+                            path.push(
+                                &album_folder_names[&(claim.artist.as_str(), album.as_str())]
+                                    [&song_claimant],
+                            );
+                        }
+                        None => path.push(&artist_folder[&song_claimant]),
+                    }
+                    path
+                })
+                .collect()
+        })
+        .collect();
+
+    let playlists = groups
+        .iter()
+        .map(|group| match group.kind {
+            ResolvedGroupKind::Album => None,
+            ResolvedGroupKind::Compilation { .. } => Some(PathBuf::from(
+                &root_names[&Claimant::Playlist(group.path.clone())],
+            )),
+        })
+        .collect();
+
+    Ok(OutputPlan {
+        groups: plan,
+        playlists,
+    })
+}
+
+impl OutputPlan {
+    /// Every planned output path including playlists, relative to the output root
+    pub fn all_paths(&self) -> BTreeSet<&Path> {
+        self.groups
+            .iter()
+            .flatten()
+            .chain(self.playlists.iter().flatten())
+            .map(PathBuf::as_path)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_model::metadata::song::Output;
+    use crate::resolver::ResolvedSong;
+
+    fn song(input_path: &str, artist: &str, album: &str, title: &str) -> ResolvedSong {
+        ResolvedSong {
+            input_path: PathBuf::from(input_path),
+            output: Output {
+                song_title: title.to_owned(),
+                song_artists: vec![artist.to_owned()],
+                album_title: Some(album.to_owned()),
+                album_artists: vec![artist.to_owned()],
+                ..Default::default()
+            },
+            provenance: Default::default(),
+            duration: None,
+        }
+    }
+
+    fn album(path: &str, songs: Vec<ResolvedSong>) -> ResolvedGroup {
+        ResolvedGroup {
+            path: PathBuf::from(path),
+            kind: ResolvedGroupKind::Album,
+            songs,
+        }
+    }
+
+    fn compilation(path: &str, title: &str, songs: Vec<ResolvedSong>) -> ResolvedGroup {
+        ResolvedGroup {
+            path: PathBuf::from(path),
+            kind: ResolvedGroupKind::Compilation {
+                title: title.to_owned(),
+            },
+            songs,
+        }
+    }
+
+    fn plan(groups: &[ResolvedGroup]) -> Vec<Vec<String>> {
+        let plan = plan_output_paths(groups, "mp3").unwrap_or_else(|diagnostics| {
+            panic!(
+                "{}",
+                diagnostics
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )
+        });
+        plan.groups
+            .iter()
+            .map(|paths| {
+                paths
+                    .iter()
+                    .map(|p| p.to_str().unwrap().replace('\\', "/"))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unique_names_are_kept() {
+        let groups = [album(
+            "/in/a",
+            vec![
+                song("/in/a/1.flac", "Artist", "Album", "One"),
+                song("/in/a/2.flac", "Artist", "Album", "Two"),
+            ],
+        )];
+        assert_eq!(
+            plan(&groups),
+            [["Artist/Album/One.mp3", "Artist/Album/Two.mp3"]]
+        );
+    }
+
+    #[test]
+    fn partial_albums_share_a_folder() {
+        let groups = [
+            album(
+                "/in/a",
+                vec![song("/in/a/1.flac", "Artist", "Album", "One")],
+            ),
+            album(
+                "/in/b",
+                vec![song("/in/b/2.flac", "Artist", "Album", "Two")],
+            ),
+        ];
+        assert_eq!(
+            plan(&groups),
+            [["Artist/Album/One.mp3"], ["Artist/Album/Two.mp3"]]
+        );
+    }
+
+    #[test]
+    fn collisions_are_postfixed_case_insensitively() {
+        let groups = [album(
+            "/in/a",
+            vec![
+                song("/in/a/1.flac", "Artist", "Album", "Intro"),
+                song("/in/a/2.flac", "Artist", "Album", "intro"),
+            ],
+        )];
+        assert_eq!(
+            plan(&groups),
+            [["Artist/Album/Intro A.mp3", "Artist/Album/intro B.mp3"]]
+        );
+    }
+
+    #[test]
+    fn postfixes_skip_taken_names() {
+        let groups = [album(
+            "/in/a",
+            vec![
+                song("/in/a/1.flac", "Artist", "Album", "Intro"),
+                song("/in/a/2.flac", "Artist", "Album", "Intro"),
+                song("/in/a/3.flac", "Artist", "Album", "Intro A"),
+            ],
+        )];
+        assert_eq!(
+            plan(&groups),
+            [[
+                "Artist/Album/Intro B.mp3",
+                "Artist/Album/Intro C.mp3",
+                "Artist/Album/Intro A.mp3"
+            ]]
+        );
+    }
+
+    #[test]
+    fn extensions_are_part_of_the_name() {
+        // The playlist "Mix.m3u8" collides with the artist folder "Mix.m3u8", but not the artist folder "Mix"
+        let groups = [
+            compilation(
+                "/in/c",
+                "Mix",
+                vec![song("/in/c/1.flac", "Mix", "", "Mix.m3u8")],
+            ),
+            album(
+                "/in/a",
+                vec![song("/in/a/1.flac", "Mix.m3u8", "Album", "One")],
+            ),
+        ];
+        let plan = plan_output_paths(&groups, "mp3").ok().unwrap();
+        assert_eq!(plan.groups[0], [PathBuf::from("Mix/Mix.m3u8.mp3")]);
+        assert_eq!(plan.groups[1], [PathBuf::from("Mix.m3u8 A/Album/One.mp3")]);
+        assert_eq!(plan.playlists[0], Some(PathBuf::from("Mix B.m3u8")));
+    }
+
+    #[test]
+    fn long_names_are_cut_to_the_filesystem_limit() {
+        let long = "é".repeat(200);
+        let groups = [album(
+            "/in/a",
+            vec![
+                song("/in/a/1.flac", "Artist", "Album", &long),
+                song("/in/a/2.flac", "Artist", "Album", &long),
+            ],
+        )];
+        let plan = plan(&groups);
+        for path in &plan[0] {
+            let name = path.rsplit('/').next().unwrap();
+            assert!(name.len() <= MAX_COMPONENT_BYTES, "{} bytes", name.len());
+            assert!(name.ends_with(".mp3"));
+        }
+        assert!(plan[0][0].ends_with(" A.mp3"));
+        assert!(plan[0][1].ends_with(" B.mp3"));
+    }
+
+    #[test]
+    fn invalid_components_are_reported() {
+        let groups = [album(
+            "/in/a",
+            vec![
+                song("/in/a/1.flac", "Artist", "Album", "AC/DC"),
+                song("/in/a/2.flac", "Artist", "Album", "CON"),
+                song("/in/a/3.flac", "Artist", "Album", "Fine"),
+            ],
+        )];
+        let diagnostics = plan_output_paths(&groups, "mp3").err().unwrap();
+        let components = diagnostics
+            .iter()
+            .map(|d| d.component.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(components, ["AC/DC", "CON"]);
+    }
+
+    #[test]
+    fn postfixes_count_like_spreadsheet_columns() {
+        assert_eq!(alpha_postfix(0), "A");
+        assert_eq!(alpha_postfix(25), "Z");
+        assert_eq!(alpha_postfix(26), "AA");
+        assert_eq!(alpha_postfix(27), "AB");
+        assert_eq!(alpha_postfix(26 * 27), "AAA");
+    }
+}
@@ -0,0 +1,171 @@
+//! Writing an extended M3U playlist for every Compilation Group, so the compilation order survives into the output library.
+//!
+//! Playlists are cheap to make, so every playlist is rewritten on every render rather than tracked in the job cache.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::planner::OutputPlan;
+use crate::resolver::{ResolvedGroup, ResolvedGroupKind};
+use crate::store::rel_path_string;
+
+/// Write the playlist for every compilation in `groups` to its path in `plan`.
+/// Returns the paths of the playlists, relative to `output_root`.
+pub fn write_playlists(
+    output_root: &Path,
+    groups: &[ResolvedGroup],
+    plan: &OutputPlan,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut written = vec![];
+    for ((group, output_paths), playlist_path) in
+        groups.iter().zip(&plan.groups).zip(&plan.playlists)
+    {
+        let (ResolvedGroupKind::Compilation { title }, Some(playlist_path)) =
+            (&group.kind, playlist_path)
+        else {
+            continue;
+        };
+        let path = output_root.join(playlist_path);
+        std::fs::write(&path, playlist(title, group, output_paths, playlist_path))
+            .with_context(|| format!("couldn't write {}", path.display()))?;
+        written.push(playlist_path.clone());
+    }
+    Ok(written)
+}
+
+/// The contents of a playlist at `playlist_path` for the songs in `group`, which are rendered to `output_paths`.
+/// Both paths are relative to the output root.
+fn playlist(
+    title: &str,
+    group: &ResolvedGroup,
+    output_paths: &[PathBuf],
+    playlist_path: &Path,
+) -> String {
+    let playlist_dir = playlist_path.parent().unwrap_or(Path::new(""));
+    let mut lines = vec!["#EXTM3U".to_owned(), format!("#PLAYLIST:{title}")];
+    for (song, output_path) in group.songs.iter().zip(output_paths) {
+        // -1 is the conventional "unknown" duration
+        let duration = song
+            .duration
+            .map(|d| d.as_secs_f64().round() as i64)
+            .unwrap_or(-1);
+        let display_title = if song.output.song_artists.is_empty() {
+            song.output.song_title.clone()
+        } else {
+            format!(
+                "{} - {}",
+                song.output.song_artists.join(", "),
+                song.output.song_title
+            )
+        };
+        lines.push(format!("#EXTINF:{duration},{display_title}"));
+        lines.push(rel_path_string(&relative_to(output_path, playlist_dir)));
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// `path` relative to the folder `dir`, going up out of `dir` as far as needed. Both are relative to the output root.
+fn relative_to(path: &Path, dir: &Path) -> PathBuf {
+    let mut path_components = path.components().peekable();
+    let mut dir_components = dir.components().peekable();
+    while let (Some(p), Some(d)) = (path_components.peek(), dir_components.peek())
+        && p == d
+    {
+        path_components.next();
+        dir_components.next();
+    }
+    dir_components
+        .map(|_| Component::ParentDir)
+        .chain(path_components)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::data_model::metadata::song::Output;
+    use crate::resolver::ResolvedSong;
+
+    fn song(artists: &[&str], title: &str, duration: Option<f64>) -> ResolvedSong {
+        ResolvedSong {
+            input_path: PathBuf::from(format!("/input/{title}.flac")),
+            output: Output {
+                song_title: title.to_owned(),
+                song_artists: artists.iter().map(|&a| a.to_owned()).collect(),
+                ..Default::default()
+            },
+            provenance: Default::default(),
+            duration: duration.map(Duration::from_secs_f64),
+        }
+    }
+
+    #[test]
+    fn entries_are_relative_to_the_playlist() {
+        let relative =
+            |path: &str, dir: &str| rel_path_string(&relative_to(Path::new(path), Path::new(dir)));
+        assert_eq!(relative("Mix/01.mp3", ""), "Mix/01.mp3");
+        assert_eq!(relative("C/Mix/01.mp3", "C"), "Mix/01.mp3");
+        assert_eq!(relative("A/B/01.mp3", "C/D"), "../../A/B/01.mp3");
+        assert_eq!(relative("C/B/01.mp3", "C/D"), "../B/01.mp3");
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn compilations_get_a_playlist_in_group_order() {
+        let groups = [
+            ResolvedGroup {
+                path: PathBuf::from("/input/album"),
+                kind: ResolvedGroupKind::Album,
+                songs: vec![song(&["Band"], "Album Song", Some(100.0))],
+            },
+            ResolvedGroup {
+                path: PathBuf::from("/input/mix"),
+                kind: ResolvedGroupKind::Compilation {
+                    title: "Summer Mix".to_owned(),
+                },
+                songs: vec![
+                    song(&["Zed", "Amy"], "Last Alphabetically", Some(201.6)),
+                    song(&[], "No Artist", None),
+                    song(&["Band"], "Elsewhere", Some(59.4)),
+                ],
+            },
+        ];
+        let plan = OutputPlan {
+            groups: vec![
+                paths(&["Band/Album/01 Album Song.mp3"]),
+                paths(&[
+                    "Compilations/Summer Mix/01 Last Alphabetically.mp3",
+                    "Compilations/Summer Mix/02 No Artist.mp3",
+                    "Band/Other/01 Elsewhere.mp3",
+                ]),
+            ],
+            playlists: vec![None, Some(PathBuf::from("Compilations/Summer Mix.m3u8"))],
+        };
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("Compilations")).unwrap();
+
+        let written = write_playlists(dir.path(), &groups, &plan).unwrap();
+        assert_eq!(written, paths(&["Compilations/Summer Mix.m3u8"]));
+        let contents =
+            std::fs::read_to_string(dir.path().join("Compilations/Summer Mix.m3u8")).unwrap();
+        assert_eq!(
+            contents,
+            "#EXTM3U
+#PLAYLIST:Summer Mix
+#EXTINF:202,Zed, Amy - Last Alphabetically
+Summer Mix/01 Last Alphabetically.mp3
+#EXTINF:-1,No Artist
+Summer Mix/02 No Artist.mp3
+#EXTINF:59,Band - Elsewhere
+../Band/Other/01 Elsewhere.mp3
+"
+        );
+    }
+}
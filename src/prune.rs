@@ -0,0 +1,302 @@
+//! Removing files from the output library that aren't supposed to be there,
+//! e.g. outputs of songs that were renamed, moved or deleted since the last render.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::store::now;
+
+pub enum PruneMode {
+    Delete,
+    /// Move stale files into a new timestamped subfolder of this folder instead of deleting them,
+    /// keeping their path relative to the output root
+    Quarantine(PathBuf),
+    /// Don't touch anything, only report what would be pruned.
+    /// The quarantine folder a real run would use is left alone, like it would be.
+    DryRun {
+        quarantine: Option<PathBuf>,
+    },
+}
+
+impl PruneMode {
+    /// The quarantine folder, which is never pruned itself
+    fn quarantine(&self) -> Option<&Path> {
+        match self {
+            PruneMode::Delete => None,
+            PruneMode::Quarantine(quarantine) => Some(quarantine),
+            PruneMode::DryRun { quarantine } => quarantine.as_deref(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PruneSummary {
+    /// Stale files, relative to the output root
+    pub files: Vec<PathBuf>,
+    /// Folders which were (or would be) left empty, relative to the output root
+    pub dirs: Vec<PathBuf>,
+    pub failures: Vec<(PathBuf, anyhow::Error)>,
+}
+
+/// Prune every file under `output_root` that isn't in `expected` (paths relative to `output_root`),
+/// then every folder left empty.
+/// If the quarantine folder is inside `output_root`, it's left alone.
+pub fn prune_output(
+    output_root: &Path,
+    expected: &BTreeSet<&Path>,
+    mode: &PruneMode,
+) -> anyhow::Result<PruneSummary> {
+    let mut pruner = Pruner {
+        output_root,
+        expected,
+        mode,
+        quarantine_batch: match mode {
+            PruneMode::Quarantine(quarantine) => Some(quarantine.join(now().to_string())),
+            _ => None,
+        },
+        summary: PruneSummary::default(),
+    };
+    if output_root.exists() {
+        pruner.prune_dir(Path::new(""))?;
+    }
+    Ok(pruner.summary)
+}
+
+struct Pruner<'a> {
+    output_root: &'a Path,
+    expected: &'a BTreeSet<&'a Path>,
+    mode: &'a PruneMode,
+    /// Where this run moves stale files, if quarantining
+    quarantine_batch: Option<PathBuf>,
+    summary: PruneSummary,
+}
+
+impl Pruner<'_> {
+    /// Returns true if the folder is (or in a dry run, would be) empty after pruning
+    fn prune_dir(&mut self, rel_dir: &Path) -> anyhow::Result<bool> {
+        let dir = self.output_root.join(rel_dir);
+        let mut entries = std::fs::read_dir(&dir)
+            .with_context(|| format!("couldn't read {}", dir.display()))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("couldn't read {}", dir.display()))?;
+        entries.sort_by_key(|e| e.file_name());
+
+        let mut empty = true;
+        for entry in entries {
+            let rel_path = rel_dir.join(entry.file_name());
+            let path = entry.path();
+            if let Some(quarantine) = self.mode.quarantine()
+                && path.starts_with(quarantine)
+            {
+                empty = false;
+                continue;
+            }
+
+            // Symlinks are pruned like files, never followed
+            let is_dir = entry
+                .file_type()
+                .with_context(|| format!("couldn't stat {}", path.display()))?
+                .is_dir();
+            let pruned = if is_dir {
+                self.prune_dir(&rel_path)? && self.prune(rel_path, true)
+            } else if self.expected.contains(rel_path.as_path()) {
+                false
+            } else {
+                self.prune(rel_path, false)
+            };
+            empty &= pruned;
+        }
+        Ok(empty)
+    }
+
+    /// Prune a single file or empty folder. Returns true if it was (or would be) pruned.
+    fn prune(&mut self, rel_path: PathBuf, is_dir: bool) -> bool {
+        let path = self.output_root.join(&rel_path);
+        let result = match (self.mode, is_dir) {
+            (PruneMode::DryRun { .. }, _) => Ok(()),
+            (_, true) => std::fs::remove_dir(&path)
+                .with_context(|| format!("couldn't remove {}", path.display())),
+            (PruneMode::Delete, false) => std::fs::remove_file(&path)
+                .with_context(|| format!("couldn't remove {}", path.display())),
+            (PruneMode::Quarantine(_), false) => {
+                let batch = self
+                    .quarantine_batch
+                    .as_ref()
+                    .expect("set when quarantining");
+                quarantine_file(&path, &batch.join(&rel_path))
+            }
+        };
+        match result {
+            Ok(()) => {
+                if is_dir {
+                    self.summary.dirs.push(rel_path);
+                } else {
+                    self.summary.files.push(rel_path);
+                }
+                true
+            }
+            Err(err) => {
+                self.summary.failures.push((rel_path, err));
+                false
+            }
+        }
+    }
+}
+
+fn quarantine_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("couldn't create {}", parent.display()))?;
+    }
+    // Moving across filesystems can't be done with a rename
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to)
+            .with_context(|| format!("couldn't copy {} to {}", from.display(), to.display()))?;
+        std::fs::remove_file(from)
+            .with_context(|| format!("couldn't remove {}", from.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An output library with two expected songs, a stale song next to them, a stale song alone in a folder,
+    /// and a file quarantined by an earlier run
+    fn output_library(root: &Path) {
+        for file in [
+            "Artist/Album/01 Kept.mp3",
+            "Artist/Album/02 Stale.mp3",
+            "Artist/Old Album/01 Stale.mp3",
+            "playlist.m3u8",
+            "quarantine/1000/Gone/01 Song.mp3",
+        ] {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, file).unwrap();
+        }
+    }
+
+    fn expected() -> BTreeSet<&'static Path> {
+        ["Artist/Album/01 Kept.mp3", "playlist.m3u8"]
+            .into_iter()
+            .map(Path::new)
+            .collect()
+    }
+
+    /// Every file under `root`, relative to it
+    fn files(root: &Path) -> Vec<String> {
+        fn walk(root: &Path, dir: &Path, files: &mut Vec<String>) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    walk(root, &path, files);
+                } else {
+                    let rel_path = path.strip_prefix(root).unwrap();
+                    files.push(rel_path.to_string_lossy().replace('\\', "/"));
+                }
+            }
+        }
+        let mut files = vec![];
+        walk(root, root, &mut files);
+        files.sort();
+        files
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn stale_files_and_empty_folders_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        output_library(dir.path());
+        // Without a quarantine, an old quarantine folder is just more stale files
+        std::fs::remove_dir_all(dir.path().join("quarantine")).unwrap();
+
+        let summary = prune_output(dir.path(), &expected(), &PruneMode::Delete).unwrap();
+        assert!(summary.failures.is_empty());
+        assert_eq!(
+            summary.files,
+            paths(&["Artist/Album/02 Stale.mp3", "Artist/Old Album/01 Stale.mp3"])
+        );
+        assert_eq!(summary.dirs, paths(&["Artist/Old Album"]));
+        assert_eq!(
+            files(dir.path()),
+            ["Artist/Album/01 Kept.mp3", "playlist.m3u8"]
+        );
+        assert!(!dir.path().join("Artist/Old Album").exists());
+    }
+
+    #[test]
+    fn quarantined_files_keep_their_relative_path() {
+        let dir = tempfile::tempdir().unwrap();
+        output_library(dir.path());
+        let quarantine = dir.path().join("quarantine");
+
+        let summary = prune_output(
+            dir.path(),
+            &expected(),
+            &PruneMode::Quarantine(quarantine.clone()),
+        )
+        .unwrap();
+        assert!(summary.failures.is_empty());
+        assert_eq!(
+            summary.files,
+            paths(&["Artist/Album/02 Stale.mp3", "Artist/Old Album/01 Stale.mp3"])
+        );
+        assert_eq!(summary.dirs, paths(&["Artist/Old Album"]));
+
+        // The earlier quarantine is left alone, and this run's files are in a new batch
+        let quarantined = files(&quarantine);
+        assert_eq!(quarantined.len(), 3, "{quarantined:?}");
+        assert!(quarantined.contains(&"1000/Gone/01 Song.mp3".to_owned()));
+        let batch = quarantined
+            .iter()
+            .find(|f| f.ends_with("Artist/Album/02 Stale.mp3"))
+            .expect("stale file was quarantined")
+            .split('/')
+            .next()
+            .unwrap();
+        assert!(quarantined.contains(&format!("{batch}/Artist/Old Album/01 Stale.mp3")));
+        assert_eq!(
+            std::fs::read_to_string(quarantine.join(batch).join("Artist/Album/02 Stale.mp3"))
+                .unwrap(),
+            "Artist/Album/02 Stale.mp3"
+        );
+    }
+
+    #[test]
+    fn dry_runs_touch_nothing_and_list_what_would_be_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        output_library(dir.path());
+        let quarantine = dir.path().join("quarantine");
+        let before = files(dir.path());
+
+        let dry_run = prune_output(
+            dir.path(),
+            &expected(),
+            &PruneMode::DryRun {
+                quarantine: Some(quarantine.clone()),
+            },
+        )
+        .unwrap();
+        assert_eq!(files(dir.path()), before);
+
+        let real =
+            prune_output(dir.path(), &expected(), &PruneMode::Quarantine(quarantine)).unwrap();
+        assert_eq!(dry_run.files, real.files);
+        assert_eq!(dry_run.dirs, real.dirs);
+    }
+
+    #[test]
+    fn missing_output_root_prunes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let summary =
+            prune_output(&dir.path().join("missing"), &expected(), &PruneMode::Delete).unwrap();
+        assert!(summary.files.is_empty() && summary.dirs.is_empty());
+    }
+}
@@ -0,0 +1,57 @@
+//! Spacing out requests to web services which limit how often each client may call them.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Allows one request per `interval`, making callers wait their turn in the order they asked
+pub struct RateLimiter {
+    interval: Duration,
+    /// When the next request is allowed
+    next_request: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_request: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until a request is allowed, and count it as made
+    pub async fn wait(&self) {
+        tokio::time::sleep(self.reserve(Instant::now())).await;
+    }
+
+    /// Book the next free slot at or after `now`, returning how long until it starts
+    fn reserve(&self, now: Instant) -> Duration {
+        let mut next_request = self.next_request.lock().expect("rate limit mutex poisoned");
+        let wait = next_request.saturating_duration_since(now);
+        *next_request = now.max(*next_request) + self.interval;
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_spaced_by_the_interval() {
+        let limiter = RateLimiter::new(Duration::from_secs(1));
+        let start = Instant::now();
+        assert_eq!(limiter.reserve(start), Duration::ZERO);
+        assert_eq!(limiter.reserve(start), Duration::from_secs(1));
+        assert_eq!(limiter.reserve(start), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn idle_time_isnt_saved_up() {
+        let limiter = RateLimiter::new(Duration::from_secs(1));
+        let start = Instant::now();
+        limiter.reserve(start);
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.reserve(later), Duration::ZERO);
+        assert_eq!(limiter.reserve(later), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,761 @@
+//! Rendering the output library with FFmpeg.
+//!
+//! Every planned output file is transcoded from its input file, with the resolved metadata written into it.
+//! A job cache file in the output root records what each output file was rendered from,
+//! so output files are only re-rendered when their input file, metadata or encoder settings change,
+//! or when the output file itself was changed or removed.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::data_model::FileHash;
+use crate::data_model::metadata;
+use crate::data_model::user_defined::OutputConfig;
+use crate::planner::OutputPlan;
+use crate::resolver::{ResolvedGroup, ResolvedSong};
+use crate::store::{FileHashes, rel_path_string};
+
+pub const JOB_CACHE_FILE_NAME: &str = "output.tm2.jobs.toml";
+
+/// Output files are written to `<prefix><hash of the name>.<extension>` first and then renamed,
+/// so an interrupted render never leaves a partial file behind.
+/// The name is a fixed length, so it fits wherever the planner's names do.
+const PARTIAL_PREFIX: &str = ".tm2-partial.";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncoderSettings {
+    pub codec: String,
+    pub bitrate: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct JobCacheFile {
+    #[serde(default)]
+    pub jobs: Vec<JobCacheEntry>,
+}
+
+/// What an output file was rendered from
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobCacheEntry {
+    /// '/' coded path relative to the output root
+    pub output_rel_path: String,
+    pub input_path: PathBuf,
+    pub input_hash: FileHash,
+    pub settings: EncoderSettings,
+    pub metadata: metadata::song::Output,
+    /// The size and modification time of the output file when it was rendered.
+    /// If either changes, the output file was touched by something else and is rendered again.
+    pub output_size: u64,
+    /// Nanoseconds since the Unix epoch
+    pub output_modified: u64,
+}
+
+impl JobCacheFile {
+    pub fn path_for_output_root(output_root: &Path) -> PathBuf {
+        output_root.join(JOB_CACHE_FILE_NAME)
+    }
+
+    /// Load the job cache for an output library, or an empty cache if it doesn't exist yet
+    pub fn load(output_root: &Path) -> anyhow::Result<JobCacheFile> {
+        let p = Self::path_for_output_root(output_root);
+        if !p.exists() {
+            return Ok(JobCacheFile::default());
+        }
+        let document = std::fs::read_to_string(&p)?.parse::<toml_edit::DocumentMut>()?;
+        let file = toml_edit::de::from_document(document)
+            .with_context(|| format!("couldn't parse {}", p.display()))?;
+        Ok(file)
+    }
+
+    pub fn save(&self, output_root: &Path) -> anyhow::Result<()> {
+        let p = Self::path_for_output_root(output_root);
+        std::fs::write(&p, toml_edit::ser::to_string_pretty(self)?)
+            .with_context(|| format!("couldn't write {}", p.display()))?;
+        Ok(())
+    }
+}
+
+pub struct RenderFailure {
+    pub input_path: PathBuf,
+    pub output_path: PathBuf,
+    pub error: anyhow::Error,
+}
+
+#[derive(Default)]
+pub struct RenderSummary {
+    /// Output paths that were (re-)rendered, relative to the output root
+    pub rendered: Vec<PathBuf>,
+    /// Number of output files that were already up to date
+    pub skipped: usize,
+    pub failures: Vec<RenderFailure>,
+}
+
+pub struct Renderer {
+    ffmpeg: PathBuf,
+    output_root: PathBuf,
+    settings: EncoderSettings,
+    container_keys: ContainerKeys,
+    jobs: JobCacheFile,
+}
+
+impl Renderer {
+    /// `ffmpeg` is the binary to invoke.
+    /// It's called as `ffmpeg <options> -i <input> <options> <output>`, so tests can substitute any script that writes its last argument.
+    pub fn new(
+        ffmpeg: PathBuf,
+        output_root: PathBuf,
+        config: &OutputConfig,
+    ) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&output_root)
+            .with_context(|| format!("couldn't create {}", output_root.display()))?;
+        let jobs = JobCacheFile::load(&output_root)?;
+        Ok(Self {
+            ffmpeg,
+            output_root,
+            settings: EncoderSettings {
+                codec: config.codec.clone(),
+                bitrate: config.bitrate.clone(),
+            },
+            container_keys: ContainerKeys::for_extension(&config.extension),
+            jobs,
+        })
+    }
+
+    /// Render every song in `groups` to its path in `plan`, skipping those which are already up to date.
+    /// A song failing to render doesn't stop the others.
+    /// The job cache is saved afterwards, forgetting every output that isn't in `plan`.
+    /// Input files are hashed through `hashes`, so files already hashed for lookups aren't hashed again.
+    pub fn render(
+        &mut self,
+        groups: &[ResolvedGroup],
+        plan: &OutputPlan,
+        hashes: &FileHashes,
+    ) -> anyhow::Result<RenderSummary> {
+        let mut summary = RenderSummary::default();
+        let mut jobs = vec![];
+
+        for (group, output_paths) in groups.iter().zip(&plan.groups) {
+            for (song, output_rel_path) in group.songs.iter().zip(output_paths) {
+                let output_path = self.output_root.join(output_rel_path);
+                match self.render_song(song, output_rel_path, &output_path, hashes) {
+                    Ok((job, rendered)) => {
+                        if rendered {
+                            summary.rendered.push(output_rel_path.clone());
+                        } else {
+                            summary.skipped += 1;
+                        }
+                        jobs.push(job);
+                    }
+                    Err(error) => summary.failures.push(RenderFailure {
+                        input_path: song.input_path.clone(),
+                        output_path,
+                        error,
+                    }),
+                }
+            }
+        }
+
+        self.jobs.jobs = jobs;
+        self.jobs.save(&self.output_root)?;
+        Ok(summary)
+    }
+
+    /// Returns the job cache entry for the output, and whether it had to be rendered
+    fn render_song(
+        &mut self,
+        song: &ResolvedSong,
+        output_rel_path: &Path,
+        output_path: &Path,
+        hashes: &FileHashes,
+    ) -> anyhow::Result<(JobCacheEntry, bool)> {
+        let output_rel_path = rel_path_string(output_rel_path);
+        let input_hash = hashes.of_file(&song.input_path)?;
+
+        let previous = self
+            .jobs
+            .jobs
+            .iter()
+            .position(|j| j.output_rel_path == output_rel_path);
+        if let Some(previous) = previous {
+            let job = &self.jobs.jobs[previous];
+            if job.input_hash == input_hash
+                && job.settings == self.settings
+                && job.metadata == song.output
+                && output_stat(output_path).ok() == Some((job.output_size, job.output_modified))
+            {
+                return Ok((self.jobs.jobs.swap_remove(previous), false));
+            }
+        }
+
+        self.run_ffmpeg(&song.input_path, &song.output, output_path)?;
+
+        let (output_size, output_modified) = output_stat(output_path)
+            .with_context(|| format!("couldn't stat {}", output_path.display()))?;
+        let job = JobCacheEntry {
+            output_rel_path,
+            input_path: song.input_path.clone(),
+            input_hash,
+            settings: self.settings.clone(),
+            metadata: song.output.clone(),
+            output_size,
+            output_modified,
+        };
+        Ok((job, true))
+    }
+
+    fn run_ffmpeg(
+        &self,
+        input_path: &Path,
+        output: &metadata::song::Output,
+        output_path: &Path,
+    ) -> anyhow::Result<()> {
+        let parent = output_path.parent().unwrap_or(&self.output_root);
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("couldn't create {}", parent.display()))?;
+        let file_name = output_path
+            .file_name()
+            .context("output path has no file name")?;
+        let mut hasher = DefaultHasher::new();
+        file_name.hash(&mut hasher);
+        // FFmpeg picks the container from the extension
+        let ext = output_path
+            .extension()
+            .map(|ext| ext.to_string_lossy())
+            .unwrap_or_default();
+        let partial_path = parent.join(format!("{PARTIAL_PREFIX}{:016x}.{ext}", hasher.finish()));
+
+        let mut command = Command::new(&self.ffmpeg);
+        command
+            .args(["-hide_banner", "-loglevel", "error", "-nostdin", "-y"])
+            .arg("-i")
+            .arg(input_path)
+            // Only keep the audio, and only the metadata we resolved
+            .args(["-map", "0:a", "-map_metadata", "-1"])
+            .args(["-c:a", &self.settings.codec]);
+        if let Some(bitrate) = &self.settings.bitrate {
+            command.args(["-b:a", bitrate]);
+        }
+        for (key, value) in metadata_tags(output, &self.container_keys) {
+            command.arg("-metadata").arg(format!("{key}={value}"));
+        }
+        command.arg(&partial_path);
+
+        let result = command
+            .output()
+            .with_context(|| format!("couldn't run {}", self.ffmpeg.display()));
+        let result = match result {
+            Ok(result) if result.status.success() => std::fs::rename(&partial_path, output_path)
+                .with_context(|| {
+                    format!(
+                        "couldn't move {} to {}",
+                        partial_path.display(),
+                        output_path.display()
+                    )
+                }),
+            Ok(result) => Err(anyhow::anyhow!(
+                "ffmpeg failed ({}): {}",
+                result.status,
+                String::from_utf8_lossy(&result.stderr).trim()
+            )),
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            let _ = std::fs::remove_file(&partial_path);
+        }
+        result
+    }
+}
+
+/// The FFmpeg metadata keys for fields which FFmpeg doesn't map consistently between containers.
+/// None if FFmpeg can't write the field to the container.
+struct ContainerKeys {
+    title_sort: &'static str,
+    artist_sort: &'static str,
+    album_sort: &'static str,
+    album_artist_sort: &'static str,
+    original_date: Option<&'static str>,
+    label: Option<&'static str>,
+    catalog_number: Option<&'static str>,
+    barcode: Option<&'static str>,
+    isrc: Option<&'static str>,
+}
+
+impl ContainerKeys {
+    fn for_extension(ext: &str) -> Self {
+        match ext.to_ascii_lowercase().as_str() {
+            // FFmpeg writes TSOT/TSOP/TSOA/TPUB for these, and the frame IDs in its ID3v2.3/2.4 tables (e.g. TDOR, TSRC) verbatim.
+            // Anything else, including other frame IDs like TSO2, becomes a TXXX frame using the key as its description,
+            // so those keys are the TXXX descriptions players and Picard read.
+            "mp3" => ContainerKeys {
+                title_sort: "title-sort",
+                artist_sort: "artist-sort",
+                album_sort: "album-sort",
+                album_artist_sort: "ALBUMARTISTSORT",
+                original_date: Some("TDOR"),
+                label: Some("publisher"),
+                catalog_number: Some("CATALOGNUMBER"),
+                barcode: Some("BARCODE"),
+                isrc: Some("TSRC"),
+            },
+            // FFmpeg only writes the MP4 atoms it knows about
+            "m4a" | "m4b" | "mp4" => ContainerKeys {
+                title_sort: "sort_name",
+                artist_sort: "sort_artist",
+                album_sort: "sort_album",
+                album_artist_sort: "sort_album_artist",
+                original_date: None,
+                label: None,
+                catalog_number: None,
+                barcode: None,
+                isrc: None,
+            },
+            // Vorbis comments (Ogg, Opus, FLAC) are written verbatim
+            _ => ContainerKeys {
+                title_sort: "TITLESORT",
+                artist_sort: "ARTISTSORT",
+                album_sort: "ALBUMSORT",
+                album_artist_sort: "ALBUMARTISTSORT",
+                original_date: Some("ORIGINALDATE"),
+                label: Some("LABEL"),
+                catalog_number: Some("CATALOGNUMBER"),
+                barcode: Some("BARCODE"),
+                isrc: Some("ISRC"),
+            },
+        }
+    }
+}
+
+/// The FFmpeg metadata keys for each resolved field.
+/// FFmpeg maps these to the right tag for each container e.g. TPE2 for `album_artist` in ID3.
+fn metadata_tags(
+    output: &metadata::song::Output,
+    keys: &ContainerKeys,
+) -> Vec<(&'static str, String)> {
+    let mut tags = vec![("title", output.song_title.clone())];
+    if !output.song_artists.is_empty() {
+        tags.push(("artist", output.song_artists.join("; ")));
+    }
+    if let Some(album_title) = &output.album_title {
+        tags.push(("album", album_title.clone()));
+    }
+    if !output.album_artists.is_empty() {
+        tags.push(("album_artist", output.album_artists.join("; ")));
+    }
+    if let Some(disc_idx) = output.disc_idx {
+        tags.push(("disc", disc_idx.to_string()));
+    }
+    if let Some(track_idx) = output.track_idx {
+        tags.push(("track", track_idx.to_string()));
+    }
+    if let Some(title_sort) = &output.song_title_sort {
+        tags.push((keys.title_sort, title_sort.clone()));
+    }
+    if !output.song_artists_sort.is_empty() {
+        tags.push((keys.artist_sort, output.song_artists_sort.join("; ")));
+    }
+    if let Some(album_sort) = &output.album_title_sort {
+        tags.push((keys.album_sort, album_sort.clone()));
+    }
+    if !output.album_artists_sort.is_empty() {
+        tags.push((keys.album_artist_sort, output.album_artists_sort.join("; ")));
+    }
+    if let Some(date) = &output.date {
+        tags.push(("date", date.clone()));
+    }
+    if !output.genres.is_empty() {
+        tags.push(("genre", output.genres.join("; ")));
+    }
+    if !output.composers.is_empty() {
+        tags.push(("composer", output.composers.join("; ")));
+    }
+    let optional = [
+        (keys.original_date, &output.original_date),
+        (keys.label, &output.label),
+        (keys.catalog_number, &output.catalog_number),
+        (keys.barcode, &output.barcode),
+        (keys.isrc, &output.isrc),
+    ];
+    for (key, value) in optional {
+        if let (Some(key), Some(value)) = (key, value) {
+            tags.push((key, value.clone()));
+        }
+    }
+    tags
+}
+
+/// Size and modification time (in nanoseconds since the Unix epoch) of a file
+fn output_stat(p: &Path) -> std::io::Result<(u64, u64)> {
+    let metadata = std::fs::metadata(p)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    Ok((metadata.len(), modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::planner::MAX_COMPONENT_BYTES;
+    use crate::resolver::ResolvedGroupKind;
+
+    /// Writing an executable while another test forks can make running it fail with ETXTBSY,
+    /// so tests that write or run a stub ffmpeg take turns
+    static FFMPEG: Mutex<()> = Mutex::new(());
+
+    /// A stub ffmpeg that copies its input to its output and appends the output's file name to `calls`.
+    /// If `fail` is set, it writes part of the output and then fails instead.
+    fn stub_ffmpeg(dir: &Path, name: &str, fail: bool) -> PathBuf {
+        let finish = if fail {
+            "printf partial > \"$out\"\necho 'Conversion failed!' >&2\nexit 1"
+        } else {
+            "cp \"$in\" \"$out\""
+        };
+        let script = format!(
+            "#!/bin/sh\nwhile [ $# -gt 1 ]; do\n  [ \"$1\" = -i ] && in=$2\n  shift\ndone\nout=$1\n\
+             basename \"$out\" >> \"{}\"\n{finish}\n",
+            dir.join("calls").display()
+        );
+        let path = dir.join(name);
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn calls(dir: &Path) -> Vec<String> {
+        std::fs::read_to_string(dir.join("calls"))
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    fn config(bitrate: &str) -> OutputConfig {
+        OutputConfig {
+            path: "out".to_owned(),
+            codec: "libmp3lame".to_owned(),
+            bitrate: Some(bitrate.to_owned()),
+            extension: "mp3".to_owned(),
+            ffmpeg: None,
+            quarantine: None,
+        }
+    }
+
+    /// An album with a song for each input file, planned to `<file stem>.mp3`
+    fn album(inputs: &[PathBuf]) -> (Vec<ResolvedGroup>, OutputPlan) {
+        let songs = inputs
+            .iter()
+            .map(|input_path| ResolvedSong {
+                input_path: input_path.clone(),
+                output: metadata::song::Output {
+                    song_title: "Song".to_owned(),
+                    ..Default::default()
+                },
+                provenance: Default::default(),
+                duration: None,
+            })
+            .collect();
+        let paths = inputs
+            .iter()
+            .map(|p| PathBuf::from(p.file_stem().unwrap()).with_extension("mp3"))
+            .collect();
+        let group = ResolvedGroup {
+            path: PathBuf::from("album"),
+            kind: ResolvedGroupKind::Album,
+            songs,
+        };
+        let plan = OutputPlan {
+            groups: vec![paths],
+            playlists: vec![None],
+        };
+        (vec![group], plan)
+    }
+
+    struct Fixture {
+        dir: tempfile::TempDir,
+        inputs: Vec<PathBuf>,
+        output_root: PathBuf,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let inputs = ["a", "b"]
+                .map(|name| {
+                    let path = dir.path().join(format!("{name}.flac"));
+                    std::fs::write(&path, name).unwrap();
+                    path
+                })
+                .to_vec();
+            let output_root = dir.path().join("out");
+            Self {
+                dir,
+                inputs,
+                output_root,
+            }
+        }
+
+        fn render(&self, ffmpeg: &Path, config: &OutputConfig) -> RenderSummary {
+            let (groups, plan) = album(&self.inputs);
+            let mut renderer =
+                Renderer::new(ffmpeg.to_owned(), self.output_root.clone(), config).unwrap();
+            renderer
+                .render(&groups, &plan, &FileHashes::default())
+                .unwrap()
+        }
+
+        fn calls(&self) -> Vec<String> {
+            calls(self.dir.path())
+        }
+    }
+
+    #[test]
+    fn unchanged_jobs_are_skipped() {
+        let _lock = FFMPEG.lock().unwrap();
+        let f = Fixture::new();
+        let ffmpeg = stub_ffmpeg(f.dir.path(), "ffmpeg", false);
+
+        let summary = f.render(&ffmpeg, &config("320k"));
+        assert_eq!(summary.rendered, [Path::new("a.mp3"), Path::new("b.mp3")]);
+        assert_eq!(summary.skipped, 0);
+        assert!(summary.failures.is_empty());
+        assert_eq!(
+            std::fs::read_to_string(f.output_root.join("a.mp3")).unwrap(),
+            "a"
+        );
+
+        let summary = f.render(&ffmpeg, &config("320k"));
+        assert!(summary.rendered.is_empty());
+        assert_eq!(summary.skipped, 2);
+        assert_eq!(f.calls().len(), 2);
+    }
+
+    #[test]
+    fn changed_inputs_and_settings_are_rendered_again() {
+        let _lock = FFMPEG.lock().unwrap();
+        let f = Fixture::new();
+        let ffmpeg = stub_ffmpeg(f.dir.path(), "ffmpeg", false);
+        f.render(&ffmpeg, &config("320k"));
+
+        std::fs::write(&f.inputs[0], "changed").unwrap();
+        let summary = f.render(&ffmpeg, &config("320k"));
+        assert_eq!(summary.rendered, [Path::new("a.mp3")]);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(
+            std::fs::read_to_string(f.output_root.join("a.mp3")).unwrap(),
+            "changed"
+        );
+
+        let summary = f.render(&ffmpeg, &config("192k"));
+        assert_eq!(summary.rendered.len(), 2);
+        assert_eq!(summary.skipped, 0);
+
+        // An output that something else touched is rendered again too
+        std::fs::write(f.output_root.join("b.mp3"), "edited").unwrap();
+        let summary = f.render(&ffmpeg, &config("192k"));
+        assert_eq!(summary.rendered, [Path::new("b.mp3")]);
+        assert_eq!(f.calls().len(), 6);
+    }
+
+    #[test]
+    fn failed_renders_are_left_out_of_the_job_cache() {
+        let _lock = FFMPEG.lock().unwrap();
+        let f = Fixture::new();
+        let ffmpeg = stub_ffmpeg(f.dir.path(), "ffmpeg", false);
+        let broken = stub_ffmpeg(f.dir.path(), "broken-ffmpeg", true);
+        f.render(&ffmpeg, &config("320k"));
+
+        // Settings changed, so both are rendered again and both fail
+        let summary = f.render(&broken, &config("192k"));
+        assert!(summary.rendered.is_empty());
+        assert_eq!(summary.failures.len(), 2);
+        assert!(
+            summary.failures[0]
+                .error
+                .to_string()
+                .contains("Conversion failed!")
+        );
+        // The previous outputs are kept, and no partial file is left behind
+        assert_eq!(
+            std::fs::read_to_string(f.output_root.join("a.mp3")).unwrap(),
+            "a"
+        );
+        let mut files = std::fs::read_dir(&f.output_root)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["a.mp3", "b.mp3", JOB_CACHE_FILE_NAME]);
+        assert!(JobCacheFile::load(&f.output_root).unwrap().jobs.is_empty());
+
+        // Nothing was recorded for the failures, so they're retried even with the old settings
+        let summary = f.render(&ffmpeg, &config("320k"));
+        assert_eq!(summary.rendered.len(), 2);
+        assert_eq!(JobCacheFile::load(&f.output_root).unwrap().jobs.len(), 2);
+    }
+
+    #[test]
+    fn unreadable_inputs_fail_without_running_ffmpeg() {
+        let _lock = FFMPEG.lock().unwrap();
+        let f = Fixture::new();
+        let ffmpeg = stub_ffmpeg(f.dir.path(), "ffmpeg", false);
+        f.render(&ffmpeg, &config("320k"));
+
+        std::fs::remove_file(&f.inputs[1]).unwrap();
+        let summary = f.render(&ffmpeg, &config("320k"));
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.failures.len(), 1);
+        assert_eq!(summary.failures[0].input_path, f.inputs[1]);
+        assert_eq!(f.calls().len(), 2);
+        let jobs = JobCacheFile::load(&f.output_root).unwrap().jobs;
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].output_rel_path, "a.mp3");
+    }
+
+    #[test]
+    fn names_as_long_as_the_planner_allows_are_rendered() {
+        let _lock = FFMPEG.lock().unwrap();
+        let f = Fixture::new();
+        let ffmpeg = stub_ffmpeg(f.dir.path(), "ffmpeg", false);
+        let (groups, mut plan) = album(&f.inputs);
+        let long_name = format!("{}.mp3", "a".repeat(MAX_COMPONENT_BYTES - ".mp3".len()));
+        plan.groups[0][0] = PathBuf::from(&long_name);
+
+        let mut renderer = Renderer::new(ffmpeg, f.output_root.clone(), &config("320k")).unwrap();
+        let summary = renderer
+            .render(&groups, &plan, &FileHashes::default())
+            .unwrap();
+        assert!(summary.failures.is_empty());
+        assert_eq!(
+            std::fs::read_to_string(f.output_root.join(&long_name)).unwrap(),
+            "a"
+        );
+    }
+
+    /// A song with every field set, so every key shows up
+    fn full_output() -> metadata::song::Output {
+        metadata::song::Output {
+            song_title: "Song".to_owned(),
+            song_artists: vec!["A".to_owned(), "B".to_owned()],
+            album_title: Some("Album".to_owned()),
+            album_artists: vec!["A".to_owned()],
+            disc_idx: Some(1),
+            track_idx: Some(2),
+            song_title_sort: Some("Song, The".to_owned()),
+            song_artists_sort: vec!["A, The".to_owned(), "B".to_owned()],
+            album_title_sort: Some("Album, The".to_owned()),
+            album_artists_sort: vec!["A, The".to_owned()],
+            date: Some("2001-05-03".to_owned()),
+            original_date: Some("1999".to_owned()),
+            genres: vec!["rock".to_owned(), "pop".to_owned()],
+            label: Some("Label".to_owned()),
+            catalog_number: Some("CAT-1".to_owned()),
+            barcode: Some("0123456789012".to_owned()),
+            isrc: Some("GBAAA0100001".to_owned()),
+            composers: vec!["C".to_owned()],
+        }
+    }
+
+    fn tags_for(ext: &str) -> Vec<(&'static str, String)> {
+        metadata_tags(&full_output(), &ContainerKeys::for_extension(ext))
+    }
+
+    /// The keys every container shares, which FFmpeg maps itself
+    const COMMON_TAGS: [(&str, &str); 9] = [
+        ("title", "Song"),
+        ("artist", "A; B"),
+        ("album", "Album"),
+        ("album_artist", "A"),
+        ("disc", "1"),
+        ("track", "2"),
+        ("date", "2001-05-03"),
+        ("genre", "rock; pop"),
+        ("composer", "C"),
+    ];
+
+    fn assert_tags(ext: &str, specific: &[(&str, &str)]) {
+        let mut expected = COMMON_TAGS
+            .iter()
+            .chain(specific)
+            .map(|&(k, v)| (k, v.to_owned()))
+            .collect::<Vec<_>>();
+        expected.sort();
+        let mut tags = tags_for(ext);
+        tags.sort();
+        assert_eq!(tags, expected, "{ext}");
+    }
+
+    #[test]
+    fn mp3_tags_use_id3_frames() {
+        assert_tags(
+            "mp3",
+            &[
+                ("title-sort", "Song, The"),
+                ("artist-sort", "A, The; B"),
+                ("album-sort", "Album, The"),
+                ("ALBUMARTISTSORT", "A, The"),
+                ("TDOR", "1999"),
+                ("publisher", "Label"),
+                ("CATALOGNUMBER", "CAT-1"),
+                ("BARCODE", "0123456789012"),
+                ("TSRC", "GBAAA0100001"),
+            ],
+        );
+    }
+
+    #[test]
+    fn mp4_tags_only_use_atoms_ffmpeg_knows() {
+        for ext in ["m4a", "M4A", "m4b", "mp4"] {
+            assert_tags(
+                ext,
+                &[
+                    ("sort_name", "Song, The"),
+                    ("sort_artist", "A, The; B"),
+                    ("sort_album", "Album, The"),
+                    ("sort_album_artist", "A, The"),
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn other_tags_use_vorbis_comments() {
+        for ext in ["flac", "ogg", "opus"] {
+            assert_tags(
+                ext,
+                &[
+                    ("TITLESORT", "Song, The"),
+                    ("ARTISTSORT", "A, The; B"),
+                    ("ALBUMSORT", "Album, The"),
+                    ("ALBUMARTISTSORT", "A, The"),
+                    ("ORIGINALDATE", "1999"),
+                    ("LABEL", "Label"),
+                    ("CATALOGNUMBER", "CAT-1"),
+                    ("BARCODE", "0123456789012"),
+                    ("ISRC", "GBAAA0100001"),
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn missing_fields_have_no_tags() {
+        let output = metadata::song::Output {
+            song_title: "Song".to_owned(),
+            ..Default::default()
+        };
+        for ext in ["mp3", "m4a", "flac"] {
+            let tags = metadata_tags(&output, &ContainerKeys::for_extension(ext));
+            assert_eq!(tags, [("title", "Song".to_owned())]);
+        }
+    }
+}
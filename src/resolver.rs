@@ -0,0 +1,633 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::MetadataDeriver;
+use crate::data_model::metadata::{self, CachedArtist, MetadataLayer};
+use crate::data_model::user_defined::ConfigFile;
+use crate::data_model::{AlbumInputGroup, CompilationInputGroup, MbId};
+use crate::scanner::Group;
+
+pub struct ResolvedSong {
+    /// Absolute path to the source music file
+    pub input_path: PathBuf,
+    pub output: metadata::song::Output,
+    pub provenance: metadata::song::OutputProvenance,
+    /// Length of the source music file, if known
+    pub duration: Option<Duration>,
+}
+
+pub enum ResolvedGroupKind {
+    Album,
+    Compilation { title: String },
+}
+
+pub struct ResolvedGroup {
+    /// Path to the group folder
+    pub path: PathBuf,
+    pub kind: ResolvedGroupKind,
+    /// Songs in the order of the group i.e. alphanumeric for albums, and the final compilation order for compilations
+    pub songs: Vec<ResolvedSong>,
+}
+
+/// Layers the metadata for each Song in a Group: native tags, then cached metadata, then the group overrides.
+pub struct Resolver {
+    artist_name_overrides: HashMap<MbId, String>,
+}
+
+impl Resolver {
+    pub fn new(config: &ConfigFile) -> Self {
+        Self {
+            artist_name_overrides: config
+                .artist_name_overrides
+                .iter()
+                .map(|o| (o.artist_id.clone(), o.artist_name.clone()))
+                .collect(),
+        }
+    }
+
+    /// Fill in the derived metadata sources and cached metadata for a group,
+    /// preferring anything the deriver has stored over deriving/fetching it again.
+    /// Returns an error if the cached metadata doesn't fit the group, e.g. songs running past the end of the release.
+    pub async fn find_missing_metadata<D: MetadataDeriver + Send + Sync + ?Sized>(
+        &self,
+        group: &mut Group,
+        deriver: &mut D,
+    ) -> anyhow::Result<()> {
+        match group {
+            Group::PartialAlbum(album, path) => {
+                let derived = match deriver.get_derived_album(path, album) {
+                    Some(derived) => Some(derived),
+                    None => deriver.try_rederive_album(path, album).await,
+                };
+                let cached = match &derived {
+                    Some(src) => match deriver.get_cached_album(path, src) {
+                        Some(cached) => Some(cached),
+                        None => deriver.try_recache_album(path, src).await,
+                    },
+                    None => None,
+                };
+                album.set_derived_metadata(derived);
+                album.set_cached_metadata(cached)
+            }
+            Group::Compilation(compilation, path) => {
+                for song in compilation.songs_mut() {
+                    let derived = match deriver.get_derived_compilation_song(path, song) {
+                        Some(derived) => Some(derived),
+                        None => deriver.try_rederive_compilation_song(path, song).await,
+                    };
+                    let cached = match &derived {
+                        Some(src) => match deriver.get_cached_compilation_song(path, song, src) {
+                            Some(cached) => Some(cached),
+                            None => deriver.try_recache_compilation_song(path, song, src).await,
+                        },
+                        None => None,
+                    };
+                    song.set_derived_metadata_src(derived);
+                    song.set_cached_metadata(cached);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn resolve(&self, group: &Group) -> ResolvedGroup {
+        match group {
+            Group::PartialAlbum(album, path) => ResolvedGroup {
+                path: path.clone(),
+                kind: ResolvedGroupKind::Album,
+                songs: self.resolve_album(album, path),
+            },
+            Group::Compilation(compilation, path) => ResolvedGroup {
+                path: path.clone(),
+                kind: ResolvedGroupKind::Compilation {
+                    title: compilation.title().to_owned(),
+                },
+                songs: self.resolve_compilation(compilation, path),
+            },
+        }
+    }
+
+    fn resolve_album(&self, album: &AlbumInputGroup, path: &Path) -> Vec<ResolvedSong> {
+        let album_override = album.override_metadata();
+        let cached_album = album.cached_metadata();
+
+        album
+            .songs()
+            .iter()
+            .enumerate()
+            .map(|(idx, song)| {
+                let native = song.native_metadata();
+                let cached = album.cached_song_metadata(idx);
+                let song_override = song.override_metadata();
+
+                let (song_title, song_title_layer) = layered_text(
+                    &native.name,
+                    cached.map(|c| c.song_title.clone()),
+                    song_override.and_then(|o| o.song_title.clone()),
+                    file_stem(song.file()),
+                );
+                let (album_title, album_title_layer) = layered_text(
+                    &native.album,
+                    cached_album.map(|c| c.title.clone()),
+                    album_override.and_then(|o| o.album_title.clone()),
+                    path.file_name()
+                        .map(|s| s.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                );
+                let (cached_song_artists, cached_song_artists_sort) =
+                    self.cached_artist_names(cached.map(|c| c.song_artists.as_slice()));
+                let (song_artists, song_artists_layer) = layered_list(
+                    &native.artist,
+                    cached_song_artists,
+                    song_override.and_then(|o| o.song_artists.clone()),
+                );
+                let (song_artists_sort, song_artists_sort_layer) = layered_sort_list(
+                    &native.artist_sort,
+                    cached_song_artists_sort,
+                    song_override.and_then(|o| o.song_artists_sort.clone()),
+                    song_artists_layer,
+                );
+                let (cached_album_artists, cached_album_artists_sort) =
+                    self.cached_artist_names(cached_album.map(|c| c.artists.as_slice()));
+                let (album_artists, album_artists_layer) = layered_list(
+                    &native.album_artists,
+                    cached_album_artists,
+                    album_override.and_then(|o| o.album_artists.clone()),
+                );
+                let (album_artists_sort, album_artists_sort_layer) = layered_sort_list(
+                    &native.album_artists_sort,
+                    cached_album_artists_sort,
+                    album_override.and_then(|o| o.album_artists_sort.clone()),
+                    album_artists_layer,
+                );
+                let (song_title_sort, song_title_sort_layer) = layered_sort_text(
+                    &native.name_sort,
+                    song_override.and_then(|o| o.song_title_sort.clone()),
+                    song_title_layer,
+                );
+                let (album_title_sort, album_title_sort_layer) = layered_sort_text(
+                    &native.album_sort,
+                    album_override.and_then(|o| o.album_title_sort.clone()),
+                    album_title_layer,
+                );
+
+                let (date, date_layer) = layered_opt_text(
+                    &native.date,
+                    cached_album.and_then(|c| c.date.clone()),
+                    album_override.and_then(|o| o.date.clone()),
+                );
+                let (original_date, original_date_layer) = layered_opt_text(
+                    &native.original_date,
+                    cached_album.and_then(|c| c.original_date.clone()),
+                    album_override.and_then(|o| o.original_date.clone()),
+                );
+                let (genres, genres_layer) = layered_list(
+                    &native.genres,
+                    cached_album.and_then(|c| non_empty_vec(&c.genres)),
+                    album_override.and_then(|o| o.genres.clone()),
+                );
+                let (label, label_layer) = layered_opt_text(
+                    &native.label,
+                    cached_album.and_then(|c| c.label.clone()),
+                    album_override.and_then(|o| o.label.clone()),
+                );
+                let (catalog_number, catalog_number_layer) = layered_opt_text(
+                    &native.catalog_number,
+                    cached_album.and_then(|c| c.catalog_number.clone()),
+                    album_override.and_then(|o| o.catalog_number.clone()),
+                );
+                let (barcode, barcode_layer) = layered_opt_text(
+                    &native.barcode,
+                    cached_album.and_then(|c| c.barcode.clone()),
+                    album_override.and_then(|o| o.barcode.clone()),
+                );
+                let (isrc, isrc_layer) = layered_opt_text(
+                    &native.isrc,
+                    cached.and_then(|c| c.isrc.clone()),
+                    song_override.and_then(|o| o.isrc.clone()),
+                );
+                let (composers, composers_layer) = layered_list(
+                    &native.composers,
+                    cached.and_then(|c| non_empty_vec(&c.composers)),
+                    song_override.and_then(|o| o.composers.clone()),
+                );
+
+                ResolvedSong {
+                    input_path: path.join(song.file()),
+                    output: metadata::song::Output {
+                        song_title,
+                        song_artists,
+                        album_title: Some(album_title),
+                        album_artists,
+                        disc_idx: Some(song.disc_idx()),
+                        track_idx: Some(song.track_idx()),
+                        song_title_sort,
+                        song_artists_sort,
+                        album_title_sort,
+                        album_artists_sort,
+                        date,
+                        original_date,
+                        genres,
+                        label,
+                        catalog_number,
+                        barcode,
+                        isrc,
+                        composers,
+                    },
+                    provenance: metadata::song::OutputProvenance {
+                        song_title: song_title_layer,
+                        song_artists: song_artists_layer,
+                        album_title: album_title_layer,
+                        album_artists: album_artists_layer,
+                        disc_idx: song.disc_track_idx_layer(),
+                        track_idx: song.disc_track_idx_layer(),
+                        song_title_sort: song_title_sort_layer,
+                        song_artists_sort: song_artists_sort_layer,
+                        album_title_sort: album_title_sort_layer,
+                        album_artists_sort: album_artists_sort_layer,
+                        date: date_layer,
+                        original_date: original_date_layer,
+                        genres: genres_layer,
+                        label: label_layer,
+                        catalog_number: catalog_number_layer,
+                        barcode: barcode_layer,
+                        isrc: isrc_layer,
+                        composers: composers_layer,
+                    },
+                    duration: native.duration,
+                }
+            })
+            .collect()
+    }
+
+    fn resolve_compilation(
+        &self,
+        compilation: &CompilationInputGroup,
+        path: &Path,
+    ) -> Vec<ResolvedSong> {
+        compilation
+            .songs()
+            .iter()
+            .map(|song| {
+                let native = song.native_metadata();
+                let cached = song.cached_metadata();
+                let song_override = song.override_metadata();
+
+                let (song_title, song_title_layer) = layered_text(
+                    &native.name,
+                    cached.map(|c| c.song_title.clone()),
+                    song_override.and_then(|o| o.song_title.clone()),
+                    file_stem(song.file()),
+                );
+                let (cached_song_artists, cached_song_artists_sort) =
+                    self.cached_artist_names(cached.map(|c| c.song_artists.as_slice()));
+                let (song_artists, song_artists_layer) = layered_list(
+                    &native.artist,
+                    cached_song_artists,
+                    song_override.and_then(|o| o.song_artists.clone()),
+                );
+                let (song_artists_sort, song_artists_sort_layer) = layered_sort_list(
+                    &native.artist_sort,
+                    cached_song_artists_sort,
+                    song_override.and_then(|o| o.song_artists_sort.clone()),
+                    song_artists_layer,
+                );
+                let (song_title_sort, song_title_sort_layer) = layered_sort_text(
+                    &native.name_sort,
+                    song_override.and_then(|o| o.song_title_sort.clone()),
+                    song_title_layer,
+                );
+
+                // The song keeps the album information of wherever it originally came from,
+                // which only its native tags know about
+                let (album_title, album_title_layer) = layered_opt_text(&native.album, None, None);
+                let (album_artists, album_artists_layer) =
+                    layered_list(&native.album_artists, None, None);
+                let (album_artists_sort, album_artists_sort_layer) =
+                    layered_sort_list(&native.album_artists_sort, None, None, album_artists_layer);
+                let (album_title_sort, album_title_sort_layer) =
+                    layered_sort_text(&native.album_sort, None, album_title_layer);
+                let (date, date_layer) = layered_opt_text(&native.date, None, None);
+                let (original_date, original_date_layer) =
+                    layered_opt_text(&native.original_date, None, None);
+                let (genres, genres_layer) = layered_list(&native.genres, None, None);
+                let (label, label_layer) = layered_opt_text(&native.label, None, None);
+                let (catalog_number, catalog_number_layer) =
+                    layered_opt_text(&native.catalog_number, None, None);
+                let (barcode, barcode_layer) = layered_opt_text(&native.barcode, None, None);
+
+                let (isrc, isrc_layer) = layered_opt_text(
+                    &native.isrc,
+                    cached.and_then(|c| c.isrc.clone()),
+                    song_override.and_then(|o| o.isrc.clone()),
+                );
+                let (composers, composers_layer) = layered_list(
+                    &native.composers,
+                    cached.and_then(|c| non_empty_vec(&c.composers)),
+                    song_override.and_then(|o| o.composers.clone()),
+                );
+                let (disc_idx, disc_idx_layer) =
+                    layered([(MetadataLayer::Native, native.disc_idx.map(Some))], None);
+                let (track_idx, track_idx_layer) =
+                    layered([(MetadataLayer::Native, native.track_idx.map(Some))], None);
+
+                ResolvedSong {
+                    input_path: path.join(song.file()),
+                    output: metadata::song::Output {
+                        song_title,
+                        song_artists,
+                        album_title,
+                        album_artists,
+                        disc_idx,
+                        track_idx,
+                        song_title_sort,
+                        song_artists_sort,
+                        album_title_sort,
+                        album_artists_sort,
+                        date,
+                        original_date,
+                        genres,
+                        label,
+                        catalog_number,
+                        barcode,
+                        isrc,
+                        composers,
+                    },
+                    provenance: metadata::song::OutputProvenance {
+                        song_title: song_title_layer,
+                        song_artists: song_artists_layer,
+                        album_title: album_title_layer,
+                        album_artists: album_artists_layer,
+                        disc_idx: disc_idx_layer,
+                        track_idx: track_idx_layer,
+                        song_title_sort: song_title_sort_layer,
+                        song_artists_sort: song_artists_sort_layer,
+                        album_title_sort: album_title_sort_layer,
+                        album_artists_sort: album_artists_sort_layer,
+                        date: date_layer,
+                        original_date: original_date_layer,
+                        genres: genres_layer,
+                        label: label_layer,
+                        catalog_number: catalog_number_layer,
+                        barcode: barcode_layer,
+                        isrc: isrc_layer,
+                        composers: composers_layer,
+                    },
+                    duration: native.duration,
+                }
+            })
+            .collect()
+    }
+
+    /// Names and sort names of cached artists, after applying the library-wide renamings.
+    /// Sort names are None if any are missing.
+    /// Renamed artists sort by their new name, as their MusicBrainz sort name no longer applies.
+    fn cached_artist_names(
+        &self,
+        artists: Option<&[CachedArtist]>,
+    ) -> (Option<Vec<String>>, Option<Vec<String>>) {
+        let Some(artists) = artists else {
+            return (None, None);
+        };
+        let (names, sort_names): (Vec<_>, Vec<_>) = artists
+            .iter()
+            .map(|a| match self.artist_name_overrides.get(&a.id) {
+                Some(name) => (name.clone(), Some(name.clone())),
+                None => (a.name.clone(), a.sort_name.clone()),
+            })
+            .unzip();
+        (Some(names), sort_names.into_iter().collect())
+    }
+}
+
+/// Layer a text field which always has a value, from the native tag (if not empty), cached metadata, then override.
+/// Uses the fallback if no layer supplies it.
+fn layered_text(
+    native: &Option<String>,
+    cached: Option<String>,
+    overridden: Option<String>,
+    fallback: String,
+) -> (String, MetadataLayer) {
+    layered(
+        [
+            (MetadataLayer::Native, non_empty_str(native)),
+            (MetadataLayer::Cached, cached),
+            (MetadataLayer::Override, overridden),
+        ],
+        fallback,
+    )
+}
+
+/// Like [layered_text], for a field which is left unset if no layer supplies it
+fn layered_opt_text(
+    native: &Option<String>,
+    cached: Option<String>,
+    overridden: Option<String>,
+) -> (Option<String>, MetadataLayer) {
+    layered(
+        [
+            (MetadataLayer::Native, non_empty_str(native).map(Some)),
+            (MetadataLayer::Cached, cached.map(Some)),
+            (MetadataLayer::Override, overridden.map(Some)),
+        ],
+        None,
+    )
+}
+
+/// Like [layered_text], for a list field which is left empty if no layer supplies it.
+/// An empty native list counts as not supplying it.
+fn layered_list(
+    native: &[String],
+    cached: Option<Vec<String>>,
+    overridden: Option<Vec<String>>,
+) -> (Vec<String>, MetadataLayer) {
+    layered(
+        [
+            (MetadataLayer::Native, non_empty_vec(native)),
+            (MetadataLayer::Cached, cached),
+            (MetadataLayer::Override, overridden),
+        ],
+        vec![],
+    )
+}
+
+/// The sort order of a text field supplied by `field_layer`.
+/// Cached metadata doesn't have sort orders for text fields, only artists.
+fn layered_sort_text(
+    native: &Option<String>,
+    overridden: Option<String>,
+    field_layer: MetadataLayer,
+) -> (Option<String>, MetadataLayer) {
+    layered_sort(
+        [
+            (MetadataLayer::Native, non_empty_str(native).map(Some)),
+            (MetadataLayer::Override, overridden.map(Some)),
+        ],
+        field_layer,
+        None,
+    )
+}
+
+/// The sort order of a list field supplied by `field_layer`
+fn layered_sort_list(
+    native: &[String],
+    cached: Option<Vec<String>>,
+    overridden: Option<Vec<String>>,
+    field_layer: MetadataLayer,
+) -> (Vec<String>, MetadataLayer) {
+    layered_sort(
+        [
+            (MetadataLayer::Native, non_empty_vec(native)),
+            (MetadataLayer::Cached, cached),
+            (MetadataLayer::Override, overridden),
+        ],
+        field_layer,
+        vec![],
+    )
+}
+
+/// Take the sort order of a field supplied by `field_layer` from the highest layer that has one.
+/// Layers below `field_layer` are ignored, because they describe a value that was overridden.
+fn layered_sort<T, const N: usize>(
+    layers: [(MetadataLayer, Option<T>); N],
+    field_layer: MetadataLayer,
+    fallback: T,
+) -> (T, MetadataLayer) {
+    layers
+        .into_iter()
+        .filter(|(layer, _)| *layer >= field_layer)
+        .filter_map(|(layer, value)| value.map(|v| (v, layer)))
+        .next_back()
+        .unwrap_or((fallback, MetadataLayer::Fallback))
+}
+
+/// Take the value from the highest layer that has one, or the fallback if none do.
+fn layered<T, const N: usize>(
+    layers: [(MetadataLayer, Option<T>); N],
+    fallback: T,
+) -> (T, MetadataLayer) {
+    layers
+        .into_iter()
+        .filter_map(|(layer, value)| value.map(|v| (v, layer)))
+        .next_back()
+        .unwrap_or((fallback, MetadataLayer::Fallback))
+}
+
+fn non_empty_str(s: &Option<String>) -> Option<String> {
+    s.as_ref().filter(|s| !s.is_empty()).cloned()
+}
+
+fn non_empty_vec(v: &[String]) -> Option<Vec<String>> {
+    if v.is_empty() { None } else { Some(v.to_vec()) }
+}
+
+fn file_stem(p: &Path) -> String {
+    p.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strs(values: &[&str]) -> Vec<String> {
+        values.iter().map(|&s| s.to_owned()).collect()
+    }
+
+    #[test]
+    fn higher_layers_win() {
+        let native = Some("native".to_owned());
+        assert_eq!(
+            layered_text(&native, Some("cached".to_owned()), None, "file".to_owned()),
+            ("cached".to_owned(), MetadataLayer::Cached)
+        );
+        assert_eq!(
+            layered_opt_text(
+                &native,
+                Some("cached".to_owned()),
+                Some("override".to_owned())
+            ),
+            (Some("override".to_owned()), MetadataLayer::Override)
+        );
+        assert_eq!(
+            layered_list(&strs(&["native"]), None, None),
+            (strs(&["native"]), MetadataLayer::Native)
+        );
+    }
+
+    #[test]
+    fn empty_native_tags_fall_through() {
+        assert_eq!(
+            layered_text(&Some(String::new()), None, None, "file".to_owned()),
+            ("file".to_owned(), MetadataLayer::Fallback)
+        );
+        assert_eq!(
+            layered_opt_text(&Some(String::new()), None, None),
+            (None, MetadataLayer::Fallback)
+        );
+        assert_eq!(
+            layered_list(&[], None, None),
+            (vec![], MetadataLayer::Fallback)
+        );
+    }
+
+    #[test]
+    fn sort_orders_below_the_field_are_ignored() {
+        // The native sort name describes the native title, which the override replaced
+        let native_sort = Some("native, The".to_owned());
+        assert_eq!(
+            layered_sort_text(&native_sort, None, MetadataLayer::Override),
+            (None, MetadataLayer::Fallback)
+        );
+        assert_eq!(
+            layered_sort_text(&native_sort, None, MetadataLayer::Native),
+            (native_sort.clone(), MetadataLayer::Native)
+        );
+        assert_eq!(
+            layered_sort_list(
+                &strs(&["native"]),
+                Some(strs(&["cached"])),
+                None,
+                MetadataLayer::Cached
+            ),
+            (strs(&["cached"]), MetadataLayer::Cached)
+        );
+    }
+
+    #[test]
+    fn cached_artists_are_renamed() {
+        let resolver = Resolver {
+            artist_name_overrides: HashMap::from([(MbId::new("renamed"), "New Name".to_owned())]),
+        };
+        let artist = |id: &str, name: &str, sort_name: Option<&str>| CachedArtist {
+            id: MbId::new(id),
+            name: name.to_owned(),
+            sort_name: sort_name.map(str::to_owned),
+        };
+
+        let artists = [
+            artist("kept", "The Band", Some("Band, The")),
+            artist("renamed", "Old Name", Some("Name, Old")),
+        ];
+        assert_eq!(
+            resolver.cached_artist_names(Some(&artists)),
+            (
+                Some(strs(&["The Band", "New Name"])),
+                Some(strs(&["Band, The", "New Name"]))
+            )
+        );
+
+        // One missing sort name means the list has no sort order
+        let artists = [
+            artist("kept", "The Band", Some("Band, The")),
+            artist("unsorted", "Someone", None),
+        ];
+        assert_eq!(
+            resolver.cached_artist_names(Some(&artists)),
+            (Some(strs(&["The Band", "Someone"])), None)
+        );
+        assert_eq!(resolver.cached_artist_names(None), (None, None));
+    }
+}
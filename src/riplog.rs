@@ -0,0 +1,633 @@
+//! Reading the logs [EAC](https://www.exactaudiocopy.de/) and [XLD](https://tmkk.undo.jp/xld/index_e.html) write when ripping a CD,
+//! which record the disc's TOC and how well each track was ripped.
+//!
+//! A rip is trusted according to, in order of preference,
+//! - [AccurateRip](http://www.accuraterip.com/), which compares each track's checksum against other people's rips of the same disc,
+//! - the CRCs of a test read and the real read, which should match if the drive read the same data twice,
+//! - the errors and suspicious positions the ripper reported.
+//!
+//! Only English logs are understood.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+
+use anyhow::Context;
+use regex::Regex;
+
+use crate::discid::Toc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ripper {
+    Eac,
+    Xld,
+}
+
+impl Display for Ripper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Ripper::Eac => write!(f, "EAC"),
+            Ripper::Xld => write!(f, "XLD"),
+        }
+    }
+}
+
+/// What AccurateRip said about a track. Ordered from least to most informative,
+/// so the results of AccurateRip v1 and v2 can be combined by taking the greatest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AccurateRip {
+    NotPresent,
+    /// The checksum didn't match any other rip of the disc
+    Inaccurate,
+    /// The checksum matched this many other rips of the disc
+    Accurate {
+        confidence: u32,
+    },
+}
+
+/// One extraction in the log, either a track or (for a range rip) the whole disc
+#[derive(Debug, Clone, Default)]
+pub struct Extraction {
+    /// None for a range rip
+    pub track: Option<u8>,
+    pub test_crc: Option<u32>,
+    pub copy_crc: Option<u32>,
+    /// Read errors and suspicious positions the ripper reported
+    pub errors: Vec<String>,
+}
+
+/// How far a rip can be trusted, from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RipQuality {
+    /// Every track matched other rips in the AccurateRip database
+    Accurate,
+    /// Every track either matched AccurateRip or read the same twice
+    Consistent,
+    /// Nothing went wrong, but nothing confirms the rip either
+    Unverified,
+    /// The ripper reported errors, a test read didn't match its copy, or a track didn't match AccurateRip
+    Suspect,
+}
+
+impl Display for RipQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RipQuality::Accurate => write!(f, "accurate"),
+            RipQuality::Consistent => write!(f, "consistent"),
+            RipQuality::Unverified => write!(f, "unverified"),
+            RipQuality::Suspect => write!(f, "suspect"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RipLog {
+    pub ripper: Ripper,
+    pub toc: Toc,
+    pub extractions: Vec<Extraction>,
+    /// By track number. Tracks AccurateRip wasn't checked for are missing.
+    pub accuraterip: BTreeMap<u8, AccurateRip>,
+}
+
+impl Ripper {
+    /// Which ripper wrote a log, if it was EAC or XLD
+    pub fn detect(log: &str) -> Option<Self> {
+        if log.contains("Exact Audio Copy") || log.contains("EAC extraction logfile") {
+            Some(Ripper::Eac)
+        } else if log.contains("X Lossless Decoder") {
+            Some(Ripper::Xld)
+        } else {
+            None
+        }
+    }
+}
+
+impl RipLog {
+    /// Read a rip log, or None if the file is some other kind of log
+    pub fn read(path: &Path) -> anyhow::Result<Option<Self>> {
+        let text = read_log_text(path)?;
+        if Ripper::detect(&text).is_none() {
+            return Ok(None);
+        }
+        Self::parse(&text).map(Some)
+    }
+
+    pub fn parse(log: &str) -> anyhow::Result<Self> {
+        let ripper = Ripper::detect(log).context("it isn't an EAC or XLD rip log")?;
+        let toc = Toc::from_rip_log(log).context("couldn't read the TOC")?;
+
+        // Each extraction starts with an unindented header, and its details are indented below it
+        let track_header = Regex::new(r"^Track\s+(\d+)\s*$").expect("valid regex");
+        // e.g. EAC's `Track  1  accurately ripped (confidence 5)  [ABCD1234]  (AR v2)`
+        let accuraterip_summary = Regex::new(r"^Track\s+(\d+)\s+(\S.*)$").expect("valid regex");
+        // EAC writes `Test CRC 1234ABCD`, XLD writes `CRC32 hash (test run)  : 1234ABCD`
+        let test_crc = Regex::new(r"^(?:Test CRC|CRC32 hash \(test run\)\s*:)\s*([0-9A-Fa-f]{8})$")
+            .expect("valid regex");
+        let copy_crc =
+            Regex::new(r"^(?:Copy CRC|CRC32 hash\s*:)\s*([0-9A-Fa-f]{8})$").expect("valid regex");
+        let xld_error = Regex::new(
+            r"^(Read error|Skipped \(treated as error\)|Damaged sector count|Inconsistency in error sectors)\s*:\s*(\d+)$",
+        )
+        .expect("valid regex");
+
+        let mut extractions = vec![];
+        let mut accuraterip = BTreeMap::new();
+        let mut in_extraction = false;
+        for line in log.lines() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            if !line.starts_with(char::is_whitespace) {
+                in_extraction = true;
+                if let Some(captures) = track_header.captures(trimmed) {
+                    extractions.push(Extraction {
+                        track: Some(captures[1].parse().context("malformed track number")?),
+                        ..Default::default()
+                    });
+                } else if trimmed == "Selected range" {
+                    extractions.push(Extraction::default());
+                } else {
+                    in_extraction = false;
+                    if let Some(captures) = accuraterip_summary.captures(trimmed)
+                        && let Some(result) = parse_accuraterip(&captures[2])
+                    {
+                        let track = captures[1].parse().context("malformed track number")?;
+                        merge_accuraterip(&mut accuraterip, track, result);
+                    }
+                }
+                continue;
+            }
+            let Some(extraction) = extractions.last_mut().filter(|_| in_extraction) else {
+                continue;
+            };
+
+            if let Some(captures) = test_crc.captures(trimmed) {
+                extraction.test_crc = u32::from_str_radix(&captures[1], 16).ok();
+            } else if let Some(captures) = copy_crc.captures(trimmed) {
+                extraction.copy_crc = u32::from_str_radix(&captures[1], 16).ok();
+            } else if let Some(captures) = xld_error.captures(trimmed) {
+                if &captures[2] != "0" {
+                    extraction
+                        .errors
+                        .push(format!("{}: {}", &captures[1], &captures[2]));
+                }
+            } else if ["Suspicious position", "Missing samples", "Timing problem"]
+                .iter()
+                .any(|p| trimmed.starts_with(p))
+            {
+                extraction.errors.push(trimmed.to_owned());
+            } else if trimmed.starts_with("List of suspicious positions") {
+                extraction.errors.push("suspicious positions".to_owned());
+            } else if trimmed == "Copy aborted" {
+                extraction.errors.push("the copy was aborted".to_owned());
+            } else if let Some(track) = extraction.track
+                && let Some(result) = parse_accuraterip(trimmed)
+            {
+                merge_accuraterip(&mut accuraterip, track, result);
+            }
+        }
+
+        Ok(Self {
+            ripper,
+            toc,
+            extractions,
+            accuraterip,
+        })
+    }
+
+    /// Everything that makes the rip [RipQuality::Suspect]
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for extraction in &self.extractions {
+            let name = match extraction.track {
+                Some(track) => format!("track {track}"),
+                None => "the range".to_owned(),
+            };
+            for error in &extraction.errors {
+                problems.push(format!("{name}: {error}"));
+            }
+            if let (Some(test), Some(copy)) = (extraction.test_crc, extraction.copy_crc)
+                && test != copy
+            {
+                problems.push(format!(
+                    "{name}: the test CRC {test:08X} doesn't match the copy CRC {copy:08X}"
+                ));
+            }
+        }
+        for (track, result) in &self.accuraterip {
+            if *result == AccurateRip::Inaccurate {
+                problems.push(format!("track {track}: AccurateRip couldn't verify it"));
+            }
+        }
+        problems
+    }
+
+    pub fn quality(&self) -> RipQuality {
+        if !self.problems().is_empty() {
+            return RipQuality::Suspect;
+        }
+        let accurate = |track: u8| {
+            matches!(
+                self.accuraterip.get(&track),
+                Some(AccurateRip::Accurate { .. })
+            )
+        };
+        if self.toc.track_numbers().all(accurate) {
+            return RipQuality::Accurate;
+        }
+        let consistent = |e: &Extraction| {
+            e.track.is_some_and(accurate) || (e.test_crc.is_some() && e.test_crc == e.copy_crc)
+        };
+        if !self.extractions.is_empty() && self.extractions.iter().all(consistent) {
+            RipQuality::Consistent
+        } else {
+            RipQuality::Unverified
+        }
+    }
+}
+
+/// An AccurateRip result line, e.g. `Accurately ripped (confidence 5)` from EAC or
+/// `->Accurately ripped (v1+v2, confidence 5+3/8)` from XLD
+fn parse_accuraterip(line: &str) -> Option<AccurateRip> {
+    let line = line.to_ascii_lowercase();
+    if line.contains("accurately ripped") {
+        // XLD adds the v1 and v2 confidences together, out of the total number of rips
+        let confidence = line
+            .split_once("confidence ")
+            .map(|(_, rest)| {
+                rest.split(['/', ')', ' ', ']'])
+                    .next()
+                    .unwrap_or_default()
+                    .split('+')
+                    .filter_map(|n| n.parse::<u32>().ok())
+                    .sum()
+            })
+            .unwrap_or(0);
+        Some(AccurateRip::Accurate { confidence })
+    } else if line.contains("cannot be verified as accurate")
+        || line.contains("may not be accurate")
+    {
+        Some(AccurateRip::Inaccurate)
+    } else if line.contains("not present") {
+        Some(AccurateRip::NotPresent)
+    } else {
+        None
+    }
+}
+
+/// The same track can have several results, e.g. one for each AccurateRip version or in both EAC's track details and summary
+fn merge_accuraterip(results: &mut BTreeMap<u8, AccurateRip>, track: u8, result: AccurateRip) {
+    let entry = results.entry(track).or_insert(result);
+    *entry = (*entry).max(result);
+}
+
+/// Read a rip log, which EAC writes as UTF-16 and XLD as UTF-8
+fn read_log_text(path: &Path) -> anyhow::Result<String> {
+    let bytes = std::fs::read(path)?;
+    let utf16 = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units = bytes
+            .chunks_exact(2)
+            .map(|b| from_bytes([b[0], b[1]]))
+            .collect::<Vec<_>>();
+        String::from_utf16_lossy(&units)
+    };
+    Ok(match bytes.as_slice() {
+        [0xff, 0xfe, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        [0xfe, 0xff, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        [0xef, 0xbb, 0xbf, rest @ ..] => String::from_utf8_lossy(rest).into_owned(),
+        _ => String::from_utf8_lossy(&bytes).into_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An EAC log with a clean track, and a track whose test and copy CRCs differ
+    const EAC_LOG: &str = "Exact Audio Copy V1.6 from 23. November 2020
+
+EAC extraction logfile from 14. March 2024, 20:41
+
+The Band / The Album
+
+Used drive  : HL-DT-STBD-RE  WH16NS40   Adapter: 1  ID: 0
+
+Read mode               : Secure
+Utilize accurate stream : Yes
+Defeat audio cache      : Yes
+Make use of C2 pointers : No
+
+Read offset correction                      : 6
+Gap handling                                : Appended to previous track
+
+TOC of the extracted CD
+
+     Track |   Start  |  Length  | Start sector | End sector 
+    ---------------------------------------------------------
+        1  |  0:00.00 |  4:24.50 |         0    |    19849   
+        2  |  4:24.50 |  4:26.50 |     19850    |    39849   
+
+
+Track  1
+
+     Filename C:\\Music\\The Band - The Album\\01 - Opening.wav
+
+     Pre-gap length  0:00:02.00
+
+     Peak level 98.8 %
+     Extraction speed 6.3 X
+     Track quality 100.0 %
+     Test CRC 1A2B3C4D
+     Copy CRC 1A2B3C4D
+     Cannot be verified as accurate  (confidence 2)  [0A1B2C3D], AccurateRip returned [FFEEDDCC]  (AR v1)
+     Accurately ripped (confidence 12)  [9F8E7D6C]  (AR v2)
+     Copy OK
+
+Track  2
+
+     Filename C:\\Music\\The Band - The Album\\02 - Closing.wav
+
+     Peak level 100.0 %
+     Extraction speed 2.1 X
+     Track quality 97.2 %
+     Test CRC 11223344
+     Copy CRC 55667788
+     Track not present in AccurateRip database
+     Copy finished
+
+1 track(s) accurately ripped
+1 track(s) not present in the AccurateRip database
+
+Some tracks could not be verified as accurate
+
+There were errors
+
+End of status report
+
+==== Log checksum 0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF ====
+";
+
+    /// An EAC range rip, whose AccurateRip results are only in the summary
+    const EAC_RANGE_LOG: &str = "Exact Audio Copy V1.6 from 23. November 2020
+
+EAC extraction logfile from 14. March 2024, 21:02
+
+The Band / The Album
+
+TOC of the extracted CD
+
+     Track |   Start  |  Length  | Start sector | End sector 
+    ---------------------------------------------------------
+        1  |  0:00.00 |  4:24.50 |         0    |    19849   
+        2  |  4:24.50 |  4:26.50 |     19850    |    39849   
+
+
+Range status and errors
+
+Selected range
+
+     Filename C:\\Music\\The Band - The Album\\Range.wav
+
+     Peak level 100.0 %
+     Extraction speed 7.4 X
+     Range quality 100.0 %
+     Test CRC 9A8B7C6D
+     Copy CRC 9A8B7C6D
+     Copy OK
+
+No errors occurred
+
+
+AccurateRip summary
+
+Track  1  accurately ripped (confidence 7)  [12AB34CD]  (AR v2)
+Track  2  accurately ripped (confidence 6)  [56EF78AB]  (AR v2)
+
+All tracks accurately ripped
+
+End of status report
+";
+
+    /// An XLD log whose first track matched both AccurateRip versions, and whose second had a read error
+    const XLD_LOG: &str = "X Lossless Decoder version 20230627 (157.2)
+
+XLD extraction logfile from 2024-03-14 20:41:00 +0100
+
+The Band / The Album
+
+Used drive : HL-DT-ST BD-RE WH16NS40 (revision 1.05)
+Use cdparanoia mode     : YES (CDParanoia III 10.2 engine)
+
+TOC of the extracted CD
+     Track |   Start  |  Length  | Start sector | End sector
+    ---------------------------------------------------------
+        1  | 00:00:00 | 04:24:50 |         0    |    19849
+        2  | 04:24:50 | 04:26:50 |     19850    |    39849
+
+AccurateRip Summary (DiscID: 0002a5e1-000dc2bc-1a0b2c02)
+    Track 01 : OK (A1 confidence 3, A2 confidence 5)
+    Track 02 : NG
+        ->0 tracks accurately ripped, 1 track not
+
+Track 01
+    Filename : /Users/me/Music/The Band - The Album/01 Opening.flac
+    Pre-gap length : 00:02:00
+
+    CRC32 hash (test run)  : 1A2B3C4D
+    CRC32 hash             : 1A2B3C4D
+    CRC32 hash (skip zero) : 0F0F0F0F
+    AccurateRip v1 signature : ABCDEF01
+        ->Accurately ripped (v1+v2, confidence 3+5/8)
+    AccurateRip v2 signature : 12345678
+        ->Accurately ripped (v2, confidence 5/8)
+    Statistics
+        Read error                           : 0
+        Jitter error (maybe fixed)           : 0
+        Retry sector count                   : 0
+        Damaged sector count                 : 0
+
+Track 02
+    Filename : /Users/me/Music/The Band - The Album/02 Closing.flac
+
+    CRC32 hash             : 55667788
+    CRC32 hash (skip zero) : 0A0A0A0A
+    AccurateRip v1 signature : 11111111
+    AccurateRip v2 signature : 22222222
+        ->Rip may not be accurate.
+    Statistics
+        Read error                           : 2
+        Jitter error (maybe fixed)           : 0
+        Retry sector count                   : 40
+        Damaged sector count                 : 2
+
+No errors occurred
+
+End of status report
+";
+
+    fn toc() -> Toc {
+        Toc::new(1, vec![150, 20000], 40000, None).unwrap()
+    }
+
+    /// Write `text` as UTF-16 with a byte order mark, the way EAC does
+    fn utf16_le(text: &str) -> Vec<u8> {
+        let mut bytes = vec![0xff, 0xfe];
+        for unit in text.encode_utf16() {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn eac_log_is_read_from_utf16() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("The Album.log");
+        std::fs::write(&path, utf16_le(EAC_LOG)).unwrap();
+        let log = RipLog::read(&path).unwrap().unwrap();
+        assert_eq!(log.ripper, Ripper::Eac);
+        assert_eq!(log.toc, toc());
+        assert_eq!(log.extractions.len(), 2);
+    }
+
+    #[test]
+    fn eac_accuraterip_versions_are_merged() {
+        let log = RipLog::parse(EAC_LOG).unwrap();
+        // v1 didn't match, but v2 did
+        assert_eq!(
+            log.accuraterip,
+            BTreeMap::from([
+                (1, AccurateRip::Accurate { confidence: 12 }),
+                (2, AccurateRip::NotPresent),
+            ])
+        );
+    }
+
+    #[test]
+    fn eac_crc_mismatch_is_suspect() {
+        let log = RipLog::parse(EAC_LOG).unwrap();
+        assert_eq!(log.extractions[0].test_crc, Some(0x1A2B3C4D));
+        assert_eq!(log.extractions[0].copy_crc, Some(0x1A2B3C4D));
+        assert_eq!(
+            log.problems(),
+            ["track 2: the test CRC 11223344 doesn't match the copy CRC 55667788"]
+        );
+        assert_eq!(log.quality(), RipQuality::Suspect);
+
+        // Without the mismatch, track 1 is accurate and track 2 only read the same twice
+        let log =
+            RipLog::parse(&EAC_LOG.replace("Copy CRC 55667788", "Copy CRC 11223344")).unwrap();
+        assert!(log.problems().is_empty());
+        assert_eq!(log.quality(), RipQuality::Consistent);
+    }
+
+    #[test]
+    fn eac_range_rip_uses_the_summary() {
+        let log = RipLog::parse(EAC_RANGE_LOG).unwrap();
+        assert_eq!(log.extractions.len(), 1);
+        assert_eq!(log.extractions[0].track, None);
+        assert_eq!(log.extractions[0].test_crc, Some(0x9A8B7C6D));
+        assert_eq!(
+            log.accuraterip,
+            BTreeMap::from([
+                (1, AccurateRip::Accurate { confidence: 7 }),
+                (2, AccurateRip::Accurate { confidence: 6 }),
+            ])
+        );
+        assert_eq!(log.quality(), RipQuality::Accurate);
+
+        // Without AccurateRip, the matching CRCs of the range still make it consistent
+        let end = EAC_RANGE_LOG.find("AccurateRip summary").unwrap();
+        let log = RipLog::parse(&EAC_RANGE_LOG[..end]).unwrap();
+        assert!(log.accuraterip.is_empty());
+        assert_eq!(log.quality(), RipQuality::Consistent);
+    }
+
+    #[test]
+    fn xld_log_is_parsed() {
+        let log = RipLog::parse(XLD_LOG).unwrap();
+        assert_eq!(log.ripper, Ripper::Xld);
+        assert_eq!(log.toc, toc());
+        assert_eq!(log.extractions.len(), 2);
+        assert_eq!(log.extractions[0].test_crc, Some(0x1A2B3C4D));
+        assert_eq!(log.extractions[0].copy_crc, Some(0x1A2B3C4D));
+        assert_eq!(log.extractions[1].test_crc, None);
+        assert_eq!(log.extractions[1].copy_crc, Some(0x55667788));
+        // The v1 and v2 confidences are added together, and the v2-only line doesn't lower them
+        assert_eq!(
+            log.accuraterip,
+            BTreeMap::from([
+                (1, AccurateRip::Accurate { confidence: 8 }),
+                (2, AccurateRip::Inaccurate),
+            ])
+        );
+        assert_eq!(
+            log.problems(),
+            [
+                "track 2: Read error: 2",
+                "track 2: Damaged sector count: 2",
+                "track 2: AccurateRip couldn't verify it",
+            ]
+        );
+        assert_eq!(log.quality(), RipQuality::Suspect);
+    }
+
+    #[test]
+    fn xld_log_without_problems_is_accurate() {
+        let log = XLD_LOG
+            .replace(
+                "Read error                           : 2",
+                "Read error                           : 0",
+            )
+            .replace(
+                "Damaged sector count                 : 2",
+                "Damaged sector count                 : 0",
+            )
+            .replace(
+                "->Rip may not be accurate.",
+                "->Accurately ripped (v2, confidence 4/4)",
+            );
+        let log = RipLog::parse(&log).unwrap();
+        assert!(log.problems().is_empty());
+        assert_eq!(log.quality(), RipQuality::Accurate);
+    }
+
+    #[test]
+    fn other_logs_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("install.log");
+        std::fs::write(&path, "2024-03-14 20:41 Installed 3 packages\n").unwrap();
+        assert!(RipLog::read(&path).unwrap().is_none());
+        assert!(RipLog::parse("2024-03-14 20:41 Installed 3 packages\n").is_err());
+    }
+
+    #[test]
+    fn rip_log_without_a_toc_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rip.log");
+        let end = EAC_LOG.find("TOC of the extracted CD").unwrap();
+        std::fs::write(&path, &EAC_LOG[..end]).unwrap();
+        assert!(RipLog::read(&path).is_err());
+    }
+
+    #[test]
+    fn accuraterip_lines_are_parsed() {
+        assert_eq!(
+            parse_accuraterip("Accurately ripped (confidence 5)  [ABCD1234]  (AR v2)"),
+            Some(AccurateRip::Accurate { confidence: 5 })
+        );
+        assert_eq!(
+            parse_accuraterip("->Accurately ripped (v1+v2, confidence 5+3/8)"),
+            Some(AccurateRip::Accurate { confidence: 8 })
+        );
+        assert_eq!(
+            parse_accuraterip("->Rip may not be accurate."),
+            Some(AccurateRip::Inaccurate)
+        );
+        assert_eq!(
+            parse_accuraterip("Track not present in AccurateRip database"),
+            Some(AccurateRip::NotPresent)
+        );
+        assert_eq!(parse_accuraterip("Copy OK"), None);
+    }
+}
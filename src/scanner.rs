@@ -1,19 +1,81 @@
-use crate::data_model::native_metadata::NATIVE_MUSIC_EXTS;
+use crate::data_model::native_metadata::{NATIVE_MUSIC_EXTS, NativeMetadataError};
 use crate::data_model::{AlbumInputGroup, CompilationInputGroup, user_defined};
 use std::collections::HashSet;
 use std::ffi::{OsStr, OsString};
-use std::path::PathBuf;
-const GROUP_FILE_NAME: &'static str = "music.tm2.toml";
+use std::path::{Path, PathBuf};
+pub const GROUP_FILE_NAME: &str = "music.tm2.toml";
 
 pub enum Group {
-    PartialAlbum(AlbumInputGroup, PathBuf),
-    Compilation(CompilationInputGroup, PathBuf),
+    PartialAlbum(Box<AlbumInputGroup>, PathBuf),
+    Compilation(Box<CompilationInputGroup>, PathBuf),
 }
 
-pub fn scan_library(root_path: PathBuf) -> anyhow::Result<Vec<Group>> {
+impl Group {
+    /// Path to the folder containing this group's `music.tm2.toml`, which all song paths are relative to
+    pub fn path(&self) -> &Path {
+        match self {
+            Group::PartialAlbum(_, path) => path,
+            Group::Compilation(_, path) => path,
+        }
+    }
+
+    pub fn num_songs(&self) -> usize {
+        match self {
+            Group::PartialAlbum(album, _) => album.num_songs(),
+            Group::Compilation(compilation, _) => compilation.num_songs(),
+        }
+    }
+
+    /// Problems with the group that don't stop it being used
+    pub fn warnings(&self) -> &[String] {
+        match self {
+            Group::PartialAlbum(album, _) => album.warnings(),
+            Group::Compilation(compilation, _) => compilation.warnings(),
+        }
+    }
+
+    /// Songs whose native metadata couldn't be read
+    pub fn tag_errors(&self) -> &[NativeMetadataError] {
+        match self {
+            Group::PartialAlbum(album, _) => album.tag_errors(),
+            Group::Compilation(compilation, _) => compilation.tag_errors(),
+        }
+    }
+}
+
+/// A group which was found but couldn't be scanned, e.g. because its `music.tm2.toml` was malformed
+/// or it referred to songs that don't exist.
+pub struct GroupScanFailure {
+    /// Either the group folder or the `music.tm2.toml` file, whichever is most relevant to the error.
+    pub path: PathBuf,
+    pub error: anyhow::Error,
+}
+
+/// The result of scanning a library.
+/// Failures in individual groups don't stop the rest of the library from being scanned.
+#[derive(Default)]
+pub struct ScanResult {
+    pub groups: Vec<Group>,
+    pub failures: Vec<GroupScanFailure>,
+}
+
+impl ScanResult {
+    pub fn has_failures(&self) -> bool {
+        !self.failures.is_empty()
+    }
+
+    /// Every song in every group whose native metadata couldn't be read.
+    /// The songs are still in their groups, so these don't count as failures.
+    pub fn tag_errors(&self) -> Vec<&NativeMetadataError> {
+        self.groups.iter().flat_map(Group::tag_errors).collect()
+    }
+}
+
+pub fn scan_library(root_path: PathBuf) -> anyhow::Result<ScanResult> {
     let mut scan_stack = vec![root_path];
     let group_file_name = OsStr::new(GROUP_FILE_NAME);
     let mut groups = vec![];
+    let mut result = ScanResult::default();
 
     while let Some(dir) = scan_stack.pop() {
         let mut files = vec![];
@@ -28,25 +90,30 @@ pub fn scan_library(root_path: PathBuf) -> anyhow::Result<Vec<Group>> {
                 dirs.push(path);
             } else if path.is_file() {
                 if path.file_name() == Some(group_file_name) {
-                    group = Some((user_defined::GroupFile::from_file(&path)?, path));
+                    group = Some((user_defined::GroupFile::from_file(&path), path));
                 } else {
                     files.push(path);
                 }
             }
         }
 
-        if let Some((group, path)) = group {
-            groups.push((path, group, dirs, files));
-        } else {
-            scan_stack.extend(dirs);
+        match group {
+            Some((Ok(group), _)) => groups.push((dir, group, dirs, files)),
+            // Don't recurse into a group we couldn't parse, the songs inside it still belong to that group.
+            Some((Err(error), path)) => result.failures.push(GroupScanFailure { path, error }),
+            None => scan_stack.extend(dirs),
         }
     }
 
     // TODO par_iter here?
-    groups
-        .into_iter()
-        .map(|(path, group, dirs, files)| scan_group(path, group, dirs, files))
-        .collect::<anyhow::Result<Vec<_>>>()
+    for (path, group, dirs, files) in groups {
+        match scan_group(path.clone(), group, dirs, files) {
+            Ok(group) => result.groups.push(group),
+            Err(error) => result.failures.push(GroupScanFailure { path, error }),
+        }
+    }
+
+    Ok(result)
 }
 
 fn scan_group(
@@ -58,6 +125,8 @@ fn scan_group(
     let mut scan_stack = root_dirs;
     // TODO have to include path-relative-to-root_dirs
     let mut music_files: Vec<PathBuf> = vec![];
+    // Only rip logs directly inside the group are used, logs in subfolders are usually for other discs
+    let mut rip_log_files: Vec<PathBuf> = vec![];
     let scan_exts: HashSet<OsString> = group.scan_filter().map_or_else(
         || NATIVE_MUSIC_EXTS.iter().map(|s| s.into()).collect(),
         |scan_filter| scan_filter.ext_filters.iter().map(|s| s.into()).collect(),
@@ -67,6 +136,8 @@ fn scan_group(
         if let Some(ext) = path.extension() {
             if scan_exts.contains(ext) {
                 music_files.push(path);
+            } else if ext.eq_ignore_ascii_case("log") {
+                rip_log_files.push(path);
             }
         }
     }
@@ -78,12 +149,11 @@ fn scan_group(
 
             if path.is_dir() {
                 scan_stack.push(path);
-            } else if path.is_file() {
-                if let Some(ext) = path.extension() {
-                    if scan_exts.contains(ext) {
-                        music_files.push(path);
-                    }
-                }
+            } else if path.is_file()
+                && let Some(ext) = path.extension()
+                && scan_exts.contains(ext)
+            {
+                music_files.push(path);
             }
         }
     }
@@ -95,7 +165,14 @@ fn scan_group(
             title,
             songs,
         } => Ok(Group::Compilation(
-            CompilationInputGroup::new(&root_path, origin, scan_filter, title, songs, music_files),
+            Box::new(CompilationInputGroup::new(
+                &root_path,
+                origin,
+                scan_filter,
+                title,
+                songs,
+                music_files,
+            )?),
             root_path,
         )),
         user_defined::GroupFile::Album {
@@ -105,15 +182,16 @@ fn scan_group(
             override_metadata,
             songs,
         } => Ok(Group::PartialAlbum(
-            AlbumInputGroup::new(
+            Box::new(AlbumInputGroup::new(
                 &root_path,
                 origin,
-                override_metadata,
+                override_metadata.map(|o| *o),
                 scan_filter,
                 album_art_rel_path,
                 songs,
                 music_files,
-            ),
+                rip_log_files,
+            )?),
             root_path,
         )),
     }
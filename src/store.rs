@@ -0,0 +1,844 @@
+//! Persistent storage for derived metadata sources and cached metadata,
+//! so that re-running over a library doesn't have to hit the network again.
+//!
+//! Each group gets a `music.tm2.cache.toml` next to its `music.tm2.toml`.
+//! Unlike the group file this is entirely tool-controlled, and can be deleted at any time to force a re-lookup.
+//! Every entry records what it was derived from and when, so stale entries are looked up again automatically.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::MetadataDeriver;
+use crate::data_model::user_defined::{CacheConfig, Origin};
+use crate::data_model::{
+    AlbumInputGroup, Chromaprint, CompilationInputSong, FileHash, MbId, metadata,
+};
+
+pub const GROUP_CACHE_FILE_NAME: &str = "music.tm2.cache.toml";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct GroupCacheFile {
+    pub album: Option<AlbumCacheEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub compilation_songs: Vec<CompilationSongCacheEntry>,
+    /// Audio fingerprints of the group's songs, see [crate::fingerprint]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fingerprints: Vec<FingerprintCacheEntry>,
+}
+
+/// The derived metadata source and cached metadata for an album group
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AlbumCacheEntry {
+    /// The Origin `derived` was derived from
+    pub origin: Origin,
+    /// The input files `derived` was derived from, in the order of the group's songs
+    pub input_hashes: Vec<InputFileHash>,
+    /// Unix timestamp in seconds
+    pub derived_at: u64,
+    /// None if nothing was found, which isn't looked up again until the entry is stale
+    pub derived: Option<metadata::album::DerivedMetadataSource>,
+    /// Cached metadata, looked up from exactly `derived`
+    pub cached: Option<Fetched<metadata::album::Cached>>,
+}
+
+/// The derived metadata source and cached metadata for a song in a compilation group
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CompilationSongCacheEntry {
+    /// '/' coded path relative to the group
+    pub file_rel_path: String,
+    /// The song's origin MBID that `derived` was derived from
+    pub origin_mbid: Option<MbId>,
+    /// The input file `derived` was derived from
+    pub input_hash: FileHash,
+    /// Unix timestamp in seconds
+    pub derived_at: u64,
+    /// None if nothing was found, which isn't looked up again until the entry is stale
+    pub derived: Option<metadata::song::CompilationDerivedMetadataSource>,
+    /// Cached metadata, looked up from exactly `derived`
+    pub cached: Option<Fetched<metadata::song::Cached>>,
+}
+
+/// The audio fingerprint of an input file.
+/// Looked up by the hash of the file, so it's only computed again when the file's contents change.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FingerprintCacheEntry {
+    pub input_hash: FileHash,
+    pub chromaprint: Chromaprint,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InputFileHash {
+    /// '/' coded path relative to the group
+    pub file_rel_path: String,
+    pub hash: FileHash,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Fetched<T> {
+    /// Unix timestamp in seconds
+    pub fetched_at: u64,
+    pub metadata: T,
+}
+
+impl GroupCacheFile {
+    pub fn path_for_group(group_path: &Path) -> PathBuf {
+        group_path.join(GROUP_CACHE_FILE_NAME)
+    }
+
+    /// Load the cache file for a group, or an empty cache if it doesn't exist yet
+    pub fn load(group_path: &Path) -> anyhow::Result<GroupCacheFile> {
+        let p = Self::path_for_group(group_path);
+        if !p.exists() {
+            return Ok(GroupCacheFile::default());
+        }
+        let document = std::fs::read_to_string(&p)?.parse::<toml_edit::DocumentMut>()?;
+        let file = toml_edit::de::from_document(document)
+            .with_context(|| format!("couldn't parse {}", p.display()))?;
+        Ok(file)
+    }
+
+    pub fn save(&self, group_path: &Path) -> anyhow::Result<()> {
+        let p = Self::path_for_group(group_path);
+        std::fs::write(&p, toml_edit::ser::to_string_pretty(self)?)
+            .with_context(|| format!("couldn't write {}", p.display()))?;
+        Ok(())
+    }
+
+    fn compilation_song(&self, song: &CompilationInputSong) -> Option<&CompilationSongCacheEntry> {
+        let file_rel_path = rel_path_string(song.file());
+        self.compilation_songs
+            .iter()
+            .find(|s| s.file_rel_path == file_rel_path)
+    }
+
+    fn compilation_song_mut(
+        &mut self,
+        song: &CompilationInputSong,
+    ) -> Option<&mut CompilationSongCacheEntry> {
+        let file_rel_path = rel_path_string(song.file());
+        self.compilation_songs
+            .iter_mut()
+            .find(|s| s.file_rel_path == file_rel_path)
+    }
+}
+
+/// '/' coded, so the stored path is the same on every platform
+pub(crate) fn rel_path_string(p: &Path) -> String {
+    p.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Unix timestamp in seconds
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Hashes of every input file needed so far, so each file is only hashed once per run.
+/// Shared by [StoredDeriver], [crate::fingerprint::Fingerprinter] and [crate::render::Renderer],
+/// which all look entries up by file hash.
+#[derive(Default)]
+pub struct FileHashes(Mutex<HashMap<PathBuf, FileHash>>);
+
+impl FileHashes {
+    pub fn of_file(&self, path: &Path) -> anyhow::Result<FileHash> {
+        let mut hashes = self.0.lock().expect("hashes mutex poisoned");
+        if let Some(hash) = hashes.get(path) {
+            return Ok(hash.clone());
+        }
+        let hash =
+            FileHash::of_file(path).with_context(|| format!("couldn't hash {}", path.display()))?;
+        hashes.insert(path.to_owned(), hash.clone());
+        Ok(hash)
+    }
+}
+
+/// A [MetadataDeriver] which answers the `get_*` hooks from each group's [GroupCacheFile],
+/// and stores whatever the inner deriver finds in the `try_*` hooks.
+///
+/// Lookups which find nothing are stored too, so they aren't repeated on every run.
+/// Lookups which fail (see [MetadataDeriver::num_failed_lookups]) aren't, so they're tried again next run.
+/// Stored entries are ignored, and so looked up again, if
+/// - the derived metadata source was derived from a different Origin or different input files
+/// - the cached metadata was looked up from a different derived metadata source
+/// - they are older than the maximum age
+pub struct StoredDeriver<D: MetadataDeriver> {
+    inner: D,
+    max_age: Option<Duration>,
+    hashes: Arc<FileHashes>,
+    /// If set, cache files are only read, e.g. when we're offline and the inner deriver can't find anything
+    read_only: bool,
+    /// Cache files that couldn't be read or written, and input files that couldn't be hashed.
+    /// They don't stop the rest of the library from resolving, but should be reported.
+    errors: Vec<anyhow::Error>,
+}
+
+impl<D: MetadataDeriver> StoredDeriver<D> {
+    /// `hashes` should be shared with everything else that hashes input files this run
+    pub fn new(inner: D, config: &CacheConfig, hashes: Arc<FileHashes>) -> Self {
+        Self {
+            inner,
+            max_age: config
+                .max_age_days
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            hashes,
+            read_only: false,
+            errors: vec![],
+        }
+    }
+
+    /// A deriver which never writes to the cache files, so whatever the inner deriver fails to find
+    /// is looked up again by the next run that doesn't use this
+    pub fn read_only(inner: D, config: &CacheConfig, hashes: Arc<FileHashes>) -> Self {
+        Self {
+            read_only: true,
+            ..Self::new(inner, config, hashes)
+        }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    /// Whether a compilation song has a fresh stored entry, even one that found nothing,
+    /// and so won't be looked up again
+    pub fn is_compilation_song_fresh(
+        &self,
+        compilation_path: &Path,
+        song: &CompilationInputSong,
+    ) -> bool {
+        self.fresh_compilation_song_entry(compilation_path, song)
+            .is_some()
+    }
+
+    /// Take every storage error encountered so far
+    pub fn take_errors(&mut self) -> Vec<anyhow::Error> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Load, modify, and save back a group's cache file, unless we're read-only
+    fn update(&mut self, group_path: &Path, f: impl FnOnce(&mut GroupCacheFile)) {
+        if self.read_only {
+            return;
+        }
+        let result = GroupCacheFile::load(group_path).and_then(|mut file| {
+            f(&mut file);
+            file.save(group_path)
+        });
+        if let Err(err) = result {
+            self.errors.push(err);
+        }
+    }
+
+    fn is_fresh(&self, timestamp: u64) -> bool {
+        match self.max_age {
+            Some(max_age) => now().saturating_sub(timestamp) <= max_age.as_secs(),
+            None => true,
+        }
+    }
+
+    fn hash(&self, group_path: &Path, file_rel_path: &Path) -> anyhow::Result<InputFileHash> {
+        Ok(InputFileHash {
+            file_rel_path: rel_path_string(file_rel_path),
+            hash: self.hashes.of_file(&group_path.join(file_rel_path))?,
+        })
+    }
+
+    /// The stored album entry, if it was derived from the album as it is now and isn't too old
+    fn fresh_album_entry(
+        &self,
+        album_path: &Path,
+        album: &AlbumInputGroup,
+    ) -> Option<AlbumCacheEntry> {
+        let entry = load_quietly(album_path).album?;
+        let current_hashes = self.album_hashes(album_path, album).ok()?;
+        (entry.origin == *album.origin()
+            && entry.input_hashes == current_hashes
+            && self.is_fresh(entry.derived_at))
+        .then_some(entry)
+    }
+
+    /// The stored entry for a compilation song, if it was derived from the song as it is now and isn't too old
+    fn fresh_compilation_song_entry(
+        &self,
+        compilation_path: &Path,
+        song: &CompilationInputSong,
+    ) -> Option<CompilationSongCacheEntry> {
+        let mut file = load_quietly(compilation_path);
+        let idx = file
+            .compilation_songs
+            .iter()
+            .position(|s| s.file_rel_path == rel_path_string(song.file()))?;
+        let entry = file.compilation_songs.swap_remove(idx);
+        let current_hash = self.hash(compilation_path, song.file()).ok()?;
+        (entry.origin_mbid.as_ref() == song.origin_mbid()
+            && entry.input_hash == current_hash.hash
+            && self.is_fresh(entry.derived_at))
+        .then_some(entry)
+    }
+
+    fn album_hashes(
+        &self,
+        album_path: &Path,
+        album: &AlbumInputGroup,
+    ) -> anyhow::Result<Vec<InputFileHash>> {
+        album
+            .songs()
+            .iter()
+            .map(|s| self.hash(album_path, s.file()))
+            .collect()
+    }
+}
+
+/// Read a group's cache file for one of the `get_*` hooks, which can't record errors.
+/// A broken cache file is treated as empty, and will be reported when we try to store the result of the lookup.
+fn load_quietly(group_path: &Path) -> GroupCacheFile {
+    GroupCacheFile::load(group_path).unwrap_or_default()
+}
+
+#[async_trait]
+impl<D: MetadataDeriver + Send + Sync> MetadataDeriver for StoredDeriver<D> {
+    fn get_derived_album(
+        &self,
+        album_path: &Path,
+        album: &AlbumInputGroup,
+    ) -> Option<metadata::album::DerivedMetadataSource> {
+        self.fresh_album_entry(album_path, album)?.derived
+    }
+
+    async fn try_rederive_album(
+        &mut self,
+        album_path: &Path,
+        album: &AlbumInputGroup,
+    ) -> Option<metadata::album::DerivedMetadataSource> {
+        // Nothing was found last time, and nothing has changed since
+        if self.fresh_album_entry(album_path, album).is_some() {
+            return None;
+        }
+        let failed_before = self.inner.num_failed_lookups();
+        let derived = self.inner.try_rederive_album(album_path, album).await;
+        if derived.is_none() && self.inner.num_failed_lookups() > failed_before {
+            return None;
+        }
+        match self.album_hashes(album_path, album) {
+            Ok(input_hashes) => self.update(album_path, |file| {
+                file.album = Some(AlbumCacheEntry {
+                    origin: album.origin().clone(),
+                    input_hashes,
+                    derived_at: now(),
+                    derived: derived.clone(),
+                    cached: None,
+                })
+            }),
+            Err(err) => self.errors.push(err),
+        }
+        derived
+    }
+
+    fn get_cached_album(
+        &self,
+        album_path: &Path,
+        src: &metadata::album::DerivedMetadataSource,
+    ) -> Option<metadata::album::Cached> {
+        let entry = load_quietly(album_path).album?;
+        if entry.derived.as_ref() != Some(src) {
+            return None;
+        }
+        entry
+            .cached
+            .filter(|c| self.is_fresh(c.fetched_at))
+            .map(|c| c.metadata)
+    }
+
+    async fn try_recache_album(
+        &mut self,
+        album_path: &Path,
+        src: &metadata::album::DerivedMetadataSource,
+    ) -> Option<metadata::album::Cached> {
+        let cached = self.inner.try_recache_album(album_path, src).await?;
+        self.update(album_path, |file| {
+            // Only store the cached metadata alongside the source it was looked up from.
+            // If that source wasn't stored, e.g. because the input files couldn't be hashed, neither is this.
+            if let Some(entry) = &mut file.album
+                && entry.derived.as_ref() == Some(src)
+            {
+                entry.cached = Some(Fetched {
+                    fetched_at: now(),
+                    metadata: cached.clone(),
+                });
+            }
+        });
+        Some(cached)
+    }
+
+    fn get_derived_compilation_song(
+        &self,
+        compilation_path: &Path,
+        song: &CompilationInputSong,
+    ) -> Option<metadata::song::CompilationDerivedMetadataSource> {
+        self.fresh_compilation_song_entry(compilation_path, song)?
+            .derived
+    }
+
+    async fn try_rederive_compilation_song(
+        &mut self,
+        compilation_path: &Path,
+        song: &CompilationInputSong,
+    ) -> Option<metadata::song::CompilationDerivedMetadataSource> {
+        // Nothing was found last time, and nothing has changed since
+        if self
+            .fresh_compilation_song_entry(compilation_path, song)
+            .is_some()
+        {
+            return None;
+        }
+        let failed_before = self.inner.num_failed_lookups();
+        let derived = self
+            .inner
+            .try_rederive_compilation_song(compilation_path, song)
+            .await;
+        if derived.is_none() && self.inner.num_failed_lookups() > failed_before {
+            return None;
+        }
+        match self.hash(compilation_path, song.file()) {
+            Ok(input_hash) => self.update(compilation_path, |file| {
+                let entry = CompilationSongCacheEntry {
+                    file_rel_path: input_hash.file_rel_path,
+                    origin_mbid: song.origin_mbid().cloned(),
+                    input_hash: input_hash.hash,
+                    derived_at: now(),
+                    derived: derived.clone(),
+                    cached: None,
+                };
+                match file.compilation_song_mut(song) {
+                    Some(existing) => *existing = entry,
+                    None => file.compilation_songs.push(entry),
+                }
+            }),
+            Err(err) => self.errors.push(err),
+        }
+        derived
+    }
+
+    fn get_cached_compilation_song(
+        &self,
+        compilation_path: &Path,
+        song: &CompilationInputSong,
+        src: &metadata::song::CompilationDerivedMetadataSource,
+    ) -> Option<metadata::song::Cached> {
+        let file = load_quietly(compilation_path);
+        let entry = file.compilation_song(song)?;
+        if entry.derived.as_ref() != Some(src) {
+            return None;
+        }
+        entry
+            .cached
+            .as_ref()
+            .filter(|c| self.is_fresh(c.fetched_at))
+            .map(|c| c.metadata.clone())
+    }
+
+    async fn try_recache_compilation_song(
+        &mut self,
+        compilation_path: &Path,
+        song: &CompilationInputSong,
+        src: &metadata::song::CompilationDerivedMetadataSource,
+    ) -> Option<metadata::song::Cached> {
+        let cached = self
+            .inner
+            .try_recache_compilation_song(compilation_path, song, src)
+            .await?;
+        self.update(compilation_path, |file| {
+            // Only store the cached metadata alongside the source it was looked up from.
+            if let Some(entry) = file.compilation_song_mut(song)
+                && entry.derived.as_ref() == Some(src)
+            {
+                entry.cached = Some(Fetched {
+                    fetched_at: now(),
+                    metadata: cached.clone(),
+                });
+            }
+        });
+        Some(cached)
+    }
+
+    fn num_failed_lookups(&self) -> usize {
+        self.inner.num_failed_lookups()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NullDeriver;
+    use crate::data_model::{CompilationInputGroup, MbDiscId};
+
+    /// Derives the same source for everything (or nothing if `nothing_found`), counting how often it's asked to.
+    /// If `fails`, every lookup fails instead, like a MusicBrainz lookup without a network.
+    #[derive(Default)]
+    struct CountingDeriver {
+        nothing_found: bool,
+        fails: bool,
+        album_lookups: usize,
+        song_lookups: usize,
+        failed_lookups: usize,
+    }
+
+    impl CountingDeriver {
+        fn lookup(&mut self) -> bool {
+            if self.fails {
+                self.failed_lookups += 1;
+            }
+            !self.fails && !self.nothing_found
+        }
+    }
+
+    #[async_trait]
+    impl MetadataDeriver for CountingDeriver {
+        async fn try_rederive_album(
+            &mut self,
+            _album_path: &Path,
+            _album: &AlbumInputGroup,
+        ) -> Option<metadata::album::DerivedMetadataSource> {
+            self.album_lookups += 1;
+            if !self.lookup() {
+                return None;
+            }
+            Some(metadata::album::DerivedMetadataSource {
+                mb_release_group_and_release_ids: Some((MbId::new("rg"), MbId::new("rel"))),
+                derived_songs: vec![],
+            })
+        }
+
+        async fn try_rederive_compilation_song(
+            &mut self,
+            _compilation_path: &Path,
+            _song: &CompilationInputSong,
+        ) -> Option<metadata::song::CompilationDerivedMetadataSource> {
+            self.song_lookups += 1;
+            if !self.lookup() {
+                return None;
+            }
+            Some(metadata::song::CompilationDerivedMetadataSource {
+                chromaprint: None,
+                mb_recording_id: Some(MbId::new("recording")),
+            })
+        }
+
+        fn num_failed_lookups(&self) -> usize {
+            self.failed_lookups
+        }
+    }
+
+    fn stored_deriver(max_age_days: Option<u64>) -> StoredDeriver<CountingDeriver> {
+        StoredDeriver::new(
+            CountingDeriver::default(),
+            &CacheConfig { max_age_days },
+            Default::default(),
+        )
+    }
+
+    fn nothing_found_deriver(max_age_days: Option<u64>) -> StoredDeriver<CountingDeriver> {
+        let inner = CountingDeriver {
+            nothing_found: true,
+            ..Default::default()
+        };
+        StoredDeriver::new(inner, &CacheConfig { max_age_days }, Default::default())
+    }
+
+    fn failing_deriver() -> StoredDeriver<CountingDeriver> {
+        let inner = CountingDeriver {
+            fails: true,
+            ..Default::default()
+        };
+        StoredDeriver::new(inner, &CacheConfig::default(), Default::default())
+    }
+
+    /// Find the derived source like the resolver does, preferring the stored one
+    async fn derive_album(
+        deriver: &mut StoredDeriver<CountingDeriver>,
+        dir: &Path,
+        album: &AlbumInputGroup,
+    ) -> Option<metadata::album::DerivedMetadataSource> {
+        match deriver.get_derived_album(dir, album) {
+            Some(derived) => Some(derived),
+            None => deriver.try_rederive_album(dir, album).await,
+        }
+    }
+
+    async fn derive_song(
+        deriver: &mut StoredDeriver<CountingDeriver>,
+        dir: &Path,
+        song: &CompilationInputSong,
+    ) -> Option<metadata::song::CompilationDerivedMetadataSource> {
+        match deriver.get_derived_compilation_song(dir, song) {
+            Some(derived) => Some(derived),
+            None => deriver.try_rederive_compilation_song(dir, song).await,
+        }
+    }
+
+    fn album(dir: &Path, origin: Origin, contents: &[u8]) -> AlbumInputGroup {
+        AlbumInputGroup::for_test(dir, origin, None, &[("01.flac", contents)])
+    }
+
+    /// Pretend every entry in the group's cache file was derived `secs` ago
+    fn age_entries(dir: &Path, secs: u64) {
+        let mut file = GroupCacheFile::load(dir).unwrap();
+        if let Some(album) = &mut file.album {
+            album.derived_at -= secs;
+        }
+        for song in &mut file.compilation_songs {
+            song.derived_at -= secs;
+        }
+        file.save(dir).unwrap();
+    }
+
+    const DAY_SECS: u64 = 24 * 60 * 60;
+
+    #[tokio::test]
+    async fn unchanged_albums_are_not_looked_up_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = album(dir.path(), Origin::default(), b"song");
+        let mut deriver = stored_deriver(None);
+        let derived = derive_album(&mut deriver, dir.path(), &album).await;
+        assert!(derived.is_some());
+
+        // A new run, which only has the cache file to go on
+        let mut deriver = stored_deriver(None);
+        assert_eq!(
+            derive_album(&mut deriver, dir.path(), &album).await,
+            derived
+        );
+        assert_eq!(deriver.inner.album_lookups, 0);
+        assert!(deriver.take_errors().is_empty());
+    }
+
+    #[tokio::test]
+    async fn changed_origins_are_looked_up_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut deriver = stored_deriver(None);
+        derive_album(
+            &mut deriver,
+            dir.path(),
+            &album(dir.path(), Origin::default(), b"song"),
+        )
+        .await;
+
+        let origin = Origin {
+            mb_discid: Some(MbDiscId::new("disc")),
+            ..Default::default()
+        };
+        let album = album(dir.path(), origin, b"song");
+        let mut deriver = stored_deriver(None);
+        derive_album(&mut deriver, dir.path(), &album).await;
+        assert_eq!(deriver.inner.album_lookups, 1);
+        // and the new entry is stored
+        derive_album(&mut deriver, dir.path(), &album).await;
+        assert_eq!(deriver.inner.album_lookups, 1);
+    }
+
+    #[tokio::test]
+    async fn replaced_files_are_looked_up_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut deriver = stored_deriver(None);
+        derive_album(
+            &mut deriver,
+            dir.path(),
+            &album(dir.path(), Origin::default(), b"song"),
+        )
+        .await;
+
+        let album = album(dir.path(), Origin::default(), b"a different rip");
+        let mut deriver = stored_deriver(None);
+        derive_album(&mut deriver, dir.path(), &album).await;
+        assert_eq!(deriver.inner.album_lookups, 1);
+    }
+
+    #[tokio::test]
+    async fn old_entries_are_looked_up_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = album(dir.path(), Origin::default(), b"song");
+        derive_album(&mut stored_deriver(None), dir.path(), &album).await;
+        age_entries(dir.path(), 2 * DAY_SECS);
+
+        // Old entries are fine without a maximum age, and younger than it
+        let mut deriver = stored_deriver(None);
+        derive_album(&mut deriver, dir.path(), &album).await;
+        let mut deriver = stored_deriver(Some(3));
+        derive_album(&mut deriver, dir.path(), &album).await;
+        assert_eq!(deriver.inner.album_lookups, 0);
+
+        let mut deriver = stored_deriver(Some(1));
+        derive_album(&mut deriver, dir.path(), &album).await;
+        assert_eq!(deriver.inner.album_lookups, 1);
+        derive_album(&mut deriver, dir.path(), &album).await;
+        assert_eq!(deriver.inner.album_lookups, 1);
+    }
+
+    #[tokio::test]
+    async fn compilation_songs_are_looked_up_when_they_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let songs = [("a.flac", &b"a"[..], None), ("b.flac", &b"b"[..], None)];
+        let compilation = CompilationInputGroup::for_test(dir.path(), &songs);
+        let mut deriver = stored_deriver(None);
+        for song in compilation.songs() {
+            derive_song(&mut deriver, dir.path(), song).await;
+        }
+        assert_eq!(deriver.inner.song_lookups, 2);
+
+        // Unchanged
+        let mut deriver = stored_deriver(None);
+        for song in compilation.songs() {
+            derive_song(&mut deriver, dir.path(), song).await;
+        }
+        assert_eq!(deriver.inner.song_lookups, 0);
+
+        // a.flac is given an origin MBID, and b.flac is replaced
+        let songs = [
+            ("a.flac", &b"a"[..], Some("recording")),
+            ("b.flac", &b"new b"[..], None),
+        ];
+        let compilation = CompilationInputGroup::for_test(dir.path(), &songs);
+        let mut deriver = stored_deriver(None);
+        for song in compilation.songs() {
+            derive_song(&mut deriver, dir.path(), song).await;
+        }
+        assert_eq!(deriver.inner.song_lookups, 2);
+
+        // Too old
+        age_entries(dir.path(), 2 * DAY_SECS);
+        let mut deriver = stored_deriver(Some(1));
+        derive_song(&mut deriver, dir.path(), &compilation.songs()[0]).await;
+        assert_eq!(deriver.inner.song_lookups, 1);
+    }
+
+    #[tokio::test]
+    async fn albums_with_nothing_found_are_not_looked_up_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = album(dir.path(), Origin::default(), b"song");
+        let mut deriver = nothing_found_deriver(None);
+        assert_eq!(derive_album(&mut deriver, dir.path(), &album).await, None);
+        assert_eq!(deriver.inner.album_lookups, 1);
+
+        let mut deriver = nothing_found_deriver(None);
+        assert_eq!(derive_album(&mut deriver, dir.path(), &album).await, None);
+        assert_eq!(deriver.inner.album_lookups, 0);
+        assert!(deriver.take_errors().is_empty());
+
+        // Until the entry is too old, or the album changes
+        age_entries(dir.path(), 2 * DAY_SECS);
+        let mut deriver = nothing_found_deriver(Some(1));
+        derive_album(&mut deriver, dir.path(), &album).await;
+        assert_eq!(deriver.inner.album_lookups, 1);
+
+        let origin = Origin {
+            mb_discid: Some(MbDiscId::new("disc")),
+            ..Default::default()
+        };
+        let album = self::album(dir.path(), origin, b"song");
+        let mut deriver = stored_deriver(None);
+        assert!(
+            derive_album(&mut deriver, dir.path(), &album)
+                .await
+                .is_some()
+        );
+        assert_eq!(deriver.inner.album_lookups, 1);
+    }
+
+    #[tokio::test]
+    async fn compilation_songs_with_nothing_found_are_not_looked_up_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let compilation = CompilationInputGroup::for_test(dir.path(), &[("a.flac", b"a", None)]);
+        let song = &compilation.songs()[0];
+        let mut deriver = nothing_found_deriver(None);
+        assert!(!deriver.is_compilation_song_fresh(dir.path(), song));
+        assert_eq!(derive_song(&mut deriver, dir.path(), song).await, None);
+        assert_eq!(deriver.inner.song_lookups, 1);
+
+        let mut deriver = nothing_found_deriver(None);
+        assert!(deriver.is_compilation_song_fresh(dir.path(), song));
+        assert_eq!(derive_song(&mut deriver, dir.path(), song).await, None);
+        assert_eq!(deriver.inner.song_lookups, 0);
+
+        let compilation =
+            CompilationInputGroup::for_test(dir.path(), &[("a.flac", b"new a", None)]);
+        let mut deriver = stored_deriver(None);
+        assert!(
+            derive_song(&mut deriver, dir.path(), &compilation.songs()[0])
+                .await
+                .is_some()
+        );
+        assert_eq!(deriver.inner.song_lookups, 1);
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_are_not_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = album(dir.path(), Origin::default(), b"song");
+        let mut deriver = failing_deriver();
+        assert_eq!(derive_album(&mut deriver, dir.path(), &album).await, None);
+        assert!(GroupCacheFile::load(dir.path()).unwrap().album.is_none());
+
+        // so the next run tries again
+        let mut deriver = stored_deriver(None);
+        assert!(
+            derive_album(&mut deriver, dir.path(), &album)
+                .await
+                .is_some()
+        );
+        assert_eq!(deriver.inner.album_lookups, 1);
+
+        let compilation = CompilationInputGroup::for_test(dir.path(), &[("a.flac", b"a", None)]);
+        let song = &compilation.songs()[0];
+        let mut deriver = failing_deriver();
+        assert_eq!(derive_song(&mut deriver, dir.path(), song).await, None);
+        assert!(!deriver.is_compilation_song_fresh(dir.path(), song));
+        assert!(
+            GroupCacheFile::load(dir.path())
+                .unwrap()
+                .compilation_songs
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn read_only_lookups_are_not_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = album(dir.path(), Origin::default(), b"song");
+        let compilation = CompilationInputGroup::for_test(dir.path(), &[("a.flac", b"a", None)]);
+        let song = &compilation.songs()[0];
+
+        // An offline run, which can't find anything
+        let mut deriver =
+            StoredDeriver::read_only(NullDeriver, &CacheConfig::default(), Default::default());
+        assert_eq!(deriver.try_rederive_album(dir.path(), &album).await, None);
+        assert_eq!(
+            deriver
+                .try_rederive_compilation_song(dir.path(), song)
+                .await,
+            None
+        );
+        assert!(!GroupCacheFile::path_for_group(dir.path()).exists());
+
+        // so the next online run still looks everything up
+        let mut deriver = stored_deriver(None);
+        assert!(
+            derive_album(&mut deriver, dir.path(), &album)
+                .await
+                .is_some()
+        );
+        assert!(derive_song(&mut deriver, dir.path(), song).await.is_some());
+        assert_eq!(deriver.inner.album_lookups, 1);
+        assert_eq!(deriver.inner.song_lookups, 1);
+    }
+}